@@ -0,0 +1,874 @@
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
+use py27_marshal::bstr::BString;
+use py27_marshal::Obj;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use unfuck::smallvm::VmVar;
+
+/// Lists created by `range()` and strings created by repetition larger than
+/// this are refused rather than allocated
+const MAX_SEQUENCE_LEN: usize = 1 << 24;
+
+/// Shifts which would produce an int with more bits than this are refused
+/// rather than allocated
+const MAX_INT_BITS: u64 = 8 * MAX_SEQUENCE_LEN as u64;
+
+/// Errors raised by builtins. `TypeError`, `ValueError`, `KeyError`, and
+/// `ZeroDivisionError` correspond to the Python exceptions of the same name.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BuiltinError {
+    #[error("TypeError: {0}")]
+    TypeError(String),
+    #[error("ValueError: {0}")]
+    ValueError(String),
+    #[error("KeyError: {0}")]
+    KeyError(String),
+    #[error("ZeroDivisionError: {0}")]
+    ZeroDivisionError(String),
+    #[error("unsupported function: {0}")]
+    UnknownFunction(String),
+    #[error("argument {1} to {0}() is not known to the VM")]
+    UnknownArgument(String, usize),
+}
+
+/// A builtin function. Arguments are in call order.
+pub type BuiltinFn = fn(&[Obj]) -> Result<Obj, BuiltinError>;
+
+/// A registry of pure Python 2.7 builtins which may be called by code running
+/// in the small VM
+#[derive(Clone)]
+pub struct Builtins {
+    functions: HashMap<&'static str, BuiltinFn>,
+}
+
+impl Builtins {
+    /// Returns a registry containing every builtin implemented here
+    pub fn python27() -> Builtins {
+        let mut functions: HashMap<&'static str, BuiltinFn> = HashMap::new();
+        functions.insert("chr", chr);
+        functions.insert("ord", ord);
+        functions.insert("len", len);
+        functions.insert("int", int);
+        functions.insert("str", str);
+        functions.insert("reversed", reversed);
+        functions.insert("zip", zip);
+        functions.insert("range", range);
+
+        // Helpers from the `operator` module
+        for (names, f) in [
+            (&["xor", "__xor__"][..], op_xor as BuiltinFn),
+            (&["and_", "__and__"][..], op_and),
+            (&["or_", "__or__"][..], op_or),
+            (&["lshift", "__lshift__"][..], op_lshift),
+            (&["rshift", "__rshift__"][..], op_rshift),
+            (&["add", "__add__"][..], op_add),
+            (&["sub", "__sub__"][..], op_sub),
+            (&["mul", "__mul__"][..], op_mul),
+            (&["mod", "__mod__"][..], op_mod),
+            (&["inv", "invert", "__inv__", "__invert__"][..], op_invert),
+            (&["neg", "__neg__"][..], op_neg),
+        ] {
+            for name in names {
+                functions.insert(name, f);
+            }
+        }
+
+        Builtins { functions }
+    }
+
+    /// Calls the builtin `name` with `args` in call order
+    pub fn call(&self, name: &str, args: &[Obj]) -> Result<Obj, BuiltinError> {
+        let f = self
+            .functions
+            .get(name)
+            .ok_or_else(|| BuiltinError::UnknownFunction(name.to_string()))?;
+
+        f(args)
+    }
+
+    /// Calls the builtin `name` with arguments as passed to the VM's function
+    /// callback, which pops them off of the stack and therefore provides them
+    /// in reverse order
+    pub fn call_vm(&self, name: &[u8], args: Vec<VmVar>) -> Result<VmVar, BuiltinError> {
+        let name = String::from_utf8_lossy(name);
+        let args = args
+            .into_iter()
+            .rev()
+            .enumerate()
+            .map(|(i, arg)| arg.ok_or_else(|| BuiltinError::UnknownArgument(name.to_string(), i)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.call(&name, &args).map(Some)
+    }
+
+    /// Whether a builtin called `name` exists
+    pub fn contains(&self, name: &[u8]) -> bool {
+        std::str::from_utf8(name).is_ok_and(|name| self.functions.contains_key(name))
+    }
+}
+
+/// Returns the items produced by iterating over `obj`. Strings yield
+/// single-character strings.
+pub fn iter_items(obj: &Obj) -> Result<Vec<Obj>, BuiltinError> {
+    sequence_items("iter", obj).map_err(|_| {
+        BuiltinError::TypeError(format!("'{}' object is not iterable", type_name(obj)))
+    })
+}
+
+fn type_name(obj: &Obj) -> &'static str {
+    match obj {
+        Obj::None => "NoneType",
+        Obj::StopIteration => "StopIteration",
+        Obj::Ellipsis => "ellipsis",
+        Obj::Bool(_) => "bool",
+        Obj::Long(_) => "int",
+        Obj::Float(_) => "float",
+        Obj::Complex(_) => "complex",
+        Obj::Bytes(_) | Obj::String(_) => "str",
+        Obj::Tuple(_) => "tuple",
+        Obj::List(_) => "list",
+        Obj::Dict(_) => "dict",
+        Obj::Set(_) => "set",
+        Obj::FrozenSet(_) => "frozenset",
+        Obj::Code(_) => "code",
+    }
+}
+
+fn expect_args(name: &str, args: &[Obj], min: usize, max: usize) -> Result<(), BuiltinError> {
+    if (min..=max).contains(&args.len()) {
+        return Ok(());
+    }
+
+    let expected = if min == max {
+        format!("exactly {}", min)
+    } else if args.len() < min {
+        format!("at least {}", min)
+    } else {
+        format!("at most {}", max)
+    };
+
+    Err(BuiltinError::TypeError(format!(
+        "{}() takes {} argument{} ({} given)",
+        name,
+        expected,
+        if min == max && min == 1 { "" } else { "s" },
+        args.len()
+    )))
+}
+
+/// Returns the value of an int or bool
+fn as_int(obj: &Obj) -> Option<BigInt> {
+    match obj {
+        Obj::Long(value) => Some(BigInt::clone(value)),
+        Obj::Bool(value) => Some(BigInt::from(*value as u8)),
+        _ => None,
+    }
+}
+
+fn expect_int(name: &str, obj: &Obj) -> Result<BigInt, BuiltinError> {
+    as_int(obj).ok_or_else(|| {
+        BuiltinError::TypeError(format!(
+            "{}() integer argument expected, got {}",
+            name,
+            type_name(obj)
+        ))
+    })
+}
+
+fn expect_usize(name: &str, value: &BigInt) -> Result<usize, BuiltinError> {
+    value
+        .to_usize()
+        .ok_or_else(|| BuiltinError::ValueError(format!("{}() argument out of range", name)))
+}
+
+/// Returns the contents of a string
+fn as_str(obj: &Obj) -> Option<&[u8]> {
+    match obj {
+        Obj::String(s) => Some(s.as_slice()),
+        Obj::Bytes(s) => Some(s.as_slice()),
+        _ => None,
+    }
+}
+
+fn long(value: impl Into<BigInt>) -> Obj {
+    Obj::Long(Arc::new(value.into()))
+}
+
+fn string(data: Vec<u8>) -> Obj {
+    Obj::String(Arc::new(BString::from(data)))
+}
+
+fn list(items: Vec<Obj>) -> Obj {
+    Obj::List(Arc::new(RwLock::new(items)))
+}
+
+/// Returns the items of a sequence. Strings yield single-character strings.
+fn sequence_items(name: &str, obj: &Obj) -> Result<Vec<Obj>, BuiltinError> {
+    if let Some(s) = as_str(obj) {
+        return Ok(s.iter().map(|b| string(vec![*b])).collect());
+    }
+
+    match obj {
+        Obj::Tuple(items) => Ok(items.to_vec()),
+        Obj::List(items) => Ok(items.read().unwrap().clone()),
+        other => Err(BuiltinError::TypeError(format!(
+            "{}() argument must be a sequence, not {}",
+            name,
+            type_name(other)
+        ))),
+    }
+}
+
+/// `chr(i)`: returns a string of one character whose ordinal is `i`
+fn chr(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("chr", args, 1, 1)?;
+    let value = expect_int("chr", &args[0])?;
+    let byte = value
+        .to_u8()
+        .ok_or_else(|| BuiltinError::ValueError("chr() arg not in range(256)".to_string()))?;
+
+    Ok(string(vec![byte]))
+}
+
+/// `ord(c)`: returns the ordinal of a one-character string
+fn ord(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("ord", args, 1, 1)?;
+    match as_str(&args[0]) {
+        Some([c]) => Ok(long(*c)),
+        Some(s) => Err(BuiltinError::TypeError(format!(
+            "ord() expected a character, but string of length {} found",
+            s.len()
+        ))),
+        None => Err(BuiltinError::TypeError(format!(
+            "ord() expected string of length 1, but {} found",
+            type_name(&args[0])
+        ))),
+    }
+}
+
+/// `len(s)`: returns the number of items in a container
+fn len(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("len", args, 1, 1)?;
+    let len = match &args[0] {
+        Obj::String(s) => s.len(),
+        Obj::Bytes(s) => s.len(),
+        Obj::Tuple(items) => items.len(),
+        Obj::List(items) => items.read().unwrap().len(),
+        Obj::Dict(items) => items.read().unwrap().len(),
+        Obj::Set(items) => items.read().unwrap().len(),
+        Obj::FrozenSet(items) => items.len(),
+        other => {
+            return Err(BuiltinError::TypeError(format!(
+                "object of type '{}' has no len()",
+                type_name(other)
+            )))
+        }
+    };
+
+    Ok(long(len))
+}
+
+/// `int(x[, base])`: converts a number or string to an int
+fn int(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("int", args, 0, 2)?;
+    let value = match args {
+        [] => BigInt::zero(),
+        [Obj::Float(f)] => {
+            if !f.is_finite() {
+                return Err(BuiltinError::ValueError(
+                    "cannot convert float NaN or infinity to integer".to_string(),
+                ));
+            }
+            // Python truncates towards zero
+            BigInt::from_f64(f.trunc()).expect("finite floats convert to ints")
+        }
+        [s] if as_str(s).is_some() => parse_int(as_str(s).unwrap(), 10)?,
+        [s, base] if as_str(s).is_some() => {
+            let s = as_str(s).unwrap();
+            let base = expect_int("int", base)?;
+            match base.to_u32() {
+                Some(base) if base == 0 || (2..=36).contains(&base) => parse_int(s, base)?,
+                _ => {
+                    return Err(BuiltinError::ValueError(
+                        "int() base must be >= 2 and <= 36".to_string(),
+                    ))
+                }
+            }
+        }
+        [value] => as_int(value).ok_or_else(|| {
+            BuiltinError::TypeError(format!(
+                "int() argument must be a string or a number, not '{}'",
+                type_name(value)
+            ))
+        })?,
+        [_, _] => {
+            return Err(BuiltinError::TypeError(
+                "int() can't convert non-string with explicit base".to_string(),
+            ))
+        }
+        _ => unreachable!("argument count was checked"),
+    };
+
+    Ok(long(value))
+}
+
+/// Parses an int literal the way `int()` does. A `base` of 0 means the base is
+/// taken from the literal's prefix.
+fn parse_int(s: &[u8], base: u32) -> Result<BigInt, BuiltinError> {
+    let invalid = || {
+        BuiltinError::ValueError(format!(
+            "invalid literal for int() with base {}: {:?}",
+            base,
+            String::from_utf8_lossy(s)
+        ))
+    };
+
+    let text = std::str::from_utf8(s).map_err(|_| invalid())?.trim();
+    // Python 2 allows whitespace between the sign and the digits
+    let (negative, digits) = match text.as_bytes().first() {
+        Some(b'-') => (true, text[1..].trim_start()),
+        Some(b'+') => (false, text[1..].trim_start()),
+        _ => (false, text),
+    };
+
+    let lower = digits.to_ascii_lowercase();
+    let prefixed = |prefix: &str, prefix_base: u32| {
+        (base == 0 || base == prefix_base)
+            .then(|| lower.strip_prefix(prefix))
+            .flatten()
+            .map(|rest| (prefix_base, rest.to_string()))
+    };
+    let (base, digits) = prefixed("0x", 16)
+        .or_else(|| prefixed("0o", 8))
+        .or_else(|| prefixed("0b", 2))
+        .unwrap_or_else(|| match base {
+            // Python 2 treats a leading zero as an octal literal
+            0 if lower.len() > 1 && lower.starts_with('0') => (8, lower[1..].to_string()),
+            0 => (10, lower.clone()),
+            base => (base, lower.clone()),
+        });
+
+    // `BigInt::parse_bytes` accepts `_` separators and a sign, which `int()`
+    // doesn't
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(base)) {
+        return Err(invalid());
+    }
+
+    let value = BigInt::parse_bytes(digits.as_bytes(), base).ok_or_else(invalid)?;
+    Ok(if negative { -value } else { value })
+}
+
+/// `str(x)`: returns the string form of an object
+fn str(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("str", args, 0, 1)?;
+    let s = match args.first() {
+        None => vec![],
+        Some(obj) if as_str(obj).is_some() => as_str(obj).unwrap().to_vec(),
+        Some(Obj::Long(value)) => value.to_string().into_bytes(),
+        Some(Obj::Bool(true)) => b"True".to_vec(),
+        Some(Obj::Bool(false)) => b"False".to_vec(),
+        Some(Obj::None) => b"None".to_vec(),
+        Some(other) => {
+            return Err(BuiltinError::TypeError(format!(
+                "str() of {} is not supported",
+                type_name(other)
+            )))
+        }
+    };
+
+    Ok(string(s))
+}
+
+/// `reversed(seq)`: returns the items of a sequence in reverse order. The VM
+/// has no iterator objects, so the items are returned as a list.
+fn reversed(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("reversed", args, 1, 1)?;
+    let mut items = sequence_items("reversed", &args[0])?;
+    items.reverse();
+
+    Ok(list(items))
+}
+
+/// `zip(seq1, ...)`: returns a list of tuples, truncated to the length of the
+/// shortest sequence
+fn zip(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    let sequences = args
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            sequence_items("zip", arg).map_err(|_| {
+                BuiltinError::TypeError(format!("zip argument #{} must support iteration", i + 1))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let len = sequences.iter().map(Vec::len).min().unwrap_or(0);
+    let tuples = (0..len)
+        .map(|i| {
+            Obj::Tuple(Arc::new(
+                sequences.iter().map(|seq| seq[i].clone()).collect(),
+            ))
+        })
+        .collect();
+
+    Ok(list(tuples))
+}
+
+/// `range([start,] stop[, step])`: returns a list of ints
+fn range(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("range", args, 1, 3)?;
+    let ints = args
+        .iter()
+        .map(|arg| expect_int("range", arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (start, stop, step) = match ints.as_slice() {
+        [stop] => (BigInt::zero(), stop.clone(), BigInt::from(1)),
+        [start, stop] => (start.clone(), stop.clone(), BigInt::from(1)),
+        [start, stop, step] => (start.clone(), stop.clone(), step.clone()),
+        _ => unreachable!("argument count was checked"),
+    };
+
+    if step.is_zero() {
+        return Err(BuiltinError::ValueError(
+            "range() step argument must not be zero".to_string(),
+        ));
+    }
+
+    // Number of items is ceil((stop - start) / step), or 0 if that's negative
+    let span = &stop - &start;
+    let len = if span.is_zero() || span.is_negative() != step.is_negative() {
+        BigInt::zero()
+    } else {
+        (span.abs() + step.abs() - 1) / step.abs()
+    };
+    let len = expect_usize("range", &len)?;
+    if len > MAX_SEQUENCE_LEN {
+        return Err(BuiltinError::ValueError(
+            "range() result has too many items".to_string(),
+        ));
+    }
+
+    let mut items = Vec::with_capacity(len);
+    let mut value = start;
+    for _ in 0..len {
+        items.push(long(value.clone()));
+        value += &step;
+    }
+
+    Ok(list(items))
+}
+
+/// Implements a binary operator from the `operator` module over ints
+fn int_binary_op(
+    name: &str,
+    args: &[Obj],
+    op: impl Fn(BigInt, BigInt) -> Result<BigInt, BuiltinError>,
+) -> Result<Obj, BuiltinError> {
+    expect_args(name, args, 2, 2)?;
+    let left = expect_int(name, &args[0])?;
+    let right = expect_int(name, &args[1])?;
+
+    op(left, right).map(long)
+}
+
+fn shift_count(name: &str, count: &BigInt) -> Result<usize, BuiltinError> {
+    if count.is_negative() {
+        return Err(BuiltinError::ValueError("negative shift count".to_string()));
+    }
+
+    expect_usize(name, count)
+}
+
+fn op_xor(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    int_binary_op("xor", args, |a, b| Ok(a ^ b))
+}
+
+fn op_and(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    int_binary_op("and_", args, |a, b| Ok(a & b))
+}
+
+fn op_or(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    int_binary_op("or_", args, |a, b| Ok(a | b))
+}
+
+fn op_lshift(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    int_binary_op("lshift", args, |a, b| {
+        let count = shift_count("lshift", &b)?;
+        if a.is_zero() {
+            return Ok(a);
+        }
+        if a.bits().saturating_add(count as u64) > MAX_INT_BITS {
+            return Err(BuiltinError::ValueError(
+                "lshift() result is too large".to_string(),
+            ));
+        }
+
+        Ok(a << count)
+    })
+}
+
+fn op_rshift(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    int_binary_op("rshift", args, |a, b| Ok(a >> shift_count("rshift", &b)?))
+}
+
+fn op_add(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("add", args, 2, 2)?;
+    match (&args[0], &args[1]) {
+        (left, right) if as_str(left).is_some() && as_str(right).is_some() => Ok(string(
+            [as_str(left).unwrap(), as_str(right).unwrap()].concat(),
+        )),
+        (Obj::Tuple(left), Obj::Tuple(right)) => Ok(Obj::Tuple(Arc::new(
+            left.iter().chain(right.iter()).cloned().collect(),
+        ))),
+        (Obj::List(left), Obj::List(right)) => {
+            let mut items = left.read().unwrap().clone();
+            items.extend(right.read().unwrap().iter().cloned());
+            Ok(list(items))
+        }
+        _ => int_binary_op("add", args, |a, b| Ok(a + b)),
+    }
+}
+
+fn op_sub(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    int_binary_op("sub", args, |a, b| Ok(a - b))
+}
+
+fn op_mul(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("mul", args, 2, 2)?;
+    // `s * n` and `n * s` repeat a string
+    let repeated = match (&args[0], &args[1]) {
+        (s, count) | (count, s) if as_str(s).is_some() && as_int(count).is_some() => {
+            Some((as_str(s).unwrap(), as_int(count).unwrap()))
+        }
+        _ => None,
+    };
+
+    match repeated {
+        Some((s, count)) => {
+            // Negative counts produce an empty string
+            let count = if count.is_negative() {
+                0
+            } else {
+                expect_usize("mul", &count)?
+            };
+            if s.len().saturating_mul(count) > MAX_SEQUENCE_LEN {
+                return Err(BuiltinError::ValueError(
+                    "repeated string is too long".to_string(),
+                ));
+            }
+
+            Ok(string(s.repeat(count)))
+        }
+        None => int_binary_op("mul", args, |a, b| Ok(a * b)),
+    }
+}
+
+fn op_mod(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    int_binary_op("mod", args, |a, b| {
+        if b.is_zero() {
+            return Err(BuiltinError::ZeroDivisionError(
+                "integer division or modulo by zero".to_string(),
+            ));
+        }
+
+        // The result takes the sign of the divisor
+        let r = &a % &b;
+        Ok(if !r.is_zero() && r.is_negative() != b.is_negative() {
+            r + b
+        } else {
+            r
+        })
+    })
+}
+
+fn op_invert(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("invert", args, 1, 1)?;
+    Ok(long(!expect_int("invert", &args[0])?))
+}
+
+fn op_neg(args: &[Obj]) -> Result<Obj, BuiltinError> {
+    expect_args("neg", args, 1, 1)?;
+    Ok(long(-expect_int("neg", &args[0])?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: &[Obj]) -> Result<Obj, BuiltinError> {
+        Builtins::python27().call(name, args)
+    }
+
+    fn s(value: &str) -> Obj {
+        string(value.as_bytes().to_vec())
+    }
+
+    fn int_value(obj: Obj) -> BigInt {
+        as_int(&obj).unwrap_or_else(|| panic!("expected an int, got {:?}", obj))
+    }
+
+    fn call_int(name: &str, args: &[Obj]) -> i64 {
+        int_value(call(name, args).unwrap()).to_i64().unwrap()
+    }
+
+    fn call_str(name: &str, args: &[Obj]) -> String {
+        let obj = call(name, args).unwrap();
+        String::from_utf8(as_str(&obj).unwrap().to_vec()).unwrap()
+    }
+
+    /// Items of a list, with ints and strings rendered as Python would
+    fn render(obj: &Obj) -> String {
+        match obj {
+            Obj::Long(value) => value.to_string(),
+            obj if as_str(obj).is_some() => {
+                format!("'{}'", String::from_utf8_lossy(as_str(obj).unwrap()))
+            }
+            Obj::Tuple(items) => format!(
+                "({})",
+                items.iter().map(render).collect::<Vec<_>>().join(", ")
+            ),
+            Obj::List(items) => format!(
+                "[{}]",
+                items
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(render)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            other => panic!("unexpected object {:?}", other),
+        }
+    }
+
+    fn is_type_error(result: Result<Obj, BuiltinError>) -> bool {
+        matches!(result, Err(BuiltinError::TypeError(_)))
+    }
+
+    fn is_value_error(result: Result<Obj, BuiltinError>) -> bool {
+        matches!(result, Err(BuiltinError::ValueError(_)))
+    }
+
+    #[test]
+    fn unknown_functions_and_arguments_are_reported() {
+        assert_eq!(
+            call("eval", &[]).unwrap_err(),
+            BuiltinError::UnknownFunction("eval".to_string())
+        );
+
+        // The VM passes arguments in reverse order
+        let builtins = Builtins::python27();
+        let result = builtins.call_vm(b"sub", vec![Some(long(3)), Some(long(10))]);
+        assert_eq!(int_value(result.unwrap().unwrap()), BigInt::from(7));
+        assert_eq!(
+            builtins
+                .call_vm(b"sub", vec![Some(long(3)), None])
+                .unwrap_err(),
+            BuiltinError::UnknownArgument("sub".to_string(), 0)
+        );
+
+        assert!(builtins.contains(b"chr"));
+        assert!(!builtins.contains(b"eval"));
+    }
+
+    #[test]
+    fn chr_and_ord() {
+        assert_eq!(call_str("chr", &[long(65)]), "A");
+        assert_eq!(call_str("chr", &[Obj::Bool(true)]), "\u{1}");
+        assert!(is_value_error(call("chr", &[long(256)])));
+        assert!(is_value_error(call("chr", &[long(-1)])));
+        assert!(is_type_error(call("chr", &[s("A")])));
+        assert!(is_type_error(call("chr", &[])));
+
+        assert_eq!(call_int("ord", &[s("A")]), 65);
+        assert!(is_type_error(call("ord", &[s("AB")])));
+        assert!(is_type_error(call("ord", &[s("")])));
+        assert!(is_type_error(call("ord", &[long(65)])));
+    }
+
+    #[test]
+    fn len_counts_items() {
+        assert_eq!(call_int("len", &[s("abc")]), 3);
+        assert_eq!(call_int("len", &[list(vec![long(1), long(2)])]), 2);
+        assert_eq!(call_int("len", &[Obj::Tuple(Arc::new(vec![]))]), 0);
+        assert!(is_type_error(call("len", &[long(1)])));
+        assert!(is_type_error(call("len", &[s("a"), s("b")])));
+    }
+
+    #[test]
+    fn int_converts_numbers_and_strings() {
+        assert_eq!(call_int("int", &[]), 0);
+        assert_eq!(call_int("int", &[long(7)]), 7);
+        assert_eq!(call_int("int", &[Obj::Bool(true)]), 1);
+        assert_eq!(call_int("int", &[Obj::Float(-2.5)]), -2);
+        assert_eq!(
+            int_value(call("int", &[Obj::Float(1e30)]).unwrap()),
+            BigInt::from(1_000_000_000_000_000_019_884_624_838_656u128)
+        );
+        assert!(is_value_error(call("int", &[Obj::Float(f64::NAN)])));
+        assert!(is_value_error(call("int", &[Obj::Float(f64::INFINITY)])));
+        assert!(is_type_error(call("int", &[Obj::None])));
+        assert!(is_type_error(call("int", &[long(10), long(16)])));
+    }
+
+    #[test]
+    fn int_parses_literals_like_python_2() {
+        let parse = |text: &str, base: i64| call("int", &[s(text), long(base)]).map(int_value);
+        let valid = [
+            (" 42\n", 10, 42),
+            ("-42", 10, -42),
+            ("- 42", 10, -42),
+            ("+7", 10, 7),
+            ("ff", 16, 255),
+            ("-0x10", 16, -16),
+            ("0x1f", 0, 31),
+            ("0o17", 0, 15),
+            ("017", 0, 15),
+            ("0b101", 0, 5),
+            ("0b", 16, 11),
+            ("08", 10, 8),
+            ("0", 0, 0),
+            ("z", 36, 35),
+        ];
+        for (text, base, expected) in valid {
+            assert_eq!(parse(text, base), Ok(BigInt::from(expected)), "{:?}", text);
+        }
+
+        let invalid = [
+            ("", 10),
+            ("-", 10),
+            ("1_0", 10),
+            ("+-5", 10),
+            ("--5", 10),
+            ("10L", 10),
+            ("0x1f", 10),
+            ("0b", 0),
+            ("08", 0),
+            ("12", 2),
+            ("1 2", 10),
+        ];
+        for (text, base) in invalid {
+            assert!(
+                matches!(parse(text, base), Err(BuiltinError::ValueError(_))),
+                "{:?} {}",
+                text,
+                base
+            );
+        }
+
+        assert_eq!(call_int("int", &[s("12")]), 12);
+        assert!(is_value_error(call("int", &[s("12"), long(1)])));
+        assert!(is_value_error(call("int", &[s("12"), long(37)])));
+        assert!(is_type_error(call("int", &[s("12"), s("10")])));
+    }
+
+    #[test]
+    fn str_formats_simple_values() {
+        assert_eq!(call_str("str", &[]), "");
+        assert_eq!(call_str("str", &[s("abc")]), "abc");
+        assert_eq!(call_str("str", &[long(-12)]), "-12");
+        assert_eq!(call_str("str", &[Obj::Bool(false)]), "False");
+        assert_eq!(call_str("str", &[Obj::None]), "None");
+        assert!(is_type_error(call("str", &[Obj::Float(1.0)])));
+    }
+
+    #[test]
+    fn sequence_builtins() {
+        let result = |name, args: &[Obj]| render(&call(name, args).unwrap());
+        assert_eq!(result("reversed", &[s("abc")]), "['c', 'b', 'a']");
+        assert_eq!(
+            result("reversed", &[list(vec![long(1), long(2)])]),
+            "[2, 1]"
+        );
+        assert!(is_type_error(call("reversed", &[long(1)])));
+
+        assert_eq!(
+            result("zip", &[s("ab"), list(vec![long(1), long(2), long(3)])]),
+            "[('a', 1), ('b', 2)]"
+        );
+        assert_eq!(result("zip", &[]), "[]");
+        assert_eq!(
+            call("zip", &[s("ab"), long(1)]).unwrap_err(),
+            BuiltinError::TypeError("zip argument #2 must support iteration".to_string())
+        );
+    }
+
+    #[test]
+    fn range_follows_python_semantics() {
+        let result = |args: &[i64]| {
+            render(&call("range", &args.iter().map(|a| long(*a)).collect::<Vec<_>>()).unwrap())
+        };
+        assert_eq!(result(&[3]), "[0, 1, 2]");
+        assert_eq!(result(&[2, 5]), "[2, 3, 4]");
+        assert_eq!(result(&[0, 10, 4]), "[0, 4, 8]");
+        assert_eq!(result(&[5, 0, -2]), "[5, 3, 1]");
+        assert_eq!(result(&[5, 0]), "[]");
+        assert_eq!(result(&[-3]), "[]");
+
+        assert!(is_value_error(call("range", &[long(0), long(5), long(0)])));
+        assert!(is_value_error(call("range", &[long(1i64 << 40)])));
+        assert!(is_type_error(call("range", &[s("5")])));
+        assert!(is_type_error(call("range", &[])));
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        assert_eq!(call_int("xor", &[long(0b1100), long(0b1010)]), 0b0110);
+        assert_eq!(call_int("__and__", &[long(0b1100), long(0b1010)]), 0b1000);
+        assert_eq!(call_int("or_", &[long(0b1100), long(0b1010)]), 0b1110);
+        assert_eq!(call_int("invert", &[long(5)]), -6);
+        assert_eq!(call_int("neg", &[long(5)]), -5);
+        assert!(is_type_error(call("xor", &[s("a"), long(1)])));
+        assert!(is_type_error(call("xor", &[long(1)])));
+    }
+
+    #[test]
+    fn shifts_are_bounded() {
+        assert_eq!(call_int("lshift", &[long(3), long(4)]), 48);
+        assert_eq!(call_int("rshift", &[long(-48), long(4)]), -3);
+        assert_eq!(call_int("rshift", &[long(1), long(1i64 << 40)]), 0);
+        assert_eq!(call_int("lshift", &[long(0), long(1i64 << 40)]), 0);
+        assert!(is_value_error(call("lshift", &[long(1), long(-1)])));
+        assert!(is_value_error(call("rshift", &[long(1), long(-1)])));
+
+        // `1 << 10**11` would need gigabytes
+        let huge = long(BigInt::from(10).pow(11));
+        assert!(is_value_error(call("lshift", &[long(1), huge])));
+        assert!(is_value_error(call(
+            "lshift",
+            &[long(1), long(MAX_INT_BITS as i64)]
+        )));
+        assert!(call("lshift", &[long(1), long(MAX_INT_BITS as i64 - 1)]).is_ok());
+    }
+
+    #[test]
+    fn arithmetic_operators() {
+        assert_eq!(call_int("add", &[long(2), long(3)]), 5);
+        assert_eq!(call_str("add", &[s("ab"), s("cd")]), "abcd");
+        assert_eq!(
+            render(&call("add", &[list(vec![long(1)]), list(vec![long(2)])]).unwrap()),
+            "[1, 2]"
+        );
+        assert!(is_type_error(call("add", &[s("a"), long(1)])));
+
+        assert_eq!(call_int("sub", &[long(2), long(3)]), -1);
+        assert_eq!(call_int("mul", &[long(4), long(-3)]), -12);
+        assert_eq!(call_str("mul", &[s("ab"), long(3)]), "ababab");
+        assert_eq!(call_str("mul", &[long(2), s("ab")]), "abab");
+        assert_eq!(call_str("mul", &[s("ab"), long(-1)]), "");
+        assert!(is_value_error(call(
+            "mul",
+            &[s("ab"), long(MAX_SEQUENCE_LEN as i64)]
+        )));
+
+        assert_eq!(call_int("mod", &[long(7), long(3)]), 1);
+        assert_eq!(call_int("mod", &[long(-7), long(3)]), 2);
+        assert_eq!(call_int("mod", &[long(7), long(-3)]), -2);
+        assert!(matches!(
+            call("mod", &[long(7), long(0)]),
+            Err(BuiltinError::ZeroDivisionError(_))
+        ));
+    }
+}
@@ -0,0 +1,660 @@
+use anyhow::{anyhow, Result};
+use cpython::{
+    exc, PyBytes, PyDict, PyErr, PyList, PyObject, PyResult, PyTuple, Python, PythonObject,
+    ToPyObject,
+};
+use num_traits::FromPrimitive;
+use py27_marshal::{Code, Obj};
+use pydis::opcode::py27::{self, Mnemonic, Standard};
+use pydis::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// Python which rebuilds each code object with its overrides applied.
+/// `overrides` holds a `(code, extra_consts, stacksize, firstlineno, lnotab)`
+/// tuple for every code object in depth-first order, where `None` keeps the
+/// code object's own value.
+const REWRITE_SOURCE: &str = r#"
+def keep(value, default):
+    return default if value is None else value
+
+def rewrite(code):
+    new_code, extra_consts, stacksize, firstlineno, lnotab = overrides.pop(0)
+    consts = [rewrite(c) if type(c) == types.CodeType else c for c in code.co_consts]
+    consts.extend(extra_consts)
+
+    return types.CodeType(code.co_argcount, code.co_nlocals, keep(stacksize, code.co_stacksize), code.co_flags, keep(new_code, code.co_code), tuple(consts), code.co_names, code.co_varnames, code.co_filename, code.co_name, keep(firstlineno, code.co_firstlineno), keep(lnotab, code.co_lnotab), code.co_freevars, code.co_cellvars)
+
+output = marshal.dumps(rewrite(marshal.loads(data)))
+"#;
+
+/// A decoded instruction along with its location in the bytecode. `EXTENDED_ARG`
+/// prefixes are folded into the argument of the instruction that follows them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedInstr<O: Opcode<Mnemonic = py27::Mnemonic>> {
+    /// Offset of the first byte of this instruction (including any `EXTENDED_ARG` prefix)
+    pub offset: u64,
+    /// Total length of this instruction in bytes (including any `EXTENDED_ARG` prefix)
+    pub len: u64,
+    pub opcode: O,
+    pub arg: Option<u32>,
+}
+
+impl<O: Opcode<Mnemonic = py27::Mnemonic>> DecodedInstr<O> {
+    /// Offset of the instruction immediately following this one
+    pub fn next_offset(&self) -> u64 {
+        self.offset + self.len
+    }
+
+    /// Returns the offset this instruction may jump to, if it is a jump
+    pub fn jump_target(&self) -> Option<u64> {
+        let arg = self.arg? as u64;
+        if self.opcode.is_relative_jump() {
+            Some(self.next_offset() + arg)
+        } else if self.opcode.is_absolute_jump() {
+            Some(arg)
+        } else {
+            None
+        }
+    }
+
+    /// Whether or not execution can never continue to the next instruction
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self.opcode.mnemonic(),
+            Mnemonic::JUMP_FORWARD
+                | Mnemonic::JUMP_ABSOLUTE
+                | Mnemonic::CONTINUE_LOOP
+                | Mnemonic::BREAK_LOOP
+                | Mnemonic::RETURN_VALUE
+                | Mnemonic::RAISE_VARARGS
+        )
+    }
+}
+
+impl<O: Opcode<Mnemonic = py27::Mnemonic>> std::fmt::Display for DecodedInstr<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.opcode.mnemonic())?;
+
+        if let Some(arg) = self.arg {
+            write!(f, " {}", arg)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes the instruction at `offset`, folding in an `EXTENDED_ARG` prefix if
+/// one is present.
+pub fn decode_at<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    bytecode: &[u8],
+    offset: u64,
+) -> Result<DecodedInstr<O>, DecodeError> {
+    let mut rdr = Cursor::new(bytecode);
+    rdr.set_position(offset);
+
+    let mut extended_arg = 0u32;
+    loop {
+        let instr = decode_py27::<O, _>(&mut rdr)?;
+        if instr.opcode.mnemonic() == Mnemonic::EXTENDED_ARG {
+            extended_arg = (extended_arg | instr.arg.unwrap_or(0) as u32) << 16;
+            continue;
+        }
+
+        return Ok(DecodedInstr {
+            offset,
+            len: rdr.position() - offset,
+            opcode: instr.opcode,
+            arg: instr.arg.map(|arg| extended_arg | arg as u32),
+        });
+    }
+}
+
+/// Looks up a mnemonic by its name, e.g. `LOAD_CONST`
+pub fn mnemonic_from_name(name: &str) -> Option<Mnemonic> {
+    (0..=u8::MAX)
+        .filter_map(Standard::from_u8)
+        .map(|opcode| opcode.mnemonic())
+        .find(|mnemonic| format!("{:?}", mnemonic) == name)
+}
+
+/// Returns the byte `mnemonic` is encoded as with the opcodes `O`. This fails
+/// if the opcode table in use has no opcode for it.
+pub fn opcode_byte<O: Opcode<Mnemonic = py27::Mnemonic>>(mnemonic: Mnemonic) -> Result<u8> {
+    O::from(mnemonic)
+        .to_u8()
+        .ok_or_else(|| anyhow!("the opcode table has no opcode for `{:?}`", mnemonic))
+}
+
+/// Unmarshals `data`, which must contain a code object
+pub fn load_code(data: &[u8]) -> Result<Arc<Code>> {
+    match py27_marshal::read::marshal_loads(data)? {
+        Obj::Code(code) => Ok(code),
+        other => Err(anyhow!(
+            "root object is a {:?}, not a code object",
+            other.typ()
+        )),
+    }
+}
+
+/// Returns `code` and all of its nested code objects in depth-first order. This
+/// is the same order `unfuck` assigns file numbers in, and deobfuscation does
+/// not change it, so the code objects of a stage and its deobfuscated version
+/// can be paired up by their position in this list.
+pub fn code_objects(code: &Arc<Code>) -> Vec<Arc<Code>> {
+    let mut out = vec![Arc::clone(code)];
+    for c in code.consts.iter() {
+        if let Obj::Code(nested) = c {
+            out.extend(code_objects(nested));
+        }
+    }
+
+    out
+}
+
+/// Changes to make to a code object with [`rewrite_code_objects`]. Fields
+/// which are `None` keep the code object's own value.
+#[derive(Debug, Clone, Default)]
+pub struct CodeOverrides {
+    pub code: Option<Vec<u8>>,
+    /// Values to append to the code object's consts. Only values which can be
+    /// consts, such as those accepted by [`is_const`](crate::pure_vm::is_const),
+    /// are supported.
+    pub extra_consts: Vec<Obj>,
+    pub stacksize: Option<u32>,
+    pub firstlineno: Option<u32>,
+    pub lnotab: Option<Vec<u8>>,
+}
+
+/// Rebuilds the code objects in the marshalled code in `data` with
+/// `overrides` applied. `overrides` holds an entry for every code object in
+/// the order of [`code_objects`]. This goes through Python's marshal so that
+/// string types and interning survive the round trip.
+pub fn rewrite_code_objects(data: &[u8], overrides: &[CodeOverrides]) -> Result<Vec<u8>> {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    run_rewrite(py, data, overrides).map_err(|e| anyhow!("failed to rewrite code: {:?}", e))
+}
+
+/// Replaces the bytecode of the code objects in the marshalled code in
+/// `data`. `codes` holds an entry for every code object in the order of
+/// [`code_objects`]: `None` to keep its bytecode, otherwise its new bytecode.
+pub fn replace_bytecode(data: &[u8], codes: &[Option<Vec<u8>>]) -> Result<Vec<u8>> {
+    let overrides: Vec<CodeOverrides> = codes
+        .iter()
+        .map(|code| CodeOverrides {
+            code: code.clone(),
+            ..Default::default()
+        })
+        .collect();
+
+    rewrite_code_objects(data, &overrides)
+}
+
+fn run_rewrite(py: Python, data: &[u8], overrides: &[CodeOverrides]) -> PyResult<Vec<u8>> {
+    let bytes = |data: &Option<Vec<u8>>| match data {
+        Some(data) => PyBytes::new(py, data.as_slice()).into_object(),
+        None => py.None(),
+    };
+    let overrides = overrides
+        .iter()
+        .map(|overrides| {
+            let extra_consts = overrides
+                .extra_consts
+                .iter()
+                .map(|value| to_py(py, value))
+                .collect::<PyResult<Vec<_>>>()?;
+            Ok((
+                bytes(&overrides.code),
+                PyList::new(py, extra_consts.as_slice()),
+                overrides.stacksize,
+                overrides.firstlineno,
+                bytes(&overrides.lnotab),
+            )
+                .to_py_object(py)
+                .into_object())
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+
+    let globals = PyDict::new(py);
+    globals.set_item(py, "__builtins__", py.import("__builtin__")?)?;
+    globals.set_item(py, "marshal", py.import("marshal")?)?;
+    globals.set_item(py, "types", py.import("types")?)?;
+    globals.set_item(py, "data", PyBytes::new(py, data))?;
+    globals.set_item(py, "overrides", PyList::new(py, overrides.as_slice()))?;
+    py.run(REWRITE_SOURCE, Some(&globals), None)?;
+
+    let output = globals
+        .get_item(py, "output")
+        .expect("the rewrite script sets `output`");
+    let output = output.cast_as::<PyBytes>(py)?.data(py).to_vec();
+
+    Ok(output)
+}
+
+/// Converts a const to a Python object
+fn to_py(py: Python, obj: &Obj) -> PyResult<PyObject> {
+    Ok(match obj {
+        Obj::None => py.None(),
+        Obj::Bool(value) => value.to_py_object(py).into_object(),
+        // Python picks between int and long
+        Obj::Long(value) => py.eval(&value.to_string(), None, None)?,
+        Obj::Float(value) => value.to_py_object(py).into_object(),
+        Obj::String(s) => PyBytes::new(py, s.as_slice()).into_object(),
+        Obj::Tuple(items) => {
+            let items = items
+                .iter()
+                .map(|item| to_py(py, item))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyTuple::new(py, items.as_slice()).into_object()
+        }
+        other => {
+            return Err(PyErr::new::<exc::TypeError, _>(
+                py,
+                format!("{:?} consts cannot be added", other.typ()),
+            ))
+        }
+    })
+}
+
+/// Returns a name for `code` which is safe to use in a file name
+pub fn sanitized_name(code: &Code) -> String {
+    code.name
+        .to_string()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Returns the net change in stack depth caused by an instruction. These match
+/// the effects CPython 2.7's compiler uses to compute `co_stacksize`, except
+/// that `JUMP_IF_*_OR_POP` pops its value when it doesn't jump. `jump` selects
+/// the effect when the instruction jumps to its target rather than continuing
+/// to the next instruction. Returns `None` for opcodes the compiler never emits.
+pub fn stack_effect(mnemonic: Mnemonic, arg: u32, jump: bool) -> Option<isize> {
+    let arg = arg as isize;
+    // Positional arguments take one slot and keyword arguments take two
+    let nargs = (arg & 0xff) + 2 * ((arg >> 8) & 0xff);
+
+    Some(match mnemonic {
+        Mnemonic::NOP
+        | Mnemonic::ROT_TWO
+        | Mnemonic::ROT_THREE
+        | Mnemonic::ROT_FOUR
+        | Mnemonic::UNARY_POSITIVE
+        | Mnemonic::UNARY_NEGATIVE
+        | Mnemonic::UNARY_NOT
+        | Mnemonic::UNARY_CONVERT
+        | Mnemonic::UNARY_INVERT
+        | Mnemonic::GET_ITER
+        | Mnemonic::SLICE_0
+        | Mnemonic::PRINT_NEWLINE
+        | Mnemonic::BREAK_LOOP
+        | Mnemonic::CONTINUE_LOOP
+        | Mnemonic::YIELD_VALUE
+        | Mnemonic::POP_BLOCK
+        | Mnemonic::DELETE_NAME
+        | Mnemonic::DELETE_GLOBAL
+        | Mnemonic::DELETE_FAST
+        | Mnemonic::LOAD_ATTR
+        | Mnemonic::SETUP_LOOP
+        | Mnemonic::JUMP_FORWARD
+        | Mnemonic::JUMP_ABSOLUTE => 0,
+        Mnemonic::POP_TOP
+        | Mnemonic::BINARY_POWER
+        | Mnemonic::BINARY_MULTIPLY
+        | Mnemonic::BINARY_DIVIDE
+        | Mnemonic::BINARY_FLOOR_DIVIDE
+        | Mnemonic::BINARY_TRUE_DIVIDE
+        | Mnemonic::BINARY_MODULO
+        | Mnemonic::BINARY_ADD
+        | Mnemonic::BINARY_SUBTRACT
+        | Mnemonic::BINARY_SUBSC
+        | Mnemonic::BINARY_LSHIFT
+        | Mnemonic::BINARY_RSHIFT
+        | Mnemonic::BINARY_AND
+        | Mnemonic::BINARY_XOR
+        | Mnemonic::BINARY_OR
+        | Mnemonic::INPLACE_POWER
+        | Mnemonic::INPLACE_MULTIPLY
+        | Mnemonic::INPLACE_DIVIDE
+        | Mnemonic::INPLACE_FLOOR_DIVIDE
+        | Mnemonic::INPLACE_TRUE_DIVIDE
+        | Mnemonic::INPLACE_MODULO
+        | Mnemonic::INPLACE_ADD
+        | Mnemonic::INPLACE_SUBTRACT
+        | Mnemonic::INPLACE_LSHIFT
+        | Mnemonic::INPLACE_RSHIFT
+        | Mnemonic::INPLACE_AND
+        | Mnemonic::INPLACE_XOR
+        | Mnemonic::INPLACE_OR
+        | Mnemonic::SLICE_1
+        | Mnemonic::SLICE_2
+        | Mnemonic::DELETE_SLICE_0
+        | Mnemonic::PRINT_EXPR
+        | Mnemonic::PRINT_ITEM
+        | Mnemonic::PRINT_NEWLINE_TO
+        | Mnemonic::LIST_APPEND
+        | Mnemonic::SET_ADD
+        | Mnemonic::WITH_CLEANUP
+        | Mnemonic::RETURN_VALUE
+        | Mnemonic::IMPORT_STAR
+        | Mnemonic::STORE_NAME
+        | Mnemonic::DELETE_ATTR
+        | Mnemonic::STORE_GLOBAL
+        | Mnemonic::COMPARE_OP
+        | Mnemonic::IMPORT_NAME
+        | Mnemonic::STORE_FAST
+        | Mnemonic::STORE_DEREF
+        | Mnemonic::POP_JUMP_IF_FALSE
+        | Mnemonic::POP_JUMP_IF_TRUE => -1,
+        Mnemonic::SLICE_3
+        | Mnemonic::STORE_SLICE_0
+        | Mnemonic::DELETE_SLICE_1
+        | Mnemonic::DELETE_SLICE_2
+        | Mnemonic::DELETE_SUBSCR
+        | Mnemonic::STORE_MAP
+        | Mnemonic::PRINT_ITEM_TO
+        | Mnemonic::MAP_ADD
+        | Mnemonic::BUILD_CLASS
+        | Mnemonic::STORE_ATTR => -2,
+        Mnemonic::STORE_SLICE_1
+        | Mnemonic::STORE_SLICE_2
+        | Mnemonic::DELETE_SLICE_3
+        | Mnemonic::STORE_SUBSCR
+        | Mnemonic::EXEC_STMT
+        | Mnemonic::END_FINALLY => -3,
+        Mnemonic::STORE_SLICE_3 => -4,
+        Mnemonic::DUP_TOP
+        | Mnemonic::LOAD_LOCALS
+        | Mnemonic::LOAD_CONST
+        | Mnemonic::LOAD_NAME
+        | Mnemonic::BUILD_MAP
+        | Mnemonic::IMPORT_FROM
+        | Mnemonic::LOAD_GLOBAL
+        | Mnemonic::LOAD_FAST
+        | Mnemonic::LOAD_CLOSURE
+        | Mnemonic::LOAD_DEREF => 1,
+        Mnemonic::SETUP_WITH => 4,
+        Mnemonic::DUP_TOPX => arg,
+        Mnemonic::UNPACK_SEQUENCE => arg - 1,
+        Mnemonic::BUILD_TUPLE | Mnemonic::BUILD_LIST | Mnemonic::BUILD_SET => 1 - arg,
+        Mnemonic::BUILD_SLICE if arg == 3 => -2,
+        Mnemonic::BUILD_SLICE => -1,
+        Mnemonic::RAISE_VARARGS => -arg,
+        Mnemonic::CALL_FUNCTION => -nargs,
+        Mnemonic::CALL_FUNCTION_VAR | Mnemonic::CALL_FUNCTION_KW => -nargs - 1,
+        Mnemonic::CALL_FUNCTION_VAR_KW => -nargs - 2,
+        Mnemonic::MAKE_FUNCTION => -arg,
+        Mnemonic::MAKE_CLOSURE => -arg - 1,
+        // The value tested is popped if execution continues
+        Mnemonic::JUMP_IF_FALSE_OR_POP | Mnemonic::JUMP_IF_TRUE_OR_POP if jump => 0,
+        Mnemonic::JUMP_IF_FALSE_OR_POP | Mnemonic::JUMP_IF_TRUE_OR_POP => -1,
+        // The exhausted iterator is popped
+        Mnemonic::FOR_ITER if jump => -1,
+        Mnemonic::FOR_ITER => 1,
+        // The exception type, value, and traceback are pushed before jumping
+        // to the handler
+        Mnemonic::SETUP_EXCEPT | Mnemonic::SETUP_FINALLY if jump => 3,
+        Mnemonic::SETUP_EXCEPT | Mnemonic::SETUP_FINALLY => 0,
+        _ => return None,
+    })
+}
+
+/// Result of simulating the stack depth of a function with [`stack_depths`]
+#[derive(Debug, Default)]
+pub struct StackDepths {
+    /// Deepest the stack gets on any path which stays within the limit
+    pub max_depth: isize,
+    /// The first instruction found which pops more values than are on the
+    /// stack, and the depth it leaves the stack at
+    pub underflow: Option<(u64, isize)>,
+    /// The first instruction found which grows the stack past the limit, and
+    /// the depth it grows it to
+    pub overflow: Option<(u64, isize)>,
+    /// The first instruction found after which execution continues past the
+    /// end of the code
+    pub falls_off_end: Option<u64>,
+    /// Reachable instructions with no known stack effect
+    pub unknown_effects: BTreeSet<u64>,
+}
+
+impl StackDepths {
+    /// Whether every path was simulated without any problems
+    pub fn is_clean(&self) -> bool {
+        self.underflow.is_none()
+            && self.overflow.is_none()
+            && self.falls_off_end.is_none()
+            && self.unknown_effects.is_empty()
+    }
+}
+
+/// Walks every path through `instrs` from the entry point the same way
+/// CPython's compiler does when it computes `co_stacksize`, keeping the
+/// deepest stack each instruction can be reached with. Paths which underflow
+/// or grow the stack past `limit` are not followed any further. Jumps to
+/// offsets which are not in `instrs` are ignored.
+pub fn stack_depths<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    instrs: &BTreeMap<u64, DecodedInstr<O>>,
+    code_len: u64,
+    limit: isize,
+) -> StackDepths {
+    let mut result = StackDepths::default();
+    let mut depths: HashMap<u64, isize> = HashMap::new();
+    let mut queue = vec![(0u64, 0isize)];
+    while let Some((offset, depth)) = queue.pop() {
+        let instr = match instrs.get(&offset) {
+            Some(instr) => instr,
+            None => continue,
+        };
+        if depths.get(&offset).is_some_and(|seen| *seen >= depth) {
+            continue;
+        }
+        depths.insert(offset, depth);
+
+        let mnemonic = instr.opcode.mnemonic();
+        let arg = instr.arg.unwrap_or(0);
+        let (fallthrough, jump) = match (
+            stack_effect(mnemonic, arg, false),
+            stack_effect(mnemonic, arg, true),
+        ) {
+            (Some(fallthrough), Some(jump)) => (fallthrough, jump),
+            _ => {
+                result.unknown_effects.insert(offset);
+                continue;
+            }
+        };
+
+        let mut successors = Vec::with_capacity(2);
+        if !instr.is_terminator() {
+            if instr.next_offset() >= code_len {
+                result.falls_off_end.get_or_insert(offset);
+            } else {
+                successors.push((instr.next_offset(), depth + fallthrough));
+            }
+        }
+        if let Some(target) = instr.jump_target() {
+            successors.push((target, depth + jump));
+        }
+
+        for (successor, depth) in successors {
+            if depth < 0 {
+                result.underflow.get_or_insert((offset, depth));
+                continue;
+            }
+            if depth > limit {
+                // Stop following the path so that loops which keep growing
+                // the stack terminate
+                result.overflow.get_or_insert((offset, depth));
+                continue;
+            }
+
+            result.max_depth = result.max_depth.max(depth);
+            queue.push((successor, depth));
+        }
+    }
+
+    result
+}
+
+/// Helpers for building bytecode in tests
+#[cfg(test)]
+pub mod testing {
+    use cpython::{PyBytes, PyDict, Python};
+    use num_traits::ToPrimitive;
+    use py27_marshal::bstr::BString;
+    use py27_marshal::{Code, CodeFlags, Obj};
+    use pydis::opcode::py27::Standard;
+    use std::sync::Arc;
+
+    /// Compiles Python 2.7 `source` and returns the marshalled module code
+    pub fn compile(source: &str) -> Vec<u8> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let globals = PyDict::new(py);
+        globals
+            .set_item(py, "__builtins__", py.import("__builtin__").unwrap())
+            .unwrap();
+        globals
+            .set_item(py, "marshal", py.import("marshal").unwrap())
+            .unwrap();
+        globals.set_item(py, "source", source).unwrap();
+        py.run(
+            "output = marshal.dumps(compile(source, 'test.py', 'exec'))",
+            Some(&globals),
+            None,
+        )
+        .unwrap();
+
+        let output = globals.get_item(py, "output").unwrap();
+        let output = output.cast_as::<PyBytes>(py).unwrap().data(py).to_vec();
+
+        output
+    }
+
+    /// Encodes each opcode followed by its 16-bit argument, if it has one
+    pub fn assemble(instrs: &[(Standard, Option<u16>)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (opcode, arg) in instrs {
+            out.push(opcode.to_u8().unwrap());
+            if let Some(arg) = arg {
+                out.extend_from_slice(&arg.to_le_bytes());
+            }
+        }
+
+        out
+    }
+
+    /// Builds a module-level code object around `code` with the given
+    /// constants, names, and local variable names
+    pub fn make_code(code: Vec<u8>, consts: Vec<Obj>, names: &[&str], varnames: &[&str]) -> Code {
+        let strings = |values: &[&str]| {
+            values
+                .iter()
+                .map(|value| Arc::new(BString::from(*value)))
+                .collect()
+        };
+
+        Code {
+            argcount: 0,
+            nlocals: varnames.len() as u32,
+            stacksize: 4,
+            flags: CodeFlags::empty(),
+            code: Arc::new(code),
+            consts: Arc::new(consts),
+            names: strings(names),
+            varnames: strings(varnames),
+            freevars: vec![],
+            cellvars: vec![],
+            filename: Arc::new(BString::from("test")),
+            name: Arc::new(BString::from("test")),
+            firstlineno: 1,
+            lnotab: Arc::new(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::compile;
+    use super::*;
+    use num_bigint::BigInt;
+
+    #[test]
+    fn rewriting_applies_overrides_and_keeps_everything_else() {
+        let data = compile("def f():\n    return 1\nx = f()\n");
+        let module = load_code(&data).unwrap();
+        let function = &code_objects(&module)[1];
+
+        let new_code = vec![opcode_byte::<Standard>(Mnemonic::NOP).unwrap()]
+            .into_iter()
+            .chain(function.code.iter().copied())
+            .collect::<Vec<u8>>();
+        let overrides = [
+            CodeOverrides::default(),
+            CodeOverrides {
+                code: Some(new_code.clone()),
+                extra_consts: vec![Obj::Long(Arc::new(BigInt::from(7)))],
+                stacksize: Some(9),
+                firstlineno: Some(5),
+                lnotab: Some(vec![1, 1]),
+            },
+        ];
+        let rewritten = load_code(&rewrite_code_objects(&data, &overrides).unwrap()).unwrap();
+        let rewritten_function = &code_objects(&rewritten)[1];
+
+        assert_eq!(rewritten.code, module.code);
+        assert_eq!(rewritten.stacksize, module.stacksize);
+        assert_eq!(rewritten.lnotab, module.lnotab);
+        assert_eq!(rewritten_function.code.as_slice(), new_code.as_slice());
+        assert_eq!(rewritten_function.consts.len(), function.consts.len() + 1);
+        assert!(matches!(
+            rewritten_function.consts.last(),
+            Some(Obj::Long(value)) if **value == BigInt::from(7)
+        ));
+        assert_eq!(rewritten_function.stacksize, 9);
+        assert_eq!(rewritten_function.firstlineno, 5);
+        assert_eq!(rewritten_function.lnotab.as_slice(), &[1, 1]);
+        assert_eq!(rewritten_function.name, function.name);
+    }
+
+    #[test]
+    fn replaced_bytecode_keeps_the_metadata() {
+        let data = compile("x = 1\n");
+        let module = load_code(&data).unwrap();
+        let new_code = module.code.to_vec();
+
+        let replaced = load_code(&replace_bytecode(&data, &[Some(new_code)]).unwrap()).unwrap();
+
+        assert_eq!(replaced.code, module.code);
+        assert_eq!(replaced.stacksize, module.stacksize);
+        assert_eq!(replaced.firstlineno, module.firstlineno);
+        assert_eq!(replaced.lnotab, module.lnotab);
+    }
+
+    #[test]
+    fn unsupported_consts_are_an_error() {
+        let data = compile("x = 1\n");
+        let overrides = CodeOverrides {
+            extra_consts: vec![Obj::List(Default::default())],
+            ..Default::default()
+        };
+
+        let error = rewrite_code_objects(&data, &[overrides]).unwrap_err();
+
+        assert!(
+            error.to_string().contains("List consts cannot be added"),
+            "{}",
+            error
+        );
+    }
+}
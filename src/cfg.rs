@@ -0,0 +1,548 @@
+use crate::bytecode::{code_objects, decode_at, load_code, sanitized_name, DecodedInstr};
+use crate::graphs::GraphSink;
+use anyhow::Result;
+use petgraph::graph::{Graph, NodeIndex};
+use petgraph::visit::EdgeRef;
+use py27_marshal::Code;
+use pydis::opcode::py27::{self, Mnemonic};
+use pydis::prelude::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+
+/// How control flows from one basic block to another
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeKind {
+    /// Execution continues to the next instruction, including when a
+    /// conditional branch is not taken
+    Fallthrough,
+    /// Unconditional jump
+    Jump,
+    /// Conditional branch taken when the condition is true
+    BranchTrue,
+    /// Conditional branch taken when the condition is false
+    BranchFalse,
+    /// `FOR_ITER` jumping out of the loop once its iterator is exhausted
+    IterExhausted,
+    /// Target of a `SETUP_*` block (loop exit, exception handler, or finally block)
+    SetupBlock,
+}
+
+impl EdgeKind {
+    /// The kind of edge for the jump taken by `instr`
+    fn for_jump<O: Opcode<Mnemonic = py27::Mnemonic>>(instr: &DecodedInstr<O>) -> EdgeKind {
+        match instr.opcode.mnemonic() {
+            Mnemonic::POP_JUMP_IF_TRUE | Mnemonic::JUMP_IF_TRUE_OR_POP => EdgeKind::BranchTrue,
+            Mnemonic::POP_JUMP_IF_FALSE | Mnemonic::JUMP_IF_FALSE_OR_POP => EdgeKind::BranchFalse,
+            Mnemonic::FOR_ITER => EdgeKind::IterExhausted,
+            Mnemonic::SETUP_LOOP
+            | Mnemonic::SETUP_EXCEPT
+            | Mnemonic::SETUP_FINALLY
+            | Mnemonic::SETUP_WITH => EdgeKind::SetupBlock,
+            _ => EdgeKind::Jump,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::BranchTrue => "branch_true",
+            EdgeKind::BranchFalse => "branch_false",
+            EdgeKind::IterExhausted => "iter_exhausted",
+            EdgeKind::SetupBlock => "setup_block",
+        }
+    }
+}
+
+/// A straight-line sequence of instructions
+#[derive(Debug, Clone)]
+pub struct BasicBlock<O: Opcode<Mnemonic = py27::Mnemonic>> {
+    /// Offset of the first instruction in this block
+    pub start: u64,
+    /// Offset immediately after the last instruction in this block
+    pub end: u64,
+    pub instrs: Vec<DecodedInstr<O>>,
+    /// The block runs into bytes which are not a valid instruction
+    pub has_bad_instr: bool,
+}
+
+/// Control flow graph of a single code object. Only instructions reachable from
+/// the start of the code object are decoded.
+#[derive(Debug)]
+pub struct FlowGraph<O: Opcode<Mnemonic = py27::Mnemonic>> {
+    pub graph: Graph<BasicBlock<O>, EdgeKind>,
+    pub root: Option<NodeIndex>,
+}
+
+impl<O: Opcode<Mnemonic = py27::Mnemonic>> FlowGraph<O> {
+    /// Builds the control flow graph for the given bytecode
+    pub fn from_bytecode(bytecode: &[u8]) -> FlowGraph<O> {
+        // First pass: find every reachable instruction and every block leader
+        let mut instrs = BTreeMap::<u64, DecodedInstr<O>>::new();
+        let mut bad_offsets = BTreeSet::<u64>::new();
+        let mut leaders = BTreeSet::<u64>::new();
+        let mut queue = VecDeque::<u64>::new();
+
+        if !bytecode.is_empty() {
+            leaders.insert(0);
+            queue.push_back(0);
+        }
+
+        while let Some(offset) = queue.pop_front() {
+            if instrs.contains_key(&offset) || bad_offsets.contains(&offset) {
+                continue;
+            }
+
+            if offset as usize >= bytecode.len() {
+                bad_offsets.insert(offset);
+                continue;
+            }
+
+            let instr = match decode_at::<O>(bytecode, offset) {
+                Ok(instr) => instr,
+                Err(_) => {
+                    bad_offsets.insert(offset);
+                    continue;
+                }
+            };
+
+            if let Some(target) = instr.jump_target() {
+                leaders.insert(target);
+                queue.push_back(target);
+                leaders.insert(instr.next_offset());
+            }
+
+            if !instr.is_terminator() {
+                queue.push_back(instr.next_offset());
+            } else {
+                leaders.insert(instr.next_offset());
+            }
+
+            instrs.insert(offset, instr);
+        }
+
+        // Second pass: split the instructions into blocks
+        let mut graph = Graph::new();
+        let mut block_at = BTreeMap::<u64, NodeIndex>::new();
+        for &leader in &leaders {
+            if !instrs.contains_key(&leader) && !bad_offsets.contains(&leader) {
+                continue;
+            }
+
+            let mut block = BasicBlock {
+                start: leader,
+                end: leader,
+                instrs: vec![],
+                has_bad_instr: false,
+            };
+
+            let mut offset = leader;
+            loop {
+                if bad_offsets.contains(&offset) {
+                    block.has_bad_instr = true;
+                    break;
+                }
+
+                let instr = match instrs.get(&offset) {
+                    Some(instr) => instr,
+                    None => break,
+                };
+
+                block.instrs.push(instr.clone());
+                offset = instr.next_offset();
+                block.end = offset;
+
+                if instr.is_terminator()
+                    || instr.jump_target().is_some()
+                    || leaders.contains(&offset)
+                {
+                    break;
+                }
+            }
+
+            block_at.insert(leader, graph.add_node(block));
+        }
+
+        // Third pass: connect the blocks
+        let nodes: Vec<NodeIndex> = graph.node_indices().collect();
+        for node in nodes {
+            let block = &graph[node];
+            let mut edges = vec![];
+            if let Some(last) = block.instrs.last() {
+                if let Some(target) = last.jump_target() {
+                    if let Some(&target_node) = block_at.get(&target) {
+                        edges.push((target_node, EdgeKind::for_jump(last)));
+                    }
+                }
+
+                if !last.is_terminator() {
+                    if let Some(&next_node) = block_at.get(&last.next_offset()) {
+                        edges.push((next_node, EdgeKind::Fallthrough));
+                    }
+                }
+            }
+
+            for (target, kind) in edges {
+                graph.add_edge(node, target, kind);
+            }
+        }
+
+        FlowGraph {
+            root: block_at.get(&0).copied(),
+            graph,
+        }
+    }
+}
+
+/// Identifying information for a code object whose graph is being exported
+#[derive(Debug, Serialize)]
+pub struct FunctionInfo {
+    /// Position of the code object in the stage (see [`crate::bytecode::code_objects`])
+    pub index: usize,
+    pub name: String,
+    pub filename: String,
+    pub firstlineno: u32,
+}
+
+impl FunctionInfo {
+    pub fn new(index: usize, code: &Code) -> FunctionInfo {
+        FunctionInfo {
+            index,
+            name: code.name.to_string(),
+            filename: code.filename.to_string(),
+            firstlineno: code.firstlineno,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonInstr {
+    offset: u64,
+    opcode: String,
+    arg: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct JsonNode {
+    id: usize,
+    start: u64,
+    end: u64,
+    has_bad_instr: bool,
+    instructions: Vec<JsonInstr>,
+}
+
+#[derive(Serialize)]
+struct JsonEdge {
+    source: usize,
+    target: usize,
+    kind: EdgeKind,
+}
+
+#[derive(Serialize)]
+struct JsonGraph<'a> {
+    function: &'a FunctionInfo,
+    root: Option<usize>,
+    nodes: Vec<JsonNode>,
+    edges: Vec<JsonEdge>,
+}
+
+/// Serializes the graph to JSON. Nodes carry their instruction lists and edges
+/// carry their [`EdgeKind`].
+pub fn to_json<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    graph: &FlowGraph<O>,
+    function: &FunctionInfo,
+) -> serde_json::Result<String> {
+    let nodes = graph
+        .graph
+        .node_indices()
+        .map(|node| {
+            let bb = &graph.graph[node];
+            JsonNode {
+                id: node.index(),
+                start: bb.start,
+                end: bb.end,
+                has_bad_instr: bb.has_bad_instr,
+                instructions: bb
+                    .instrs
+                    .iter()
+                    .map(|instr| JsonInstr {
+                        offset: instr.offset,
+                        opcode: format!("{:?}", instr.opcode.mnemonic()),
+                        arg: instr.arg,
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    let edges = graph
+        .graph
+        .edge_references()
+        .map(|edge| JsonEdge {
+            source: edge.source().index(),
+            target: edge.target().index(),
+            kind: *edge.weight(),
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&JsonGraph {
+        function,
+        root: graph.root.map(|root| root.index()),
+        nodes,
+        edges,
+    })
+}
+
+/// Serializes the graph to GraphML. Each node's instructions are stored one per
+/// line in its `instructions` attribute and each edge's kind in its `kind` attribute.
+pub fn to_graphml<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    graph: &FlowGraph<O>,
+    function: &FunctionInfo,
+) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+    out.push_str(
+        "  <key id=\"function\" for=\"graph\" attr.name=\"function\" attr.type=\"string\"/>\n",
+    );
+    out.push_str(
+        "  <key id=\"firstlineno\" for=\"graph\" attr.name=\"firstlineno\" attr.type=\"int\"/>\n",
+    );
+    out.push_str("  <key id=\"start\" for=\"node\" attr.name=\"start\" attr.type=\"long\"/>\n");
+    out.push_str("  <key id=\"end\" for=\"node\" attr.name=\"end\" attr.type=\"long\"/>\n");
+    out.push_str("  <key id=\"has_bad_instr\" for=\"node\" attr.name=\"has_bad_instr\" attr.type=\"boolean\"/>\n");
+    out.push_str("  <key id=\"instructions\" for=\"node\" attr.name=\"instructions\" attr.type=\"string\"/>\n");
+    out.push_str("  <key id=\"kind\" for=\"edge\" attr.name=\"kind\" attr.type=\"string\"/>\n");
+
+    let _ = writeln!(
+        out,
+        "  <graph id=\"{}\" edgedefault=\"directed\">",
+        xml_escape(&format!("{}_{}", function.index, function.name))
+    );
+    let _ = writeln!(
+        out,
+        "    <data key=\"function\">{}</data>",
+        xml_escape(&function.name)
+    );
+    let _ = writeln!(
+        out,
+        "    <data key=\"firstlineno\">{}</data>",
+        function.firstlineno
+    );
+
+    for node in graph.graph.node_indices() {
+        let bb = &graph.graph[node];
+        let instructions = bb
+            .instrs
+            .iter()
+            .map(|instr| format!("{} {}", instr.offset, instr))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let _ = writeln!(out, "    <node id=\"n{}\">", node.index());
+        let _ = writeln!(out, "      <data key=\"start\">{}</data>", bb.start);
+        let _ = writeln!(out, "      <data key=\"end\">{}</data>", bb.end);
+        let _ = writeln!(
+            out,
+            "      <data key=\"has_bad_instr\">{}</data>",
+            bb.has_bad_instr
+        );
+        let _ = writeln!(
+            out,
+            "      <data key=\"instructions\">{}</data>",
+            xml_escape(&instructions)
+        );
+        out.push_str("    </node>\n");
+    }
+
+    for (i, edge) in graph.graph.edge_references().enumerate() {
+        let _ = writeln!(
+            out,
+            "    <edge id=\"e{}\" source=\"n{}\" target=\"n{}\">",
+            i,
+            edge.source().index(),
+            edge.target().index()
+        );
+        let _ = writeln!(
+            out,
+            "      <data key=\"kind\">{}</data>",
+            edge.weight().as_str()
+        );
+        out.push_str("    </edge>\n");
+    }
+
+    out.push_str("  </graph>\n");
+    out.push_str("</graphml>\n");
+
+    out
+}
+
+/// Exports the control flow graph of every code object in the marshalled `data`
+/// as both JSON and GraphML. Graphs are written to `<stage>/<pass>/` in the
+/// module's graph directory.
+pub fn export_stage<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    graph_sink: &GraphSink,
+    stage: &str,
+    pass: &str,
+    data: &[u8],
+) -> Result<()> {
+    let code = load_code(data)?;
+
+    for (index, code) in code_objects(&code).iter().enumerate() {
+        let graph = FlowGraph::<O>::from_bytecode(code.code.as_slice());
+        let function = FunctionInfo::new(index, code);
+        let file_stem = format!("{}_{}", index, sanitized_name(code));
+
+        graph_sink.write(
+            stage,
+            pass,
+            &format!("{}.json", file_stem),
+            &to_json(&graph, &function)?,
+        )?;
+        graph_sink.write(
+            stage,
+            pass,
+            &format!("{}.graphml", file_stem),
+            &to_graphml(&graph, &function),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\n' => out.push_str("&#10;"),
+            // Other control characters are not allowed in XML 1.0, even escaped
+            c if c.is_control() && c != '\t' => out.push(char::REPLACEMENT_CHARACTER),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::assemble;
+    use pydis::opcode::py27::Standard::{self, *};
+
+    /// `if x: return 1` with a junk byte the `else` jumps over
+    fn branchy() -> Vec<u8> {
+        let mut code = assemble(&[
+            (LOAD_FAST, Some(0)),
+            (POP_JUMP_IF_FALSE, Some(13)),
+            (LOAD_CONST, Some(0)),
+            (JUMP_FORWARD, Some(1)),
+        ]);
+        code.push(0xff);
+        code.extend(assemble(&[(LOAD_CONST, Some(1)), (RETURN_VALUE, None)]));
+        code
+    }
+
+    fn edges(graph: &FlowGraph<Standard>) -> Vec<(u64, u64, EdgeKind)> {
+        let mut edges: Vec<_> = graph
+            .graph
+            .edge_references()
+            .map(|edge| {
+                (
+                    graph.graph[edge.source()].start,
+                    graph.graph[edge.target()].start,
+                    *edge.weight(),
+                )
+            })
+            .collect();
+        edges.sort_by_key(|(source, target, _)| (*source, *target));
+        edges
+    }
+
+    #[test]
+    fn blocks_are_split_at_jumps_and_targets() {
+        let graph = FlowGraph::<Standard>::from_bytecode(&branchy());
+        let mut blocks: Vec<(u64, u64)> = graph
+            .graph
+            .node_weights()
+            .map(|bb| (bb.start, bb.end))
+            .collect();
+        blocks.sort();
+
+        // The junk byte at 12 is never reached, so it isn't decoded
+        assert_eq!(blocks, [(0, 6), (6, 12), (13, 17)]);
+        assert_eq!(graph.graph[graph.root.unwrap()].start, 0);
+        assert_eq!(
+            edges(&graph),
+            [
+                (0, 6, EdgeKind::Fallthrough),
+                (0, 13, EdgeKind::BranchFalse),
+                (6, 13, EdgeKind::Jump),
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps_into_junk_end_in_a_bad_block() {
+        let mut code = assemble(&[(LOAD_FAST, Some(0)), (POP_JUMP_IF_TRUE, Some(7))]);
+        code.push(0);
+        code.push(0xff);
+        let graph = FlowGraph::<Standard>::from_bytecode(&code);
+
+        let bad: Vec<u64> = graph
+            .graph
+            .node_weights()
+            .filter(|bb| bb.has_bad_instr)
+            .map(|bb| bb.start)
+            .collect();
+        assert_eq!(bad, [7]);
+        // STOP_CODE is a valid instruction, and the block it is in ends at
+        // the jump target
+        assert!(edges(&graph).contains(&(6, 7, EdgeKind::Fallthrough)));
+    }
+
+    #[test]
+    fn exports_carry_instructions_and_edge_kinds() {
+        let graph = FlowGraph::<Standard>::from_bytecode(&branchy());
+        let function = FunctionInfo {
+            index: 2,
+            name: "f<lambda>".to_string(),
+            filename: "test.py".to_string(),
+            firstlineno: 7,
+        };
+
+        let json: serde_json::Value =
+            serde_json::from_str(&to_json(&graph, &function).unwrap()).unwrap();
+        assert_eq!(json["function"]["name"], "f<lambda>");
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            json["nodes"][0]["instructions"][1]["opcode"],
+            "POP_JUMP_IF_FALSE"
+        );
+        assert_eq!(json["nodes"][0]["instructions"][1]["arg"], 13);
+        let kinds: BTreeSet<&str> = json["edges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|edge| edge["kind"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            ["branch_false", "fallthrough", "jump"]
+                .into_iter()
+                .collect()
+        );
+
+        let graphml = to_graphml(&graph, &function);
+        assert!(graphml.contains("<graph id=\"2_f&lt;lambda&gt;\""));
+        assert!(graphml.contains("0 LOAD_FAST 0&#10;3 POP_JUMP_IF_FALSE 13"));
+        assert!(graphml.contains("<data key=\"kind\">branch_false</data>"));
+    }
+}
@@ -0,0 +1,586 @@
+use crate::bytecode::{code_objects, load_code, sanitized_name, DecodedInstr};
+use crate::cfg::FlowGraph;
+use crate::graphs::GraphSink;
+use anyhow::{bail, Result};
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use py27_marshal::Code;
+use pydis::opcode::py27::{self, Mnemonic};
+use pydis::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// How many instructions past a folded predicate are compared to decide which
+/// of its branches the deobfuscated code continues with
+const BRANCH_LOOKAHEAD: usize = 8;
+
+/// What happened to an instruction of the original code during deobfuscation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InstrFate {
+    /// The instruction is still present in the deobfuscated code
+    Kept,
+    /// A conditional jump which was folded, or the `LOAD_CONST` its condition
+    /// was loaded from
+    ConstPredicate,
+    /// An unconditional jump which was removed when the blocks around it were
+    /// merged
+    Jump,
+    /// Any other instruction which was removed, such as junk between real
+    /// instructions or code only reachable through a folded predicate
+    Garbage,
+}
+
+/// What happened to a basic block of the original code during deobfuscation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum BlockStatus {
+    /// All of the block's instructions are still present
+    Kept,
+    /// Some of the block's instructions were removed
+    Trimmed,
+    /// None of the block's instructions are still present
+    Removed,
+    /// The block only contained a jump, which was merged into its neighbors
+    Merged,
+}
+
+type InstrMap<O> = BTreeMap<u64, DecodedInstr<O>>;
+
+/// Whether two instructions are the same apart from where they jump to, since
+/// deobfuscation moves code around
+fn same_instr<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    before: &DecodedInstr<O>,
+    after: &DecodedInstr<O>,
+) -> bool {
+    before.opcode.mnemonic() == after.opcode.mnemonic()
+        && (before.arg == after.arg
+            || (before.jump_target().is_some() && after.jump_target().is_some()))
+}
+
+fn is_unconditional_jump<O: Opcode<Mnemonic = py27::Mnemonic>>(instr: &DecodedInstr<O>) -> bool {
+    matches!(
+        instr.opcode.mnemonic(),
+        Mnemonic::JUMP_FORWARD | Mnemonic::JUMP_ABSOLUTE
+    )
+}
+
+/// Follows unconditional jumps from `offset` to the first instruction which
+/// isn't one
+fn skip_jumps<O: Opcode<Mnemonic = py27::Mnemonic>>(instrs: &InstrMap<O>, mut offset: u64) -> u64 {
+    for _ in 0..instrs.len() {
+        match instrs.get(&offset) {
+            Some(instr) if is_unconditional_jump(instr) => {
+                offset = instr.jump_target().unwrap();
+            }
+            _ => break,
+        }
+    }
+
+    offset
+}
+
+/// Number of instructions the straight-line code at `before` and `after` have
+/// in common, looking through unconditional jumps
+fn agreement<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    before_instrs: &InstrMap<O>,
+    after_instrs: &InstrMap<O>,
+    mut before: u64,
+    mut after: u64,
+) -> usize {
+    for matched in 0..BRANCH_LOOKAHEAD {
+        before = skip_jumps(before_instrs, before);
+        after = skip_jumps(after_instrs, after);
+        match (before_instrs.get(&before), after_instrs.get(&after)) {
+            (Some(b), Some(a)) if same_instr(b, a) && !b.is_terminator() => {
+                before = b.next_offset();
+                after = a.next_offset();
+            }
+            (Some(b), Some(a)) if same_instr(b, a) => return matched + 1,
+            _ => return matched,
+        }
+    }
+
+    BRANCH_LOOKAHEAD
+}
+
+/// Differences between a function's control flow graph before and after
+/// deobfuscation. Instructions of the original code are matched to the
+/// deobfuscated code by walking both graphs in parallel from their entry
+/// points, so each original instruction is identified by its original offset
+/// and gets exactly one [`InstrFate`]. Instructions of the deobfuscated code
+/// which nothing was matched to were added by the deobfuscator.
+pub struct CfgDiff<O: Opcode<Mnemonic = py27::Mnemonic>> {
+    before: FlowGraph<O>,
+    after_instrs: InstrMap<O>,
+    fates: BTreeMap<u64, InstrFate>,
+    /// Offsets of deobfuscated instructions which were not matched
+    added: BTreeSet<u64>,
+    /// Offset of each folded conditional jump and the offset of the branch
+    /// the deobfuscated code continues with
+    folded: HashMap<u64, u64>,
+}
+
+impl<O: Opcode<Mnemonic = py27::Mnemonic>> CfgDiff<O> {
+    /// Compares the original and deobfuscated bytecode of a single function.
+    ///
+    /// Both graphs are built with [`FlowGraph`], the same CFG `cfg-export`
+    /// writes, rather than taken from `unfuck`. Its `enable_graphs` and
+    /// `on_graph_generated` hooks only hand out each graph already rendered
+    /// to dot text, and `unfuck` doesn't expose the graphs themselves, so
+    /// there is nothing to walk instructions through. Building both sides
+    /// from the bytecode also means the original and deobfuscated graphs
+    /// split blocks the same way.
+    pub fn new(before_code: &[u8], after_code: &[u8]) -> CfgDiff<O> {
+        let before = FlowGraph::<O>::from_bytecode(before_code);
+        let before_instrs = instructions(&before);
+        let after_instrs = instructions(&FlowGraph::<O>::from_bytecode(after_code));
+
+        let mut matched = HashMap::<u64, u64>::new();
+        let mut matched_after = HashSet::<u64>::new();
+        let mut predicates = HashSet::<u64>::new();
+        let mut folded = HashMap::<u64, u64>::new();
+        let mut visited = HashSet::<(u64, u64)>::new();
+        let mut queue = vec![(0u64, 0u64)];
+        while let Some((b, a)) = queue.pop() {
+            if matched.contains_key(&b) || !visited.insert((b, a)) {
+                continue;
+            }
+            let instr = match before_instrs.get(&b) {
+                Some(instr) => instr,
+                None => continue,
+            };
+
+            let after_instr = after_instrs.get(&a).filter(|_| !matched_after.contains(&a));
+            if let Some(after_instr) = after_instr.filter(|ai| same_instr(instr, ai)) {
+                matched.insert(b, a);
+                matched_after.insert(a);
+                if let (Some(target), Some(after_target)) =
+                    (instr.jump_target(), after_instr.jump_target())
+                {
+                    queue.push((target, after_target));
+                }
+                if !instr.is_terminator() {
+                    queue.push((instr.next_offset(), after_instr.next_offset()));
+                }
+                continue;
+            }
+
+            if is_unconditional_jump(instr) {
+                queue.push((instr.jump_target().unwrap(), a));
+            } else if let Some(after_instr) = after_instr.filter(|ai| is_unconditional_jump(*ai)) {
+                // A jump the deobfuscator added after moving blocks around
+                queue.push((b, after_instr.jump_target().unwrap()));
+            } else if instr.opcode.is_conditional_jump() {
+                let target = instr.jump_target().unwrap();
+                let next = instr.next_offset();
+                let taken = if agreement(&before_instrs, &after_instrs, target, a)
+                    > agreement(&before_instrs, &after_instrs, next, a)
+                {
+                    target
+                } else {
+                    next
+                };
+                predicates.insert(b);
+                folded.insert(b, taken);
+                queue.push((taken, a));
+            } else {
+                let next = before_instrs.get(&instr.next_offset());
+                if instr.opcode.mnemonic() == Mnemonic::LOAD_CONST
+                    && next.is_some_and(|next| next.opcode.is_conditional_jump())
+                {
+                    predicates.insert(b);
+                }
+                if !instr.is_terminator() {
+                    queue.push((instr.next_offset(), a));
+                }
+            }
+        }
+
+        let fates = before_instrs
+            .iter()
+            .map(|(offset, instr)| {
+                let fate = if matched.contains_key(offset) {
+                    InstrFate::Kept
+                } else if predicates.contains(offset) {
+                    InstrFate::ConstPredicate
+                } else if is_unconditional_jump(instr) {
+                    InstrFate::Jump
+                } else {
+                    InstrFate::Garbage
+                };
+                (*offset, fate)
+            })
+            .collect();
+        // A predicate whose jump was matched on another path wasn't folded
+        folded.retain(|offset, _| !matched.contains_key(offset));
+        let added = after_instrs
+            .keys()
+            .filter(|offset| !matched_after.contains(offset))
+            .copied()
+            .collect();
+
+        CfgDiff {
+            before,
+            after_instrs,
+            fates,
+            added,
+            folded,
+        }
+    }
+
+    /// Number of reachable instructions in the original code
+    pub fn instructions_before(&self) -> usize {
+        self.fates.len()
+    }
+
+    /// Number of reachable instructions in the deobfuscated code
+    pub fn instructions_after(&self) -> usize {
+        self.after_instrs.len()
+    }
+
+    /// Number of original instructions with the given fate
+    pub fn count(&self, fate: InstrFate) -> usize {
+        self.fates.values().filter(|f| **f == fate).count()
+    }
+
+    /// Number of conditional jumps the deobfuscator folded. Predicates which
+    /// are computed from names rather than loaded directly from a constant are
+    /// folded too.
+    pub fn const_predicates_removed(&self) -> usize {
+        self.folded.len()
+    }
+
+    /// Number of deobfuscated instructions which don't correspond to any
+    /// original instruction
+    pub fn instructions_added(&self) -> usize {
+        self.added.len()
+    }
+
+    /// Number of `RETURN_VALUE` instructions added by the deobfuscator
+    pub fn returns_added(&self) -> usize {
+        self.added
+            .iter()
+            .filter(|offset| self.after_instrs[offset].opcode.mnemonic() == Mnemonic::RETURN_VALUE)
+            .count()
+    }
+
+    fn fate(&self, offset: u64) -> InstrFate {
+        self.fates[&offset]
+    }
+
+    fn status(&self, node: NodeIndex) -> BlockStatus {
+        let instrs = &self.before.graph[node].instrs;
+        let kept = instrs
+            .iter()
+            .filter(|instr| self.fate(instr.offset) == InstrFate::Kept)
+            .count();
+        if kept == instrs.len() && !instrs.is_empty() {
+            BlockStatus::Kept
+        } else if kept > 0 {
+            BlockStatus::Trimmed
+        } else if !instrs.is_empty()
+            && instrs
+                .iter()
+                .all(|instr| self.fate(instr.offset) == InstrFate::Jump)
+        {
+            BlockStatus::Merged
+        } else {
+            BlockStatus::Removed
+        }
+    }
+
+    /// The folded predicate `node` ends in, and the branch that was taken
+    fn folded_branch(&self, node: NodeIndex) -> Option<u64> {
+        let last = self.before.graph[node].instrs.last()?;
+        self.folded.get(&last.offset).copied()
+    }
+
+    /// An edge is removed if either of the blocks it connects was removed, or
+    /// it is the branch of a folded predicate which isn't taken
+    fn is_edge_removed(&self, source: NodeIndex, target: NodeIndex) -> bool {
+        self.status(source) == BlockStatus::Removed
+            || self.status(target) == BlockStatus::Removed
+            || self
+                .folded_branch(source)
+                .is_some_and(|taken| taken != self.before.graph[target].start)
+    }
+
+    /// Renders the original graph in dot format with removed blocks, removed
+    /// instructions, removed edges, and folded const predicates highlighted
+    pub fn to_dot(&self, title: &str) -> String {
+        let graph = &self.before.graph;
+        let removed_blocks = graph
+            .node_indices()
+            .filter(|node| self.status(*node) == BlockStatus::Removed)
+            .count();
+        let removed_edges = graph
+            .edge_references()
+            .filter(|edge| self.is_edge_removed(edge.source(), edge.target()))
+            .count();
+
+        let mut out = String::new();
+        out.push_str("digraph {\n");
+        let _ = writeln!(
+            out,
+            "    label=\"{}\\lremoved blocks: {}\\lremoved edges: {}\\lconst predicates: {}\\l\";",
+            dot_escape(title),
+            removed_blocks,
+            removed_edges,
+            self.const_predicates_removed()
+        );
+        out.push_str("    labelloc=t;\n");
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        for node in graph.node_indices() {
+            let bb = &graph[node];
+            let mut label = String::new();
+            for instr in &bb.instrs {
+                let marker = if self.fate(instr.offset) == InstrFate::Kept {
+                    ' '
+                } else {
+                    '-'
+                };
+                let _ = write!(
+                    label,
+                    "{} {} {}\\l",
+                    marker,
+                    instr.offset,
+                    dot_escape(&instr.to_string())
+                );
+            }
+            if bb.has_bad_instr {
+                label.push_str("  <bad instruction>\\l");
+            }
+
+            let (style, fill) = match self.status(node) {
+                BlockStatus::Removed => ("filled", "#f4cccc"),
+                BlockStatus::Trimmed => ("filled", "#fce5cd"),
+                BlockStatus::Merged => ("filled,dashed", "#eeeeee"),
+                BlockStatus::Kept => ("filled", "#ffffff"),
+            };
+            let (color, penwidth) = if self.folded_branch(node).is_some() {
+                ("#e69138", 3)
+            } else {
+                ("#000000", 1)
+            };
+
+            let _ = writeln!(
+                out,
+                "    {} [label=\"{}\", style=\"{}\", fillcolor=\"{}\", color=\"{}\", penwidth={}];",
+                node.index(),
+                label,
+                style,
+                fill,
+                color,
+                penwidth
+            );
+        }
+
+        for edge in graph.edge_references() {
+            let (source, target) = (edge.source(), edge.target());
+            let (style, color) = if self.is_edge_removed(source, target) {
+                ("dashed", "#cc0000")
+            } else if self.folded_branch(source).is_some() {
+                ("dotted", "#e69138")
+            } else {
+                ("solid", "#000000")
+            };
+
+            let _ = writeln!(
+                out,
+                "    {} -> {} [label=\"{:?}\", style={}, color=\"{}\"];",
+                source.index(),
+                target.index(),
+                edge.weight(),
+                style,
+                color
+            );
+        }
+
+        out.push_str("}\n");
+
+        out
+    }
+}
+
+/// Pairs up the code objects of a stage with those of its deobfuscated version.
+/// Deobfuscation keeps the depth-first order of code objects, so they are
+/// paired by position, but only after checking that the two trees have the
+/// same shape.
+pub fn paired_code_objects(before: &[u8], after: &[u8]) -> Result<Vec<(Arc<Code>, Arc<Code>)>> {
+    let before = code_objects(&load_code(before)?);
+    let after = code_objects(&load_code(after)?);
+    if before.len() != after.len() {
+        bail!(
+            "the stage has {} code objects but its deobfuscated version has {}",
+            before.len(),
+            after.len()
+        );
+    }
+
+    for (index, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+        if before.argcount != after.argcount
+            || before.varnames.len() != after.varnames.len()
+            || before.flags != after.flags
+        {
+            bail!(
+                "code object {} ({}) does not match its deobfuscated version",
+                index,
+                before.name
+            );
+        }
+    }
+
+    Ok(before.into_iter().zip(after).collect())
+}
+
+/// Writes a diff graph for every function in the stage. Graphs are written to
+/// `<stage>/diff/` in the module's graph directory.
+pub fn write_stage_diffs<O: Opcode<Mnemonic = py27::Mnemonic>>(
+    graph_sink: &GraphSink,
+    stage: &str,
+    before: &[u8],
+    after: &[u8],
+) -> Result<()> {
+    for (index, (before, after)) in paired_code_objects(before, after)?.iter().enumerate() {
+        let diff = CfgDiff::<O>::new(before.code.as_slice(), after.code.as_slice());
+        let title = format!("{} ({})", before.name, index);
+
+        graph_sink.write(
+            stage,
+            "diff",
+            &format!("{}_{}.dot", index, sanitized_name(before)),
+            &diff.to_dot(&title),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Every reachable instruction in `graph`, by offset
+fn instructions<O: Opcode<Mnemonic = py27::Mnemonic>>(graph: &FlowGraph<O>) -> InstrMap<O> {
+    graph
+        .graph
+        .node_weights()
+        .flat_map(|bb| bb.instrs.iter())
+        .map(|instr| (instr.offset, instr.clone()))
+        .collect()
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::assemble;
+    use pydis::opcode::py27::Standard::{self, *};
+
+    fn fates(diff: &CfgDiff<Standard>) -> Vec<(u64, InstrFate)> {
+        diff.fates
+            .iter()
+            .map(|(offset, fate)| (*offset, *fate))
+            .collect()
+    }
+
+    /// Every original instruction is accounted for exactly once
+    fn assert_counts_add_up(diff: &CfgDiff<Standard>) {
+        assert_eq!(
+            diff.instructions_before() + diff.instructions_added(),
+            diff.instructions_after()
+                + diff.count(InstrFate::ConstPredicate)
+                + diff.count(InstrFate::Jump)
+                + diff.count(InstrFate::Garbage)
+        );
+    }
+
+    #[test]
+    fn folded_predicates_and_their_dead_branch_are_removed() {
+        let before = assemble(&[
+            (LOAD_CONST, Some(0)),
+            (POP_JUMP_IF_FALSE, Some(10)),
+            (LOAD_NAME, Some(0)),
+            (RETURN_VALUE, None),
+            (LOAD_FAST, Some(0)),
+            (RETURN_VALUE, None),
+        ]);
+        let after = assemble(&[(LOAD_FAST, Some(0)), (RETURN_VALUE, None)]);
+        let diff = CfgDiff::<Standard>::new(&before, &after);
+
+        assert_eq!(
+            fates(&diff),
+            vec![
+                (0, InstrFate::ConstPredicate),
+                (3, InstrFate::ConstPredicate),
+                (6, InstrFate::Garbage),
+                (9, InstrFate::Garbage),
+                (10, InstrFate::Kept),
+                (13, InstrFate::Kept),
+            ]
+        );
+        assert_eq!(diff.const_predicates_removed(), 1);
+        assert_eq!(diff.instructions_added(), 0);
+        assert_counts_add_up(&diff);
+
+        let dot = diff.to_dot("test");
+        assert!(dot.contains("removed blocks: 2"), "{}", dot);
+        assert!(dot.contains("const predicates: 1"), "{}", dot);
+        assert!(dot.contains("- 6 "), "{}", dot);
+        assert!(dot.contains("  10 "), "{}", dot);
+        assert!(dot.contains("color=\"#e69138\", penwidth=3"), "{}", dot);
+    }
+
+    #[test]
+    fn merged_jumps_are_not_garbage() {
+        let mut before = assemble(&[(LOAD_FAST, Some(0)), (JUMP_FORWARD, Some(1))]);
+        before.push(0xff);
+        before.extend(assemble(&[(RETURN_VALUE, None)]));
+        let after = assemble(&[(LOAD_FAST, Some(0)), (RETURN_VALUE, None)]);
+        let diff = CfgDiff::<Standard>::new(&before, &after);
+
+        assert_eq!(
+            fates(&diff),
+            vec![
+                (0, InstrFate::Kept),
+                (3, InstrFate::Jump),
+                (7, InstrFate::Kept),
+            ]
+        );
+        assert_eq!(diff.const_predicates_removed(), 0);
+        assert_counts_add_up(&diff);
+
+        // The block the jump was in lost an instruction but wasn't removed
+        let dot = diff.to_dot("test");
+        assert!(dot.contains("#fce5cd"), "{}", dot);
+        assert!(dot.contains("removed blocks: 0"), "{}", dot);
+    }
+
+    #[test]
+    fn deoptimized_returns_are_added_instructions() {
+        let before = assemble(&[
+            (LOAD_FAST, Some(0)),
+            (POP_JUMP_IF_FALSE, Some(12)),
+            (LOAD_CONST, Some(0)),
+            (JUMP_FORWARD, Some(3)),
+            (LOAD_CONST, Some(1)),
+            (RETURN_VALUE, None),
+        ]);
+        let after = assemble(&[
+            (LOAD_FAST, Some(0)),
+            (POP_JUMP_IF_FALSE, Some(10)),
+            (LOAD_CONST, Some(0)),
+            (RETURN_VALUE, None),
+            (LOAD_CONST, Some(1)),
+            (RETURN_VALUE, None),
+        ]);
+        let diff = CfgDiff::<Standard>::new(&before, &after);
+
+        assert_eq!(diff.count(InstrFate::Kept), 5);
+        assert_eq!(diff.count(InstrFate::Jump), 1);
+        assert_eq!(diff.instructions_added(), 1);
+        assert_eq!(diff.returns_added(), 1);
+        assert_eq!(diff.const_predicates_removed(), 0);
+        assert_counts_add_up(&diff);
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Contents of the file passed with `--config`. Sections that are not present
+/// take their default values.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub passes: PassConfig,
+}
+
+impl ConfigFile {
+    /// Loads a JSON config file from disk
+    pub fn load(path: &Path) -> Result<ConfigFile> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("failed to read config file {:?}", path))?;
+
+        serde_json::from_slice(data.as_slice())
+            .with_context(|| format!("failed to parse config file {:?}", path))
+    }
+}
+
+/// Controls which deobfuscation passes run and their limits. The same settings
+/// are applied to every stage that gets deobfuscated (stage2, stage3, and stage4).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PassConfig {
+    /// Run `unfuck`'s deobfuscator over the stage. This covers const predicate
+    /// removal, garbage instruction removal, and return deoptimization.
+    pub deobfuscate: bool,
+    /// Report anti-decompiler tricks found in each stage, such as jumps into
+    /// the middle of instructions and invalid opcodes in dead code
+    pub detect_tricks: bool,
+    /// Remove the anti-decompiler tricks which can be removed without moving
+    /// any instructions from the innermost stage before it is deobfuscated
+    pub normalize_tricks: bool,
+    /// Replace calls to helper functions which decode strings at import time
+    /// with the decoded strings. Only applies to the innermost stage.
+    pub inline_strings: bool,
+    /// Put loads whose values are shuffled into place on the stack back in
+    /// order after the innermost stage is deobfuscated
+    pub reorder_stack: bool,
+    /// Recompute `co_stacksize` and `co_lnotab` for the deobfuscated innermost
+    /// stage
+    pub recompute_metadata: bool,
+    /// Set `co_firstlineno` to the first line of each function's surviving
+    /// instructions when recomputing metadata
+    pub normalize_firstlineno: bool,
+    /// Check each deobfuscated stage for bad jumps, stack depth problems,
+    /// out-of-range indices, and marshal round trip failures before it is
+    /// written
+    pub validate: bool,
+    /// Run the pure functions of the innermost stage before and after
+    /// deobfuscation on the same generated inputs and report any that behave
+    /// differently
+    pub differential_check: bool,
+    /// Emit dot graphs from the deobfuscator
+    pub graphs: bool,
+    /// Export each function's control flow graph as JSON and GraphML, both
+    /// before and after deobfuscation
+    pub cfg_export: bool,
+    /// Render each function's graph before and after deobfuscation as a single
+    /// annotated graph highlighting what the deobfuscator removed
+    pub graph_diff: bool,
+    /// Stages whose marshalled data is larger than this many bytes are not
+    /// deobfuscated
+    pub max_input_size: Option<usize>,
+    /// Stages with more than this many code objects (including nested ones)
+    /// are not deobfuscated
+    pub max_code_objects: Option<usize>,
+}
+
+impl Default for PassConfig {
+    fn default() -> Self {
+        PassConfig {
+            deobfuscate: true,
+            detect_tricks: true,
+            normalize_tricks: false,
+            inline_strings: false,
+            reorder_stack: false,
+            recompute_metadata: true,
+            normalize_firstlineno: false,
+            validate: true,
+            differential_check: false,
+            graphs: false,
+            cfg_export: false,
+            graph_diff: false,
+            max_input_size: None,
+            max_code_objects: None,
+        }
+    }
+}
+
+impl PassConfig {
+    /// Whether any pass writes to the module's graph directory
+    pub fn writes_graphs(&self) -> bool {
+        self.graphs || self.cfg_export || self.graph_diff
+    }
+
+    /// Turns the given pass on or off
+    pub fn set(&mut self, pass: Pass, enabled: bool) {
+        match pass {
+            Pass::Deobfuscate => self.deobfuscate = enabled,
+            Pass::DetectTricks => self.detect_tricks = enabled,
+            Pass::NormalizeTricks => self.normalize_tricks = enabled,
+            Pass::InlineStrings => self.inline_strings = enabled,
+            Pass::ReorderStack => self.reorder_stack = enabled,
+            Pass::RecomputeMetadata => self.recompute_metadata = enabled,
+            Pass::NormalizeFirstlineno => self.normalize_firstlineno = enabled,
+            Pass::Validate => self.validate = enabled,
+            Pass::DifferentialCheck => self.differential_check = enabled,
+            Pass::Graphs => self.graphs = enabled,
+            Pass::CfgExport => self.cfg_export = enabled,
+            Pass::GraphDiff => self.graph_diff = enabled,
+        }
+    }
+}
+
+/// A pass which may be toggled with `--enable-pass`/`--disable-pass`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pass {
+    Deobfuscate,
+    DetectTricks,
+    NormalizeTricks,
+    InlineStrings,
+    ReorderStack,
+    RecomputeMetadata,
+    NormalizeFirstlineno,
+    Validate,
+    DifferentialCheck,
+    Graphs,
+    CfgExport,
+    GraphDiff,
+}
+
+impl Pass {
+    pub const ALL: &'static [&'static str] = &[
+        "deobfuscate",
+        "detect-tricks",
+        "normalize-tricks",
+        "inline-strings",
+        "reorder-stack",
+        "recompute-metadata",
+        "normalize-firstlineno",
+        "validate",
+        "differential-check",
+        "graphs",
+        "cfg-export",
+        "graph-diff",
+    ];
+}
+
+#[derive(Error, Debug)]
+#[error("unknown pass `{0}`. Valid passes are: {}", Pass::ALL.join(", "))]
+pub struct UnknownPass(String);
+
+impl FromStr for Pass {
+    type Err = UnknownPass;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deobfuscate" => Ok(Pass::Deobfuscate),
+            "detect-tricks" => Ok(Pass::DetectTricks),
+            "normalize-tricks" => Ok(Pass::NormalizeTricks),
+            "inline-strings" => Ok(Pass::InlineStrings),
+            "reorder-stack" => Ok(Pass::ReorderStack),
+            "recompute-metadata" => Ok(Pass::RecomputeMetadata),
+            "normalize-firstlineno" => Ok(Pass::NormalizeFirstlineno),
+            "validate" => Ok(Pass::Validate),
+            "differential-check" => Ok(Pass::DifferentialCheck),
+            "graphs" => Ok(Pass::Graphs),
+            "cfg-export" => Ok(Pass::CfgExport),
+            "graph-diff" => Ok(Pass::GraphDiff),
+            other => Err(UnknownPass(other.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_files_override_only_the_given_passes() {
+        let config: ConfigFile = serde_json::from_str(
+            r#"{"passes": {"inline-strings": true, "graphs": true, "max-input-size": 100}}"#,
+        )
+        .unwrap();
+        let passes = config.passes;
+        assert!(passes.inline_strings);
+        assert!(passes.graphs);
+        assert!(passes.writes_graphs());
+        assert_eq!(passes.max_input_size, Some(100));
+
+        // Everything else keeps its default
+        let defaults = PassConfig::default();
+        assert_eq!(passes.deobfuscate, defaults.deobfuscate);
+        assert_eq!(passes.reorder_stack, defaults.reorder_stack);
+        assert_eq!(passes.max_code_objects, None);
+    }
+
+    #[test]
+    fn defaults_only_recompute_metadata() {
+        let defaults = serde_json::to_value(PassConfig::default()).unwrap();
+        let enabled: Vec<&str> = Pass::ALL
+            .iter()
+            .copied()
+            .filter(|name| defaults[name] == true)
+            .collect();
+
+        assert_eq!(
+            enabled,
+            [
+                "deobfuscate",
+                "detect-tricks",
+                "recompute-metadata",
+                "validate",
+            ]
+        );
+    }
+
+    #[test]
+    fn rewriting_passes_can_be_turned_on() {
+        let mut passes = PassConfig::default();
+        for name in ["inline-strings", "reorder-stack", "differential-check"] {
+            passes.set(name.parse().unwrap(), true);
+        }
+        assert!(passes.inline_strings && passes.reorder_stack && passes.differential_check);
+        assert!(passes.deobfuscate);
+
+        let config: ConfigFile = serde_json::from_str(
+            r#"{"passes": {"inline-strings": true, "reorder-stack": true, "differential-check": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&config.passes).unwrap(),
+            serde_json::to_value(&passes).unwrap()
+        );
+    }
+
+    #[test]
+    fn config_files_reject_unknown_fields() {
+        assert!(serde_json::from_str::<ConfigFile>(r#"{"pases": {}}"#).is_err());
+        assert!(serde_json::from_str::<ConfigFile>(r#"{"passes": {"inline": true}}"#).is_err());
+    }
+
+    #[test]
+    fn every_pass_name_parses_and_toggles_its_pass() {
+        for name in Pass::ALL {
+            let pass: Pass = name.parse().unwrap();
+            let mut passes = PassConfig::default();
+            passes.set(pass, false);
+            let disabled = serde_json::to_value(&passes).unwrap();
+            passes.set(pass, true);
+            let enabled = serde_json::to_value(&passes).unwrap();
+            assert_eq!(disabled[name], false, "{}", name);
+            assert_eq!(enabled[name], true, "{}", name);
+        }
+
+        assert!("not-a-pass".parse::<Pass>().is_err());
+    }
+}
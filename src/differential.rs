@@ -0,0 +1,372 @@
+use crate::builtins::{BuiltinError, Builtins};
+use crate::bytecode::{code_objects, load_code};
+use crate::pure_vm::{is_pure, module_bindings, EvalError, Interpreter};
+use anyhow::Result;
+use num_bigint::BigInt;
+use py27_marshal::bstr::BString;
+use py27_marshal::{Code, Obj};
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// Number of generated argument lists each function is called with
+const INPUTS_PER_FUNCTION: usize = 16;
+
+/// String consts longer than this are not used as inputs
+const MAX_CONST_INPUT_LEN: usize = 64;
+
+/// The result of running the pure functions of a stage before and after
+/// deobfuscation on the same inputs
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DifferentialReport {
+    pub stage: String,
+    /// Functions which are pure both before and after deobfuscation
+    pub functions_compared: usize,
+    /// Calls whose outcome could be compared, across all functions
+    pub inputs_compared: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+/// A function which behaved differently after deobfuscation. The arguments
+/// and outcomes are those of the first input which diverged.
+#[derive(Debug, Clone, Serialize)]
+pub struct Divergence {
+    /// Position of the function in the stage's depth-first code object order
+    pub function_index: usize,
+    pub function: String,
+    /// Number of compared inputs which diverged
+    pub inputs_diverged: usize,
+    pub args: Vec<String>,
+    pub original: String,
+    pub deobfuscated: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "function {} ({}) called with ({}): {} before deobfuscation, {} after ({} divergent inputs)",
+            self.function_index,
+            self.function,
+            self.args.join(", "),
+            self.original,
+            self.deobfuscated,
+            self.inputs_diverged
+        )
+    }
+}
+
+/// What calling a function did
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Returned(String),
+    /// Name and message of the exception raised
+    Raised(&'static str, String),
+    /// The function's bytecode could not be executed
+    Crashed(String),
+    /// The VM does not model something the function did
+    Inconclusive,
+}
+
+impl Outcome {
+    fn of(result: Result<Obj, EvalError>) -> Outcome {
+        match result {
+            Ok(value) => Outcome::Returned(format!("{:?}", value)),
+            Err(EvalError::Builtin(BuiltinError::TypeError(message))) => {
+                Outcome::Raised("TypeError", message)
+            }
+            Err(EvalError::Builtin(BuiltinError::ValueError(message))) => {
+                Outcome::Raised("ValueError", message)
+            }
+            Err(EvalError::Builtin(BuiltinError::KeyError(message))) => {
+                Outcome::Raised("KeyError", message)
+            }
+            Err(EvalError::Builtin(BuiltinError::ZeroDivisionError(message))) => {
+                Outcome::Raised("ZeroDivisionError", message)
+            }
+            Err(
+                e @ (EvalError::StackUnderflow | EvalError::BadInstruction(_) | EvalError::NoLoop),
+            ) => Outcome::Crashed(e.to_string()),
+            Err(_) => Outcome::Inconclusive,
+        }
+    }
+
+    /// Whether the outcomes are the same as far as the caller can tell.
+    /// Exceptions only need to be of the same type.
+    fn matches(&self, other: &Outcome) -> bool {
+        match (self, other) {
+            (Outcome::Returned(a), Outcome::Returned(b)) => a == b,
+            (Outcome::Raised(a, _), Outcome::Raised(b, _)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Returned(value) => write!(f, "returned {}", value),
+            Outcome::Raised(name, message) => write!(f, "raised {}: {}", name, message),
+            Outcome::Crashed(reason) => write!(f, "crashed: {}", reason),
+            Outcome::Inconclusive => f.write_str("inconclusive"),
+        }
+    }
+}
+
+/// Runs every function which is pure both in `original` and in `deob` on the
+/// same generated inputs in the small VM and compares what they return or
+/// raise. Functions are paired up by their position in the depth-first code
+/// object order.
+///
+/// Inputs for which either version does something the VM doesn't model, or
+/// for which the original version can't be executed, are skipped.
+pub fn compare<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    stage: &str,
+    original: &[u8],
+    deob: &[u8],
+) -> Result<DifferentialReport> {
+    let original = code_objects(&load_code(original)?);
+    let deob = code_objects(&load_code(deob)?);
+    let builtins = Builtins::python27();
+
+    let mut report = DifferentialReport {
+        stage: stage.to_string(),
+        ..Default::default()
+    };
+
+    let (original_bindings, deob_bindings) =
+        match (module_bindings::<O>(&original), module_bindings::<O>(&deob)) {
+            (Some(original), Some(deob)) => (original, deob),
+            _ => return Ok(report),
+        };
+
+    for (index, (original, deob)) in original.iter().zip(deob.iter()).enumerate() {
+        if original.argcount != deob.argcount
+            || !is_pure::<O>(original, &builtins, &original_bindings)
+            || !is_pure::<O>(deob, &builtins, &deob_bindings)
+        {
+            continue;
+        }
+
+        report.functions_compared += 1;
+        let mut divergence: Option<Divergence> = None;
+        let mut rng = XorShift::new(index as u64);
+        for _ in 0..INPUTS_PER_FUNCTION {
+            let picks: Vec<usize> = (0..original.argcount)
+                .map(|_| rng.next() as usize)
+                .collect();
+            // Each call gets its own copy of the inputs in case they are
+            // mutated
+            let args = || {
+                let candidates = candidates(original);
+                picks
+                    .iter()
+                    .map(|pick| candidates[pick % candidates.len()].clone())
+                    .collect::<Vec<Obj>>()
+            };
+
+            let before =
+                Outcome::of(Interpreter::<O>::new(Arc::clone(original), &builtins).call(args()));
+            if matches!(before, Outcome::Inconclusive | Outcome::Crashed(_)) {
+                continue;
+            }
+            let after =
+                Outcome::of(Interpreter::<O>::new(Arc::clone(deob), &builtins).call(args()));
+            if after == Outcome::Inconclusive {
+                continue;
+            }
+
+            report.inputs_compared += 1;
+            if before.matches(&after) {
+                continue;
+            }
+
+            match &mut divergence {
+                Some(divergence) => divergence.inputs_diverged += 1,
+                None => {
+                    divergence = Some(Divergence {
+                        function_index: index,
+                        function: original.name.to_string(),
+                        inputs_diverged: 1,
+                        args: args().iter().map(|arg| format!("{:?}", arg)).collect(),
+                        original: before.to_string(),
+                        deobfuscated: after.to_string(),
+                    })
+                }
+            }
+        }
+
+        report.divergences.extend(divergence);
+    }
+
+    Ok(report)
+}
+
+/// Values a function may be called with: a fixed set of common values of each
+/// type, followed by the function's own short string and int consts, which
+/// tend to steer it down its more interesting paths
+fn candidates(code: &Code) -> Vec<Obj> {
+    let int = |value: i64| Obj::Long(Arc::new(BigInt::from(value)));
+    let string = |value: &[u8]| Obj::String(Arc::new(BString::from(value)));
+    let list = |items: Vec<Obj>| Obj::List(Arc::new(RwLock::new(items)));
+
+    let mut candidates = vec![
+        int(0),
+        int(1),
+        int(-1),
+        int(2),
+        int(7),
+        int(255),
+        int(256),
+        string(b""),
+        string(b"a"),
+        string(b"abc"),
+        string(b"Hello, World!"),
+        string(b"\x00\x01\xff"),
+        Obj::Tuple(Arc::new(vec![int(1), int(2), int(3)])),
+        Obj::Tuple(Arc::new(vec![string(b"a"), string(b"b")])),
+        list(vec![int(1), int(2), int(3)]),
+        list(vec![string(b"x"), string(b"y")]),
+        Obj::None,
+        Obj::Bool(true),
+        Obj::Bool(false),
+        Obj::Float(1.5),
+    ];
+
+    candidates.extend(
+        code.consts
+            .iter()
+            .filter(|c| match c {
+                Obj::Long(_) => true,
+                Obj::String(s) => s.len() <= MAX_CONST_INPUT_LEN,
+                _ => false,
+            })
+            .cloned(),
+    );
+
+    candidates
+}
+
+/// A small deterministic PRNG, so that a module is always checked with the
+/// same inputs
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // The state must never be zero
+        XorShift(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::replace_bytecode;
+    use crate::bytecode::testing::{assemble, compile};
+    use pydis::opcode::py27::Standard;
+
+    const CLASSIFY: &str = "def f(x):\n    if x == 1:\n        return 'one'\n    return 'other'\n";
+
+    fn compare_sources(original: &str, deob: &str) -> DifferentialReport {
+        compare::<Standard>("stage4", &compile(original), &compile(deob)).unwrap()
+    }
+
+    /// What the first function in `data` does when called with `0`
+    fn outcome(data: &[u8]) -> Outcome {
+        let function = Arc::clone(&code_objects(&load_code(data).unwrap())[1]);
+        let builtins = Builtins::python27();
+        let arg = Obj::Long(Arc::new(BigInt::from(0)));
+
+        Outcome::of(Interpreter::<Standard>::new(function, &builtins).call(vec![arg]))
+    }
+
+    #[test]
+    fn unchanged_functions_do_not_diverge() {
+        let report = compare_sources(CLASSIFY, CLASSIFY);
+
+        assert_eq!(report.stage, "stage4");
+        assert_eq!(report.functions_compared, 1);
+        assert_eq!(report.inputs_compared, INPUTS_PER_FUNCTION);
+        assert!(report.divergences.is_empty());
+    }
+
+    #[test]
+    fn changed_functions_are_reported() {
+        // The predicate was folded the wrong way, flipping the branch
+        let flipped = CLASSIFY.replace("==", "!=");
+
+        let report = compare_sources(CLASSIFY, &flipped);
+
+        assert_eq!(report.functions_compared, 1);
+        assert_eq!(report.divergences.len(), 1);
+        let divergence = &report.divergences[0];
+        assert_eq!(divergence.function_index, 1);
+        assert_eq!(divergence.function, "f");
+        // Every input takes the other branch
+        assert_eq!(divergence.inputs_diverged, report.inputs_compared);
+        assert_eq!(divergence.args.len(), 1);
+
+        assert_eq!(divergence.original, "returned \"other\"");
+        assert_eq!(divergence.deobfuscated, "returned \"one\"");
+    }
+
+    #[test]
+    fn inconclusive_originals_are_skipped() {
+        let original = compile("def f(x):\n    while 1:\n        pass\n");
+        assert_eq!(outcome(&original), Outcome::Inconclusive);
+
+        let report =
+            compare::<Standard>("stage4", &original, &compile("def f(x):\n    return 1\n"))
+                .unwrap();
+
+        assert_eq!(report.functions_compared, 1);
+        assert_eq!(report.inputs_compared, 0);
+        assert!(report.divergences.is_empty());
+    }
+
+    #[test]
+    fn crashed_originals_are_skipped() {
+        use Standard::*;
+
+        let deob = compile("def f(x):\n    return x\n");
+        // Pops a value which was never pushed
+        let crashing = assemble(&[(POP_TOP, None), (LOAD_CONST, Some(0)), (RETURN_VALUE, None)]);
+        let original = replace_bytecode(&deob, &[None, Some(crashing)]).unwrap();
+        assert!(matches!(outcome(&original), Outcome::Crashed(_)));
+
+        let report = compare::<Standard>("stage4", &original, &deob).unwrap();
+
+        assert_eq!(report.functions_compared, 1);
+        assert_eq!(report.inputs_compared, 0);
+        assert!(report.divergences.is_empty());
+    }
+
+    #[test]
+    fn inputs_are_the_same_on_every_run() {
+        let values = |seed| {
+            let mut rng = XorShift::new(seed);
+            (0..INPUTS_PER_FUNCTION)
+                .map(|_| rng.next())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(values(0), values(0));
+        assert_ne!(values(0), values(1));
+        assert!(values(0).iter().all(|value| *value != 0));
+
+        let flipped = CLASSIFY.replace("==", "!=");
+        assert_eq!(
+            compare_sources(CLASSIFY, &flipped).divergences[0].args,
+            compare_sources(CLASSIFY, &flipped).divergences[0].args
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Names of the passes `unfuck` generates graphs for. These are used to split
+/// the pass name back out of the graph's file name since both the pass name
+/// and the function name may contain underscores.
+const KNOWN_PASSES: &[&str] = &[
+    "unused_partially_removed_edges",
+    "unused_all_removed_edges",
+    "const_conditions_solved",
+    "instructions_removed",
+    "last_merged",
+    "before_dead",
+    "after_dead",
+    "updated_bb",
+    "compileall",
+    "offsets",
+    "joined",
+    "target",
+    "before",
+];
+
+/// Name of the index file written to each module's graph directory
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// An entry in a module's graph index
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphEntry {
+    /// Stage the graph was generated for (e.g. `stage4`)
+    pub stage: String,
+    /// Deobfuscation pass which generated the graph
+    pub pass: String,
+    /// File name of the graph
+    pub name: String,
+    /// Path of the graph, relative to the module's graph directory
+    pub path: PathBuf,
+}
+
+/// Collects graphs generated while deobfuscating a single module and writes
+/// them to `<output_dir>/graphs/<module path>/<stage>/<pass>/`.
+#[derive(Debug)]
+pub struct GraphSink {
+    root: PathBuf,
+    entries: Mutex<Vec<GraphEntry>>,
+}
+
+impl GraphSink {
+    /// Creates a new sink for the module at `module_path`, which is relative
+    /// to `output_dir`
+    pub fn new(output_dir: &Path, module_path: &Path) -> GraphSink {
+        GraphSink {
+            root: output_dir
+                .join("graphs")
+                .join(module_path.with_extension("")),
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Writes a graph generated by the deobfuscator for the given `stage`.
+    /// `name` is the file name provided by `unfuck`, which is of the form
+    /// `$FILENUMBER_phase$PHASE_$FILENAME_$NAME_$PASS.dot`.
+    pub fn write_graph(&self, stage: &str, name: &str, data: &str) -> Result<()> {
+        let (file_name, pass) = split_pass_name(name);
+        self.write(stage, pass, &file_name, data)?;
+
+        Ok(())
+    }
+
+    /// Writes a graph to `<stage>/<pass>/<file_name>` and records it in the index
+    pub fn write(&self, stage: &str, pass: &str, file_name: &str, data: &str) -> Result<PathBuf> {
+        let relative_path = Path::new(stage).join(pass).join(file_name);
+        let target_path = self.root.join(&relative_path);
+        std::fs::create_dir_all(target_path.parent().unwrap())?;
+        std::fs::write(&target_path, data.as_bytes())?;
+
+        self.entries.lock().unwrap().push(GraphEntry {
+            stage: stage.to_string(),
+            pass: pass.to_string(),
+            name: file_name.to_string(),
+            path: relative_path,
+        });
+
+        Ok(target_path)
+    }
+
+    /// Writes the index of all graphs generated so far for this module. This
+    /// is safe to call multiple times -- the index is rewritten each time.
+    pub fn write_index(&self) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        std::fs::create_dir_all(&self.root)?;
+        let serialized_data = serde_json::to_string_pretty(&entries)?;
+        std::fs::write(self.root.join(INDEX_FILE_NAME), serialized_data.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Splits the pass name off of a graph file name generated by `unfuck`. Returns
+/// the file name without the pass, and the pass name.
+fn split_pass_name(name: &str) -> (String, &'static str) {
+    let stem = name.strip_suffix(".dot").unwrap_or(name);
+    for pass in KNOWN_PASSES {
+        if let Some(file_name) = stem
+            .strip_suffix(pass)
+            .and_then(|prefix| prefix.strip_suffix('_'))
+        {
+            return (format!("{}.dot", file_name), pass);
+        }
+    }
+
+    (name.to_string(), "unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_names_are_split_off_of_graph_file_names() {
+        assert_eq!(
+            split_pass_name("0_phase1_foo_bar_unused_all_removed_edges.dot"),
+            (
+                "0_phase1_foo_bar.dot".to_string(),
+                "unused_all_removed_edges"
+            )
+        );
+        assert_eq!(
+            split_pass_name("3_phase0_mod_fn_before_dead.dot"),
+            ("3_phase0_mod_fn.dot".to_string(), "before_dead")
+        );
+        assert_eq!(
+            split_pass_name("3_phase0_mod_fn_before.dot"),
+            ("3_phase0_mod_fn.dot".to_string(), "before")
+        );
+        assert_eq!(
+            split_pass_name("strange.dot"),
+            ("strange.dot".to_string(), "unknown")
+        );
+    }
+
+    #[test]
+    fn graphs_are_grouped_by_stage_and_pass_and_indexed() {
+        let output_dir =
+            std::env::temp_dir().join(format!("wowsdeob-graphs-{}", std::process::id()));
+        let sink = GraphSink::new(&output_dir, Path::new("scripts/module.pyc"));
+        sink.write_graph("stage4", "1_phase0_module_f_target.dot", "digraph {}")
+            .unwrap();
+        sink.write("stage3", "cfg", "f.json", "{}").unwrap();
+        sink.write_index().unwrap();
+
+        let root = output_dir.join("graphs/scripts/module");
+        assert_eq!(
+            std::fs::read_to_string(root.join("stage4/target/1_phase0_module_f.dot")).unwrap(),
+            "digraph {}"
+        );
+        let index: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(root.join(INDEX_FILE_NAME)).unwrap()).unwrap();
+        let passes: Vec<&str> = index
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["pass"].as_str().unwrap())
+            .collect();
+        // Sorted by path, so stage3 comes first
+        assert_eq!(passes, ["cfg", "target"]);
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
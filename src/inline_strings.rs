@@ -0,0 +1,326 @@
+use crate::builtins::Builtins;
+use crate::bytecode::{code_objects, load_code, opcode_byte, rewrite_code_objects, CodeOverrides};
+use crate::pure_vm::{
+    basic_blocks, is_const, is_pure, module_bindings, name_arg, type_name, Interpreter,
+};
+use anyhow::Result;
+use log::debug;
+use py27_marshal::bstr::BString;
+use py27_marshal::{Code, Obj};
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The result of inlining a stage's decoded strings
+pub struct InlinedStrings {
+    /// Marshalled code of the stage with the call sites replaced
+    pub data: Vec<u8>,
+    /// Number of call sites which were replaced
+    pub call_sites: usize,
+}
+
+/// Finds module-level helper functions which only transform their arguments,
+/// evaluates every call to them whose arguments are all consts with the
+/// [`pure_vm`](crate::pure_vm) interpreter, and replaces each call with a `LOAD_CONST` of the result. This undoes
+/// strings being stored encoded and decoded at import time.
+///
+/// A helper may only use its arguments, its consts, and builtins, and may not
+/// have default arguments or nested functions. Its name must not be rebound
+/// anywhere in the module. Calls which look the helper up with `LOAD_NAME` are
+/// only inlined in the module's own code, as a class body may shadow it.
+///
+/// Returns `None` if nothing was inlined.
+pub fn inline<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    data: &[u8],
+) -> Result<Option<InlinedStrings>> {
+    let module = load_code(data)?;
+    let code_objects = code_objects(&module);
+    let builtins = Builtins::python27();
+    let helpers = find_helpers::<O>(&code_objects, &builtins);
+    if helpers.is_empty() {
+        return Ok(None);
+    }
+
+    let mut overrides = Vec::with_capacity(code_objects.len());
+    let mut call_sites = 0;
+    for (index, code) in code_objects.iter().enumerate() {
+        let code_overrides = inline_calls::<O>(code, index == 0, &helpers, &builtins)?;
+        call_sites += code_overrides.extra_consts.len();
+        overrides.push(code_overrides);
+    }
+
+    if call_sites == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(InlinedStrings {
+        data: rewrite_code_objects(data, &overrides)?,
+        call_sites,
+    }))
+}
+
+/// Returns the pure helper functions defined at module level, keyed by name
+fn find_helpers<O: Opcode<Mnemonic = Mnemonic>>(
+    code_objects: &[Arc<Code>],
+    builtins: &Builtins,
+) -> HashMap<Arc<BString>, Arc<Code>> {
+    let module = &code_objects[0];
+    let module_blocks = match basic_blocks::<O>(module) {
+        Some(blocks) => blocks,
+        None => return HashMap::new(),
+    };
+    let bindings = match module_bindings::<O>(code_objects) {
+        Some(bindings) => bindings,
+        None => return HashMap::new(),
+    };
+
+    let mut helpers = HashMap::new();
+    for block in &module_blocks {
+        for window in block.windows(3) {
+            let (load, make_function, store) = (&window[0], &window[1], &window[2]);
+            if load.opcode.mnemonic() != Mnemonic::LOAD_CONST
+                || make_function.opcode.mnemonic() != Mnemonic::MAKE_FUNCTION
+                || make_function.arg != Some(0)
+                || store.opcode.mnemonic() != Mnemonic::STORE_NAME
+            {
+                continue;
+            }
+
+            let (function, name) = match (
+                load.arg.and_then(|arg| module.consts.get(arg as usize)),
+                name_arg(module, store),
+            ) {
+                (Some(Obj::Code(function)), Some(name)) => (function, name),
+                _ => continue,
+            };
+
+            if bindings.get(name) == Some(&1) && is_pure::<O>(function, builtins, &bindings) {
+                debug!("Found string decoding helper `{}`", name);
+                helpers.insert(Arc::clone(name), Arc::clone(function));
+            }
+        }
+    }
+
+    helpers
+}
+
+/// Replaces the helper calls in `code` whose arguments are all consts. Helpers
+/// looked up with `LOAD_NAME` are only replaced if `code` is the module's code,
+/// since elsewhere it resolves class body names first.
+fn inline_calls<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+    is_module: bool,
+    helpers: &HashMap<Arc<BString>, Arc<Code>>,
+    builtins: &Builtins,
+) -> Result<CodeOverrides> {
+    let mut new_code = code.code.to_vec();
+    let mut new_consts = Vec::new();
+    let blocks = match basic_blocks::<O>(code) {
+        Some(blocks) => blocks,
+        None => return Ok(CodeOverrides::default()),
+    };
+
+    for block in blocks {
+        for (call_index, call) in block.iter().enumerate() {
+            let argc = match (call.opcode.mnemonic(), call.arg) {
+                (Mnemonic::CALL_FUNCTION, Some(argc)) if argc <= 0xff => argc as usize,
+                _ => continue,
+            };
+
+            // The call site is the helper's name, each argument, and the call.
+            // Being in one block guarantees nothing jumps into the middle of it.
+            let start = match call_index.checked_sub(argc + 1) {
+                Some(start) => start,
+                None => continue,
+            };
+            let callee = &block[start];
+            let helper = match callee.opcode.mnemonic() {
+                Mnemonic::LOAD_NAME if !is_module => None,
+                Mnemonic::LOAD_NAME | Mnemonic::LOAD_GLOBAL => {
+                    name_arg(code, callee).and_then(|name| Some((name, helpers.get(name)?)))
+                }
+                _ => None,
+            };
+            let (name, helper) = match helper {
+                Some((name, helper)) if helper.argcount as usize == argc => (name, helper),
+                _ => continue,
+            };
+
+            let args = block[start + 1..call_index]
+                .iter()
+                .map(|instr| match instr.opcode.mnemonic() {
+                    Mnemonic::LOAD_CONST => code.consts.get(instr.arg? as usize).cloned(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<Obj>>>();
+            let args = match args {
+                Some(args) => args,
+                None => continue,
+            };
+
+            let const_index = code.consts.len() + new_consts.len();
+            if const_index > u16::MAX as usize {
+                continue;
+            }
+
+            let value = match Interpreter::<O>::new(Arc::clone(helper), builtins).call(args) {
+                Ok(value) if is_const(&value) => value,
+                Ok(value) => {
+                    debug!(
+                        "The call to `{}` at offset {} of `{}` returned a {}, which cannot be stored as a const",
+                        name, callee.offset, code.name, type_name(&value)
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    debug!(
+                        "Could not evaluate the call to `{}` at offset {} of `{}`: {}",
+                        name, callee.offset, code.name, e
+                    );
+                    continue;
+                }
+            };
+
+            // Load the value and skip over the rest of the call site, which is
+            // left in place as unreachable code
+            let site_start = callee.offset as usize;
+            let site_len = (call.next_offset() - callee.offset) as usize;
+            let mut replacement = encode::<O>(Mnemonic::LOAD_CONST, const_index as u16)?;
+            replacement.extend(encode::<O>(Mnemonic::JUMP_FORWARD, (site_len - 6) as u16)?);
+            new_code[site_start..site_start + replacement.len()].copy_from_slice(&replacement);
+            new_consts.push(value);
+        }
+    }
+
+    if new_consts.is_empty() {
+        return Ok(CodeOverrides::default());
+    }
+
+    Ok(CodeOverrides {
+        code: Some(new_code),
+        extra_consts: new_consts,
+        ..Default::default()
+    })
+}
+
+fn encode<O: Opcode<Mnemonic = Mnemonic>>(mnemonic: Mnemonic, arg: u16) -> Result<Vec<u8>> {
+    let mut out = vec![opcode_byte::<O>(mnemonic)?];
+    out.extend_from_slice(&arg.to_le_bytes());
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::compile;
+    use pydis::opcode::py27::Standard;
+
+    fn string_consts(code: &Code) -> Vec<String> {
+        code.consts
+            .iter()
+            .filter_map(|c| match c {
+                Obj::String(s) => Some(s.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn calls_with_const_arguments_are_replaced() {
+        let data =
+            compile("def decode(s):\n    return s[::-1]\nx = decode('olleh')\ny = decode(x)\n");
+
+        let inlined = inline::<Standard>(&data).unwrap().unwrap();
+        let module = load_code(&inlined.data).unwrap();
+
+        assert_eq!(inlined.call_sites, 1);
+        assert_eq!(string_consts(&module).last().unwrap(), "hello");
+    }
+
+    #[test]
+    fn rotated_values_are_evaluated_in_python_order() {
+        let data = compile(concat!(
+            "def swap(a, b):\n    a, b = b, a\n    return a + b\n",
+            "def reverse(a, b, c):\n    a, b, c = c, b, a\n    return a + b + c\n",
+            "x = swap('a', 'b')\ny = reverse('a', 'b', 'c')\n",
+        ));
+        let module = load_code(&data).unwrap();
+        let functions = &code_objects(&module)[1..];
+        let mnemonics = |code: &Code| {
+            basic_blocks::<Standard>(code)
+                .unwrap()
+                .iter()
+                .flatten()
+                .map(|instr| instr.opcode.mnemonic())
+                .collect::<Vec<_>>()
+        };
+        assert!(mnemonics(&functions[0]).contains(&Mnemonic::ROT_TWO));
+        assert!(mnemonics(&functions[1]).contains(&Mnemonic::ROT_THREE));
+
+        let inlined = inline::<Standard>(&data).unwrap().unwrap();
+        let module = load_code(&inlined.data).unwrap();
+
+        assert_eq!(inlined.call_sites, 2);
+        assert_eq!(
+            string_consts(&module)[string_consts(&module).len() - 2..],
+            ["ba", "cba"]
+        );
+    }
+
+    #[test]
+    fn class_bodies_which_shadow_a_helper_are_left_alone() {
+        let data = compile(concat!(
+            "def decode(s):\n    return s + '!'\n",
+            "class C:\n    decode = len\n    x = decode('abc')\n",
+            "def f():\n    return decode('def')\n",
+            "y = decode('ghi')\n",
+        ));
+        let module = load_code(&data).unwrap();
+        let class_body = code_objects(&module)
+            .into_iter()
+            .find(|code| code.name.as_slice() == b"C")
+            .unwrap();
+
+        let inlined = inline::<Standard>(&data).unwrap().unwrap();
+        let inlined_module = load_code(&inlined.data).unwrap();
+        let inlined_codes = code_objects(&inlined_module);
+        let inlined_class_body = inlined_codes
+            .iter()
+            .find(|code| code.name.as_slice() == b"C")
+            .unwrap();
+        let inlined_function = inlined_codes
+            .iter()
+            .find(|code| code.name.as_slice() == b"f")
+            .unwrap();
+
+        assert_eq!(inlined.call_sites, 2);
+        assert_eq!(inlined_class_body.code, class_body.code);
+        assert_eq!(inlined_class_body.consts.len(), class_body.consts.len());
+        assert!(string_consts(inlined_function).contains(&"def!".to_string()));
+        assert!(string_consts(&inlined_module).contains(&"ghi!".to_string()));
+    }
+
+    #[test]
+    fn helpers_rebound_in_the_module_are_not_inlined() {
+        let data = compile(concat!(
+            "def decode(s):\n    return s + '!'\n",
+            "x = decode('abc')\n",
+            "decode = len\n",
+        ));
+
+        assert!(inline::<Standard>(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn impure_helpers_are_not_inlined() {
+        let data = compile(concat!(
+            "suffix = '!'\n",
+            "def decode(s):\n    return s + suffix\n",
+            "x = decode('abc')\n",
+        ));
+
+        assert!(inline::<Standard>(&data).unwrap().is_none());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::layers::{Layer, LayerKey};
+use crate::profiles::Profile;
+use crate::smallvm::SwapMap;
+use anyhow::Result;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::path::Path;
+
+/// Obfuscation keys recovered while unpacking a module's layers. If a module
+/// has more than one layer of a kind, only the outermost one's key is kept.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyMaterial {
+    /// Name of the profile the module was unpacked with
+    pub profile: Option<String>,
+    pub profile_version: Option<u32>,
+    /// The key of the XOR layer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage1: Option<Stage1Key>,
+    /// The swapmap of the swapmap loader layer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage2: Option<SwapMap>,
+    /// The location of the compressed payload layer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage3: Option<Stage3Payload>,
+}
+
+/// The XOR key the stage2 payload is encrypted with
+#[derive(Debug, Clone, Serialize)]
+pub struct Stage1Key {
+    /// Index of the key in the module's consts
+    pub const_index: usize,
+    #[serde(serialize_with = "serialize_hex")]
+    pub key: Vec<u8>,
+}
+
+/// Where the stage4 payload is stored in the stage3 code object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayloadSource {
+    /// The code bytes
+    Code,
+    /// The string const at this index
+    Const(usize),
+}
+
+impl fmt::Display for PayloadSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadSource::Code => f.write_str("code"),
+            PayloadSource::Const(index) => write!(f, "const {}", index),
+        }
+    }
+}
+
+/// How a compressed payload is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayloadEncoding {
+    Base64,
+    Raw,
+}
+
+impl fmt::Display for PayloadEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PayloadEncoding::Base64 => "base64",
+            PayloadEncoding::Raw => "raw",
+        })
+    }
+}
+
+/// The encoded stage4 payload embedded in the stage3 code object
+#[derive(Debug, Clone, Serialize)]
+pub struct Stage3Payload {
+    pub source: PayloadSource,
+    /// Offset of the payload in its source
+    pub offset: usize,
+    /// Whether the payload was stored back to front
+    pub reversed: bool,
+    pub encoding: PayloadEncoding,
+    /// The payload in decoding order. Raw payloads are base64-encoded here.
+    pub base64: String,
+}
+
+impl KeyMaterial {
+    /// Collects the keys of `layers`. Returns `None` if there are no layers.
+    pub fn from_layers(profile: Option<&Profile>, layers: &[Layer]) -> Option<KeyMaterial> {
+        if layers.is_empty() {
+            return None;
+        }
+
+        let mut keys = KeyMaterial {
+            profile: profile.map(|profile| profile.name.clone()),
+            profile_version: profile.map(|profile| profile.version),
+            stage1: None,
+            stage2: None,
+            stage3: None,
+        };
+        for layer in layers {
+            match &layer.key {
+                LayerKey::Xor(key) => {
+                    keys.stage1.get_or_insert_with(|| key.clone());
+                }
+                LayerKey::SwapMap(swapmap) => {
+                    keys.stage2.get_or_insert_with(|| swapmap.clone());
+                }
+                LayerKey::Payload(payload) => {
+                    keys.stage3.get_or_insert_with(|| payload.clone());
+                }
+            }
+        }
+
+        Some(keys)
+    }
+
+    /// Writes the key material as JSON to `path`
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let serialized_data = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, serialized_data.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+fn serialize_hex<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!(
+        "{}",
+        data.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::{peel, LayerDecoder};
+    use crate::profiles::ProfileSet;
+    use crate::smallvm::Stage2Mode;
+    use pydis::opcode::py27::Standard;
+    use std::collections::BTreeMap;
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test_data/obfuscated/compiler/ast.pyc"
+    );
+
+    fn xor_layer(const_index: usize, key: &[u8]) -> Layer {
+        Layer {
+            decoder: LayerDecoder::Xor,
+            data: vec![],
+            key: LayerKey::Xor(Stage1Key {
+                const_index,
+                key: key.to_vec(),
+            }),
+        }
+    }
+
+    fn payload_layer(source: PayloadSource) -> Layer {
+        Layer {
+            decoder: LayerDecoder::CompressedPayload,
+            data: vec![],
+            key: LayerKey::Payload(Stage3Payload {
+                source,
+                offset: 2,
+                reversed: false,
+                encoding: PayloadEncoding::Raw,
+                base64: "eJw=".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn only_the_outermost_key_of_each_kind_is_kept() {
+        assert!(KeyMaterial::from_layers(None, &[]).is_none());
+
+        let layers = [
+            xor_layer(3, &[1]),
+            payload_layer(PayloadSource::Const(4)),
+            xor_layer(5, &[2]),
+            payload_layer(PayloadSource::Code),
+        ];
+        let keys = KeyMaterial::from_layers(None, &layers).unwrap();
+        assert_eq!(keys.profile, None);
+        assert_eq!(keys.stage1.unwrap().const_index, 3);
+        assert!(keys.stage2.is_none());
+        assert_eq!(keys.stage3.unwrap().source, PayloadSource::Const(4));
+    }
+
+    #[test]
+    fn keys_serialize_with_hex_keys_and_missing_layers_omitted() {
+        let profiles = ProfileSet::builtin();
+        let layers = [
+            xor_layer(3, &[0x0a, 0xff]),
+            Layer {
+                decoder: LayerDecoder::SwapMapLoader,
+                data: vec![],
+                key: LayerKey::SwapMap(SwapMap {
+                    function_index: 8,
+                    const_index: 1,
+                    entries: BTreeMap::from([(1, 2)]),
+                }),
+            },
+        ];
+        let keys = KeyMaterial::from_layers(Some(&profiles.profiles[0]), &layers).unwrap();
+
+        let json = serde_json::to_value(&keys).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "profile": "lesta",
+                "profile_version": profiles.profiles[0].version,
+                "stage1": {"const_index": 3, "key": "0aff"},
+                "stage2": {"function_index": 8, "const_index": 1, "entries": {"1": 2}},
+            })
+        );
+
+        let payload = serde_json::to_value(KeyMaterial::from_layers(
+            None,
+            &[payload_layer(PayloadSource::Const(4))],
+        ))
+        .unwrap();
+        assert_eq!(payload["stage3"]["source"], serde_json::json!({"const": 4}));
+        assert_eq!(payload["stage3"]["encoding"], "raw");
+    }
+
+    #[test]
+    fn keys_are_recovered_from_an_encrypted_module() {
+        let profiles = ProfileSet::builtin();
+        let profile = &profiles.profiles[0];
+        let data = std::fs::read(FIXTURE).unwrap();
+        let layers = peel::<Standard>(&data[8..], Some(profile), Stage2Mode::Auto, None).unwrap();
+        let keys = KeyMaterial::from_layers(Some(profile), &layers).unwrap();
+
+        let stage1 = keys.stage1.unwrap();
+        assert_eq!(stage1.const_index, profile.stage1.key_const_index.unwrap());
+        assert!(!stage1.key.is_empty());
+
+        let stage2 = keys.stage2.unwrap();
+        assert_eq!(stage2.function_index, 8);
+        assert_eq!(stage2.entries.len(), 256);
+
+        let stage3 = keys.stage3.unwrap();
+        assert_eq!(stage3.source, PayloadSource::Code);
+        assert_eq!(stage3.reversed, profile.stage3.reverse_payload);
+        assert_eq!(stage3.encoding, PayloadEncoding::Base64);
+        assert!(stage3.base64.starts_with("eJ"));
+    }
+}
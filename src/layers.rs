@@ -0,0 +1,344 @@
+use crate::bytecode::load_code;
+use crate::key_material::{PayloadSource, Stage1Key, Stage3Payload};
+use crate::payload;
+use crate::profiles::Profile;
+use crate::smallvm::{exec_stage2, Stage2Mode, Stage2Observer, SwapMap};
+use crate::stage1;
+use anyhow::{anyhow, Result};
+use log::debug;
+use py27_marshal::{Code, Obj};
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Peeling stops after this many layers, in case a layer decodes to itself
+pub const MAX_LAYERS: usize = 16;
+
+/// A kind of obfuscation layer which wraps the next layer's code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LayerDecoder {
+    /// The module's code is the next layer XORed with a key in its consts
+    Xor,
+    /// A loader which decodes the previous layer's code with a swapmap and a
+    /// decode loop
+    SwapMapLoader,
+    /// `marshal.loads(zlib.decompress(...))` on a payload embedded in the code
+    CompressedPayload,
+}
+
+impl LayerDecoder {
+    /// Every decoder, in the order they are tried
+    pub const ALL: [LayerDecoder; 3] = [
+        LayerDecoder::Xor,
+        LayerDecoder::SwapMapLoader,
+        LayerDecoder::CompressedPayload,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LayerDecoder::Xor => "xor",
+            LayerDecoder::SwapMapLoader => "swapmap-loader",
+            LayerDecoder::CompressedPayload => "compressed-payload",
+        }
+    }
+
+    /// Whether `code` looks like a layer this decoder can peel. `outer` is
+    /// the code of the layer `code` was peeled from, if any.
+    fn fingerprint(self, code: &Code, outer: Option<&Code>, profile: Option<&Profile>) -> bool {
+        self.matches(&CodeShape::of(code), outer.is_some(), profile)
+    }
+
+    /// Whether a code object with the given shape looks like a layer this
+    /// decoder can peel. `has_outer` is whether it was peeled from another
+    /// layer.
+    pub fn matches(self, code: &CodeShape, has_outer: bool, profile: Option<&Profile>) -> bool {
+        let has_name = |name: &str| code.names.contains(&name.as_bytes());
+        let profile_filename = profile
+            .is_some_and(|profile| code.filename == profile.stage1.encrypted_filename.as_bytes());
+
+        match self {
+            LayerDecoder::Xor => profile_filename && !code.has_code_consts && !has_name("marshal"),
+            LayerDecoder::SwapMapLoader => {
+                profile_filename && has_outer && code.has_code_consts && has_name("marshal")
+            }
+            LayerDecoder::CompressedPayload => {
+                !code.has_code_consts && has_name("loads") && has_name("decompress")
+            }
+        }
+    }
+}
+
+/// The parts of a code object the layer fingerprints look at, independent of
+/// the marshal format it was read from
+pub struct CodeShape<'a> {
+    pub filename: &'a [u8],
+    pub names: Vec<&'a [u8]>,
+    pub has_code_consts: bool,
+}
+
+impl<'a> CodeShape<'a> {
+    fn of(code: &'a Code) -> CodeShape<'a> {
+        CodeShape {
+            filename: code.filename.as_slice(),
+            names: code.names.iter().map(|name| name.as_slice()).collect(),
+            has_code_consts: code.consts.iter().any(|c| matches!(c, Obj::Code(_))),
+        }
+    }
+}
+
+/// Key material recovered while peeling a layer
+#[derive(Debug, Clone)]
+pub enum LayerKey {
+    Xor(Stage1Key),
+    SwapMap(SwapMap),
+    Payload(Stage3Payload),
+}
+
+/// A layer peeled off of a module
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub decoder: LayerDecoder,
+    /// Marshalled code of the layer this one wrapped
+    pub data: Vec<u8>,
+    pub key: LayerKey,
+}
+
+/// Peeling failed partway through a module
+#[derive(Error, Debug)]
+#[error("{error:#}")]
+pub struct PeelError {
+    /// Layers peeled before the failure, outermost first
+    pub layers: Vec<Layer>,
+    pub error: anyhow::Error,
+}
+
+/// Repeatedly peels layers off of the marshalled code in `data`, stopping when
+/// no decoder recognizes the innermost code. The decoders which need a
+/// profile are only tried if one is provided.
+///
+/// `observer` observes the swapmap loader, if one is found.
+pub fn peel<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    data: &[u8],
+    profile: Option<&Profile>,
+    stage2_mode: Stage2Mode,
+    observer: Option<&mut dyn Stage2Observer<O>>,
+) -> Result<Vec<Layer>, PeelError> {
+    let mut layers = Vec::new();
+    match peel_into(data, profile, stage2_mode, observer, &mut layers) {
+        Ok(()) => Ok(layers),
+        Err(error) => Err(PeelError { layers, error }),
+    }
+}
+
+/// Peels layers off of `data` into `layers`, so that the layers peeled before
+/// a failure are kept
+fn peel_into<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    data: &[u8],
+    profile: Option<&Profile>,
+    stage2_mode: Stage2Mode,
+    mut observer: Option<&mut dyn Stage2Observer<O>>,
+    layers: &mut Vec<Layer>,
+) -> Result<()> {
+    let mut outer: Option<Arc<Code>> = None;
+    let mut code = load_code(data)?;
+
+    while let Some(decoder) = LayerDecoder::ALL
+        .iter()
+        .copied()
+        .find(|decoder| decoder.fingerprint(&code, outer.as_deref(), profile))
+    {
+        if layers.len() == MAX_LAYERS {
+            return Err(anyhow!("gave up after peeling {} layers", MAX_LAYERS));
+        }
+
+        debug!("Peeling a {} layer", decoder.name());
+        let (inner, key) = match decoder {
+            LayerDecoder::Xor => {
+                let profile = profile.expect("xor layers require a profile");
+                let (inner, key) = stage1::decrypt(&code, &profile.stage1)?;
+                (inner, LayerKey::Xor(key))
+            }
+            LayerDecoder::SwapMapLoader => {
+                let profile = profile.expect("swapmap loaders require a profile");
+                let outer = outer
+                    .clone()
+                    .expect("swapmap loaders require an outer layer");
+                let output = exec_stage2(
+                    Arc::clone(&code),
+                    outer,
+                    &profile.stage2,
+                    stage2_mode,
+                    observer
+                        .as_mut()
+                        .map(|observer| &mut **observer as &mut dyn Stage2Observer<O>),
+                )?;
+                (output.payload, LayerKey::SwapMap(output.swapmap))
+            }
+            LayerDecoder::CompressedPayload => {
+                let follows_loader =
+                    layers.last().map(|layer| layer.decoder) == Some(LayerDecoder::SwapMapLoader);
+                match payload::locate(&code, profile.map(|profile| &profile.stage3)) {
+                    Ok((inner, location)) => (inner, LayerKey::Payload(location)),
+                    // A swapmap loader always decodes to a payload layer, but
+                    // other modules may use marshal and zlib for their own
+                    // purposes
+                    Err(e) if !follows_loader => {
+                        debug!("Not a compressed payload layer: {}", e);
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        };
+
+        outer = Some(code);
+        code = load_code(&inner)?;
+        layers.push(Layer {
+            decoder,
+            data: inner,
+            key,
+        });
+    }
+
+    Ok(())
+}
+
+/// Peels the layers which can be decoded without knowing how opcodes are
+/// numbered off of the marshalled code in `data`: XOR layers, and compressed
+/// payloads. Swapmap loaders are found and run by their instructions, so
+/// peeling stops at one. Returns the code of every layer reached whose
+/// bytecode is real code, outermost first. Layers whose bytecode holds the
+/// next layer's ciphertext or payload are left out.
+pub fn peel_without_opcodes(data: &[u8], profiles: &[&Profile]) -> Result<Vec<Arc<Code>>> {
+    let mut layers = Vec::new();
+    let mut profile: Option<&Profile> = None;
+    let mut code = load_code(data)?;
+
+    for _ in 0..MAX_LAYERS {
+        let encrypted_with: Vec<&Profile> = profiles
+            .iter()
+            .copied()
+            .filter(|profile| LayerDecoder::Xor.fingerprint(&code, None, Some(profile)))
+            .collect();
+        let inner = if !encrypted_with.is_empty() {
+            let decrypted = encrypted_with.iter().find_map(|candidate| {
+                let (inner, _key) = stage1::decrypt(&code, &candidate.stage1).ok()?;
+                profile = Some(candidate);
+                Some(inner)
+            });
+            match decrypted {
+                Some(inner) => inner,
+                None => return Ok(layers),
+            }
+        } else if LayerDecoder::CompressedPayload.fingerprint(&code, None, profile) {
+            match payload::locate(&code, profile.map(|profile| &profile.stage3)) {
+                Ok((inner, location)) => {
+                    if location.source != PayloadSource::Code {
+                        layers.push(Arc::clone(&code));
+                    }
+                    inner
+                }
+                Err(e) => {
+                    debug!("Not a compressed payload layer: {}", e);
+                    layers.push(code);
+                    return Ok(layers);
+                }
+            }
+        } else {
+            layers.push(code);
+            return Ok(layers);
+        };
+
+        code = load_code(&inner)?;
+    }
+
+    Err(anyhow!("gave up after peeling {} layers", MAX_LAYERS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiles::ProfileSet;
+    use pydis::opcode::py27::Standard;
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test_data/obfuscated/compiler/ast.pyc"
+    );
+
+    fn decoders(layers: &[Layer]) -> Vec<LayerDecoder> {
+        layers.iter().map(|layer| layer.decoder).collect()
+    }
+
+    #[test]
+    fn every_layer_of_an_encrypted_module_is_peeled() {
+        let profiles = ProfileSet::builtin();
+        let data = std::fs::read(FIXTURE).unwrap();
+        let layers = peel::<Standard>(
+            &data[8..],
+            Some(&profiles.profiles[0]),
+            Stage2Mode::Auto,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(decoders(&layers), LayerDecoder::ALL);
+    }
+
+    #[test]
+    fn layers_peeled_before_a_failure_are_kept() {
+        let mut profile = ProfileSet::builtin().profiles.remove(0);
+        profile.stage2.swapmap_function_occurrence = 99;
+        let data = std::fs::read(FIXTURE).unwrap();
+
+        let err = peel::<Standard>(&data[8..], Some(&profile), Stage2Mode::Auto, None).unwrap_err();
+        assert_eq!(decoders(&err.layers), [LayerDecoder::Xor]);
+        assert!(load_code(&err.layers[0].data).is_ok());
+        assert!(err.to_string().contains("pattern not found"), "{}", err);
+    }
+
+    #[test]
+    fn modules_without_layers_peel_to_nothing() {
+        let data = std::fs::read(FIXTURE).unwrap();
+        let layers = peel::<Standard>(&data[8..], None, Stage2Mode::Auto, None).unwrap();
+        assert!(layers.is_empty());
+    }
+
+    #[test]
+    fn opcode_independent_layers_are_peeled() {
+        let profiles = ProfileSet::builtin();
+        let profiles: Vec<&Profile> = profiles.profiles.iter().collect();
+        let data = std::fs::read(FIXTURE).unwrap();
+        let layers =
+            peel::<Standard>(&data[8..], Some(profiles[0]), Stage2Mode::Auto, None).unwrap();
+
+        // The XOR layer is decrypted, but the swapmap loader it wraps can't be
+        // run without knowing the opcodes
+        let codes = peel_without_opcodes(&data[8..], &profiles).unwrap();
+        assert_eq!(codes.len(), 1);
+        assert_eq!(codes[0].code, load_code(&layers[0].data).unwrap().code);
+
+        // Stage3 stores its payload in its bytecode, so only stage4 is kept
+        let codes = peel_without_opcodes(&layers[1].data, &profiles).unwrap();
+        assert_eq!(codes.len(), 1);
+        assert_eq!(codes[0].code, load_code(&layers[2].data).unwrap().code);
+    }
+
+    #[test]
+    fn encrypted_modules_which_cannot_be_decrypted_are_left_out() {
+        let profiles = ProfileSet::builtin();
+        let profiles: Vec<&Profile> = profiles.profiles.iter().collect();
+        let data = std::fs::read(FIXTURE).unwrap();
+        // No key decrypts this to a code object
+        let garbled =
+            crate::bytecode::replace_bytecode(&data[8..], &[Some(vec![0x41; 64])]).unwrap();
+
+        assert!(peel_without_opcodes(&garbled, &profiles)
+            .unwrap()
+            .is_empty());
+
+        // Without a profile which recognizes it, it is treated as plain code
+        assert_eq!(peel_without_opcodes(&garbled, &[]).unwrap().len(), 1);
+    }
+}
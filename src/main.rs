@@ -1,34 +1,97 @@
 #![feature(get_mut_unchecked)]
-#![feature(map_first_last)]
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use bytecode::load_code;
+use config::{ConfigFile, Pass, PassConfig};
 use flate2::read::ZlibDecoder;
+use graphs::GraphSink;
+use key_material::KeyMaterial;
+use layers::Layer;
 use log::trace;
-use py27_marshal::bstr::BString;
+use opcode_detect::{OpcodeCorpus, LOW_CONFIDENCE_MARGIN};
+use opcode_table::{OpcodeTableChoice, Opcodes, Remapped};
+use profiles::{Profile, ProfileSet, Stage2Profile};
 use pydis::opcode::py27::Mnemonic;
 use pydis::opcode::py27::Standard;
 use rayon::prelude::*;
+use smallvm::{Stage2Mode, Stage2Observer, Stage2Output};
+use stage2_debugger::{Debugger, DebuggerQuit};
+use stage2_trace::TraceRecorder;
+use stats::{LayerStats, ModuleError, ModuleStats, RunManifest, StageStats};
 
 use log::{debug, error};
 use memmap::MmapOptions;
-use once_cell::sync::OnceCell;
 use py27_marshal::{Code, Obj};
 use pydis::opcode::Opcode;
-use rayon::Scope;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io;
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::io::{BufWriter, Cursor};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
+/// Python builtins callable from the small VM
+mod builtins;
+/// Bytecode decoding helpers
+mod bytecode;
+/// Control flow graph construction and export
+mod cfg;
+/// Before/after deobfuscation graph diffs
+mod cfg_diff;
+/// Pass configuration
+mod config;
+/// Differential execution of functions before and after deobfuscation
+mod differential;
+/// Graph output
+mod graphs;
+/// Inlining of strings decoded by helper functions at import time
+mod inline_strings;
+/// Obfuscation key material dumps
+mod key_material;
+/// Peeling obfuscation layers off of modules
+mod layers;
+/// Recomputing code object metadata after deobfuscation
+mod metadata;
+/// Statistical detection of permuted opcode tables
+mod opcode_detect;
+/// Opcode tables for builds which renumber the opcodes
+mod opcode_table;
+/// Instruction sequence patterns
+mod pattern;
+/// Stage3 payload location
+mod payload;
+/// Obfuscation profiles for different game versions
+mod profiles;
+/// Evaluation of pure functions in the small VM
+mod pure_vm;
+/// Python 3 .pyc files
+mod py3;
+/// Python 3 bytecode disassembly
+mod py3_dis;
+/// Python 3 marshal format
+mod py3_marshal;
 /// Python VM
 mod smallvm;
+/// Putting shuffled stack sequences back in order
+mod stack_reorder;
+/// Stage1 decryption
+mod stage1;
+/// Interactive stage2 debugger
+mod stage2_debugger;
+/// Static stage2 decoding
+mod stage2_static;
+/// Stage2 execution traces
+mod stage2_trace;
+/// Deobfuscation statistics and the run manifest
+mod stats;
+/// Detection of anti-decompiler tricks
+mod tricks;
+/// Structural validation of deobfuscated code
+mod validate;
 
 #[derive(Debug, Clone, StructOpt)]
 #[cfg_attr(
@@ -61,11 +124,25 @@ struct Opt {
     #[structopt(short = "q")]
     quiet: bool,
 
-    /// Enable outputting code graphs to dot format
+    /// Enable outputting code graphs to dot format. Graphs are written to
+    /// `<output-dir>/graphs/<module>/<stage>/<pass>/`
     #[structopt(short = "g")]
     #[cfg(not(feature = "reduced_functionality"))]
     graphs: bool,
 
+    /// Export each function's control flow graph as JSON and GraphML, before and
+    /// after deobfuscation
+    #[structopt(long)]
+    #[cfg(not(feature = "reduced_functionality"))]
+    cfg_export: bool,
+
+    /// Render each function's graph before and after deobfuscation as a single
+    /// annotated graph with removed blocks, removed edges, and folded const
+    /// predicates highlighted
+    #[structopt(long)]
+    #[cfg(not(feature = "reduced_functionality"))]
+    graph_diff: bool,
+
     /// Dry run only -- do not write any files
     #[structopt(long = "dry")]
     dry: bool,
@@ -76,6 +153,75 @@ struct Opt {
     #[structopt(long, default_value = "uncompyle6", env = "UNFUCK_DECOMPILER")]
     decompiler: String,
 
+    /// JSON config file. Its `passes` section controls which deobfuscation passes
+    /// run and their limits. Command-line flags take precedence over the file
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Enable a deobfuscation pass for all stages. May be specified multiple times
+    #[structopt(long = "enable-pass", number_of_values = 1)]
+    enable_passes: Vec<Pass>,
+
+    /// Disable a deobfuscation pass for all stages. May be specified multiple times
+    #[structopt(long = "disable-pass", number_of_values = 1)]
+    disable_passes: Vec<Pass>,
+
+    /// Skip deobfuscating stages whose data is larger than this many bytes
+    #[structopt(long)]
+    max_input_size: Option<usize>,
+
+    /// Skip deobfuscating stages containing more than this many code objects
+    #[structopt(long)]
+    max_code_objects: Option<usize>,
+
+    /// Pass configuration resolved from the config file and the flags above
+    #[structopt(skip)]
+    passes: PassConfig,
+
+    /// JSON file of obfuscation profiles to use instead of the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    profiles_file: Option<PathBuf>,
+
+    /// Name of the obfuscation profile to unpack modules with. By default each
+    /// profile is tried in turn until one succeeds
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// Profiles loaded from `--profiles-file`, or the built-in profiles
+    #[structopt(skip)]
+    profiles: ProfileSet,
+
+    /// How to run the stage2 decode loop: `auto` decodes it statically when its
+    /// structure is recognized and emulates it otherwise, `static` never
+    /// emulates it, and `vm` always emulates it
+    #[structopt(long, default_value = "auto")]
+    stage2_mode: Stage2Mode,
+
+    /// Opcode table to decode bytecode with: `auto` picks one from each
+    /// module's .pyc magic, `standard` is the numbering this tool has always
+    /// used, `python27` is CPython 2.7's, and anything else is the path to a
+    /// JSON opcode table
+    #[structopt(long, default_value = "auto")]
+    opcode_table: OpcodeTableChoice,
+
+    /// Record every instruction processed while unpacking stage2 to
+    /// `<module>_stage2_trace.jsonl`
+    #[structopt(long)]
+    #[cfg(not(feature = "reduced_functionality"))]
+    trace_stage2: bool,
+
+    /// Only trace stage2 of modules whose path ends with this path. Implies
+    /// `--trace-stage2`
+    #[structopt(long)]
+    #[cfg(not(feature = "reduced_functionality"))]
+    trace_stage2_module: Option<String>,
+
+    /// Write the keys recovered from each encrypted module (the stage1 XOR key,
+    /// the stage2 swapmap, and the stage3 payload) to `<module>_keys.json`
+    #[structopt(long)]
+    #[cfg(not(feature = "reduced_functionality"))]
+    dump_keys: bool,
+
     /// Only dump strings frmo the stage4 code. Do not do any further processing
     #[structopt(subcommand)]
     #[cfg(not(feature = "reduced_functionality"))]
@@ -86,10 +232,24 @@ struct Opt {
 enum Command {
     StringsOnly,
     ModuleMap,
+    /// Unpack stage2 of a single .pyc file under an interactive debugger
+    DebugStage2,
+    /// Infer the opcode table of a build which permutes its opcodes from the
+    /// .pyc files in the input, and write it to `<output-dir>/opcodes.json`
+    DetectOpcodes,
 }
 
 fn main() -> Result<()> {
-    let opt = Arc::new(Opt::from_args());
+    let mut opt = Opt::from_args();
+    opt.passes = resolve_pass_config(&opt)?;
+    opt.profiles = match &opt.profiles_file {
+        Some(path) => ProfileSet::load(path)?,
+        None => ProfileSet::builtin(),
+    };
+    // Fail early if the selected profile doesn't exist
+    opt.profiles.candidates(opt.profile.as_deref())?;
+    opt.opcode_table.install()?;
+    let opt = Arc::new(opt);
 
     // Set up our logger if the user passed the debug flag. With reduced
     // functionality enabled we don't want any logging to avoid outputting info
@@ -114,6 +274,16 @@ fn main() -> Result<()> {
             .unwrap();
     }
 
+    #[cfg(not(feature = "reduced_functionality"))]
+    if let Some(Command::DebugStage2) = opt.cmd {
+        return debug_stage2(&opt);
+    }
+
+    #[cfg(not(feature = "reduced_functionality"))]
+    if let Some(Command::DetectOpcodes) = opt.cmd {
+        return detect_opcodes(&opt);
+    }
+
     let file = File::open(&opt.input)?;
     let mmap = unsafe { MmapOptions::new().map(&file)? };
 
@@ -134,6 +304,8 @@ fn main() -> Result<()> {
     )));
 
     let module_map = Arc::new(Mutex::new(HashMap::new()));
+    let run_stats = Arc::new(Mutex::new(Vec::new()));
+    let mut module_errors = Vec::new();
     match opt.input.extension().map(|ext| ext.to_str().unwrap()) {
         Some("zip") => {
             let mut zip = zip::ZipArchive::new(reader)?;
@@ -176,6 +348,7 @@ fn main() -> Result<()> {
                     let opt = Arc::clone(&opt);
                     let results = Arc::clone(&results);
                     let module_map = Arc::clone(&module_map);
+                    let run_stats = Arc::clone(&run_stats);
                     s.spawn(move |_| {
                         let res = dump_pyc(
                             decompressed_file.as_slice(),
@@ -183,6 +356,7 @@ fn main() -> Result<()> {
                             csv_output,
                             Arc::clone(&opt),
                             Arc::clone(&module_map),
+                            run_stats,
                         );
                         if res.is_ok() {
                             file_count.fetch_add(1, Ordering::Relaxed);
@@ -203,6 +377,10 @@ fn main() -> Result<()> {
             for (filename, result) in &*results {
                 if let Err(err) = result {
                     eprintln!("Error dumping {:?}: {}", filename, err);
+                    module_errors.push(ModuleError {
+                        module: filename.clone(),
+                        error: err.to_string(),
+                    });
                 }
             }
         }
@@ -217,6 +395,7 @@ fn main() -> Result<()> {
                 csv_output,
                 Arc::clone(&opt),
                 Arc::clone(&module_map),
+                Arc::clone(&run_stats),
             )? {
                 file_count.fetch_add(1, Ordering::Relaxed);
             }
@@ -225,279 +404,1047 @@ fn main() -> Result<()> {
 
     if let Some(Command::ModuleMap) = opt.cmd.as_ref() {
         let target_path = opt.output_dir.join("module_map.json");
-        let mut module_map = module_map.lock().unwrap();
+        let module_map = module_map.lock().unwrap();
         let serialized_data =
             serde_json::to_string_pretty(&*module_map).expect("failed to serialize module_map");
         std::fs::write(target_path, serialized_data.as_bytes())?;
     }
 
+    #[cfg(not(feature = "reduced_functionality"))]
+    if !opt.dry {
+        let run_stats = run_stats.lock().unwrap();
+        std::fs::create_dir_all(&opt.output_dir)?;
+        RunManifest {
+            input: &opt.input,
+            passes: &opt.passes,
+            extracted_files: file_count.load(Ordering::Relaxed),
+            modules: run_stats.as_slice(),
+            errors: module_errors.as_slice(),
+        }
+        .write(&opt.output_dir)?;
+        stats::write_csv(&opt.output_dir, run_stats.as_slice())?;
+    }
+
     println!("Extracted {} files", file_count.load(Ordering::Relaxed));
 
     Ok(())
 }
 
+/// Builds the pass configuration from the config file (if any), then applies
+/// the command-line overrides on top of it
+fn resolve_pass_config(opt: &Opt) -> Result<PassConfig> {
+    let mut passes = match &opt.config {
+        Some(path) => ConfigFile::load(path)?.passes,
+        None => PassConfig::default(),
+    };
+
+    #[cfg(not(feature = "reduced_functionality"))]
+    if opt.graphs {
+        passes.graphs = true;
+    }
+
+    #[cfg(not(feature = "reduced_functionality"))]
+    if opt.cfg_export {
+        passes.cfg_export = true;
+    }
+
+    #[cfg(not(feature = "reduced_functionality"))]
+    if opt.graph_diff {
+        passes.graph_diff = true;
+    }
+
+    for pass in &opt.enable_passes {
+        passes.set(*pass, true);
+    }
+
+    for pass in &opt.disable_passes {
+        passes.set(*pass, false);
+    }
+
+    if opt.max_input_size.is_some() {
+        passes.max_input_size = opt.max_input_size;
+    }
+
+    if opt.max_code_objects.is_some() {
+        passes.max_code_objects = opt.max_code_objects;
+    }
+
+    Ok(passes)
+}
+
+/// Dumps a single .pyc file, decoding its bytecode with the opcode table
+/// chosen for its magic
 fn dump_pyc(
     decompressed_file: &[u8],
     target_path: &Path,
     strings_output: Option<Arc<Mutex<csv::Writer<std::fs::File>>>>,
     opt: Arc<Opt>,
     module_map: Arc<Mutex<HashMap<String, String>>>,
+    run_stats: Arc<Mutex<Vec<ModuleStats>>>,
 ) -> Result<bool> {
     use std::convert::TryInto;
     let magic = u32::from_le_bytes(decompressed_file[0..4].try_into().unwrap());
-    let moddate = u32::from_le_bytes(decompressed_file[4..8].try_into().unwrap());
+    if py3::is_python3(magic) {
+        return dump_py3_module(
+            decompressed_file,
+            target_path,
+            strings_output,
+            opt,
+            run_stats,
+        );
+    }
+
+    match opt.opcode_table.opcodes(magic)? {
+        Opcodes::Standard => dump_module::<Standard>(
+            decompressed_file,
+            target_path,
+            strings_output,
+            opt,
+            module_map,
+            run_stats,
+        ),
+        Opcodes::Remapped => dump_module::<Remapped>(
+            decompressed_file,
+            target_path,
+            strings_output,
+            opt,
+            module_map,
+            run_stats,
+        ),
+    }
+}
+
+fn dump_module<O: 'static + Opcode<Mnemonic = Mnemonic> + PartialEq>(
+    decompressed_file: &[u8],
+    target_path: &Path,
+    strings_output: Option<Arc<Mutex<csv::Writer<std::fs::File>>>>,
+    opt: Arc<Opt>,
+    module_map: Arc<Mutex<HashMap<String, String>>>,
+    run_stats: Arc<Mutex<Vec<ModuleStats>>>,
+) -> Result<bool> {
+    let header = &decompressed_file[..8];
     let cmd = opt.cmd.as_ref();
     let write_deobfuscated_files = cmd.is_none() || opt.dry;
-    match decrypt_stage1(decompressed_file, Arc::clone(&opt)) {
-        Ok(decrypted_data) => {
-            if write_deobfuscated_files {
-                let mut original_file = File::create(&target_path)?;
-                original_file.write_all(decompressed_file)?;
-
-                // Write the decrypted (stage2) data
-                let stage2_path = make_target_filename(&target_path, "_stage2");
-                let mut stage2_file = File::create(stage2_path)?;
-                stage2_file.write_all(&magic.to_le_bytes()[..])?;
-                stage2_file.write_all(&moddate.to_le_bytes()[..])?;
-                stage2_file.write_all(decrypted_data.original.as_slice())?;
-
-                if let Some(stage_2_data) = &decrypted_data.deob {
-                    // Write the decrypted (stage2) data
-                    let stage2_path = make_target_filename(&target_path, "_stage2_deob");
-
-                    let mut stage2_file = File::create(stage2_path)?;
-                    stage2_file.write_all(&magic.to_le_bytes()[..])?;
-                    stage2_file.write_all(&moddate.to_le_bytes()[..])?;
-                    stage2_file.write_all(stage_2_data.as_slice())?;
-                }
-            }
+    let module_path = target_path
+        .strip_prefix(&opt.output_dir)
+        .unwrap_or(target_path);
+    let mut module_stats = ModuleStats {
+        module: module_path.to_path_buf(),
+        profile: None,
+        stage1_key_index: None,
+        layers: Vec::new(),
+        strings_inlined: 0,
+        stack_sequences_reordered: 0,
+        stages: Vec::new(),
+        validation_issues: Vec::new(),
+        tricks: Vec::new(),
+        tricks_normalized: 0,
+        differential: None,
+        unpack_error: None,
+    };
+    let graph_sink = if opt.passes.writes_graphs() {
+        Some(Arc::new(GraphSink::new(&opt.output_dir, module_path)))
+    } else {
+        None
+    };
+    let profiles = opt.profiles.candidates(opt.profile.as_deref())?;
+    let mut trace = if !opt.dry && traces_module(&opt, module_path) {
+        let trace_path = make_target_filename(target_path, "_stage2_trace").with_extension("jsonl");
+        Some(TraceRecorder::new(BufWriter::new(File::create(
+            trace_path,
+        )?)))
+    } else {
+        None
+    };
+    let unpacked = unpack_layers(
+        decompressed_file,
+        &profiles,
+        opt.stage2_mode,
+        trace
+            .as_mut()
+            .map(|trace| trace as &mut dyn Stage2Observer<O>),
+    );
+    if let Some(trace) = trace {
+        trace.finish()?;
+    }
 
-            if decrypted_data.has_next_stage {
-                let stage3_data =
-                    decrypt_stage2(&decrypted_data.original, &decompressed_file[8..])?;
-
-                let deobfuscator = unfuck::Deobfuscator::<Standard>::new(stage3_data.as_slice());
-                let deobfuscator = if opt.graphs {
-                    deobfuscator
-                        .enable_graphs()
-                        .on_graph_generated(|name, data| {
-                            std::fs::write(name, data.as_bytes()).expect("failed to write graph")
-                        })
-                } else {
-                    deobfuscator
-                };
+    let unpacked = unpacked.unwrap_or_else(|e| UnpackedLayers {
+        profile: None,
+        layers: Vec::new(),
+        error: Some(e),
+    });
+
+    if let Some(profile) = unpacked.profile {
+        debug!("Unpacked using profile `{}`", profile.name);
+        module_stats.profile = Some(profile.name.clone());
+    }
+    let keys = KeyMaterial::from_layers(unpacked.profile, &unpacked.layers);
+    module_stats.stage1_key_index = keys
+        .as_ref()
+        .and_then(|keys| keys.stage1.as_ref())
+        .map(|key| key.const_index);
+    module_stats.layers = unpacked
+        .layers
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| LayerStats {
+            stage: stage_name(i),
+            decoder: layer.decoder.name(),
+            size: layer.data.len(),
+        })
+        .collect();
+
+    #[cfg(not(feature = "reduced_functionality"))]
+    if opt.dump_keys && !opt.dry {
+        if let Some(keys) = &keys {
+            let keys_path = make_target_filename(target_path, "_keys").with_extension("json");
+            keys.write(&keys_path)?;
+        }
+    }
 
-                let x = deobfuscator.deobfuscate().unwrap().data;
-                if write_deobfuscated_files {
-                    let stage3_path = make_target_filename(&target_path, "_stage3");
-                    let mut stage3_file = File::create(stage3_path)?;
-                    stage3_file.write_all(&magic.to_le_bytes()[..])?;
-                    stage3_file.write_all(&moddate.to_le_bytes()[..])?;
-                    stage3_file.write_all(stage3_data.as_slice())?;
-
-                    let stage3_path = make_target_filename(&target_path, "_stage3_deob");
-                    let mut stage3_file = File::create(stage3_path)?;
-                    stage3_file.write_all(&magic.to_le_bytes()[..])?;
-                    stage3_file.write_all(&moddate.to_le_bytes()[..])?;
-                    stage3_file.write_all(x.as_slice())?;
+    if let Some(e) = &unpacked.error {
+        error!("Error unpacking layers: {:#}", e);
+        module_stats.unpack_error = Some(format!("{:#}", e));
+        run_stats.lock().unwrap().push(module_stats);
+        return Ok(false);
+    }
+
+    if write_deobfuscated_files {
+        let mut original_file = File::create(target_path)?;
+        original_file.write_all(decompressed_file)?;
+    }
+
+    // A module without any layers is deobfuscated as if it were stage2, but
+    // isn't decompiled
+    let stages: Vec<&[u8]> = if unpacked.layers.is_empty() {
+        vec![&decompressed_file[8..]]
+    } else {
+        unpacked
+            .layers
+            .iter()
+            .map(|layer| layer.data.as_slice())
+            .collect()
+    };
+
+    for (i, data) in stages.iter().copied().enumerate() {
+        let stage = stage_name(i);
+        let innermost = !unpacked.layers.is_empty() && i == stages.len() - 1;
+        if opt.passes.detect_tricks {
+            detect_tricks::<O>(&mut module_stats, &stage, data);
+        }
+
+        if !innermost {
+            let deob =
+                deobfuscate_stage::<O, _>(data, &opt, &stage, graph_sink.as_ref(), |deob| deob)?;
+            if let Some(deob) = &deob {
+                record_stage_stats::<O>(&mut module_stats, &stage, data, deob);
+                if opt.passes.validate {
+                    validate_stage::<O>(&mut module_stats, &stage, deob);
                 }
+            }
 
-                if let py27_marshal::Obj::Code(code) =
-                    py27_marshal::read::marshal_loads(stage3_data.as_slice()).unwrap()
-                {
-                    let b64_string: Vec<u8> = code.code
-                        [code.code.iter().position(|b| *b == b'\n').unwrap() + 1..]
-                        .iter()
-                        .rev()
-                        .copied()
-                        .collect();
-
-                    let stage4_data = unpack_b64_compressed_data(b64_string.as_slice())?;
-
-                    if write_deobfuscated_files {
-                        let stage4_path = make_target_filename(&target_path, "_stage4");
-                        let mut stage4_file = File::create(&stage4_path)?;
-                        stage4_file.write_all(&magic.to_le_bytes()[..])?;
-                        stage4_file.write_all(&moddate.to_le_bytes()[..])?;
-                        stage4_file.write_all(stage4_data.as_slice())?;
-                    }
+            if write_deobfuscated_files {
+                write_stage(target_path, &format!("_{}", stage), header, data)?;
+                if let Some(deob) = &deob {
+                    write_stage(target_path, &format!("_{}_deob", stage), header, deob)?;
+                }
+            }
 
-                    match cmd {
-                        Some(Command::StringsOnly) => {
-                            // Dump strings for this file
-                            let pyc_filename = target_path
-                                .strip_prefix(&opt.output_dir)
-                                .unwrap()
-                                .to_str()
-                                .unwrap();
+            continue;
+        }
 
-                            let path = PathBuf::from(pyc_filename);
+        if write_deobfuscated_files {
+            write_stage(target_path, &format!("_{}", stage), header, data)?;
+        }
 
-                            let strings = unfuck::dump_strings(&path, stage4_data.as_slice())?;
+        // Deobfuscation is checked against the stage as it was unpacked, so
+        // the passes rewriting it beforehand are checked as well
+        let unpacked_data = data;
 
-                            let strings_output = strings_output.as_ref().unwrap();
-                            strings.par_iter().for_each(|s| {
-                                strings_output
+        // Tricks are removed before anything else looks at the stage
+        let normalized = if opt.passes.normalize_tricks {
+            tricks::normalize::<O>(data).unwrap_or_else(|e| {
+                error!("Failed to normalize anti-decompiler tricks: {:#}", e);
+                None
+            })
+        } else {
+            None
+        };
+        if let Some(normalized) = &normalized {
+            debug!("Normalized {} anti-decompiler tricks", normalized.tricks);
+            module_stats.tricks_normalized = normalized.tricks;
+        }
+        let data = normalized
+            .as_ref()
+            .map_or(data, |normalized| normalized.data.as_slice());
+
+        // Strings decoded by helper functions at import time are inlined
+        // before strings are dumped or the stage is deobfuscated
+        let inlined = if opt.passes.inline_strings {
+            inline_strings::inline::<O>(data).unwrap_or_else(|e| {
+                error!("Failed to inline decoded strings: {:#}", e);
+                None
+            })
+        } else {
+            None
+        };
+        if let Some(inlined) = &inlined {
+            debug!("Inlined {} decoded strings", inlined.call_sites);
+            module_stats.strings_inlined = inlined.call_sites;
+        }
+        let data = inlined
+            .as_ref()
+            .map_or(data, |inlined| inlined.data.as_slice());
+
+        match cmd {
+            Some(Command::StringsOnly) => {
+                // Dump strings for this file
+                let pyc_filename = target_path
+                    .strip_prefix(&opt.output_dir)
+                    .unwrap()
+                    .to_str()
+                    .unwrap();
+
+                let path = PathBuf::from(pyc_filename);
+
+                let strings = unfuck::dump_strings(&path, data)?;
+
+                let strings_output = strings_output.as_ref().unwrap();
+                strings.par_iter().for_each(|s| {
+                    strings_output
+                        .lock()
+                        .unwrap()
+                        .serialize(s)
+                        .expect("failed to serialize output string");
+                });
+            }
+            Some(Command::DebugStage2) => {
+                unreachable!("debug-stage2 does not dump modules")
+            }
+            Some(Command::DetectOpcodes) => {
+                unreachable!("detect-opcodes does not dump modules")
+            }
+            Some(Command::ModuleMap) | None => {
+                let write_module_map = matches!(cmd, Some(Command::ModuleMap));
+                // Deobfuscate the innermost layer
+                let module_map = Arc::clone(&module_map);
+                let deob = deobfuscate_stage::<O, _>(
+                    data,
+                    &opt,
+                    &stage,
+                    graph_sink.as_ref(),
+                    |deobfuscator| {
+                        if opt.passes.graphs {
+                            deobfuscator
+                        } else if write_module_map {
+                            deobfuscator.on_store_to_named_var(
+                            move |code_obj, plain_modules, code_graph, store_instr, (_obj, accessing_instructions)| {
+                                trace!("Found a STORE_NAME or STORE_FAST");
+                                let graph = code_graph.read().unwrap();
+                                // this is the data we're storing. where does it originate?
+                                let import_name_instr = accessing_instructions
+                                    .0
                                     .lock()
                                     .unwrap()
-                                    .serialize(s)
-                                    .expect("failed to serialize output string");
-                            });
-                        }
-                        Some(Command::ModuleMap) | None => {
-                            let write_module_map = matches!(cmd, Some(Command::ModuleMap));
-                            // Deobfuscate stage4
-                            let deobfuscator =
-                                unfuck::Deobfuscator::<Standard>::new(stage4_data.as_slice());
-                            let module_map = Arc::clone(&module_map);
-                            let deobfuscator = if opt.graphs {
-                                deobfuscator
-                                    .enable_graphs()
-                                    .on_graph_generated(|name, data| {
-                                        std::fs::write(name, data.as_bytes())
-                                            .expect("failed to write graph")
-                                    })
-                            } else if write_module_map {
-                                deobfuscator.on_store_to_named_var(
-                                    move |code_obj, plain_modules, code_graph, store_instr, (obj, accessing_instructions)| {
-                                        trace!("Found a STORE_NAME or STORE_FAST");
-                                        let graph = code_graph.read().unwrap();
-                                        // this is the data we're storing. where does it originate?
-                                        let import_name_instr = accessing_instructions
-                                            .0
-                                            .lock()
-                                            .unwrap()
-                                            .iter()
-                                            .find_map(|(source_node, idx)| {
-                                                let source_instruction =
-                                                    graph.instr_at(*source_node, *idx).unwrap();
-                                                if source_instruction.opcode.mnemonic()
-                                                    == Mnemonic::IMPORT_NAME {
-                                                        Some(source_instruction)
-                                                    } else {
-                                                        None
-                                                    }
-                                            });
-
-
-                                        // Does the data originate from an IMPORT_NAME?
-                                        if let Some(import_name_instr) = import_name_instr {
-                                            trace!(
-                                                "An IMPORT_NAME preceded the STORE_NAME/STORE_FAST"
-                                            );
-
-                                                let import_name_idx =
-                                                    import_name_instr.arg.unwrap() as usize;
-
-                                                // TODO: figure out why this Arc::clone is needed and we cannot
-                                                // just take a reference...
-                                                let name = if store_instr.opcode.mnemonic()
-                                                    == Mnemonic::STORE_FAST
-                                                    && (store_instr.arg.unwrap() as usize)
-                                                        < code_obj.varnames.len()
-                                                {
-                                                    Some(Arc::clone(
-                                                        &code_obj.varnames
-                                                            [store_instr.arg.unwrap() as usize],
-                                                    ))
-                                                } else if (store_instr.arg.unwrap() as usize)
-                                                    < code_obj.names.len()
-                                                {
-                                                    Some(Arc::clone(
-                                                        &code_obj.names
-                                                            [store_instr.arg.unwrap() as usize],
-                                                    ))
-                                                } else {
-                                                    None
-                                                };
-
-                                                if let Some(name) = name {
-                                                    let obfuscated_name =
-                                                        code_obj.names[import_name_idx].to_string();
-
-                                                    if plain_modules.contains(obfuscated_name.as_str()) {
-
-                                                    module_map
-                                                        .lock()
-                                                        .unwrap()
-                                                        .insert(obfuscated_name, name.to_string());
-                                                    }
-                                                }
+                                    .iter()
+                                    .find_map(|(source_node, idx)| {
+                                        let source_instruction =
+                                            graph.instr_at(*source_node, *idx).unwrap();
+                                        if source_instruction.opcode.mnemonic()
+                                            == Mnemonic::IMPORT_NAME {
+                                                Some(source_instruction)
+                                            } else {
+                                                None
+                                            }
+                                    });
+
+
+                                // Does the data originate from an IMPORT_NAME?
+                                if let Some(import_name_instr) = import_name_instr {
+                                    trace!(
+                                        "An IMPORT_NAME preceded the STORE_NAME/STORE_FAST"
+                                    );
+
+                                        let import_name_idx =
+                                            import_name_instr.arg.unwrap() as usize;
+
+                                        // TODO: figure out why this Arc::clone is needed and we cannot
+                                        // just take a reference...
+                                        let name = if store_instr.opcode.mnemonic()
+                                            == Mnemonic::STORE_FAST
+                                            && (store_instr.arg.unwrap() as usize)
+                                                < code_obj.varnames.len()
+                                        {
+                                            Some(Arc::clone(
+                                                &code_obj.varnames
+                                                    [store_instr.arg.unwrap() as usize],
+                                            ))
+                                        } else if (store_instr.arg.unwrap() as usize)
+                                            < code_obj.names.len()
+                                        {
+                                            Some(Arc::clone(
+                                                &code_obj.names
+                                                    [store_instr.arg.unwrap() as usize],
+                                            ))
+                                        } else {
+                                            None
+                                        };
+
+                                        if let Some(name) = name {
+                                            let obfuscated_name =
+                                                code_obj.names[import_name_idx].to_string();
+
+                                            if plain_modules.contains(obfuscated_name.as_str()) {
+
+                                            module_map
+                                                .lock()
+                                                .unwrap()
+                                                .insert(obfuscated_name, name.to_string());
+                                            }
                                         }
-                                    },
-                                )
-                            } else {
-                                deobfuscator
-                            };
-
-                            let stage4_deob = deobfuscator.deobfuscate()?;
-
-                            if write_deobfuscated_files {
-                                let stage4_path =
-                                    make_target_filename(&target_path, "_stage4_deob");
-                                let mut stage4_file = File::create(&stage4_path)?;
-                                stage4_file.write_all(&magic.to_le_bytes()[..])?;
-                                stage4_file.write_all(&moddate.to_le_bytes()[..])?;
-                                stage4_file.write_all(stage4_deob.data.as_slice())?;
-
-                                decompile_pyc(&stage4_path, opt.decompiler.as_ref());
+                                }
+                            },
+                        )
+                        } else {
+                            deobfuscator
+                        }
+                    },
+                )?;
+
+                // Shuffled stack sequences are put back in order before the
+                // stack sizes are recomputed
+                let deob = match deob {
+                    Some(deob) if opt.passes.reorder_stack => {
+                        match stack_reorder::reorder::<O>(&deob) {
+                            Ok(Some(reordered)) => {
+                                debug!("Reordered {} stack sequences", reordered.sequences);
+                                module_stats.stack_sequences_reordered = reordered.sequences;
+                                Some(reordered.data)
+                            }
+                            Ok(None) => Some(deob),
+                            Err(e) => {
+                                error!("Failed to reorder stack sequences: {:#}", e);
+                                Some(deob)
                             }
                         }
                     }
+                    deob => deob,
+                };
+
+                // Deobfuscation leaves the original stack sizes and line
+                // numbers in place, which no longer match the code
+                let deob = match deob {
+                    Some(deob) if opt.passes.recompute_metadata => Some(
+                        metadata::recompute::<O>(data, &deob, opt.passes.normalize_firstlineno)
+                            .unwrap_or_else(|e| {
+                                error!("Failed to recompute code metadata: {:#}", e);
+                                None
+                            })
+                            .unwrap_or(deob),
+                    ),
+                    deob => deob,
+                };
+
+                if let Some(deob) = &deob {
+                    record_stage_stats::<O>(&mut module_stats, &stage, data, deob);
+                    if opt.passes.validate {
+                        validate_stage::<O>(&mut module_stats, &stage, deob);
+                    }
+                    if opt.passes.differential_check {
+                        compare_stage::<O>(&mut module_stats, &stage, unpacked_data, deob);
+                    }
+                }
+
+                if let (true, Some(deob)) = (write_deobfuscated_files, &deob) {
+                    let deob_path =
+                        write_stage(target_path, &format!("_{}_deob", stage), header, deob)?;
+
+                    decompile_pyc(&deob_path, opt.decompiler.as_ref());
                 }
             }
+        }
+    }
+
+    run_stats.lock().unwrap().push(module_stats);
+
+    Ok(true)
+}
+
+/// Dumps a single Python 3 .pyc file. Layers which don't need the Python 2.7
+/// VM are peeled, and every stage is written along with its disassembly.
+/// Nothing is deobfuscated.
+fn dump_py3_module(
+    decompressed_file: &[u8],
+    target_path: &Path,
+    strings_output: Option<Arc<Mutex<csv::Writer<std::fs::File>>>>,
+    opt: Arc<Opt>,
+    run_stats: Arc<Mutex<Vec<ModuleStats>>>,
+) -> Result<bool> {
+    let pyc = py3::Pyc::parse(decompressed_file)?;
+    debug!("Python 3.{} module", pyc.minor);
+    let spec = py3_dis::OpcodeSpec::builtin(pyc.minor)
+        .ok_or_else(|| anyhow!("no opcode specification for Python 3.{}", pyc.minor))?;
+    let cmd = opt.cmd.as_ref();
+    let write_files = cmd.is_none() || opt.dry;
+    let module_path = target_path
+        .strip_prefix(&opt.output_dir)
+        .unwrap_or(target_path);
+
+    let profiles = opt.profiles.candidates(opt.profile.as_deref())?;
+    let unpacked = match py3::unpack(&pyc, &profiles) {
+        Ok(unpacked) => unpacked,
+        Err(e) => {
+            error!("Error unpacking layers: {:#}", e);
+            run_stats.lock().unwrap().push(ModuleStats {
+                module: module_path.to_path_buf(),
+                unpack_error: Some(format!("{:#}", e)),
+                ..Default::default()
+            });
+            return Ok(false);
+        }
+    };
 
-            // if let Some(deob) = decrypted_data.deob {
-            //     // Write the decrypted (stage1) data
-            //     let stage1_path = make_target_filename(&target_path, "_stage2_deob");
-            //     std::fs::write(stage1_path, deob.as_slice())?;
-            // }
+    let mut module_stats = ModuleStats {
+        module: module_path.to_path_buf(),
+        profile: unpacked.profile.map(|profile| profile.name.clone()),
+        stage1_key_index: None,
+        layers: Vec::new(),
+        strings_inlined: 0,
+        stack_sequences_reordered: 0,
+        stages: Vec::new(),
+        validation_issues: Vec::new(),
+        tricks: Vec::new(),
+        tricks_normalized: 0,
+        differential: None,
+        unpack_error: None,
+    };
+    let keys = KeyMaterial::from_layers(unpacked.profile, &unpacked.layers);
+    module_stats.stage1_key_index = keys
+        .as_ref()
+        .and_then(|keys| keys.stage1.as_ref())
+        .map(|key| key.const_index);
+    module_stats.layers = unpacked
+        .layers
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| LayerStats {
+            stage: stage_name(i),
+            decoder: layer.decoder.name(),
+            size: layer.data.len(),
+        })
+        .collect();
+
+    #[cfg(not(feature = "reduced_functionality"))]
+    if opt.dump_keys && !opt.dry {
+        if let Some(keys) = &keys {
+            let keys_path = make_target_filename(target_path, "_keys").with_extension("json");
+            keys.write(&keys_path)?;
+        }
+    }
+
+    if let Some(decoder) = unpacked.unpeeled {
+        error!(
+            "{} is a {} layer, which can only be peeled off of Python 2.7 modules",
+            stage_name(unpacked.layers.len()),
+            decoder.name()
+        );
+    }
+
+    if write_files {
+        let mut original_file = File::create(target_path)?;
+        original_file.write_all(decompressed_file)?;
+    }
 
-            return Ok(true);
+    // A module without any layers is disassembled as if it were stage2
+    let stages: Vec<&[u8]> = if unpacked.layers.is_empty() {
+        vec![pyc.data]
+    } else {
+        unpacked
+            .layers
+            .iter()
+            .map(|layer| layer.data.as_slice())
+            .collect()
+    };
+
+    for (i, data) in stages.iter().copied().enumerate() {
+        let stage = stage_name(i);
+        let code = py3_marshal::load_code(data, pyc.minor)?;
+        if write_files {
+            let suffix = format!("_{}", stage);
+            write_stage(target_path, &suffix, pyc.header, data)?;
+            let dis_path = make_target_filename(target_path, &suffix).with_extension("dis");
+            std::fs::write(dis_path, py3_dis::disassemble(&code, spec))?;
+        }
+
+        if i < stages.len() - 1 {
+            continue;
         }
+
+        match cmd {
+            Some(Command::StringsOnly) => {
+                let path = PathBuf::from(module_path);
+                let strings = py3::dump_strings(&path, &code);
+
+                let strings_output = strings_output.as_ref().unwrap();
+                let mut strings_output = strings_output.lock().unwrap();
+                for s in &strings {
+                    strings_output
+                        .serialize(s)
+                        .expect("failed to serialize output string");
+                }
+            }
+            Some(Command::ModuleMap) => {
+                debug!("Module maps are only built for Python 2.7 modules");
+            }
+            Some(Command::DebugStage2) => {
+                unreachable!("debug-stage2 does not dump modules")
+            }
+            Some(Command::DetectOpcodes) => {
+                unreachable!("detect-opcodes does not dump modules")
+            }
+            None => {}
+        }
+    }
+
+    run_stats.lock().unwrap().push(module_stats);
+
+    Ok(true)
+}
+
+/// Name of the stage produced by peeling the `index`th layer. The module
+/// itself is stage1.
+fn stage_name(index: usize) -> String {
+    format!("stage{}", index + 2)
+}
+
+/// Writes a stage's marshalled code as a .pyc next to `target_path`, with
+/// `suffix` appended to its file name. `header` is the original .pyc header.
+/// Returns the path written to.
+fn write_stage(target_path: &Path, suffix: &str, header: &[u8], data: &[u8]) -> Result<PathBuf> {
+    let path = make_target_filename(target_path, suffix);
+    let mut file = File::create(&path)?;
+    file.write_all(header)?;
+    file.write_all(data)?;
+
+    Ok(path)
+}
+
+/// Runs the configured deobfuscation passes over a marshalled code object. The
+/// same pass configuration is used for every stage. `customize` may register
+/// additional callbacks on the deobfuscator before it runs. Graphs are written
+/// to `graph_sink` under the given `stage` name.
+///
+/// Returns `None` if the passes are disabled or the stage exceeds one of the
+/// configured limits.
+fn deobfuscate_stage<'a, O, F>(
+    data: &'a [u8],
+    opt: &Opt,
+    stage: &str,
+    graph_sink: Option<&Arc<GraphSink>>,
+    customize: F,
+) -> Result<Option<Vec<u8>>>
+where
+    O: 'static + Opcode<Mnemonic = Mnemonic> + PartialEq,
+    F: FnOnce(unfuck::Deobfuscator<'a, O>) -> unfuck::Deobfuscator<'a, O>,
+{
+    let passes = &opt.passes;
+    if !passes.deobfuscate {
+        return Ok(None);
+    }
+
+    if let Some(max_input_size) = passes.max_input_size {
+        if data.len() > max_input_size {
+            debug!(
+                "Skipping deobfuscation: input is {} bytes (limit is {})",
+                data.len(),
+                max_input_size
+            );
+            return Ok(None);
+        }
+    }
+
+    if let Some(max_code_objects) = passes.max_code_objects {
+        if let Obj::Code(code) = py27_marshal::read::marshal_loads(data)? {
+            let code_objects = count_code_objects(&code);
+            if code_objects > max_code_objects {
+                debug!(
+                    "Skipping deobfuscation: input has {} code objects (limit is {})",
+                    code_objects, max_code_objects
+                );
+                return Ok(None);
+            }
+        }
+    }
+
+    let deobfuscator = unfuck::Deobfuscator::<O>::new(data);
+    let deobfuscator = match graph_sink.filter(|_| passes.graphs) {
+        Some(graph_sink) => {
+            let graph_sink = Arc::clone(graph_sink);
+            let stage = stage.to_string();
+            deobfuscator
+                .enable_graphs()
+                .on_graph_generated(move |name, data| {
+                    if let Err(e) = graph_sink.write_graph(&stage, name, data) {
+                        error!("Failed to write graph {:?}: {}", name, e);
+                    }
+                })
+        }
+        None => deobfuscator,
+    };
+
+    let result = customize(deobfuscator).deobfuscate();
+
+    if let (Some(graph_sink), true) = (graph_sink, passes.cfg_export) {
+        cfg::export_stage::<O>(graph_sink, stage, "cfg_before", data)?;
+        if let Ok(result) = &result {
+            cfg::export_stage::<O>(graph_sink, stage, "cfg_after", result.data.as_slice())?;
+        }
+    }
+
+    if let (Some(graph_sink), true, Ok(result)) = (graph_sink, passes.graph_diff, &result) {
+        cfg_diff::write_stage_diffs::<O>(graph_sink, stage, data, result.data.as_slice())?;
+    }
+
+    // Write the index even if deobfuscation failed so that the graphs leading
+    // up to the failure can be found
+    if let Some(graph_sink) = graph_sink {
+        graph_sink.write_index()?;
+    }
+
+    Ok(Some(result?.data))
+}
+
+/// Collects statistics for a deobfuscated stage. Failing to collect statistics
+/// is not fatal since the stage itself was deobfuscated successfully.
+fn record_stage_stats<O: Opcode<Mnemonic = Mnemonic>>(
+    module_stats: &mut ModuleStats,
+    stage: &str,
+    before: &[u8],
+    after: &[u8],
+) {
+    match StageStats::collect::<O>(stage, before, after) {
+        Ok(stats) => module_stats.stages.push(stats),
+        Err(e) => error!(
+            "Failed to collect {} statistics for {:?}: {}",
+            stage, module_stats.module, e
+        ),
+    }
+}
+
+/// Records the anti-decompiler tricks in a stage's original code in the
+/// module's statistics
+fn detect_tricks<O: Opcode<Mnemonic = Mnemonic>>(
+    module_stats: &mut ModuleStats,
+    stage: &str,
+    data: &[u8],
+) {
+    let functions = match tricks::detect::<O>(stage, data) {
+        Ok(functions) => functions,
+        Err(e) => {
+            error!(
+                "Failed to detect anti-decompiler tricks in {}: {:#}",
+                stage, e
+            );
+            return;
+        }
+    };
+
+    for function in &functions {
+        for trick in &function.tricks {
+            debug!(
+                "{} function {} ({}) at offset {}: {}: {}",
+                stage,
+                function.function_index,
+                function.function,
+                trick.offset,
+                trick.kind,
+                trick.message
+            );
+        }
+    }
+
+    module_stats.tricks.extend(functions);
+}
+
+/// Validates a deobfuscated stage before it is written. Problems are logged
+/// and recorded in the module's statistics, but the stage is still written so
+/// that it can be inspected.
+fn validate_stage<O: Opcode<Mnemonic = Mnemonic>>(
+    module_stats: &mut ModuleStats,
+    stage: &str,
+    deob: &[u8],
+) {
+    let issues = validate::validate::<O>(stage, deob);
+    if !issues.is_empty() {
+        error!(
+            "Deobfuscated {} of {:?} failed validation with {} issues",
+            stage,
+            module_stats.module,
+            issues.len()
+        );
+        for issue in &issues {
+            debug!("{}", issue);
+        }
+    }
+
+    module_stats.validation_issues.extend(issues);
+}
+
+/// Runs the pure functions of a stage before and after deobfuscation on the
+/// same inputs. Functions which behave differently are logged and recorded in
+/// the module's statistics.
+fn compare_stage<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    module_stats: &mut ModuleStats,
+    stage: &str,
+    original: &[u8],
+    deob: &[u8],
+) {
+    let report = match differential::compare::<O>(stage, original, deob) {
+        Ok(report) => report,
         Err(e) => {
-            error!("Error decrypting stage1: {}", e);
+            error!(
+                "Failed to compare {} before and after deobfuscation: {:#}",
+                stage, e
+            );
+            return;
+        }
+    };
+
+    debug!(
+        "Compared {} pure functions of {} on {} inputs",
+        report.functions_compared, stage, report.inputs_compared
+    );
+    if !report.divergences.is_empty() {
+        error!(
+            "{} functions of {} in {:?} behave differently after deobfuscation",
+            report.divergences.len(),
+            stage,
+            module_stats.module
+        );
+        for divergence in &report.divergences {
+            debug!("{}", divergence);
         }
     }
 
-    Ok(false)
+    module_stats.differential = Some(report);
+}
+
+/// Returns the number of code objects in `code`, including itself and any
+/// nested code objects
+fn count_code_objects(code: &Code) -> usize {
+    1 + code
+        .consts
+        .iter()
+        .map(|c| match c {
+            Obj::Code(nested) => count_code_objects(nested),
+            _ => 0,
+        })
+        .sum::<usize>()
 }
 
 fn make_target_filename<P: AsRef<Path>>(existing_file_name: P, file_suffix: &str) -> PathBuf {
     let path_ref = existing_file_name.as_ref();
+    // Not `with_extension`, which would cut off the suffix along with the rest
+    // of a dotted stem such as `module.cpython-38`
+    let mut file_name = path_ref
+        .file_stem()
+        .expect("target has no file name?")
+        .to_os_string();
+    file_name.push(file_suffix);
+    file_name.push(".");
+    file_name.push(path_ref.extension().expect("target has no extension?"));
     path_ref
         .parent()
         .expect("target has no parent directory?")
-        .join(
-            path_ref
-                .file_stem()
-                .expect("target has no file name?")
-                .to_str()
-                .unwrap()
-                .to_owned()
-                + file_suffix,
-        )
-        .with_extension(path_ref.extension().expect("target has no extension?"))
+        .join(file_name)
 }
 
-struct DeobfuscatedCode {
-    original: Vec<u8>,
-    deob: Option<Vec<u8>>,
-    has_next_stage: bool,
+/// Unpacks stage2 of the module in `opt.input` with the VM, stopping for
+/// debugger commands from stdin
+#[cfg(not(feature = "reduced_functionality"))]
+fn debug_stage2(opt: &Opt) -> Result<()> {
+    let data = std::fs::read(&opt.input)?;
+    if data.len() < 8 {
+        return Err(anyhow!("{:?} is not a .pyc file", opt.input));
+    }
+
+    let magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let opcodes = opt.opcode_table.opcodes(magic)?;
+    let code = load_code(&data[8..])?;
+    let internal_filename = String::from_utf8_lossy(code.filename.as_ref());
+    let profile = opt
+        .profiles
+        .candidates(opt.profile.as_deref())?
+        .into_iter()
+        .find(|profile| profile.stage1.encrypted_filename == internal_filename)
+        .ok_or_else(|| anyhow!("the module is not encrypted with any known profile"))?;
+    let (stage2, _key) = stage1::decrypt(&code, &profile.stage1)?;
+
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut debugger = Debugger::new(stdin.lock(), stdout.lock());
+    let result = match opcodes {
+        Opcodes::Standard => decrypt_stage2::<Standard>(
+            &stage2,
+            &data[8..],
+            &profile.stage2,
+            Stage2Mode::Vm,
+            Some(&mut debugger),
+        ),
+        Opcodes::Remapped => decrypt_stage2::<Remapped>(
+            &stage2,
+            &data[8..],
+            &profile.stage2,
+            Stage2Mode::Vm,
+            Some(&mut debugger),
+        ),
+    };
+    drop(debugger);
+
+    match result {
+        Ok(stage3) => println!("Unpacked {} bytes of stage3", stage3.payload.len()),
+        Err(e) if e.is::<DebuggerQuit>() => {}
+        Err(e) => return Err(e),
+    }
+
+    Ok(())
 }
 
-fn unpack_b64_compressed_data(data: &[u8]) -> Result<Vec<u8>> {
-    let b64_data = std::str::from_utf8(data)?;
-    let decoded_data = base64::decode(&b64_data.trim())?;
+/// Infers the opcode table of the modules in `opt.input` and writes it to
+/// `<output-dir>/opcodes.json`
+#[cfg(not(feature = "reduced_functionality"))]
+fn detect_opcodes(opt: &Opt) -> Result<()> {
+    let profiles = opt.profiles.candidates(opt.profile.as_deref())?;
+    let mut corpus = OpcodeCorpus::default();
+    let mut modules = 0;
+    for data in read_modules(&opt.input)? {
+        // Encrypted bytecode would only add noise, so the layers which can be
+        // decoded without knowing the opcodes are peeled first
+        match data
+            .get(8..)
+            .map(|data| layers::peel_without_opcodes(data, &profiles))
+        {
+            Some(Ok(layers)) => {
+                for layer in layers {
+                    for code in bytecode::code_objects(&layer) {
+                        corpus.add(&code);
+                    }
+                }
+                modules += 1;
+            }
+            Some(Err(e)) => debug!("Skipping a module which can't be unmarshalled: {:#}", e),
+            None => debug!("Skipping a module which is too short to be a .pyc file"),
+        }
+    }
 
-    let mut zlib_decoder = ZlibDecoder::new(decoded_data.as_slice());
-    let mut inflated_data: Vec<u8> = Vec::new();
-    zlib_decoder.read_to_end(&mut inflated_data)?;
+    let mut detected = opcode_detect::detect(&corpus)?;
+    detected.table.description = format!(
+        "Detected from {} instructions in {} code objects of {} modules",
+        corpus.instructions(),
+        corpus.code_objects(),
+        modules
+    );
+    println!("{}", detected.table.description);
+
+    let uncertain: Vec<_> = detected
+        .assignments
+        .iter()
+        .filter(|assignment| assignment.margin < LOW_CONFIDENCE_MARGIN)
+        .collect();
+    println!(
+        "Assigned {} opcodes, {} of them uncertain. {} bytes only appear in junk. {} opcodes never appear and were placed on unused bytes",
+        detected.assignments.len(),
+        uncertain.len(),
+        detected.junk.len(),
+        detected.unseen.len()
+    );
+    for assignment in uncertain {
+        println!(
+            "  byte {:>3}: {:?} ({} occurrences, margin {:.1})",
+            assignment.byte, assignment.mnemonic, assignment.occurrences, assignment.margin
+        );
+    }
 
-    Ok(inflated_data)
+    if !opt.dry {
+        std::fs::create_dir_all(&opt.output_dir)?;
+        let path = opt.output_dir.join("opcodes.json");
+        detected.table.write(&path)?;
+        println!("Wrote the opcode table to {:?}", path);
+    }
+
+    Ok(())
 }
 
-fn decrypt_stage1(data: &[u8], opt: Arc<Opt>) -> Result<DeobfuscatedCode> {
+/// Reads every .pyc file in `input`, which may be a .zip file, a directory,
+/// or a single .pyc file
+#[cfg(not(feature = "reduced_functionality"))]
+fn read_modules(input: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut modules = Vec::new();
+    if input.is_dir() {
+        for entry in std::fs::read_dir(input)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                modules.extend(read_modules(&path)?);
+            } else if path.extension().and_then(|ext| ext.to_str()) == Some("pyc") {
+                modules.push(std::fs::read(&path)?);
+            }
+        }
+    } else if input.extension().and_then(|ext| ext.to_str()) == Some("zip") {
+        let mut zip = zip::ZipArchive::new(File::open(input)?)?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            if file.name().ends_with(".pyc") {
+                let mut data = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut data)?;
+                modules.push(data);
+            }
+        }
+    } else {
+        modules.push(std::fs::read(input)?);
+    }
+
+    Ok(modules)
+}
+
+/// Whether `--trace-stage2` selects the module at `module_path`
+#[cfg(not(feature = "reduced_functionality"))]
+fn traces_module(opt: &Opt, module_path: &Path) -> bool {
+    match &opt.trace_stage2_module {
+        Some(filter) => module_path.ends_with(filter),
+        None => opt.trace_stage2,
+    }
+}
+
+#[cfg(feature = "reduced_functionality")]
+fn traces_module(_opt: &Opt, _module_path: &Path) -> bool {
+    false
+}
+
+/// The layers of a module, unpacked using a particular profile
+struct UnpackedLayers<'p> {
+    /// The profile which successfully unpacked the module, if any of the
+    /// profiles matched it. If unpacking failed, this is the profile which
+    /// peeled the most layers.
+    profile: Option<&'p Profile>,
+    /// Layers peeled off of the module, outermost first
+    layers: Vec<Layer>,
+    /// Why the module could not be unpacked completely. `layers` holds the
+    /// layers peeled before the failure.
+    error: Option<anyhow::Error>,
+}
+
+/// Peels every layer off of the module in `data` by trying each of `profiles`
+/// in order. Only profiles whose encrypted file name matches the module's
+/// internal file name are tried -- if none match, only the layers which don't
+/// need a profile are peeled. If no profile unpacks the module completely, the
+/// layers peeled by the profile which got furthest are returned along with the
+/// error.
+fn unpack_layers<'p, O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    data: &[u8],
+    profiles: &[&'p Profile],
+    stage2_mode: Stage2Mode,
+    mut observer: Option<&mut dyn Stage2Observer<O>>,
+) -> Result<UnpackedLayers<'p>> {
     let mut file_reader = Cursor::new(&data);
     let magic = file_reader.read_u32::<LittleEndian>()?;
     let moddate = file_reader.read_u32::<LittleEndian>()?;
@@ -505,87 +1452,104 @@ fn decrypt_stage1(data: &[u8], opt: Arc<Opt>) -> Result<DeobfuscatedCode> {
     debug!("Magic: 0x{:X}", magic);
     debug!("Mod Date: 0x{:X}", moddate);
 
-    let obj = py27_marshal::read::marshal_loads(&data[file_reader.position() as usize..])?;
-    if let py27_marshal::Obj::Code(code) = obj {
-        for name in &code.names {
-            debug!(
-                "Name: {}",
-                std::str::from_utf8(name).unwrap_or("BAD_UNICODE_DATA")
-            );
-        }
-
-        let internal_filename =
-            std::str::from_utf8(code.filename.as_ref()).unwrap_or("BAD_UNICODE_DATA");
-
-        debug!("Internal file name: {}", internal_filename);
-
-        let is_encrypted = internal_filename == "Lesta";
-        let payload_to_deob = if is_encrypted {
-            // If the payload is encrypted we need to to decrypt and decompress
-            // the data
-            let consts = if let py27_marshal::Obj::String(b) = &code.consts[3] {
-                b
-            } else {
-                error!("{:#?}", code.consts);
-                return Err(unfuck::error::Error::ObjectError::<Standard>(
-                    "consts[3]",
-                    code.consts[3].clone(),
-                )
-                .into());
-            };
+    let stage1 = &data[file_reader.position() as usize..];
+    let code = load_code(stage1)?;
+    for name in &code.names {
+        debug!(
+            "Name: {}",
+            std::str::from_utf8(name).unwrap_or("BAD_UNICODE_DATA")
+        );
+    }
 
-            debug!(
-                "Internal file name: {}",
-                std::str::from_utf8(code.filename.as_ref()).unwrap_or("BAD_UNICODE_DATA")
-            );
+    let internal_filename =
+        std::str::from_utf8(code.filename.as_ref()).unwrap_or("BAD_UNICODE_DATA");
+
+    debug!("Internal file name: {}", internal_filename);
+
+    let matching: Vec<&Profile> = profiles
+        .iter()
+        .copied()
+        .filter(|profile| profile.stage1.encrypted_filename == internal_filename)
+        .collect();
+    if matching.is_empty() {
+        return Ok(match layers::peel(stage1, None, stage2_mode, observer) {
+            Ok(layers) => UnpackedLayers {
+                profile: None,
+                layers,
+                error: None,
+            },
+            Err(e) => UnpackedLayers {
+                profile: None,
+                layers: e.layers,
+                error: Some(e.error),
+            },
+        });
+    }
 
-            let mut decrypted_code: Vec<u8> = Vec::with_capacity(code.code.len());
-            for i in 0..consts.len() {
-                decrypted_code.push(code.code[i % code.code.len()] ^ consts[i])
+    let mut errors = Vec::new();
+    let mut furthest: Option<(&Profile, Vec<Layer>)> = None;
+    for profile in matching {
+        let observer = observer
+            .as_mut()
+            .map(|observer| &mut **observer as &mut dyn Stage2Observer<O>);
+        match layers::peel(stage1, Some(profile), stage2_mode, observer) {
+            Ok(layers) => {
+                return Ok(UnpackedLayers {
+                    profile: Some(profile),
+                    layers,
+                    error: None,
+                })
+            }
+            Err(e) => {
+                debug!("Profile `{}` failed: {:#}", profile.name, e);
+                errors.push(format!("{}: {:#}", profile.name, e));
+                if furthest
+                    .as_ref()
+                    .is_none_or(|(_, layers)| e.layers.len() > layers.len())
+                {
+                    furthest = Some((profile, e.layers));
+                }
             }
+        }
+    }
 
-            unpack_b64_compressed_data(decrypted_code.as_slice())?
-        } else {
-            data[8..].to_vec()
-        };
+    let (profile, layers) = furthest.expect("at least one profile was tried");
+    Ok(UnpackedLayers {
+        profile: Some(profile),
+        layers,
+        error: Some(anyhow!(
+            "no profile could unpack the module ({})",
+            errors.join("; ")
+        )),
+    })
+}
 
-        // println!("{}", pretty_hex::pretty_hex(&&decrypted_code[0..0x20]));
-        let deobfuscator = unfuck::Deobfuscator::<Standard>::new(payload_to_deob.as_slice());
-        let deobfuscator = if opt.graphs {
-            deobfuscator
-                .enable_graphs()
-                .on_graph_generated(|name, data| {
-                    std::fs::write(name, data.as_bytes()).expect("failed to write graph")
-                })
-        } else {
-            deobfuscator
-        };
+/// Decodes base64 data and inflates it
+fn unpack_b64_compressed_data(data: &[u8]) -> Result<Vec<u8>> {
+    let b64_data = std::str::from_utf8(data)?;
+    let decoded_data = base64::decode(b64_data.trim())?;
 
-        let x = deobfuscator.deobfuscate().map(|deob| DeobfuscatedCode {
-            original: payload_to_deob,
-            deob: Some(deob.data),
-            has_next_stage: is_encrypted,
-        })?;
+    let mut zlib_decoder = ZlibDecoder::new(decoded_data.as_slice());
+    let mut inflated_data: Vec<u8> = Vec::new();
+    zlib_decoder.read_to_end(&mut inflated_data)?;
 
-        Ok(x)
-    } else {
-        Err(unfuck::error::Error::ObjectError::<Standard>("root obj", obj.clone()).into())
-    }
+    Ok(inflated_data)
 }
 
-fn decrypt_stage2(stage2: &[u8], stage1: &[u8]) -> Result<Vec<u8>> {
-    if let py27_marshal::Obj::Code(stage1_code) = py27_marshal::read::marshal_loads(stage1).unwrap()
-    {
-        if let py27_marshal::Obj::Code(stage2_code) =
-            py27_marshal::read::marshal_loads(stage2).unwrap()
-        {
-            crate::smallvm::exec_stage2(stage2_code, stage1_code)
-        } else {
-            panic!("stage2 is not a code object?");
-        }
-    } else {
-        panic!("stage1 is not a code object?");
-    }
+fn decrypt_stage2<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    stage2: &[u8],
+    stage1: &[u8],
+    profile: &Stage2Profile,
+    mode: Stage2Mode,
+    observer: Option<&mut dyn Stage2Observer<O>>,
+) -> Result<Stage2Output> {
+    crate::smallvm::exec_stage2(
+        load_code(stage2)?,
+        load_code(stage1)?,
+        profile,
+        mode,
+        observer,
+    )
 }
 
 /// Runs the decompiler on the provided PYC file
@@ -0,0 +1,439 @@
+use crate::bytecode::{
+    code_objects, decode_at, load_code, rewrite_code_objects, stack_depths, CodeOverrides,
+    DecodedInstr,
+};
+use crate::cfg::{BasicBlock, FlowGraph};
+use anyhow::{anyhow, Result};
+use py27_marshal::Code;
+use pydis::opcode::py27::Mnemonic;
+use pydis::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+/// Stack depth past which a function is assumed to grow its stack without
+/// bound, in which case its `co_stacksize` is left alone
+const MAX_STACKSIZE: isize = 1 << 16;
+
+/// Comparable representation of an instruction. Jump arguments are not
+/// comparable since deobfuscation moves code around.
+type InstrKey = (u8, Option<u32>);
+
+/// Recomputed metadata for a single code object
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Metadata {
+    stacksize: u32,
+    firstlineno: u32,
+    lnotab: Vec<u8>,
+}
+
+/// Recomputes `co_stacksize`, `co_lnotab`, and optionally `co_firstlineno` for
+/// every code object in the deobfuscated data `deob`, which was produced from
+/// `original`.
+///
+/// The stack size is computed by simulating the deobfuscated code. Lines are
+/// taken from the original code's line number table: runs of instructions
+/// which survived deobfuscation are matched up with the original basic blocks
+/// they came from, and instructions which weren't matched take the line of the
+/// instruction before them. Python 2 line number tables can't go backwards, so
+/// instructions which were moved before code on an earlier line keep the later
+/// line. If `normalize_firstlineno` is set, `co_firstlineno` becomes the first
+/// line of the code object's surviving instructions (or 1 if it has no line
+/// information).
+///
+/// Returns `None` if nothing changed.
+pub fn recompute<O: Opcode<Mnemonic = Mnemonic>>(
+    original: &[u8],
+    deob: &[u8],
+    normalize_firstlineno: bool,
+) -> Result<Option<Vec<u8>>> {
+    let original = code_objects(&load_code(original)?);
+    let deob_code = code_objects(&load_code(deob)?);
+    if original.len() != deob_code.len() {
+        return Err(anyhow!(
+            "deobfuscation changed the number of code objects from {} to {}",
+            original.len(),
+            deob_code.len()
+        ));
+    }
+
+    let metadata: Vec<Metadata> = original
+        .iter()
+        .zip(deob_code.iter())
+        .map(|(original, deob)| code_metadata::<O>(original, deob, normalize_firstlineno))
+        .collect();
+
+    let unchanged = deob_code
+        .iter()
+        .zip(metadata.iter())
+        .all(|(code, metadata)| {
+            code.stacksize == metadata.stacksize
+                && code.firstlineno == metadata.firstlineno
+                && code.lnotab.as_slice() == metadata.lnotab.as_slice()
+        });
+    if unchanged {
+        return Ok(None);
+    }
+
+    let overrides: Vec<CodeOverrides> = metadata
+        .into_iter()
+        .map(|metadata| CodeOverrides {
+            stacksize: Some(metadata.stacksize),
+            firstlineno: Some(metadata.firstlineno),
+            lnotab: Some(metadata.lnotab),
+            ..Default::default()
+        })
+        .collect();
+
+    rewrite_code_objects(deob, &overrides).map(Some)
+}
+
+fn code_metadata<O: Opcode<Mnemonic = Mnemonic>>(
+    original: &Code,
+    deob: &Code,
+    normalize_firstlineno: bool,
+) -> Metadata {
+    let bytecode = deob.code.as_slice();
+    let mut instrs = BTreeMap::new();
+    let mut offset = 0;
+    while offset < bytecode.len() as u64 {
+        match decode_at::<O>(bytecode, offset) {
+            Ok(instr) => {
+                offset = instr.next_offset();
+                instrs.insert(instr.offset, instr);
+            }
+            // Code which can't be decoded is left exactly as it is
+            Err(_) => {
+                return Metadata {
+                    stacksize: deob.stacksize,
+                    firstlineno: deob.firstlineno,
+                    lnotab: deob.lnotab.to_vec(),
+                }
+            }
+        }
+    }
+
+    let depths = stack_depths(&instrs, bytecode.len() as u64, MAX_STACKSIZE);
+    let stacksize = if depths.is_clean() {
+        depths.max_depth as u32
+    } else {
+        deob.stacksize
+    };
+
+    let instrs: Vec<DecodedInstr<O>> = instrs.into_values().collect();
+    let lines = map_lines(original, instrs.as_slice());
+    let firstlineno = if normalize_firstlineno {
+        lines.iter().copied().min().unwrap_or(1).max(1)
+    } else {
+        deob.firstlineno
+    };
+
+    Metadata {
+        stacksize,
+        firstlineno,
+        lnotab: build_lnotab(firstlineno, instrs.as_slice(), lines.as_slice()),
+    }
+}
+
+/// An instruction of one of the original code's basic blocks
+struct BlockInstr {
+    key: InstrKey,
+    is_jump: bool,
+    line: u32,
+}
+
+/// Returns the original line of each instruction of the deobfuscated code
+fn map_lines<O: Opcode<Mnemonic = Mnemonic>>(
+    original: &Code,
+    instrs: &[DecodedInstr<O>],
+) -> Vec<u32> {
+    let original_lines = decode_lnotab(original.firstlineno, original.lnotab.as_slice());
+    let line_at = |offset: u64| {
+        original_lines
+            .range(..=offset)
+            .next_back()
+            .map_or(original.firstlineno, |(_, line)| *line)
+    };
+
+    let graph = FlowGraph::<O>::from_bytecode(original.code.as_slice());
+    let mut bbs: Vec<&BasicBlock<O>> = graph.graph.node_weights().collect();
+    bbs.sort_by_key(|bb| bb.start);
+
+    // Blocks which only contain jumps (e.g. a lone `SETUP_EXCEPT`) can't be
+    // told apart, so they are merged into the block they fall through to, or
+    // left out if they don't fall through
+    let mut blocks: Vec<(u64, Vec<BlockInstr>)> = Vec::new();
+    let mut pending: Option<(u64, Vec<BlockInstr>)> = None;
+    for (index, bb) in bbs.iter().enumerate() {
+        let (start, mut instrs) = pending.take().unwrap_or((bb.start, Vec::new()));
+        instrs.extend(bb.instrs.iter().filter_map(|instr| {
+            Some(BlockInstr {
+                key: instr_key(instr)?,
+                is_jump: instr.jump_target().is_some(),
+                line: line_at(instr.offset),
+            })
+        }));
+
+        if instrs.iter().any(|instr| !instr.is_jump) {
+            blocks.push((start, instrs));
+        } else if let (Some(last), Some(next)) = (bb.instrs.last(), bbs.get(index + 1)) {
+            if !last.is_terminator() && last.next_offset() == next.start {
+                pending = Some((start, instrs));
+            }
+        }
+    }
+
+    // A block may be found by its first instruction, or by its first
+    // non-jump instruction if its leading jumps were removed
+    let mut blocks_by_key: HashMap<InstrKey, Vec<usize>> = HashMap::new();
+    for (index, (_, block)) in blocks.iter().enumerate() {
+        let first = block[0].key;
+        let first_non_jump = block.iter().find(|instr| !instr.is_jump).unwrap().key;
+        blocks_by_key.entry(first).or_default().push(index);
+        if first_non_jump != first {
+            blocks_by_key.entry(first_non_jump).or_default().push(index);
+        }
+    }
+
+    let keyed: Vec<(usize, InstrKey)> = instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instr)| Some((index, instr_key(instr)?)))
+        .collect();
+
+    // Greedily match the original block covering the most instructions at
+    // each position. Identical blocks are told apart by preferring ones which
+    // haven't been matched yet, then the one closest after the previous match
+    // since deobfuscation mostly keeps blocks in their original order.
+    let mut lines: Vec<Option<u32>> = vec![None; instrs.len()];
+    let mut used = vec![false; blocks.len()];
+    let mut previous_start = None;
+    let mut pos = 0;
+    while pos < keyed.len() {
+        let distance = |start: u64| match previous_start {
+            Some(previous) if start <= previous => u64::MAX / 2 + (previous - start),
+            Some(previous) => start - previous,
+            None => start,
+        };
+        let matched = blocks_by_key
+            .get(&keyed[pos].1)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter_map(|index| Some((index, match_block(&blocks[index].1, &keyed[pos..])?)))
+            .max_by_key(|(index, block_lines)| {
+                (
+                    !used[*index],
+                    block_lines.len(),
+                    Reverse(distance(blocks[*index].0)),
+                )
+            });
+
+        match matched {
+            Some((index, block_lines)) => {
+                used[index] = true;
+                previous_start = Some(blocks[index].0);
+                for (line, (instr_index, _)) in block_lines.iter().zip(&keyed[pos..]) {
+                    lines[*instr_index] = Some(*line);
+                }
+                pos += block_lines.len();
+            }
+            None => pos += 1,
+        }
+    }
+
+    // Instructions which weren't matched take the line of the instruction
+    // before them, or the first matched line if they come before any match
+    let mut current = lines
+        .iter()
+        .flatten()
+        .copied()
+        .next()
+        .unwrap_or(original.firstlineno);
+    lines
+        .into_iter()
+        .map(|line| {
+            if let Some(line) = line {
+                current = line;
+            }
+            current
+        })
+        .collect()
+}
+
+/// Matches an original block against the start of `keyed`. Every non-jump
+/// instruction of the block must be present in order, but jumps may have
+/// been removed or rewritten. Returns the line of each instruction matched.
+fn match_block(block: &[BlockInstr], keyed: &[(usize, InstrKey)]) -> Option<Vec<u32>> {
+    let mut lines = Vec::with_capacity(block.len());
+    for instr in block {
+        match keyed.get(lines.len()) {
+            Some((_, key)) if *key == instr.key => lines.push(instr.line),
+            _ if instr.is_jump => continue,
+            _ => return None,
+        }
+    }
+
+    Some(lines)
+}
+
+/// Returns the comparable representation of an instruction, or `None` for
+/// `NOP`s, which carry no information
+fn instr_key<O: Opcode<Mnemonic = Mnemonic>>(instr: &DecodedInstr<O>) -> Option<InstrKey> {
+    if instr.opcode.mnemonic() == Mnemonic::NOP {
+        return None;
+    }
+
+    let arg = if instr.jump_target().is_some() {
+        None
+    } else {
+        instr.arg
+    };
+
+    Some((instr.opcode.to_u8().unwrap(), arg))
+}
+
+/// Returns the line each offset listed in a line number table starts
+fn decode_lnotab(firstlineno: u32, lnotab: &[u8]) -> BTreeMap<u64, u32> {
+    let mut lines = BTreeMap::new();
+    let mut offset = 0u64;
+    let mut line = firstlineno;
+    lines.insert(offset, line);
+    for pair in lnotab.chunks_exact(2) {
+        offset += pair[0] as u64;
+        line += pair[1] as u32;
+        lines.insert(offset, line);
+    }
+
+    lines
+}
+
+/// Builds a line number table the same way CPython 2.7's compiler does,
+/// skipping lines which would go backwards
+fn build_lnotab<O: Opcode<Mnemonic = Mnemonic>>(
+    firstlineno: u32,
+    instrs: &[DecodedInstr<O>],
+    lines: &[u32],
+) -> Vec<u8> {
+    let mut lnotab = Vec::new();
+    let mut last_offset = 0u64;
+    let mut last_line = firstlineno;
+    for (instr, line) in instrs.iter().zip(lines.iter().copied()) {
+        if line <= last_line {
+            continue;
+        }
+
+        let mut offset_delta = instr.offset - last_offset;
+        let mut line_delta = line - last_line;
+        while offset_delta > 255 {
+            lnotab.extend_from_slice(&[255, 0]);
+            offset_delta -= 255;
+        }
+        while line_delta > 255 {
+            lnotab.extend_from_slice(&[offset_delta as u8, 255]);
+            offset_delta = 0;
+            line_delta -= 255;
+        }
+        lnotab.extend_from_slice(&[offset_delta as u8, line_delta as u8]);
+
+        last_offset = instr.offset;
+        last_line = line;
+    }
+
+    lnotab
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::opcode_byte;
+    use crate::bytecode::testing::compile;
+    use pydis::opcode::py27::Standard;
+
+    /// Applies `overrides` to every code object of `data`
+    fn rewrite_all(data: &[u8], overrides: impl Fn(&Code) -> CodeOverrides) -> Vec<u8> {
+        let overrides: Vec<CodeOverrides> = code_objects(&load_code(data).unwrap())
+            .iter()
+            .map(|code| overrides(code))
+            .collect();
+
+        rewrite_code_objects(data, &overrides).unwrap()
+    }
+
+    #[test]
+    fn stacksizes_account_for_exception_handlers_and_blocks() {
+        let data = compile(concat!(
+            "def f(a, b):\n",
+            "    try:\n",
+            "        with a as c:\n",
+            "            x = b[c] + (a, b, c)\n",
+            "    except (KeyError, ValueError) as e:\n",
+            "        x = e\n",
+            "    finally:\n",
+            "        for y in b:\n",
+            "            x = {y: [a, b]}\n",
+            "    return x\n",
+        ));
+        let shrunk = rewrite_all(&data, |_| CodeOverrides {
+            stacksize: Some(1),
+            ..Default::default()
+        });
+
+        let recomputed = recompute::<Standard>(&data, &shrunk, false)
+            .unwrap()
+            .unwrap();
+
+        let stacksizes = |data: &[u8]| -> Vec<u32> {
+            code_objects(&load_code(data).unwrap())
+                .iter()
+                .map(|code| code.stacksize)
+                .collect()
+        };
+        assert_eq!(stacksizes(&recomputed), stacksizes(&data));
+    }
+
+    #[test]
+    fn unchanged_metadata_is_not_rewritten() {
+        let data = compile("def f(a):\n    return a\nx = f(1)\n");
+
+        assert!(recompute::<Standard>(&data, &data, false)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn lines_follow_instructions_which_moved() {
+        let data = compile("x = 1\ny = 2\n\nz = x + y\n");
+        let module = load_code(&data).unwrap();
+        assert_eq!(module.lnotab.as_slice(), &[6, 1, 6, 2]);
+
+        // Inserting a `NOP` moves every instruction forward by a byte
+        let nop = opcode_byte::<Standard>(Mnemonic::NOP).unwrap();
+        let shifted = rewrite_all(&data, |code| CodeOverrides {
+            code: Some([&[nop], code.code.as_slice()].concat()),
+            lnotab: Some(Vec::new()),
+            ..Default::default()
+        });
+
+        let recomputed = recompute::<Standard>(&data, &shifted, false)
+            .unwrap()
+            .unwrap();
+
+        let recomputed = load_code(&recomputed).unwrap();
+        assert_eq!(recomputed.lnotab.as_slice(), &[7, 1, 6, 2]);
+        assert_eq!(recomputed.firstlineno, 1);
+    }
+
+    #[test]
+    fn first_line_numbers_can_be_normalized() {
+        let data = compile("\n\ndef f():\n    return 1\n");
+        let function = &code_objects(&load_code(&data).unwrap())[1];
+        assert_eq!(function.firstlineno, 3);
+        assert_eq!(function.lnotab.as_slice(), &[0, 1]);
+
+        let recomputed = recompute::<Standard>(&data, &data, true).unwrap().unwrap();
+
+        let function = &code_objects(&load_code(&recomputed).unwrap())[1];
+        assert_eq!(function.firstlineno, 4);
+        assert!(function.lnotab.is_empty());
+    }
+}
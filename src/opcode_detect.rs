@@ -0,0 +1,889 @@
+use crate::bytecode::mnemonic_from_name;
+use crate::opcode_table::{OpcodeTable, OpcodeTableError};
+use py27_marshal::Code;
+use pydis::opcode::py27::{Mnemonic, Standard};
+use pydis::opcode::Opcode;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+/// Opcode statistics of CPython 2.7 bytecode, which a corpus is compared to
+const REFERENCE_STATS: &str = include_str!("../data/opcodes/python27_stats.json");
+
+/// Bytes at or above this take a 16-bit argument. The interpreter decides
+/// this with a comparison rather than a table lookup, so builds which permute
+/// their opcodes keep the split.
+const HAVE_ARGUMENT: u8 = 90;
+
+/// Upper bounds of the buckets arguments are counted in. The last bucket
+/// holds everything at or above the last bound.
+const ARG_BUCKET_BOUNDS: [u32; 8] = [1, 2, 3, 4, 8, 16, 64, 256];
+const ARG_BUCKETS: usize = ARG_BUCKET_BOUNDS.len() + 1;
+
+/// Number of valid `COMPARE_OP` arguments
+const COMPARE_OPS: u32 = 11;
+
+/// Pseudo-tokens marking the start and end of a code object in bigrams
+const START: usize = 256;
+const END: usize = 257;
+
+/// Log-likelihood of an argument which is invalid for the opcode. Obfuscated
+/// code contains junk which is never executed, so this isn't impossible.
+const INVALID_ARG_LOG_LIKELIHOOD: f64 = -6.9;
+
+/// Probability of a given junk byte following an instruction. Obfuscated
+/// code hides random bytes which don't encode any opcode, and each of them is
+/// any of the 256 possible bytes.
+const JUNK_RATE: f64 = 0.002 / 256.0;
+
+/// Probability of a given junk byte following an instruction which never
+/// falls through. Junk is mostly hidden behind unconditional jumps.
+const DEAD_JUNK_RATE: f64 = 0.1 / 256.0;
+
+/// Weight of the unigram probability mixed into each bigram probability.
+/// Obfuscated code reorders blocks, which makes bigrams less reliable.
+const UNIGRAM_WEIGHT: f64 = 0.05;
+
+/// Added to every reference count so that unseen events aren't impossible
+const SMOOTHING: f64 = 0.5;
+
+/// Hill climbing gives up after this many passes over every move
+const MAX_PASSES: usize = 100;
+
+/// Assignments whose log-likelihood margin is below this are reported as
+/// uncertain
+pub const LOW_CONFIDENCE_MARGIN: f64 = 5.0;
+
+#[derive(Error, Debug)]
+pub enum DetectError {
+    #[error("the corpus contains no instructions")]
+    EmptyCorpus,
+    #[error("{misaligned} of {total} code objects end in the middle of an instruction, so the corpus is not Python 2.7 bytecode with a permuted opcode table")]
+    Misaligned { misaligned: usize, total: usize },
+    #[error(transparent)]
+    Table(#[from] OpcodeTableError),
+}
+
+/// The on-disk form of the reference statistics. Opcodes are keyed by
+/// mnemonic, and bigrams may also use the `<start>` and `<end>` pseudo-tokens.
+#[derive(Deserialize)]
+struct ReferenceFile {
+    unigrams: BTreeMap<String, u64>,
+    bigrams: BTreeMap<String, BTreeMap<String, u64>>,
+    args: BTreeMap<String, Vec<u64>>,
+}
+
+/// Log-probabilities of CPython 2.7's opcodes. Opcodes are indexed by their
+/// position in `mnemonics`, followed by the start and end pseudo-tokens and
+/// junk.
+struct Reference {
+    mnemonics: Vec<Mnemonic>,
+    unigrams: Vec<f64>,
+    /// Log-probability of the column token following the row token
+    bigrams: Vec<Vec<f64>>,
+    /// Log-probability of each argument bucket, indexed by token
+    args: Vec<[f64; ARG_BUCKETS]>,
+}
+
+impl Reference {
+    fn builtin() -> Reference {
+        let file: ReferenceFile =
+            serde_json::from_str(REFERENCE_STATS).expect("built-in opcode statistics are invalid");
+        let mnemonics: Vec<Mnemonic> = {
+            let table = OpcodeTable::builtin("python27").unwrap();
+            (0..=u8::MAX)
+                .filter_map(|byte| table.mnemonic(byte))
+                .collect()
+        };
+        let tokens = mnemonics.len() + 2;
+        let junk_rate = JUNK_RATE.ln();
+        let index = |name: &str| match name {
+            "<start>" => mnemonics.len(),
+            "<end>" => mnemonics.len() + 1,
+            name => {
+                let mnemonic = mnemonic_from_name(name)
+                    .unwrap_or_else(|| panic!("unknown opcode `{}` in opcode statistics", name));
+                mnemonics.iter().position(|m| *m == mnemonic).unwrap()
+            }
+        };
+
+        let mut unigram_counts = vec![0.0; mnemonics.len()];
+        for (name, count) in &file.unigrams {
+            unigram_counts[index(name)] = *count as f64;
+        }
+
+        let mut bigrams = vec![vec![0.0; tokens]; tokens];
+        for (prev, nexts) in &file.bigrams {
+            for (next, count) in nexts {
+                bigrams[index(prev)][index(next)] = *count as f64;
+            }
+        }
+
+        let mut args = vec![[0.0; ARG_BUCKETS]; mnemonics.len()];
+        for (name, counts) in &file.args {
+            for (bucket, count) in counts.iter().enumerate() {
+                args[index(name)][bucket] = *count as f64;
+            }
+        }
+
+        // Junk may follow anything, and is followed by whatever follows real
+        // code. The start and end pseudo-tokens aren't mixed with unigrams.
+        let unigrams = log_probabilities(&unigram_counts);
+        let mut bigrams: Vec<Vec<f64>> = bigrams
+            .iter()
+            .enumerate()
+            .map(|(token, row)| {
+                let mut row: Vec<f64> = log_probabilities(row)
+                    .iter()
+                    .enumerate()
+                    .map(|(next, bigram)| {
+                        let unigram = unigrams.get(next).map_or(*bigram, |unigram| *unigram);
+                        ((1.0 - UNIGRAM_WEIGHT) * bigram.exp() + UNIGRAM_WEIGHT * unigram.exp())
+                            .ln()
+                    })
+                    .collect();
+                let falls_through = mnemonics.get(token).is_none_or(|m| falls_through(*m));
+                row.push(if falls_through {
+                    junk_rate
+                } else {
+                    DEAD_JUNK_RATE.ln()
+                });
+                row
+            })
+            .collect();
+        let mut junk_row = unigrams.clone();
+        junk_row.extend([junk_rate, junk_rate, DEAD_JUNK_RATE.ln()]);
+        bigrams.push(junk_row);
+
+        let mut args: Vec<[f64; ARG_BUCKETS]> = args
+            .iter()
+            .map(|counts| log_probabilities(counts).try_into().unwrap())
+            .collect();
+        // The start and end never have arguments, and junk's are random
+        args.extend([[0.0; ARG_BUCKETS]; 2]);
+        args.push([(1.0 / ARG_BUCKETS as f64).ln(); ARG_BUCKETS]);
+
+        Reference {
+            unigrams,
+            bigrams,
+            args,
+            mnemonics,
+        }
+    }
+
+    fn start(&self) -> usize {
+        self.mnemonics.len()
+    }
+
+    fn end(&self) -> usize {
+        self.mnemonics.len() + 1
+    }
+
+    fn junk(&self) -> usize {
+        self.mnemonics.len() + 2
+    }
+}
+
+/// Whether execution may continue with the next instruction after
+/// `mnemonic`
+fn falls_through(mnemonic: Mnemonic) -> bool {
+    !matches!(
+        mnemonic,
+        Mnemonic::JUMP_ABSOLUTE
+            | Mnemonic::JUMP_FORWARD
+            | Mnemonic::CONTINUE_LOOP
+            | Mnemonic::BREAK_LOOP
+            | Mnemonic::RETURN_VALUE
+            | Mnemonic::RAISE_VARARGS
+    )
+}
+
+fn log_probabilities(counts: &[f64]) -> Vec<f64> {
+    let total: f64 = counts.iter().sum::<f64>() + SMOOTHING * counts.len() as f64;
+    counts
+        .iter()
+        .map(|count| ((count + SMOOTHING) / total).ln())
+        .collect()
+}
+
+/// What an opcode's argument refers to, which limits the values it can take
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ArgKind {
+    Const,
+    Name,
+    Local,
+    Free,
+    AbsoluteJump,
+    RelativeJump,
+    Compare,
+}
+
+const ARG_KINDS: usize = 7;
+
+impl ArgKind {
+    fn of(mnemonic: Mnemonic) -> Option<ArgKind> {
+        let opcode = Standard::from(mnemonic);
+        if opcode.has_const() {
+            Some(ArgKind::Const)
+        } else if opcode.has_name() {
+            Some(ArgKind::Name)
+        } else if opcode.has_local() {
+            Some(ArgKind::Local)
+        } else if opcode.has_free() {
+            Some(ArgKind::Free)
+        } else if opcode.is_absolute_jump() {
+            Some(ArgKind::AbsoluteJump)
+        } else if opcode.is_relative_jump() {
+            Some(ArgKind::RelativeJump)
+        } else if opcode.has_comp() {
+            Some(ArgKind::Compare)
+        } else {
+            None
+        }
+    }
+
+    const ALL: [ArgKind; ARG_KINDS] = [
+        ArgKind::Const,
+        ArgKind::Name,
+        ArgKind::Local,
+        ArgKind::Free,
+        ArgKind::AbsoluteJump,
+        ArgKind::RelativeJump,
+        ArgKind::Compare,
+    ];
+
+    /// Whether `arg` is valid for the instruction at `offset` of `code`.
+    /// `boundaries` marks the offsets at which an instruction starts.
+    fn accepts(self, code: &Code, boundaries: &[bool], offset: usize, arg: u32) -> bool {
+        let arg = arg as usize;
+        let is_boundary = |target: usize| boundaries.get(target).copied().unwrap_or(false);
+        match self {
+            ArgKind::Const => arg < code.consts.len(),
+            ArgKind::Name => arg < code.names.len(),
+            ArgKind::Local => arg < code.varnames.len(),
+            ArgKind::Free => arg < code.cellvars.len() + code.freevars.len(),
+            ArgKind::AbsoluteJump => is_boundary(arg),
+            ArgKind::RelativeJump => is_boundary(offset + 3 + arg),
+            ArgKind::Compare => arg < COMPARE_OPS as usize,
+        }
+    }
+}
+
+fn arg_bucket(arg: u32) -> usize {
+    ARG_BUCKET_BOUNDS
+        .iter()
+        .position(|bound| arg < *bound)
+        .unwrap_or(ARG_BUCKET_BOUNDS.len())
+}
+
+/// Opcode statistics of a corpus of code objects whose opcode table is
+/// unknown. Bytes are used as tokens, with [`START`] and [`END`] marking the
+/// ends of each code object.
+pub struct OpcodeCorpus {
+    code_objects: usize,
+    /// Code objects whose last instruction runs past the end of the code
+    misaligned: usize,
+    instructions: u64,
+    counts: Vec<u64>,
+    bigrams: HashMap<(usize, usize), u64>,
+    args: Vec<[u64; ARG_BUCKETS]>,
+    /// Arguments which would be invalid for each kind of argument
+    invalid: Vec<[u64; ARG_KINDS]>,
+}
+
+impl Default for OpcodeCorpus {
+    fn default() -> Self {
+        OpcodeCorpus {
+            code_objects: 0,
+            misaligned: 0,
+            instructions: 0,
+            counts: vec![0; 256],
+            bigrams: HashMap::new(),
+            args: vec![[0; ARG_BUCKETS]; 256],
+            invalid: vec![[0; ARG_KINDS]; 256],
+        }
+    }
+}
+
+impl OpcodeCorpus {
+    /// Adds the instructions of a single code object, ignoring any nested code
+    /// objects
+    pub fn add(&mut self, code: &Code) {
+        let bytecode = code.code.as_slice();
+        let mut instrs = Vec::new();
+        let mut offset = 0;
+        while offset < bytecode.len() {
+            let byte = bytecode[offset];
+            if byte < HAVE_ARGUMENT {
+                instrs.push((offset, byte, None));
+                offset += 1;
+            } else if offset + 3 <= bytecode.len() {
+                let arg = u16::from_le_bytes([bytecode[offset + 1], bytecode[offset + 2]]);
+                instrs.push((offset, byte, Some(arg as u32)));
+                offset += 3;
+            } else {
+                self.misaligned += 1;
+                return;
+            }
+        }
+
+        let mut boundaries = vec![false; bytecode.len() + 1];
+        for (offset, _, _) in &instrs {
+            boundaries[*offset] = true;
+        }
+
+        self.code_objects += 1;
+        let mut prev = START;
+        for (offset, byte, arg) in instrs {
+            let byte = byte as usize;
+            self.instructions += 1;
+            self.counts[byte] += 1;
+            *self.bigrams.entry((prev, byte)).or_default() += 1;
+            prev = byte;
+
+            if let Some(arg) = arg {
+                self.args[byte][arg_bucket(arg)] += 1;
+                for (kind, invalid) in ArgKind::ALL.iter().zip(self.invalid[byte].iter_mut()) {
+                    if !kind.accepts(code, &boundaries, offset, arg) {
+                        *invalid += 1;
+                    }
+                }
+            }
+        }
+        *self.bigrams.entry((prev, END)).or_default() += 1;
+    }
+
+    /// Number of code objects added, including misaligned ones
+    pub fn code_objects(&self) -> usize {
+        self.code_objects + self.misaligned
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+}
+
+/// The mnemonic a byte of the corpus was found to encode
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub byte: u8,
+    pub mnemonic: Mnemonic,
+    /// Number of times the byte occurs in the corpus
+    pub occurrences: u64,
+    /// How much less likely the corpus is under the best alternative for this
+    /// byte, as a log-likelihood ratio. Small margins are uncertain.
+    pub margin: f64,
+}
+
+/// The result of detecting a corpus' opcode table
+pub struct DetectedTable {
+    /// The table: the bytes seen in the corpus, plus the opcodes which were
+    /// never seen on bytes the corpus doesn't use
+    pub table: OpcodeTable,
+    /// Every byte of the table seen in the corpus, in order
+    pub assignments: Vec<Assignment>,
+    /// Opcodes which never appear in the corpus and the unused bytes they
+    /// were placed on, so that code can still be encoded with them (e.g. the
+    /// `NOP`s passes fill removed code with)
+    pub unseen: Vec<(u8, Mnemonic)>,
+    /// Bytes seen in the corpus which only appear to be junk
+    pub junk: Vec<u8>,
+}
+
+/// Infers the opcode table of a corpus of Python 2.7 bytecode whose opcodes
+/// were permuted. Each byte is first matched to the opcode whose frequency,
+/// arguments, and position at the start or end of code objects fit best, and
+/// the assignment is then improved by swapping opcodes while that makes the
+/// corpus' bigrams more likely. Bytes which fit no opcode better than random
+/// junk are left out of the table.
+pub fn detect(corpus: &OpcodeCorpus) -> Result<DetectedTable, DetectError> {
+    if corpus.instructions == 0 {
+        return Err(DetectError::EmptyCorpus);
+    }
+    if corpus.misaligned * 2 > corpus.code_objects() {
+        return Err(DetectError::Misaligned {
+            misaligned: corpus.misaligned,
+            total: corpus.code_objects(),
+        });
+    }
+
+    let reference = Reference::builtin();
+    let scorer = Scorer::new(corpus, &reference);
+    let mut assignment = vec![None; 256];
+    for with_argument in [false, true] {
+        let bytes: Vec<usize> = (0..256)
+            .filter(|byte| (*byte >= HAVE_ARGUMENT as usize) == with_argument)
+            .filter(|byte| corpus.counts[*byte] > 0)
+            .collect();
+        // Every byte may be junk, so there is a junk column for each of them
+        let mut columns = scorer.class(with_argument);
+        columns.extend(bytes.iter().map(|_| reference.junk()));
+
+        let costs: Vec<Vec<f64>> = bytes
+            .iter()
+            .map(|byte| {
+                columns
+                    .iter()
+                    .map(|mnemonic| -scorer.initial(*byte, *mnemonic))
+                    .collect()
+            })
+            .collect();
+        for (byte, column) in bytes.iter().zip(min_cost_assignment(&costs)) {
+            assignment[*byte] = Some(columns[column]);
+        }
+    }
+
+    scorer.climb(&mut assignment);
+
+    let junk: Vec<u8> = (0..256)
+        .filter(|byte| assignment[*byte] == Some(reference.junk()))
+        .map(|byte| byte as u8)
+        .collect();
+    let assignments: Vec<Assignment> = (0..256)
+        .filter_map(|byte| {
+            let mnemonic = assignment[byte].filter(|mnemonic| *mnemonic != reference.junk())?;
+            Some(Assignment {
+                byte: byte as u8,
+                mnemonic: reference.mnemonics[mnemonic],
+                occurrences: corpus.counts[byte],
+                margin: scorer.margin(&assignment, byte),
+            })
+        })
+        .collect();
+    let unseen = place_unseen(corpus, &reference, &assignments);
+    let table = OpcodeTable::new(
+        "detected",
+        assignments
+            .iter()
+            .map(|assignment| (assignment.byte, assignment.mnemonic))
+            .chain(unseen.iter().copied()),
+    )?;
+
+    Ok(DetectedTable {
+        table,
+        assignments,
+        unseen,
+        junk,
+    })
+}
+
+/// Places each reference opcode which wasn't assigned a byte on a byte the
+/// corpus never uses and which agrees on whether it takes an argument: its
+/// byte in CPython if that is free, otherwise the lowest one. Opcodes are left
+/// out if no such byte is left.
+fn place_unseen(
+    corpus: &OpcodeCorpus,
+    reference: &Reference,
+    assignments: &[Assignment],
+) -> Vec<(u8, Mnemonic)> {
+    let mut free: Vec<u8> = (0..=u8::MAX)
+        .filter(|byte| corpus.counts[*byte as usize] == 0)
+        .collect();
+
+    let python27 = OpcodeTable::builtin("python27").unwrap();
+    let missing: Vec<Mnemonic> = reference
+        .mnemonics
+        .iter()
+        .copied()
+        .filter(|mnemonic| {
+            assignments
+                .iter()
+                .all(|assignment| assignment.mnemonic != *mnemonic)
+        })
+        .collect();
+
+    // Opcodes whose own byte is free take it before any are handed out
+    let mut unseen = Vec::new();
+    let mut displaced = Vec::new();
+    for mnemonic in missing {
+        match python27
+            .byte(mnemonic)
+            .and_then(|byte| free.iter().position(|free| *free == byte))
+        {
+            Some(index) => unseen.push((free.remove(index), mnemonic)),
+            None => displaced.push(mnemonic),
+        }
+    }
+    for mnemonic in displaced {
+        let with_argument = Standard::from(mnemonic).has_arg();
+        if let Some(index) = free
+            .iter()
+            .position(|byte| (*byte >= HAVE_ARGUMENT) == with_argument)
+        {
+            unseen.push((free.remove(index), mnemonic));
+        }
+    }
+    unseen.sort_by_key(|(byte, _)| *byte);
+
+    unseen
+}
+
+/// Scores assignments of the corpus' bytes to reference opcodes by the
+/// log-likelihood of the corpus
+struct Scorer<'a> {
+    corpus: &'a OpcodeCorpus,
+    reference: &'a Reference,
+    /// Bigrams leaving and entering each byte
+    outgoing: Vec<Vec<(usize, u64)>>,
+    incoming: Vec<Vec<(usize, u64)>>,
+}
+
+impl<'a> Scorer<'a> {
+    fn new(corpus: &'a OpcodeCorpus, reference: &'a Reference) -> Scorer<'a> {
+        let mut outgoing = vec![Vec::new(); END + 1];
+        let mut incoming = vec![Vec::new(); END + 1];
+        let mut bigrams: Vec<_> = corpus.bigrams.iter().collect();
+        bigrams.sort();
+        for (&(prev, next), &count) in bigrams {
+            outgoing[prev].push((next, count));
+            incoming[next].push((prev, count));
+        }
+
+        Scorer {
+            corpus,
+            reference,
+            outgoing,
+            incoming,
+        }
+    }
+
+    /// Reference opcodes which do or don't take an argument
+    fn class(&self, with_argument: bool) -> Vec<usize> {
+        (0..self.reference.mnemonics.len())
+            .filter(|index| {
+                Standard::from(self.reference.mnemonics[*index]).has_arg() == with_argument
+            })
+            .collect()
+    }
+
+    /// Log-likelihood of `byte`'s arguments if it encodes `mnemonic`
+    fn arguments(&self, byte: usize, mnemonic: usize) -> f64 {
+        let args: f64 = self.corpus.args[byte]
+            .iter()
+            .zip(self.reference.args[mnemonic].iter())
+            .map(|(count, log_p)| *count as f64 * log_p)
+            .sum();
+        let kind = self
+            .reference
+            .mnemonics
+            .get(mnemonic)
+            .and_then(|mnemonic| ArgKind::of(*mnemonic));
+        let invalid = match kind {
+            Some(kind) => self.corpus.invalid[byte][kind as usize] as f64,
+            None => 0.0,
+        };
+
+        args + invalid * INVALID_ARG_LOG_LIKELIHOOD
+    }
+
+    /// Log-likelihood of `byte` encoding `mnemonic` regardless of what the
+    /// other bytes encode
+    fn initial(&self, byte: usize, mnemonic: usize) -> f64 {
+        let count = |prev, next| *self.corpus.bigrams.get(&(prev, next)).unwrap_or(&0) as f64;
+        let bigrams = &self.reference.bigrams;
+        let unigram = match self.reference.unigrams.get(mnemonic) {
+            Some(unigram) => *unigram,
+            None => JUNK_RATE.ln(),
+        };
+
+        self.corpus.counts[byte] as f64 * unigram
+            + count(START, byte) * bigrams[self.reference.start()][mnemonic]
+            + count(byte, END) * bigrams[mnemonic][self.reference.end()]
+            + self.arguments(byte, mnemonic)
+    }
+
+    fn token(&self, assignment: &[Option<usize>], byte: usize) -> usize {
+        match byte {
+            START => self.reference.start(),
+            END => self.reference.end(),
+            byte => assignment[byte].expect("byte in the corpus was not assigned"),
+        }
+    }
+
+    /// Log-likelihood of everything in the corpus involving `bytes`
+    fn local(&self, assignment: &[Option<usize>], bytes: &[usize]) -> f64 {
+        let bigrams = &self.reference.bigrams;
+        let mut score = 0.0;
+        for &byte in bytes {
+            let token = self.token(assignment, byte);
+            score += self.arguments(byte, token);
+            for &(next, count) in &self.outgoing[byte] {
+                score += count as f64 * bigrams[token][self.token(assignment, next)];
+            }
+            for &(prev, count) in &self.incoming[byte] {
+                if !bytes.contains(&prev) {
+                    score += count as f64 * bigrams[self.token(assignment, prev)][token];
+                }
+            }
+        }
+
+        score
+    }
+
+    /// Every move which changes what `byte` encodes: swapping with another
+    /// byte of its class, taking an opcode no byte encodes, or becoming junk.
+    /// Returns the bytes involved and their new opcodes.
+    fn moves(&self, assignment: &[Option<usize>], byte: usize) -> Vec<Vec<(usize, usize)>> {
+        let with_argument = byte >= HAVE_ARGUMENT as usize;
+        let junk = self.reference.junk();
+        let mnemonic = assignment[byte].unwrap();
+        let mut moves = Vec::new();
+        for (other, other_mnemonic) in assignment.iter().enumerate() {
+            if other == byte || (other >= HAVE_ARGUMENT as usize) != with_argument {
+                continue;
+            }
+            match other_mnemonic {
+                Some(other_mnemonic) if *other_mnemonic != mnemonic => {
+                    moves.push(vec![(byte, *other_mnemonic), (other, mnemonic)]);
+                }
+                _ => {}
+            }
+        }
+        for free in self.class(with_argument) {
+            if !assignment.contains(&Some(free)) {
+                moves.push(vec![(byte, free)]);
+            }
+        }
+        if mnemonic != junk {
+            moves.push(vec![(byte, junk)]);
+        }
+
+        moves
+    }
+
+    /// Change in log-likelihood if `change` were applied
+    fn delta(&self, assignment: &mut [Option<usize>], change: &[(usize, usize)]) -> f64 {
+        let bytes: Vec<usize> = change.iter().map(|(byte, _)| *byte).collect();
+        let before = self.local(assignment, &bytes);
+        let old: Vec<Option<usize>> = bytes.iter().map(|byte| assignment[*byte]).collect();
+        for (byte, mnemonic) in change {
+            assignment[*byte] = Some(*mnemonic);
+        }
+        let after = self.local(assignment, &bytes);
+        for (byte, mnemonic) in bytes.iter().zip(old) {
+            assignment[*byte] = mnemonic;
+        }
+
+        after - before
+    }
+
+    /// Applies improving moves until none are left
+    fn climb(&self, assignment: &mut [Option<usize>]) {
+        for _ in 0..MAX_PASSES {
+            let mut improved = false;
+            for byte in 0..256 {
+                if assignment[byte].is_none() {
+                    continue;
+                }
+
+                // The remaining moves are stale once one is applied, so the
+                // byte is revisited on the next pass
+                for change in self.moves(assignment, byte) {
+                    if self.delta(assignment, &change) > 1e-9 {
+                        for (byte, mnemonic) in change {
+                            assignment[byte] = Some(mnemonic);
+                        }
+                        improved = true;
+                        break;
+                    }
+                }
+            }
+
+            if !improved {
+                break;
+            }
+        }
+    }
+
+    /// How much the log-likelihood drops under the best move changing `byte`
+    fn margin(&self, assignment: &[Option<usize>], byte: usize) -> f64 {
+        let mut assignment = assignment.to_vec();
+        self.moves(&assignment, byte)
+            .iter()
+            .map(|change| -self.delta(&mut assignment, change))
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+/// Assigns each row a distinct column so that the sum of their costs is
+/// minimal, with the Hungarian algorithm. There must be at least as many
+/// columns as rows. Returns the column of each row.
+fn min_cost_assignment(costs: &[Vec<f64>]) -> Vec<usize> {
+    let rows = costs.len();
+    if rows == 0 {
+        return Vec::new();
+    }
+    let columns = costs[0].len();
+
+    // Potentials and matching are 1-based, with row 0 as a sentinel
+    let mut u = vec![0.0; rows + 1];
+    let mut v = vec![0.0; columns + 1];
+    let mut matched = vec![0; columns + 1];
+    let mut way = vec![0; columns + 1];
+    for row in 1..=rows {
+        matched[0] = row;
+        let mut column = 0;
+        let mut min = vec![f64::INFINITY; columns + 1];
+        let mut used = vec![false; columns + 1];
+        loop {
+            used[column] = true;
+            let current = matched[column];
+            let mut delta = f64::INFINITY;
+            let mut next = 0;
+            for j in 1..=columns {
+                if used[j] {
+                    continue;
+                }
+                let reduced = costs[current - 1][j - 1] - u[current] - v[j];
+                if reduced < min[j] {
+                    min[j] = reduced;
+                    way[j] = column;
+                }
+                if min[j] < delta {
+                    delta = min[j];
+                    next = j;
+                }
+            }
+            for j in 0..=columns {
+                if used[j] {
+                    u[matched[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min[j] -= delta;
+                }
+            }
+            column = next;
+            if matched[column] == 0 {
+                break;
+            }
+        }
+        loop {
+            let prev = way[column];
+            matched[column] = matched[prev];
+            column = prev;
+            if column == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0; rows];
+    for j in 1..=columns {
+        if matched[j] != 0 {
+            assignment[matched[j] - 1] = j - 1;
+        }
+    }
+
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{code_objects, load_code};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/expected/compiler");
+
+    /// Reverses the order of the opcodes with and without arguments
+    fn permute(byte: u8) -> u8 {
+        if byte < HAVE_ARGUMENT {
+            HAVE_ARGUMENT - 1 - byte
+        } else {
+            u8::MAX - (byte - HAVE_ARGUMENT)
+        }
+    }
+
+    fn permute_code(code: &Code) -> Code {
+        let mut bytecode = code.code.to_vec();
+        let mut offset = 0;
+        while offset < bytecode.len() {
+            let byte = bytecode[offset];
+            bytecode[offset] = permute(byte);
+            offset += if byte < HAVE_ARGUMENT { 1 } else { 3 };
+        }
+
+        let mut code = code.clone();
+        code.code = Arc::new(bytecode);
+        code
+    }
+
+    #[test]
+    fn min_cost_assignment_finds_the_optimum() {
+        let costs = vec![
+            vec![4.0, 1.0, 3.0, 9.0],
+            vec![2.0, 0.0, 5.0, 9.0],
+            vec![3.0, 2.0, 2.0, 9.0],
+        ];
+        assert_eq!(min_cost_assignment(&costs), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn detects_permuted_fixtures() {
+        let mut corpus = OpcodeCorpus::default();
+        for entry in std::fs::read_dir(Path::new(FIXTURE_DIR)).unwrap() {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            for code in code_objects(&load_code(&data[8..]).unwrap()) {
+                corpus.add(&permute_code(&code));
+            }
+        }
+
+        let detected = detect(&corpus).unwrap();
+        assert!(detected.junk.is_empty());
+        assert_eq!(
+            detected.assignments.len() + detected.unseen.len(),
+            Reference::builtin().mnemonics.len()
+        );
+
+        // Every frequent opcode must be recovered, and rare ones mostly
+        let python27 = OpcodeTable::builtin("python27").unwrap();
+        let mut correct = 0;
+        for assignment in &detected.assignments {
+            let original = (0..=u8::MAX)
+                .find(|byte| permute(*byte) == assignment.byte)
+                .unwrap();
+            if python27.mnemonic(original) == Some(assignment.mnemonic) {
+                correct += assignment.occurrences;
+            } else {
+                assert!(
+                    assignment.occurrences < 100,
+                    "byte {} is {:?}, not {:?}",
+                    assignment.byte,
+                    assignment.mnemonic,
+                    python27.mnemonic(original)
+                );
+            }
+        }
+        assert!(correct * 100 >= corpus.instructions() * 99);
+    }
+
+    #[test]
+    fn unseen_opcodes_are_placed_on_unused_bytes() {
+        let data = std::fs::read(Path::new(FIXTURE_DIR).join("ast.pyc")).unwrap();
+        let mut corpus = OpcodeCorpus::default();
+        for code in code_objects(&load_code(&data[8..]).unwrap()) {
+            corpus.add(&permute_code(&code));
+        }
+
+        let detected = detect(&corpus).unwrap();
+
+        // The compiler never emits `NOP`s, but passes fill code with them
+        assert!(detected
+            .assignments
+            .iter()
+            .all(|assignment| assignment.mnemonic != Mnemonic::NOP));
+        let nop = detected.table.byte(Mnemonic::NOP).unwrap();
+        assert!(nop < HAVE_ARGUMENT);
+        assert_eq!(corpus.counts[nop as usize], 0);
+
+        for (byte, mnemonic) in &detected.unseen {
+            assert_eq!(corpus.counts[*byte as usize], 0);
+            assert_eq!(*byte >= HAVE_ARGUMENT, Standard::from(*mnemonic).has_arg());
+            assert_eq!(detected.table.mnemonic(*byte), Some(*mnemonic));
+        }
+        for mnemonic in Reference::builtin().mnemonics {
+            assert!(detected.table.byte(mnemonic).is_some(), "{:?}", mnemonic);
+        }
+    }
+}
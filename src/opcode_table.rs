@@ -0,0 +1,396 @@
+use crate::bytecode::mnemonic_from_name;
+use anyhow::{Context, Result};
+use num_traits::{FromPrimitive, ToPrimitive};
+use pydis::opcode::py27::{Mnemonic, Standard};
+use pydis::opcode::Opcode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+/// Magic number of .pyc files written by Python 2.7
+pub const PYTHON27_MAGIC: u32 = 0x0a0d_f303;
+
+/// Opcode tables shipped with the tool, by name
+const BUILTIN_TABLES: &[(&str, &str)] =
+    &[("python27", include_str!("../data/opcodes/python27.json"))];
+
+/// The table [`Remapped`] opcodes are decoded with. A run uses a single
+/// table, so it is installed once before any module is processed.
+static INSTALLED: OnceLock<OpcodeTable> = OnceLock::new();
+
+#[derive(Error, Debug)]
+pub enum OpcodeTableError {
+    #[error("unknown opcode `{0}` in opcode table")]
+    UnknownOpcode(String),
+    #[error("byte {byte} is assigned to both {first:?} and {second:?}")]
+    DuplicateByte {
+        byte: u8,
+        first: Mnemonic,
+        second: Mnemonic,
+    },
+    #[error("{0:?} is assigned more than one byte")]
+    DuplicateOpcode(Mnemonic),
+    #[error("a different opcode table is already installed")]
+    AlreadyInstalled,
+    #[error("unsupported .pyc magic {0:#010x}. Use --opcode-table to choose an opcode table")]
+    UnsupportedMagic(u32),
+}
+
+/// The on-disk form of an opcode table: the byte each opcode is encoded as,
+/// keyed by mnemonic
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OpcodeTableFile {
+    name: String,
+    #[serde(default)]
+    description: String,
+    opcodes: BTreeMap<String, u8>,
+}
+
+/// Maps each byte of a build's bytecode to the instruction it encodes. Builds
+/// of Python with a permuted opcode table use the same instructions as
+/// CPython, but number them differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeTable {
+    pub name: String,
+    pub description: String,
+    by_byte: [Option<Mnemonic>; 256],
+    /// Bytes indexed by the opcode's number in pydis' standard table, which
+    /// is unique for every mnemonic
+    by_standard: [Option<u8>; 256],
+}
+
+impl OpcodeTable {
+    /// Builds a table from `(byte, mnemonic)` pairs. Every byte and every
+    /// mnemonic may only appear once.
+    pub fn new(
+        name: &str,
+        entries: impl IntoIterator<Item = (u8, Mnemonic)>,
+    ) -> Result<OpcodeTable, OpcodeTableError> {
+        let mut table = OpcodeTable {
+            name: name.to_string(),
+            description: String::new(),
+            by_byte: [None; 256],
+            by_standard: [None; 256],
+        };
+
+        for (byte, mnemonic) in entries {
+            if let Some(first) = table.by_byte[byte as usize] {
+                return Err(OpcodeTableError::DuplicateByte {
+                    byte,
+                    first,
+                    second: mnemonic,
+                });
+            }
+
+            let standard = standard_index(mnemonic);
+            if table.by_standard[standard].is_some() {
+                return Err(OpcodeTableError::DuplicateOpcode(mnemonic));
+            }
+
+            table.by_byte[byte as usize] = Some(mnemonic);
+            table.by_standard[standard] = Some(byte);
+        }
+
+        Ok(table)
+    }
+
+    /// Returns the built-in table called `name`, if there is one
+    pub fn builtin(name: &str) -> Option<OpcodeTable> {
+        BUILTIN_TABLES
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, data)| {
+                OpcodeTable::parse(data.as_bytes()).expect("built-in opcode table is invalid")
+            })
+    }
+
+    /// Loads a JSON opcode table from disk
+    pub fn load(path: &Path) -> Result<OpcodeTable> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("failed to read opcode table {:?}", path))?;
+
+        OpcodeTable::parse(data.as_slice())
+            .with_context(|| format!("failed to parse opcode table {:?}", path))
+    }
+
+    fn parse(data: &[u8]) -> Result<OpcodeTable> {
+        let file: OpcodeTableFile = serde_json::from_slice(data)?;
+        let entries = file
+            .opcodes
+            .iter()
+            .map(|(name, byte)| {
+                mnemonic_from_name(name)
+                    .map(|mnemonic| (*byte, mnemonic))
+                    .ok_or_else(|| OpcodeTableError::UnknownOpcode(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(OpcodeTable {
+            description: file.description,
+            ..OpcodeTable::new(&file.name, entries)?
+        })
+    }
+
+    /// Writes the table to disk as JSON, in the format [`OpcodeTable::load`]
+    /// reads
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = OpcodeTableFile {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            opcodes: (0..=u8::MAX)
+                .filter_map(|byte| Some((format!("{:?}", self.mnemonic(byte)?), byte)))
+                .collect(),
+        };
+        let data = serde_json::to_string_pretty(&file)?;
+
+        std::fs::write(path, data)
+            .with_context(|| format!("failed to write opcode table {:?}", path))
+    }
+
+    /// The instruction `byte` encodes, if any
+    pub fn mnemonic(&self, byte: u8) -> Option<Mnemonic> {
+        self.by_byte[byte as usize]
+    }
+
+    /// The byte `mnemonic` is encoded as, if the table has it
+    pub fn byte(&self, mnemonic: Mnemonic) -> Option<u8> {
+        self.by_standard[standard_index(mnemonic)]
+    }
+}
+
+fn standard_index(mnemonic: Mnemonic) -> usize {
+    Standard::from(mnemonic).to_u8().unwrap() as usize
+}
+
+/// Makes `table` the table [`Remapped`] opcodes are decoded with. Installing
+/// the table which is already installed does nothing.
+pub fn install(table: OpcodeTable) -> Result<(), OpcodeTableError> {
+    let installed = INSTALLED.get_or_init(|| table.clone());
+    if *installed != table {
+        return Err(OpcodeTableError::AlreadyInstalled);
+    }
+
+    Ok(())
+}
+
+fn installed() -> &'static OpcodeTable {
+    INSTALLED
+        .get()
+        .expect("Remapped opcodes used before an opcode table was installed")
+}
+
+/// An opcode of the [installed](install) opcode table. It behaves exactly like
+/// the standard opcode with the same mnemonic, but is encoded as a different
+/// byte.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Remapped(Mnemonic);
+
+impl Remapped {
+    fn standard(&self) -> Standard {
+        Standard::from(self.0)
+    }
+}
+
+impl fmt::Debug for Remapped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl From<Mnemonic> for Remapped {
+    fn from(mnemonic: Mnemonic) -> Self {
+        Remapped(mnemonic)
+    }
+}
+
+impl FromPrimitive for Remapped {
+    fn from_i64(n: i64) -> Option<Self> {
+        Self::from_u64(u64::try_from(n).ok()?)
+    }
+
+    fn from_u64(n: u64) -> Option<Self> {
+        installed().mnemonic(u8::try_from(n).ok()?).map(Remapped)
+    }
+}
+
+impl ToPrimitive for Remapped {
+    fn to_i64(&self) -> Option<i64> {
+        self.to_u64().map(|byte| byte as i64)
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        installed().byte(self.0).map(u64::from)
+    }
+}
+
+impl Opcode for Remapped {
+    type Mnemonic = Mnemonic;
+
+    fn has_arg(&self) -> bool {
+        self.standard().has_arg()
+    }
+
+    fn has_extended_arg(&self) -> bool {
+        self.standard().has_extended_arg()
+    }
+
+    fn has_const(&self) -> bool {
+        self.standard().has_const()
+    }
+
+    fn has_comp(&self) -> bool {
+        self.standard().has_comp()
+    }
+
+    fn is_other_conditional_jump(&self) -> bool {
+        self.standard().is_other_conditional_jump()
+    }
+
+    fn is_relative_jump(&self) -> bool {
+        self.standard().is_relative_jump()
+    }
+
+    fn is_absolute_jump(&self) -> bool {
+        self.standard().is_absolute_jump()
+    }
+
+    fn is_conditional_jump(&self) -> bool {
+        self.standard().is_conditional_jump()
+    }
+
+    fn has_name(&self) -> bool {
+        self.standard().has_name()
+    }
+
+    fn has_local(&self) -> bool {
+        self.standard().has_local()
+    }
+
+    fn has_free(&self) -> bool {
+        self.standard().has_free()
+    }
+
+    fn mnemonic(&self) -> Mnemonic {
+        self.0
+    }
+}
+
+/// Which opcode table modules are decoded with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpcodeTableChoice {
+    /// Chosen from each module's .pyc magic
+    Auto,
+    /// pydis' standard table
+    Standard,
+    /// A built-in table, or the path to a table file
+    Table(String),
+}
+
+impl std::str::FromStr for OpcodeTableChoice {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => OpcodeTableChoice::Auto,
+            "standard" => OpcodeTableChoice::Standard,
+            other => OpcodeTableChoice::Table(other.to_string()),
+        })
+    }
+}
+
+/// The opcode type a module is decoded with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcodes {
+    Standard,
+    /// The installed opcode table
+    Remapped,
+}
+
+impl OpcodeTableChoice {
+    /// Loads and installs the chosen table, if a table was chosen
+    pub fn install(&self) -> Result<()> {
+        if let OpcodeTableChoice::Table(name) = self {
+            let table = match OpcodeTable::builtin(name) {
+                Some(table) => table,
+                None => OpcodeTable::load(Path::new(name))?,
+            };
+            install(table)?;
+        }
+
+        Ok(())
+    }
+
+    /// The opcodes a module with the given .pyc magic is decoded with
+    pub fn opcodes(&self, magic: u32) -> Result<Opcodes, OpcodeTableError> {
+        match self {
+            OpcodeTableChoice::Auto if magic == PYTHON27_MAGIC => Ok(Opcodes::Standard),
+            OpcodeTableChoice::Auto => Err(OpcodeTableError::UnsupportedMagic(magic)),
+            OpcodeTableChoice::Standard => Ok(Opcodes::Standard),
+            OpcodeTableChoice::Table(_) => Ok(Opcodes::Remapped),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::decode_at;
+
+    #[test]
+    fn python27_differs_from_standard_only_where_pydis_does() {
+        let table = OpcodeTable::builtin("python27").unwrap();
+        assert_eq!(table.mnemonic(5), Some(Mnemonic::ROT_FOUR));
+        assert_eq!(table.mnemonic(137), Some(Mnemonic::STORE_DEREF));
+        assert_eq!(table.byte(Mnemonic::ROT_FOUR), Some(5));
+
+        for byte in 0..=255u8 {
+            if matches!(byte, 5 | 6 | 137 | 138) {
+                continue;
+            }
+
+            assert_eq!(
+                table.mnemonic(byte),
+                Standard::from_u8(byte).map(|opcode| opcode.mnemonic()),
+                "byte {}",
+                byte
+            );
+        }
+    }
+
+    #[test]
+    fn tables_reject_duplicates() {
+        assert!(matches!(
+            OpcodeTable::new("dup", [(1, Mnemonic::POP_TOP), (1, Mnemonic::ROT_TWO)]),
+            Err(OpcodeTableError::DuplicateByte { byte: 1, .. })
+        ));
+        assert!(matches!(
+            OpcodeTable::new("dup", [(1, Mnemonic::POP_TOP), (2, Mnemonic::POP_TOP)]),
+            Err(OpcodeTableError::DuplicateOpcode(Mnemonic::POP_TOP))
+        ));
+        assert!(
+            OpcodeTable::parse(br#"{"name": "bad", "opcodes": {"NOT_AN_OPCODE": 1}}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn remapped_opcodes_decode_with_the_installed_table() {
+        install(OpcodeTable::builtin("python27").unwrap()).unwrap();
+        assert!(matches!(
+            install(OpcodeTable::new("other", []).unwrap()),
+            Err(OpcodeTableError::AlreadyInstalled)
+        ));
+
+        // ROT_FOUR; STORE_DEREF 1
+        let bytecode = [5, 137, 1, 0];
+        let rot_four = decode_at::<Remapped>(&bytecode, 0).unwrap();
+        assert_eq!(rot_four.opcode.mnemonic(), Mnemonic::ROT_FOUR);
+        let store_deref = decode_at::<Remapped>(&bytecode, 1).unwrap();
+        assert_eq!(store_deref.opcode.mnemonic(), Mnemonic::STORE_DEREF);
+        assert_eq!(store_deref.arg, Some(1));
+        assert_eq!(Remapped::from(Mnemonic::ROT_FOUR).to_u8(), Some(5));
+    }
+}
@@ -0,0 +1,450 @@
+use crate::bytecode::mnemonic_from_name;
+use pydis::opcode::py27::Mnemonic;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A pattern over a sequence of instructions. Patterns are written as
+/// whitespace-separated elements, each of which matches one instruction:
+///
+/// - `LOAD_CONST` matches an instruction with that mnemonic
+/// - `LOAD_FAST|LOAD_NAME` matches any of the listed mnemonics
+/// - `!LOAD_CONST` matches any instruction except the listed mnemonics
+/// - `_` matches any instruction
+///
+/// An element may be followed by `?` (optional), `*` (zero or more), or `+`
+/// (one or more), and may be prefixed with `name=` to capture the argument of
+/// the instruction it matched. If a repeated element captures, the argument
+/// of the last instruction it matched is kept.
+///
+/// For example, `func=LOAD_CONST !LOAD_CONST* MAKE_FUNCTION` matches a function
+/// being made and captures the const index of its code object.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    elements: Vec<Element>,
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    capture: Option<String>,
+    /// Mnemonics this element matches. Empty if it matches any instruction.
+    mnemonics: Vec<Mnemonic>,
+    /// Whether this element matches any instruction *except* `mnemonics`
+    negated: bool,
+    repeat: Repeat,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Repeat {
+    One,
+    Optional,
+    Any,
+}
+
+impl Element {
+    fn matches(&self, mnemonic: Mnemonic) -> bool {
+        if self.negated {
+            !self.mnemonics.contains(&mnemonic)
+        } else {
+            self.mnemonics.is_empty() || self.mnemonics.contains(&mnemonic)
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PatternError {
+    #[error("pattern is empty")]
+    Empty,
+    #[error("unknown opcode `{0}` in pattern")]
+    UnknownOpcode(String),
+    #[error("invalid pattern element `{0}`")]
+    InvalidElement(String),
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut elements = Vec::new();
+        for token in s.split_whitespace() {
+            let (capture, rest) = match token.split_once('=') {
+                Some((name, rest)) => {
+                    if name.is_empty()
+                        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        return Err(PatternError::InvalidElement(token.to_string()));
+                    }
+                    (Some(name.to_string()), rest)
+                }
+                None => (None, token),
+            };
+
+            let (atom, repeat) = match rest.chars().last() {
+                Some('?') => (&rest[..rest.len() - 1], Some(Repeat::Optional)),
+                Some('*') => (&rest[..rest.len() - 1], Some(Repeat::Any)),
+                // `+` is expanded to one required element followed by `*`
+                Some('+') => (&rest[..rest.len() - 1], None),
+                _ => (rest, Some(Repeat::One)),
+            };
+
+            let (atom, negated) = match atom.strip_prefix('!') {
+                Some(atom) => (atom, true),
+                None => (atom, false),
+            };
+
+            if atom.is_empty() || (negated && atom == "_") {
+                return Err(PatternError::InvalidElement(token.to_string()));
+            }
+
+            let mnemonics = if atom == "_" {
+                vec![]
+            } else {
+                atom.split('|')
+                    .map(|name| {
+                        mnemonic_from_name(name)
+                            .ok_or_else(|| PatternError::UnknownOpcode(name.to_string()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+            };
+
+            let element = Element {
+                capture,
+                mnemonics,
+                negated,
+                repeat: repeat.unwrap_or(Repeat::One),
+            };
+            if repeat.is_none() {
+                elements.push(element.clone());
+                elements.push(Element {
+                    repeat: Repeat::Any,
+                    ..element
+                });
+            } else {
+                elements.push(element);
+            }
+        }
+
+        if elements.is_empty() {
+            return Err(PatternError::Empty);
+        }
+
+        Ok(Pattern {
+            source: s.split_whitespace().collect::<Vec<_>>().join(" "),
+            elements,
+        })
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        source.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Instruction arguments captured by a pattern, keyed by capture name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captures(HashMap<String, u32>);
+
+impl Captures {
+    /// Returns the argument captured under `name`. Returns `None` if the
+    /// element was optional and did not match, or the instruction had no
+    /// argument.
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.0.get(name).copied()
+    }
+}
+
+/// Result of feeding an instruction to a [`Matcher`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchState {
+    /// The instructions so far are a prefix of a match
+    Incomplete,
+    /// The pattern matched, ending with the last instruction fed
+    Matched(Captures),
+    /// The instructions cannot match the pattern
+    Failed,
+}
+
+/// A position in the pattern along with the captures made to get there
+#[derive(Debug, Clone)]
+struct Thread {
+    element: usize,
+    captures: Captures,
+}
+
+/// Matches a pattern against a stream of instructions, one instruction at a
+/// time. This allows patterns to be matched against instructions in the order
+/// they are executed rather than the order they appear in the bytecode.
+///
+/// Matches are reported as soon as possible, so trailing repeated elements
+/// match zero instructions.
+#[derive(Debug, Clone)]
+pub struct Matcher<'p> {
+    pattern: &'p Pattern,
+    threads: Vec<Thread>,
+    /// Whether the match must start at the first instruction fed
+    anchored: bool,
+}
+
+impl<'p> Matcher<'p> {
+    /// Creates a matcher which requires the first instruction fed to be the
+    /// start of the match
+    pub fn anchored(pattern: &'p Pattern) -> Matcher<'p> {
+        Matcher::new(pattern, true)
+    }
+
+    /// Creates a matcher which finds the first match anywhere in the stream.
+    /// An unanchored matcher never fails.
+    pub fn unanchored(pattern: &'p Pattern) -> Matcher<'p> {
+        Matcher::new(pattern, false)
+    }
+
+    fn new(pattern: &'p Pattern, anchored: bool) -> Matcher<'p> {
+        Matcher {
+            pattern,
+            threads: vec![Thread {
+                element: 0,
+                captures: Captures::default(),
+            }],
+            anchored,
+        }
+    }
+
+    /// The pattern being matched
+    pub fn pattern(&self) -> &'p Pattern {
+        self.pattern
+    }
+
+    /// Discards any progress made so far
+    pub fn reset(&mut self) {
+        *self = Matcher::new(self.pattern, self.anchored);
+    }
+
+    /// Advances the matcher by one instruction
+    pub fn feed(&mut self, mnemonic: Mnemonic, arg: Option<u32>) -> MatchState {
+        let elements = &self.pattern.elements;
+        let mut threads = std::mem::take(&mut self.threads);
+        if !self.anchored {
+            threads.push(Thread {
+                element: 0,
+                captures: Captures::default(),
+            });
+        }
+
+        let mut next: Vec<Thread> = Vec::new();
+        for thread in self.closure(threads) {
+            let element = match elements.get(thread.element) {
+                Some(element) if element.matches(mnemonic) => element,
+                _ => continue,
+            };
+
+            let mut captures = thread.captures;
+            if let (Some(name), Some(arg)) = (&element.capture, arg) {
+                captures.0.insert(name.clone(), arg);
+            }
+
+            let target = if element.repeat == Repeat::Any {
+                thread.element
+            } else {
+                thread.element + 1
+            };
+
+            if !next.iter().any(|t| t.element == target) {
+                next.push(Thread {
+                    element: target,
+                    captures,
+                });
+            }
+        }
+
+        let next = self.closure(next);
+        if let Some(done) = next.iter().find(|t| t.element == elements.len()) {
+            let captures = done.captures.clone();
+            self.reset();
+            return MatchState::Matched(captures);
+        }
+
+        self.threads = next;
+        if self.threads.is_empty() && self.anchored {
+            MatchState::Failed
+        } else {
+            MatchState::Incomplete
+        }
+    }
+
+    /// Adds the threads reachable by skipping optional and repeated elements
+    fn closure(&self, threads: Vec<Thread>) -> Vec<Thread> {
+        let elements = &self.pattern.elements;
+        let mut out: Vec<Thread> = Vec::with_capacity(threads.len());
+        for mut thread in threads {
+            loop {
+                if !out.iter().any(|t| t.element == thread.element) {
+                    out.push(thread.clone());
+                }
+
+                match elements.get(thread.element) {
+                    Some(element) if element.repeat != Repeat::One => thread.element += 1,
+                    _ => break,
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Mnemonic::*;
+
+    /// Feeds `instrs` to a matcher and returns the state after each one
+    fn feed(pattern: &str, anchored: bool, instrs: &[(Mnemonic, Option<u32>)]) -> Vec<MatchState> {
+        let pattern: Pattern = pattern.parse().unwrap();
+        let mut matcher = if anchored {
+            Matcher::anchored(&pattern)
+        } else {
+            Matcher::unanchored(&pattern)
+        };
+        instrs
+            .iter()
+            .map(|(mnemonic, arg)| matcher.feed(*mnemonic, *arg))
+            .collect()
+    }
+
+    /// Whether the instructions match the anchored pattern exactly
+    fn matches(pattern: &str, mnemonics: &[Mnemonic]) -> bool {
+        let instrs: Vec<_> = mnemonics.iter().map(|m| (*m, None)).collect();
+        matches!(
+            feed(pattern, true, &instrs).last(),
+            Some(MatchState::Matched(_))
+        )
+    }
+
+    fn captures(state: &MatchState) -> &Captures {
+        match state {
+            MatchState::Matched(captures) => captures,
+            other => panic!("expected a match, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let parse = |s: &str| s.parse::<Pattern>().map(|_| ());
+        assert_eq!(parse("  "), Err(PatternError::Empty));
+        assert_eq!(
+            parse("LOAD_CONST LOAD_THING"),
+            Err(PatternError::UnknownOpcode("LOAD_THING".to_string()))
+        );
+        for element in ["=LOAD_CONST", "a-b=LOAD_CONST", "!_", "?", "LOAD_CONST|"] {
+            assert!(parse(element).is_err(), "{}", element);
+        }
+    }
+
+    #[test]
+    fn patterns_display_normalized() {
+        let pattern: Pattern = " LOAD_CONST\n  x=_?  ".parse().unwrap();
+        assert_eq!(pattern.to_string(), "LOAD_CONST x=_?");
+    }
+
+    #[test]
+    fn alternation_and_negation() {
+        let alternation = "LOAD_FAST|LOAD_NAME RETURN_VALUE";
+        assert!(matches(alternation, &[LOAD_FAST, RETURN_VALUE]));
+        assert!(matches(alternation, &[LOAD_NAME, RETURN_VALUE]));
+        assert!(!matches(alternation, &[LOAD_CONST, RETURN_VALUE]));
+
+        let negation = "!LOAD_CONST|LOAD_FAST RETURN_VALUE";
+        assert!(matches(negation, &[LOAD_NAME, RETURN_VALUE]));
+        assert!(!matches(negation, &[LOAD_CONST, RETURN_VALUE]));
+        assert!(!matches(negation, &[LOAD_FAST, RETURN_VALUE]));
+
+        assert!(matches("_ RETURN_VALUE", &[POP_TOP, RETURN_VALUE]));
+    }
+
+    #[test]
+    fn quantifiers() {
+        assert!(matches("LOAD_CONST? RETURN_VALUE", &[RETURN_VALUE]));
+        assert!(matches(
+            "LOAD_CONST? RETURN_VALUE",
+            &[LOAD_CONST, RETURN_VALUE]
+        ));
+        assert!(!matches(
+            "LOAD_CONST? RETURN_VALUE",
+            &[LOAD_CONST, LOAD_CONST, RETURN_VALUE]
+        ));
+
+        assert!(matches("NOP* RETURN_VALUE", &[RETURN_VALUE]));
+        assert!(matches("NOP* RETURN_VALUE", &[NOP, NOP, NOP, RETURN_VALUE]));
+
+        assert!(!matches("NOP+ RETURN_VALUE", &[RETURN_VALUE]));
+        assert!(matches("NOP+ RETURN_VALUE", &[NOP, RETURN_VALUE]));
+        assert!(matches("NOP+ RETURN_VALUE", &[NOP, NOP, RETURN_VALUE]));
+    }
+
+    #[test]
+    fn anchored_matchers_fail_and_unanchored_ones_keep_looking() {
+        let instrs = [(POP_TOP, None), (LOAD_CONST, Some(1)), (RETURN_VALUE, None)];
+        let anchored = feed("LOAD_CONST RETURN_VALUE", true, &instrs);
+        assert_eq!(anchored[0], MatchState::Failed);
+
+        let unanchored = feed("LOAD_CONST RETURN_VALUE", false, &instrs);
+        assert_eq!(unanchored[0], MatchState::Incomplete);
+        assert_eq!(unanchored[1], MatchState::Incomplete);
+        assert!(matches!(unanchored[2], MatchState::Matched(_)));
+    }
+
+    #[test]
+    fn captures_keep_the_last_argument_matched() {
+        let states = feed(
+            "function=LOAD_CONST !LOAD_CONST* MAKE_FUNCTION",
+            true,
+            &[
+                (LOAD_CONST, Some(4)),
+                (LOAD_FAST, Some(0)),
+                (MAKE_FUNCTION, Some(0)),
+            ],
+        );
+        assert_eq!(captures(&states[2]).get("function"), Some(4));
+
+        let states = feed(
+            "value=LOAD_CONST+ missing=POP_TOP? RETURN_VALUE",
+            true,
+            &[
+                (LOAD_CONST, Some(1)),
+                (LOAD_CONST, Some(2)),
+                (RETURN_VALUE, None),
+            ],
+        );
+        let captures = captures(&states[2]);
+        assert_eq!(captures.get("value"), Some(2));
+        assert_eq!(captures.get("missing"), None);
+    }
+
+    #[test]
+    fn matchers_reset_after_a_match() {
+        let states = feed(
+            "x=LOAD_CONST",
+            true,
+            &[(LOAD_CONST, Some(1)), (LOAD_CONST, Some(2))],
+        );
+        assert_eq!(captures(&states[0]).get("x"), Some(1));
+        assert_eq!(captures(&states[1]).get("x"), Some(2));
+    }
+}
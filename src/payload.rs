@@ -0,0 +1,465 @@
+use crate::bytecode::load_code;
+use crate::key_material::{PayloadEncoding, PayloadSource, Stage3Payload};
+use crate::profiles::Stage3Profile;
+use flate2::read::ZlibDecoder;
+use log::debug;
+use py27_marshal::{Code, Obj};
+use std::fmt;
+use std::io::Read;
+use thiserror::Error;
+
+/// Runs of base64 characters and string consts shorter than this are not
+/// considered payloads
+const MIN_PAYLOAD_LEN: usize = 16;
+/// Most candidates listed in a [`PayloadError`] message
+const MAX_LISTED_CANDIDATES: usize = 10;
+
+/// Why a candidate payload was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadRejection {
+    NotBase64,
+    NotZlib,
+    NotCode,
+}
+
+impl fmt::Display for PayloadRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PayloadRejection::NotBase64 => "not base64",
+            PayloadRejection::NotZlib => "not zlib data",
+            PayloadRejection::NotCode => "not a marshalled code object",
+        })
+    }
+}
+
+/// A run of base64 characters that was tried as the payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadCandidate {
+    pub source: PayloadSource,
+    pub offset: usize,
+    pub len: usize,
+    pub reversed: bool,
+    pub encoding: PayloadEncoding,
+    /// Why the candidate was rejected, or `None` if it was valid
+    pub rejection: Option<PayloadRejection>,
+}
+
+impl fmt::Display for PayloadCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {} ({} {} bytes{})",
+            self.source,
+            self.offset,
+            self.len,
+            self.encoding,
+            if self.reversed { ", reversed" } else { "" },
+        )?;
+        if let Some(rejection) = self.rejection {
+            write!(f, ": {}", rejection)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PayloadError {
+    #[error("no base64 runs or string consts of at least {MIN_PAYLOAD_LEN} bytes")]
+    NoCandidates,
+    #[error("no payload found. Tried {}", format_candidates(.0))]
+    NotFound(Vec<PayloadCandidate>),
+}
+
+fn format_candidates(candidates: &[PayloadCandidate]) -> String {
+    let mut listed = candidates
+        .iter()
+        .take(MAX_LISTED_CANDIDATES)
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ");
+    if candidates.len() > MAX_LISTED_CANDIDATES {
+        listed += &format!(" and {} more", candidates.len() - MAX_LISTED_CANDIDATES);
+    }
+
+    listed
+}
+
+fn is_base64(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=')
+}
+
+/// Returns the `(offset, len)` of every maximal run of base64 characters in
+/// `data` that is long enough to be a payload
+fn base64_runs(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for (i, b) in data.iter().copied().chain(std::iter::once(0)).enumerate() {
+        match (is_base64(b), start) {
+            (true, None) => start = Some(i),
+            (false, Some(run_start)) => {
+                if i - run_start >= MIN_PAYLOAD_LEN {
+                    runs.push((run_start, i - run_start));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    runs
+}
+
+/// Inflates zlib-compressed data
+fn inflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut inflated)?;
+
+    Ok(inflated)
+}
+
+/// Decodes a candidate payload, returning the inner code if it's valid
+fn try_payload(
+    data: &[u8],
+    encoding: PayloadEncoding,
+    is_code: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, PayloadRejection> {
+    let compressed = match encoding {
+        PayloadEncoding::Base64 => std::str::from_utf8(data)
+            .ok()
+            .and_then(|text| base64::decode(text.trim()).ok())
+            .ok_or(PayloadRejection::NotBase64)?,
+        PayloadEncoding::Raw => data.to_vec(),
+    };
+    let inner = inflate(&compressed).map_err(|_| PayloadRejection::NotZlib)?;
+    if !is_code(inner.as_slice()) {
+        return Err(PayloadRejection::NotCode);
+    }
+
+    Ok(inner)
+}
+
+/// A location to try decoding a payload from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Candidate {
+    /// Index into the sources
+    source: usize,
+    offset: usize,
+    len: usize,
+    reversed: bool,
+    encoding: PayloadEncoding,
+}
+
+/// Locates and decodes a payload of compressed, marshalled code stored either
+/// in `code`'s bytes or one of its string consts.
+///
+/// The layout described by `profile`, if any, is tried first. After that every
+/// run of base64 characters in the code and string consts is tried, longest
+/// first, both forwards and back to front. Finally each string const is tried
+/// as raw zlib data. The first candidate which decodes to a code object is
+/// used.
+pub fn locate(
+    code: &Code,
+    profile: Option<&Stage3Profile>,
+) -> Result<(Vec<u8>, Stage3Payload), PayloadError> {
+    let consts: Vec<Option<&[u8]>> = code
+        .consts
+        .iter()
+        .map(|obj| match obj {
+            Obj::String(s) => Some(s.as_slice()),
+            _ => None,
+        })
+        .collect();
+
+    locate_parts(code.code.as_slice(), &consts, profile, |data| {
+        load_code(data).is_ok()
+    })
+}
+
+/// Like [`locate`], for code read from any marshal format. `consts` holds
+/// each const's bytes if it can hold a payload, and `is_code` checks whether
+/// decoded data is a marshalled code object.
+pub fn locate_parts(
+    code: &[u8],
+    consts: &[Option<&[u8]>],
+    profile: Option<&Stage3Profile>,
+    is_code: impl Fn(&[u8]) -> bool,
+) -> Result<(Vec<u8>, Stage3Payload), PayloadError> {
+    let mut sources = vec![(PayloadSource::Code, code)];
+    sources.extend(
+        consts
+            .iter()
+            .enumerate()
+            .filter_map(|(index, data)| Some((PayloadSource::Const(index), (*data)?))),
+    );
+
+    let mut candidates = Vec::new();
+    if let Some((profile, delimiter)) = profile.and_then(|profile| {
+        code.iter()
+            .position(|b| *b == profile.payload_delimiter)
+            .map(|delimiter| (profile, delimiter))
+    }) {
+        let offset = delimiter + 1;
+        if offset < code.len() {
+            candidates.push(Candidate {
+                source: 0,
+                offset,
+                len: code.len() - offset,
+                reversed: profile.reverse_payload,
+                encoding: PayloadEncoding::Base64,
+            });
+        }
+    }
+
+    let mut runs = Vec::new();
+    for (source, (_, data)) in sources.iter().enumerate() {
+        runs.extend(
+            base64_runs(data)
+                .into_iter()
+                .map(|(offset, len)| (source, offset, len)),
+        );
+    }
+    runs.sort_by_key(|(_, _, len)| std::cmp::Reverse(*len));
+    let base64_candidates = runs.into_iter().flat_map(|(source, offset, len)| {
+        [false, true].map(|reversed| Candidate {
+            source,
+            offset,
+            len,
+            reversed,
+            encoding: PayloadEncoding::Base64,
+        })
+    });
+    let raw_candidates = sources
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, (_, data))| data.len() >= MIN_PAYLOAD_LEN)
+        .map(|(source, (_, data))| Candidate {
+            source,
+            offset: 0,
+            len: data.len(),
+            reversed: false,
+            encoding: PayloadEncoding::Raw,
+        });
+    for candidate in base64_candidates.chain(raw_candidates) {
+        if !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+
+    if candidates.is_empty() {
+        return Err(PayloadError::NoCandidates);
+    }
+
+    let mut tried = Vec::new();
+    for candidate in candidates {
+        let (source, data) = &sources[candidate.source];
+        let mut payload = data[candidate.offset..candidate.offset + candidate.len].to_vec();
+        if candidate.reversed {
+            payload.reverse();
+        }
+
+        let tried_candidate = |rejection| PayloadCandidate {
+            source: *source,
+            offset: candidate.offset,
+            len: candidate.len,
+            reversed: candidate.reversed,
+            encoding: candidate.encoding,
+            rejection,
+        };
+        match try_payload(&payload, candidate.encoding, &is_code) {
+            Ok(inner) => {
+                debug!("Found a payload in {}", tried_candidate(None));
+                let location = Stage3Payload {
+                    source: *source,
+                    offset: candidate.offset,
+                    reversed: candidate.reversed,
+                    encoding: candidate.encoding,
+                    base64: match candidate.encoding {
+                        PayloadEncoding::Base64 => String::from_utf8_lossy(&payload).into_owned(),
+                        PayloadEncoding::Raw => base64::encode(&payload),
+                    },
+                };
+
+                return Ok((inner, location));
+            }
+            Err(rejection) => tried.push(tried_candidate(Some(rejection))),
+        }
+    }
+
+    Err(PayloadError::NotFound(tried))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const STAGE4: &[u8] = b"marshalled stage4";
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn encoded(data: &[u8]) -> Vec<u8> {
+        base64::encode(zlib(data)).into_bytes()
+    }
+
+    fn profile() -> Stage3Profile {
+        Stage3Profile {
+            payload_delimiter: b'\n',
+            reverse_payload: true,
+        }
+    }
+
+    fn locate(
+        code: &[u8],
+        consts: &[Option<&[u8]>],
+        profile: Option<&Stage3Profile>,
+    ) -> Result<(Vec<u8>, Stage3Payload), PayloadError> {
+        locate_parts(code, consts, profile, |data| data == STAGE4)
+    }
+
+    #[test]
+    fn the_profile_layout_is_tried_first() {
+        let mut payload = encoded(STAGE4);
+        payload.reverse();
+        let code = [&b"\x01\x02\n"[..], &payload].concat();
+
+        let (inner, location) = locate(&code, &[], Some(&profile())).unwrap();
+        assert_eq!(inner, STAGE4);
+        assert_eq!(location.source, PayloadSource::Code);
+        assert_eq!(location.offset, 3);
+        assert!(location.reversed);
+        assert_eq!(location.encoding, PayloadEncoding::Base64);
+        assert_eq!(location.base64.as_bytes(), encoded(STAGE4));
+    }
+
+    #[test]
+    fn base64_runs_are_searched_when_the_layout_does_not_hold() {
+        let payload = encoded(STAGE4);
+        let string = [&b"x = '"[..], &payload, b"'"].concat();
+        let decoy = encoded(b"something else");
+        let consts = [None, Some(decoy.as_slice()), Some(string.as_slice())];
+
+        let (inner, location) = locate(b"no delimiter", &consts, Some(&profile())).unwrap();
+        assert_eq!(inner, STAGE4);
+        assert_eq!(location.source, PayloadSource::Const(2));
+        assert_eq!(location.offset, 5);
+        assert!(!location.reversed);
+    }
+
+    #[test]
+    fn string_consts_are_tried_as_raw_zlib_data() {
+        let payload = zlib(STAGE4);
+        let consts = [Some(payload.as_slice())];
+
+        let (inner, location) = locate(b"", &consts, None).unwrap();
+        assert_eq!(inner, STAGE4);
+        assert_eq!(location.source, PayloadSource::Const(0));
+        assert_eq!(location.encoding, PayloadEncoding::Raw);
+        assert_eq!(location.base64, base64::encode(&payload));
+    }
+
+    #[test]
+    fn short_data_has_no_candidates() {
+        let consts = [Some(&b"short"[..])];
+        assert!(matches!(
+            locate(b"also short", &consts, None),
+            Err(PayloadError::NoCandidates)
+        ));
+    }
+
+    #[test]
+    fn every_rejected_candidate_is_listed() {
+        let wrong_code = encoded(b"something else");
+        let not_zlib = b"QUFBQUFBQUFBQUFBQUFBQUFB".to_vec();
+        let consts = [Some(wrong_code.as_slice()), Some(not_zlib.as_slice())];
+
+        let err = locate(b"", &consts, None).unwrap_err();
+        let tried = match &err {
+            PayloadError::NotFound(tried) => tried,
+            other => panic!("unexpected error {:?}", other),
+        };
+
+        // Runs are tried longest first, forwards then reversed, then each const
+        // as raw data
+        let summary: Vec<_> = tried
+            .iter()
+            .map(|c| (c.source, c.reversed, c.encoding, c.rejection.unwrap()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    PayloadSource::Const(0),
+                    false,
+                    PayloadEncoding::Base64,
+                    PayloadRejection::NotCode
+                ),
+                (
+                    PayloadSource::Const(0),
+                    true,
+                    PayloadEncoding::Base64,
+                    PayloadRejection::NotBase64
+                ),
+                (
+                    PayloadSource::Const(1),
+                    false,
+                    PayloadEncoding::Base64,
+                    PayloadRejection::NotZlib
+                ),
+                (
+                    PayloadSource::Const(1),
+                    true,
+                    PayloadEncoding::Base64,
+                    PayloadRejection::NotZlib
+                ),
+                (
+                    PayloadSource::Const(0),
+                    false,
+                    PayloadEncoding::Raw,
+                    PayloadRejection::NotZlib
+                ),
+                (
+                    PayloadSource::Const(1),
+                    false,
+                    PayloadEncoding::Raw,
+                    PayloadRejection::NotZlib
+                ),
+            ]
+        );
+        assert!(
+            err.to_string().starts_with(&format!(
+                "no payload found. Tried const 0 at 0 ({} base64 bytes): not a marshalled code object; ",
+                wrong_code.len()
+            )),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn long_candidate_lists_are_truncated() {
+        let candidates = vec![
+            PayloadCandidate {
+                source: PayloadSource::Code,
+                offset: 0,
+                len: 16,
+                reversed: true,
+                encoding: PayloadEncoding::Base64,
+                rejection: Some(PayloadRejection::NotZlib),
+            };
+            MAX_LISTED_CANDIDATES + 3
+        ];
+
+        let listed = format_candidates(&candidates);
+        assert_eq!(listed.matches("; ").count(), MAX_LISTED_CANDIDATES - 1);
+        assert!(listed.starts_with("code at 0 (16 base64 bytes, reversed): not zlib data; "));
+        assert!(listed.ends_with(" and 3 more"), "{}", listed);
+    }
+}
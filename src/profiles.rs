@@ -0,0 +1,209 @@
+use crate::pattern::Pattern;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+
+/// Profiles shipped with the tool. These are used unless `--profiles-file` points
+/// to a different file.
+const BUILTIN_PROFILES: &str = include_str!("../data/profiles.json");
+
+/// Describes how a particular version of the game's obfuscator lays out its
+/// layers. Adapting to a new game patch should only require adding a new
+/// profile to the data file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    pub name: String,
+    /// Revision of this profile. Bumped whenever its parameters change.
+    pub version: u32,
+    #[serde(default)]
+    pub description: String,
+    pub stage1: Stage1Profile,
+    pub stage2: Stage2Profile,
+    pub stage3: Stage3Profile,
+}
+
+/// Parameters for the outermost layer, which XORs the stage2 payload with a
+/// key stored in the module's consts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Stage1Profile {
+    /// The module's internal file name if its payload is encrypted
+    pub encrypted_filename: String,
+    /// Index of the XOR key in the module's consts. This const is tried
+    /// first, then every other string const until one yields a valid payload.
+    #[serde(default)]
+    pub key_const_index: Option<usize>,
+}
+
+/// Patterns for locating the swapmap and decode loop in the stage2 loader.
+/// See [`Pattern`] for the pattern syntax.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Stage2Profile {
+    /// Matches a function being made. If the pattern captures `function`, the
+    /// capture is the const index of the function's code object. Otherwise
+    /// the argument of the last `LOAD_CONST` executed is used.
+    pub swapmap_function: Pattern,
+    /// Which match of `swapmap-function` (counting from 1) makes the swapmap
+    /// function
+    pub swapmap_function_occurrence: usize,
+    /// Matches the code which invokes the swapmap function. Must immediately
+    /// follow the swapmap function being made.
+    pub swapmap_call: Pattern,
+    /// Matches the code which sets up the decode loop. Must immediately follow
+    /// the swapmap call.
+    pub decode_loop: Pattern,
+}
+
+/// Where the stage4 payload is expected in the stage3 code. This layout is
+/// tried first -- if it doesn't hold a valid payload, the code and consts are
+/// searched for one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Stage3Profile {
+    /// The payload starts after the first occurrence of this byte in the code
+    pub payload_delimiter: u8,
+    /// Whether the payload is stored back to front
+    pub reverse_payload: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("unknown profile `{0}`. Available profiles are: {1}")]
+    UnknownProfile(String, String),
+    #[error("no profiles are defined")]
+    NoProfiles,
+}
+
+/// The set of profiles available to a run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSet {
+    pub profiles: Vec<Profile>,
+}
+
+impl ProfileSet {
+    /// Returns the profiles shipped with the tool
+    pub fn builtin() -> ProfileSet {
+        ProfileSet::parse(BUILTIN_PROFILES.as_bytes()).expect("built-in profiles are invalid")
+    }
+
+    /// Loads a JSON profile file from disk
+    pub fn load(path: &Path) -> Result<ProfileSet> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("failed to read profile file {:?}", path))?;
+
+        ProfileSet::parse(data.as_slice())
+            .with_context(|| format!("failed to parse profile file {:?}", path))
+    }
+
+    fn parse(data: &[u8]) -> Result<ProfileSet> {
+        let profiles: ProfileSet = serde_json::from_slice(data)?;
+        if profiles.profiles.is_empty() {
+            return Err(ProfileError::NoProfiles.into());
+        }
+
+        Ok(profiles)
+    }
+
+    /// Returns the profiles to try, in order. If `selected` is provided only
+    /// that profile is returned.
+    pub fn candidates(&self, selected: Option<&str>) -> Result<Vec<&Profile>, ProfileError> {
+        match selected {
+            Some(name) => self
+                .profiles
+                .iter()
+                .find(|profile| profile.name == name)
+                .map(|profile| vec![profile])
+                .ok_or_else(|| {
+                    ProfileError::UnknownProfile(
+                        name.to_string(),
+                        self.profiles
+                            .iter()
+                            .map(|profile| profile.name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    )
+                }),
+            None => Ok(self.profiles.iter().collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_profiles() -> ProfileSet {
+        let builtin = ProfileSet::builtin().profiles.remove(0);
+        let mut other = builtin.clone();
+        other.name = "other".to_string();
+        ProfileSet {
+            profiles: vec![builtin, other],
+        }
+    }
+
+    #[test]
+    fn builtin_profiles_parse() {
+        let profiles = ProfileSet::builtin();
+        let lesta = &profiles.profiles[0];
+        assert_eq!(lesta.name, "lesta");
+        assert_eq!(lesta.stage1.key_const_index, Some(3));
+        assert_eq!(lesta.stage2.swapmap_function_occurrence, 3);
+        assert!(lesta.stage3.reverse_payload);
+    }
+
+    #[test]
+    fn profile_files_round_trip() {
+        let profiles = two_profiles();
+        let data = serde_json::to_vec(&profiles).unwrap();
+        let parsed = ProfileSet::parse(&data).unwrap();
+        let names: Vec<_> = parsed.profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["lesta", "other"]);
+    }
+
+    #[test]
+    fn invalid_profile_files_are_rejected() {
+        let err = ProfileSet::parse(br#"{"profiles": []}"#).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProfileError>(),
+            Some(ProfileError::NoProfiles)
+        ));
+
+        let mut profile = serde_json::to_value(ProfileSet::builtin()).unwrap();
+        profile["profiles"][0]["stage3"]["payload-offset"] = 1.into();
+        assert!(ProfileSet::parse(profile.to_string().as_bytes()).is_err());
+
+        let err = ProfileSet::load(Path::new("/nonexistent/profiles.json")).unwrap_err();
+        assert!(
+            err.to_string().contains("failed to read profile file"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn candidates_are_every_profile_unless_one_is_selected() {
+        let profiles = two_profiles();
+        let names = |selected| {
+            profiles
+                .candidates(selected)
+                .unwrap()
+                .iter()
+                .map(|p| p.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(None), ["lesta", "other"]);
+        assert_eq!(names(Some("other")), ["other"]);
+
+        match profiles.candidates(Some("missing")) {
+            Err(ProfileError::UnknownProfile(name, available)) => {
+                assert_eq!(name, "missing");
+                assert_eq!(available, "lesta, other");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
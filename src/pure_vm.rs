@@ -0,0 +1,992 @@
+use crate::builtins::{iter_items, BuiltinError, Builtins};
+use crate::bytecode::{decode_at, DecodedInstr};
+use crate::cfg::FlowGraph;
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive, Zero};
+use py27_marshal::bstr::BString;
+use py27_marshal::{Code, CodeFlags, Obj};
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::{Instruction, Opcode};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use unfuck::smallvm::{execute_instruction, InstructionTracker, VmNames, VmStack, VmVars};
+
+/// Instructions a function may execute for a single call before it's assumed
+/// to never return
+const MAX_STEPS: usize = 1 << 20;
+
+/// Why a call to a pure function could not be evaluated
+#[derive(Error, Debug)]
+pub enum EvalError {
+    #[error("unsupported instruction {0:?}")]
+    Unsupported(Mnemonic),
+    #[error("invalid instruction at offset {0}")]
+    BadInstruction(u64),
+    #[error("stack underflow")]
+    StackUnderflow,
+    #[error("a value is not known to the VM")]
+    UnknownValue,
+    #[error("`break` outside of a loop")]
+    NoLoop,
+    #[error("{0}")]
+    Builtin(#[from] BuiltinError),
+    #[error("the VM failed: {0}")]
+    Vm(String),
+    #[error("gave up after {} instructions", MAX_STEPS)]
+    StepLimit,
+}
+
+/// Returns the reachable instructions of `code`, grouped into basic blocks, or
+/// `None` if any of them could not be decoded
+pub fn basic_blocks<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+) -> Option<Vec<Vec<DecodedInstr<O>>>> {
+    let flow_graph = FlowGraph::<O>::from_bytecode(code.code.as_slice());
+    flow_graph
+        .graph
+        .node_indices()
+        .map(|node| &flow_graph.graph[node])
+        .map(|block| (!block.has_bad_instr).then(|| block.instrs.clone()))
+        .collect()
+}
+
+pub fn name_arg<'a, O: Opcode<Mnemonic = Mnemonic>>(
+    code: &'a Code,
+    instr: &DecodedInstr<O>,
+) -> Option<&'a Arc<BString>> {
+    code.names.get(instr.arg? as usize)
+}
+
+/// Returns every name bound at module level by the module in `code_objects`
+/// (the module itself followed by its nested code objects), and how many
+/// times. Returns `None` if the module may bind any name with `import *`.
+pub fn module_bindings<O: Opcode<Mnemonic = Mnemonic>>(
+    code_objects: &[Arc<Code>],
+) -> Option<HashMap<Arc<BString>, usize>> {
+    let module = &code_objects[0];
+    let mut bindings: HashMap<Arc<BString>, usize> = HashMap::new();
+    for code in code_objects {
+        let is_module = Arc::ptr_eq(code, module);
+        for instr in basic_blocks::<O>(code).unwrap_or_default().iter().flatten() {
+            match instr.opcode.mnemonic() {
+                // Any name may be rebound
+                Mnemonic::IMPORT_STAR => return None,
+                Mnemonic::STORE_GLOBAL | Mnemonic::DELETE_GLOBAL => {}
+                Mnemonic::STORE_NAME | Mnemonic::DELETE_NAME if is_module => {}
+                _ => continue,
+            }
+
+            if let Some(name) = name_arg(code, instr) {
+                *bindings.entry(Arc::clone(name)).or_default() += 1;
+            }
+        }
+    }
+
+    Some(bindings)
+}
+
+/// Whether `code` only uses its arguments, consts, and builtins which the
+/// module does not shadow
+pub fn is_pure<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+    builtins: &Builtins,
+    bindings: &HashMap<Arc<BString>, usize>,
+) -> bool {
+    let impure_flags = CodeFlags::VARARGS | CodeFlags::VARKEYWORDS | CodeFlags::GENERATOR;
+    if code.argcount == 0
+        || code.flags.intersects(impure_flags)
+        || !code.freevars.is_empty()
+        || !code.cellvars.is_empty()
+        || code.consts.iter().any(|c| matches!(c, Obj::Code(_)))
+    {
+        return false;
+    }
+
+    let blocks = match basic_blocks::<O>(code) {
+        Some(blocks) => blocks,
+        None => return false,
+    };
+
+    blocks.iter().flatten().all(|instr| {
+        if stack_inputs(instr).is_none() {
+            return false;
+        }
+
+        match instr.opcode.mnemonic() {
+            Mnemonic::LOAD_GLOBAL => name_arg(code, instr).is_some_and(|name| {
+                builtins.contains(name.as_slice()) && !bindings.contains_key(name)
+            }),
+            Mnemonic::LOAD_ATTR => {
+                name_arg(code, instr).is_some_and(|name| name.as_slice() == b"join")
+            }
+            Mnemonic::LOAD_CONST => instr
+                .arg
+                .is_some_and(|arg| (arg as usize) < code.consts.len()),
+            _ => true,
+        }
+    })
+}
+
+/// Number of values an instruction takes off of the stack, or `None` if it
+/// can't be evaluated
+fn stack_inputs<O: Opcode<Mnemonic = Mnemonic>>(instr: &DecodedInstr<O>) -> Option<usize> {
+    let arg = instr.arg.unwrap_or(0) as usize;
+    if arg > u16::MAX as usize {
+        return None;
+    }
+
+    Some(match instr.opcode.mnemonic() {
+        Mnemonic::NOP
+        | Mnemonic::LOAD_CONST
+        | Mnemonic::LOAD_FAST
+        | Mnemonic::LOAD_GLOBAL
+        | Mnemonic::JUMP_FORWARD
+        | Mnemonic::JUMP_ABSOLUTE
+        | Mnemonic::SETUP_LOOP
+        | Mnemonic::POP_BLOCK
+        | Mnemonic::BREAK_LOOP => 0,
+        Mnemonic::POP_TOP
+        | Mnemonic::DUP_TOP
+        | Mnemonic::STORE_FAST
+        | Mnemonic::UNARY_NOT
+        | Mnemonic::UNARY_NEGATIVE
+        | Mnemonic::UNARY_INVERT
+        | Mnemonic::LOAD_ATTR
+        | Mnemonic::GET_ITER
+        | Mnemonic::FOR_ITER
+        | Mnemonic::UNPACK_SEQUENCE
+        | Mnemonic::POP_JUMP_IF_FALSE
+        | Mnemonic::POP_JUMP_IF_TRUE
+        | Mnemonic::JUMP_IF_FALSE_OR_POP
+        | Mnemonic::JUMP_IF_TRUE_OR_POP
+        | Mnemonic::RETURN_VALUE
+        | Mnemonic::SLICE_0 => 1,
+        Mnemonic::ROT_TWO
+        | Mnemonic::COMPARE_OP
+        | Mnemonic::BINARY_SUBSC
+        | Mnemonic::SLICE_1
+        | Mnemonic::SLICE_2 => 2,
+        Mnemonic::ROT_THREE | Mnemonic::SLICE_3 => 3,
+        Mnemonic::BUILD_TUPLE | Mnemonic::BUILD_LIST | Mnemonic::BUILD_SLICE => arg,
+        // The list being appended to is `arg` deep once the value is popped,
+        // so `arg` can't be 0
+        Mnemonic::LIST_APPEND if arg > 0 => arg + 1,
+        // Keyword arguments are not supported
+        Mnemonic::CALL_FUNCTION if arg <= 0xff => arg + 1,
+        mnemonic if binary_operator(mnemonic).is_some() => 2,
+        _ => return None,
+    })
+}
+
+/// Name of the builtin `operator` function implementing a binary operator
+fn binary_operator(mnemonic: Mnemonic) -> Option<&'static str> {
+    Some(match mnemonic {
+        Mnemonic::BINARY_ADD | Mnemonic::INPLACE_ADD => "add",
+        Mnemonic::BINARY_SUBTRACT | Mnemonic::INPLACE_SUBTRACT => "sub",
+        Mnemonic::BINARY_MULTIPLY | Mnemonic::INPLACE_MULTIPLY => "mul",
+        Mnemonic::BINARY_MODULO | Mnemonic::INPLACE_MODULO => "mod",
+        Mnemonic::BINARY_XOR | Mnemonic::INPLACE_XOR => "xor",
+        Mnemonic::BINARY_AND | Mnemonic::INPLACE_AND => "and_",
+        Mnemonic::BINARY_OR | Mnemonic::INPLACE_OR => "or_",
+        Mnemonic::BINARY_LSHIFT | Mnemonic::INPLACE_LSHIFT => "lshift",
+        Mnemonic::BINARY_RSHIFT | Mnemonic::INPLACE_RSHIFT => "rshift",
+        _ => return None,
+    })
+}
+
+/// A value the small VM cannot represent. These are pushed onto the VM's stack
+/// as unknown values whose tracker starts with the offset of the instruction
+/// which produced them.
+#[derive(Debug, Clone)]
+enum Opaque {
+    Builtin(String),
+    /// `sep.join`
+    Join(Vec<u8>),
+    Slice(Option<BigInt>, Option<BigInt>, Option<BigInt>),
+    /// Items left in an iterator, last item first
+    Iter(Vec<Obj>),
+}
+
+/// What to do after an instruction
+enum Flow {
+    Next,
+    Jump(u64),
+    Return(Obj),
+}
+
+/// Runs a pure function in the small VM. Control flow, calls, iteration,
+/// and anything involving opaque values is handled here, and the VM handles
+/// moving values between the stack, consts, and locals.
+pub struct Interpreter<'a, O> {
+    code: Arc<Code>,
+    builtins: &'a Builtins,
+    stack: VmStack<u64>,
+    vars: VmVars<u64>,
+    opaque: HashMap<u64, Opaque>,
+    /// End offset and stack depth of each loop being executed, innermost last
+    loops: Vec<(u64, usize)>,
+    opcodes: PhantomData<O>,
+}
+
+impl<'a, O: 'static + Opcode<Mnemonic = Mnemonic>> Interpreter<'a, O> {
+    pub fn new(code: Arc<Code>, builtins: &'a Builtins) -> Interpreter<'a, O> {
+        Interpreter {
+            code,
+            builtins,
+            stack: Vec::new(),
+            vars: HashMap::new(),
+            opaque: HashMap::new(),
+            loops: Vec::new(),
+            opcodes: PhantomData,
+        }
+    }
+
+    /// Calls the function with `args` and returns its result
+    pub fn call(mut self, args: Vec<Obj>) -> Result<Obj, EvalError> {
+        for (index, arg) in args.into_iter().enumerate() {
+            self.vars
+                .insert(index as u16, (Some(arg), InstructionTracker::new()));
+        }
+
+        let mut offset = 0;
+        for _ in 0..MAX_STEPS {
+            let instr = decode_at::<O>(self.code.code.as_slice(), offset)
+                .map_err(|_| EvalError::BadInstruction(offset))?;
+            let inputs = stack_inputs(&instr)
+                .ok_or_else(|| EvalError::Unsupported(instr.opcode.mnemonic()))?;
+            if self.stack.len() < inputs {
+                return Err(EvalError::StackUnderflow);
+            }
+
+            match self.step(&instr)? {
+                Flow::Next => offset = instr.next_offset(),
+                Flow::Jump(target) => offset = target,
+                Flow::Return(value) => return Ok(value),
+            }
+        }
+
+        Err(EvalError::StepLimit)
+    }
+
+    fn step(&mut self, instr: &DecodedInstr<O>) -> Result<Flow, EvalError> {
+        let offset = instr.offset;
+        let arg = instr.arg.unwrap_or(0) as usize;
+        let target = || instr.jump_target().expect("jumps have a target");
+
+        match instr.opcode.mnemonic() {
+            Mnemonic::NOP => {}
+            Mnemonic::RETURN_VALUE => return Ok(Flow::Return(self.pop_value()?)),
+            Mnemonic::JUMP_FORWARD | Mnemonic::JUMP_ABSOLUTE => return Ok(Flow::Jump(target())),
+            mnemonic @ (Mnemonic::POP_JUMP_IF_FALSE | Mnemonic::POP_JUMP_IF_TRUE) => {
+                let condition = truthy(&self.pop_value()?)?;
+                if condition == (mnemonic == Mnemonic::POP_JUMP_IF_TRUE) {
+                    return Ok(Flow::Jump(target()));
+                }
+            }
+            mnemonic @ (Mnemonic::JUMP_IF_FALSE_OR_POP | Mnemonic::JUMP_IF_TRUE_OR_POP) => {
+                let condition = match self.stack.last() {
+                    Some((Some(value), _)) => truthy(value)?,
+                    _ => return Err(EvalError::UnknownValue),
+                };
+                if condition == (mnemonic == Mnemonic::JUMP_IF_TRUE_OR_POP) {
+                    return Ok(Flow::Jump(target()));
+                }
+
+                self.stack.pop();
+            }
+            // The small VM pushes rotated values back in their original order
+            mnemonic @ (Mnemonic::ROT_TWO | Mnemonic::ROT_THREE) => {
+                let depth = if mnemonic == Mnemonic::ROT_TWO { 2 } else { 3 };
+                let top = self.stack.pop().expect("stack inputs were checked");
+                self.stack.insert(self.stack.len() + 1 - depth, top);
+            }
+            Mnemonic::SETUP_LOOP => self.loops.push((target(), self.stack.len())),
+            Mnemonic::POP_BLOCK => {
+                self.loops.pop();
+            }
+            Mnemonic::BREAK_LOOP => {
+                let (end, depth) = self.loops.pop().ok_or(EvalError::NoLoop)?;
+                self.stack.truncate(depth);
+                return Ok(Flow::Jump(end));
+            }
+            Mnemonic::GET_ITER => {
+                let mut items = iter_items(&self.pop_value()?)?;
+                items.reverse();
+                self.push_opaque(offset, Opaque::Iter(items));
+            }
+            Mnemonic::FOR_ITER => {
+                let iterator = self
+                    .stack
+                    .last()
+                    .and_then(|(_, tracker)| opaque_key(tracker));
+                let next = match iterator.and_then(|key| self.opaque.get_mut(&key)) {
+                    Some(Opaque::Iter(items)) => items.pop(),
+                    _ => return Err(type_error("FOR_ITER on a value which is not an iterator")),
+                };
+
+                match next {
+                    Some(item) => self.push(item, offset),
+                    None => {
+                        self.stack.pop();
+                        return Ok(Flow::Jump(target()));
+                    }
+                }
+            }
+            Mnemonic::BUILD_TUPLE => {
+                let items = self.pop_values(arg)?;
+                self.push(Obj::Tuple(Arc::new(items)), offset);
+            }
+            Mnemonic::BUILD_LIST => {
+                let items = self.pop_values(arg)?;
+                self.push(Obj::List(Arc::new(RwLock::new(items))), offset);
+            }
+            Mnemonic::LIST_APPEND => {
+                let value = self.pop_value()?;
+                let index = self.stack.len() - arg;
+                match &self.stack[index].0 {
+                    Some(Obj::List(items)) => items.write().unwrap().push(value),
+                    _ => return Err(type_error("LIST_APPEND to a value which is not a list")),
+                }
+            }
+            Mnemonic::UNPACK_SEQUENCE => {
+                let items = iter_items(&self.pop_value()?)?;
+                if items.len() != arg {
+                    return Err(BuiltinError::ValueError(format!(
+                        "expected {} values to unpack, got {}",
+                        arg,
+                        items.len()
+                    ))
+                    .into());
+                }
+
+                for item in items.into_iter().rev() {
+                    self.push(item, offset);
+                }
+            }
+            Mnemonic::LOAD_GLOBAL => {
+                let name = self.code.names[arg].to_string();
+                self.push_opaque(offset, Opaque::Builtin(name));
+            }
+            Mnemonic::LOAD_ATTR => {
+                let name = self.code.names[arg].to_string();
+                match (name.as_str(), self.pop_value()?) {
+                    ("join", Obj::String(separator)) => {
+                        self.push_opaque(offset, Opaque::Join(separator.to_vec()))
+                    }
+                    (name, value) => {
+                        return Err(type_error(&format!(
+                            "'{}' attribute `{}` is not supported",
+                            type_name(&value),
+                            name
+                        )))
+                    }
+                }
+            }
+            Mnemonic::CALL_FUNCTION => {
+                let args = self.pop_values(arg)?;
+                let result = match self.pop_opaque()? {
+                    Opaque::Builtin(name) => self.builtins.call(&name, &args)?,
+                    Opaque::Join(separator) => join(&separator, &args)?,
+                    _ => return Err(type_error("object is not callable")),
+                };
+                self.push(result, offset);
+            }
+            Mnemonic::BUILD_SLICE => {
+                let mut bounds = self
+                    .pop_values(arg)?
+                    .iter()
+                    .map(slice_bound)
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter();
+                let start = bounds.next().flatten();
+                let stop = bounds.next().flatten();
+                let step = bounds.next().flatten();
+                self.push_opaque(offset, Opaque::Slice(start, stop, step));
+            }
+            Mnemonic::BINARY_SUBSC => {
+                let result = match self.stack.last() {
+                    Some((None, _)) => match self.pop_opaque()? {
+                        Opaque::Slice(start, stop, step) => {
+                            slice(&self.pop_value()?, start, stop, step)?
+                        }
+                        _ => return Err(type_error("unsupported subscript")),
+                    },
+                    _ => {
+                        let index = self.pop_value()?;
+                        subscript(&self.pop_value()?, &index)?
+                    }
+                };
+                self.push(result, offset);
+            }
+            mnemonic @ (Mnemonic::SLICE_0
+            | Mnemonic::SLICE_1
+            | Mnemonic::SLICE_2
+            | Mnemonic::SLICE_3) => {
+                let stop = if matches!(mnemonic, Mnemonic::SLICE_2 | Mnemonic::SLICE_3) {
+                    slice_bound(&self.pop_value()?)?
+                } else {
+                    None
+                };
+                let start = if matches!(mnemonic, Mnemonic::SLICE_1 | Mnemonic::SLICE_3) {
+                    slice_bound(&self.pop_value()?)?
+                } else {
+                    None
+                };
+                let result = slice(&self.pop_value()?, start, stop, None)?;
+                self.push(result, offset);
+            }
+            Mnemonic::COMPARE_OP => {
+                let right = self.pop_value()?;
+                let left = self.pop_value()?;
+                self.push(Obj::Bool(compare(arg, &left, &right)?), offset);
+            }
+            Mnemonic::UNARY_NOT => {
+                let value = truthy(&self.pop_value()?)?;
+                self.push(Obj::Bool(!value), offset);
+            }
+            mnemonic @ (Mnemonic::UNARY_NEGATIVE | Mnemonic::UNARY_INVERT) => {
+                let name = if mnemonic == Mnemonic::UNARY_NEGATIVE {
+                    "neg"
+                } else {
+                    "invert"
+                };
+                let value = self.pop_value()?;
+                let result = self.builtins.call(name, &[value])?;
+                self.push(result, offset);
+            }
+            mnemonic => match binary_operator(mnemonic) {
+                Some(name) => {
+                    let right = self.pop_value()?;
+                    let left = self.pop_value()?;
+                    let result = self.builtins.call(name, &[left, right])?;
+                    self.push(result, offset);
+                }
+                // Loads, stores, and stack manipulation
+                None => self.execute(instr)?,
+            },
+        }
+
+        Ok(Flow::Next)
+    }
+
+    /// Runs an instruction in the small VM
+    fn execute(&mut self, instr: &DecodedInstr<O>) -> Result<(), EvalError> {
+        let vm_instr = Instruction {
+            opcode: instr.opcode,
+            arg: instr.arg.map(|arg| arg as u16),
+        };
+
+        let mut names: VmNames<u64> = HashMap::new();
+        let mut globals: VmNames<u64> = HashMap::new();
+        execute_instruction(
+            &vm_instr,
+            Arc::clone(&self.code),
+            &mut self.stack,
+            &mut self.vars,
+            &mut names,
+            &mut globals,
+            Default::default(),
+            |_function, _args, _kwargs| None,
+            instr.offset,
+        )
+        .map_err(|e| EvalError::Vm(format!("{:?}", e)))
+    }
+
+    fn push(&mut self, value: Obj, offset: u64) {
+        let tracker = InstructionTracker::new();
+        tracker.push(offset);
+        self.stack.push((Some(value), tracker));
+    }
+
+    fn push_opaque(&mut self, offset: u64, value: Opaque) {
+        let tracker = InstructionTracker::new();
+        tracker.push(offset);
+        self.stack.push((None, tracker));
+        self.opaque.insert(offset, value);
+    }
+
+    fn pop_value(&mut self) -> Result<Obj, EvalError> {
+        match self.stack.pop() {
+            Some((Some(value), _)) => Ok(value),
+            Some((None, _)) => Err(EvalError::UnknownValue),
+            None => Err(EvalError::StackUnderflow),
+        }
+    }
+
+    /// Pops `count` values, returning them in the order they were pushed
+    fn pop_values(&mut self, count: usize) -> Result<Vec<Obj>, EvalError> {
+        let mut values = (0..count)
+            .map(|_| self.pop_value())
+            .collect::<Result<Vec<_>, _>>()?;
+        values.reverse();
+
+        Ok(values)
+    }
+
+    fn pop_opaque(&mut self) -> Result<Opaque, EvalError> {
+        match self.stack.pop() {
+            Some((None, tracker)) => opaque_key(&tracker)
+                .and_then(|key| self.opaque.get(&key).cloned())
+                .ok_or(EvalError::UnknownValue),
+            Some((Some(value), _)) => Err(type_error(&format!(
+                "'{}' object is not callable",
+                type_name(&value)
+            ))),
+            None => Err(EvalError::StackUnderflow),
+        }
+    }
+}
+
+/// The offset of the instruction which produced a stack value
+fn opaque_key(tracker: &InstructionTracker<u64>) -> Option<u64> {
+    tracker.0.lock().unwrap().first().copied()
+}
+
+fn type_error(message: &str) -> EvalError {
+    BuiltinError::TypeError(message.to_string()).into()
+}
+
+pub fn type_name(obj: &Obj) -> &'static str {
+    match obj {
+        Obj::None => "NoneType",
+        Obj::Bool(_) => "bool",
+        Obj::Long(_) => "int",
+        Obj::Float(_) => "float",
+        Obj::String(_) | Obj::Bytes(_) => "str",
+        Obj::Tuple(_) => "tuple",
+        Obj::List(_) => "list",
+        Obj::Dict(_) => "dict",
+        _ => "object",
+    }
+}
+
+/// Whether `obj` can be stored in a code object's consts
+pub fn is_const(obj: &Obj) -> bool {
+    match obj {
+        Obj::None | Obj::Bool(_) | Obj::Long(_) | Obj::Float(_) | Obj::String(_) => true,
+        Obj::Tuple(items) => items.iter().all(is_const),
+        _ => false,
+    }
+}
+
+fn truthy(obj: &Obj) -> Result<bool, EvalError> {
+    Ok(match obj {
+        Obj::None => false,
+        Obj::Bool(value) => *value,
+        Obj::Long(value) => !value.is_zero(),
+        Obj::Float(value) => *value != 0.0,
+        Obj::String(s) => !s.is_empty(),
+        Obj::Bytes(s) => !s.is_empty(),
+        Obj::Tuple(items) => !items.is_empty(),
+        Obj::List(items) => !items.read().unwrap().is_empty(),
+        Obj::Dict(items) => !items.read().unwrap().is_empty(),
+        other => {
+            return Err(type_error(&format!(
+                "truth value of '{}' is not supported",
+                type_name(other)
+            )))
+        }
+    })
+}
+
+/// `separator.join(items)`
+fn join(separator: &[u8], args: &[Obj]) -> Result<Obj, EvalError> {
+    let items = match args {
+        [items] => iter_items(items)?,
+        _ => return Err(type_error("join() takes exactly one argument")),
+    };
+
+    let mut out = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.extend_from_slice(separator);
+        }
+
+        match item {
+            Obj::String(s) => out.extend_from_slice(s.as_slice()),
+            other => {
+                return Err(type_error(&format!(
+                    "sequence item {}: expected string, {} found",
+                    i,
+                    type_name(other)
+                )))
+            }
+        }
+    }
+
+    Ok(Obj::String(Arc::new(BString::from(out))))
+}
+
+fn slice_bound(obj: &Obj) -> Result<Option<BigInt>, EvalError> {
+    match obj {
+        Obj::None => Ok(None),
+        Obj::Long(value) => Ok(Some(BigInt::clone(value))),
+        Obj::Bool(value) => Ok(Some(BigInt::from(*value as u8))),
+        other => Err(type_error(&format!(
+            "slice indices must be integers or None, not {}",
+            type_name(other)
+        ))),
+    }
+}
+
+/// Builds a sequence from a subset of another sequence's items
+type Rebuild = fn(Vec<Obj>) -> Obj;
+
+/// Returns the items of a string, tuple, or list, and a function rebuilding a
+/// value of the same type from a subset of them
+fn sequence(obj: &Obj) -> Result<(Vec<Obj>, Rebuild), EvalError> {
+    let rebuild: Rebuild = match obj {
+        Obj::String(_) | Obj::Bytes(_) => |items| {
+            let bytes = items
+                .iter()
+                .map(|item| match item {
+                    Obj::String(s) => s[0],
+                    _ => unreachable!("string items are strings"),
+                })
+                .collect::<Vec<u8>>();
+            Obj::String(Arc::new(BString::from(bytes)))
+        },
+        Obj::Tuple(_) => |items| Obj::Tuple(Arc::new(items)),
+        Obj::List(_) => |items| Obj::List(Arc::new(RwLock::new(items))),
+        other => {
+            return Err(type_error(&format!(
+                "'{}' object is not subscriptable",
+                type_name(other)
+            )))
+        }
+    };
+
+    Ok((iter_items(obj)?, rebuild))
+}
+
+/// `obj[index]`
+fn subscript(obj: &Obj, index: &Obj) -> Result<Obj, EvalError> {
+    if let Obj::Dict(items) = obj {
+        let key = index
+            .try_into()
+            .map_err(|_| type_error("unhashable dict key"))?;
+        return items
+            .read()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| BuiltinError::KeyError(format!("{:?}", key)).into());
+    }
+
+    let (items, _) = sequence(obj)?;
+    let index = match slice_bound(index)? {
+        Some(index) => index,
+        None => return Err(type_error("indices must be integers")),
+    };
+    let len = BigInt::from(items.len());
+    let resolved = if index.is_negative() {
+        index + &len
+    } else {
+        index
+    };
+
+    resolved
+        .to_usize()
+        .and_then(|index| items.get(index).cloned())
+        .ok_or_else(|| BuiltinError::ValueError("index out of range".to_string()).into())
+}
+
+/// `obj[start:stop:step]`, with Python's clamping of out of range bounds
+fn slice(
+    obj: &Obj,
+    start: Option<BigInt>,
+    stop: Option<BigInt>,
+    step: Option<BigInt>,
+) -> Result<Obj, EvalError> {
+    let (items, rebuild) = sequence(obj)?;
+    let len = items.len() as i64;
+    let step = match step {
+        None => 1,
+        // Steps this large keep their direction and skip every item after
+        // the first
+        Some(step) => step.to_i64().unwrap_or(if step.is_negative() {
+            i64::MIN + 1
+        } else {
+            i64::MAX
+        }),
+    };
+    if step == 0 {
+        return Err(BuiltinError::ValueError("slice step cannot be zero".to_string()).into());
+    }
+
+    // Bounds are clamped to [0, len] going forwards and [-1, len - 1] going
+    // backwards, where -1 is before the first item
+    let (lower, upper) = if step > 0 { (0, len) } else { (-1, len - 1) };
+    let resolve = |bound: Option<BigInt>, default: i64| match bound {
+        None => default,
+        Some(bound) => {
+            let bound = bound.to_i64().unwrap_or(if bound.is_negative() {
+                i64::MIN / 2
+            } else {
+                i64::MAX / 2
+            });
+            let bound = if bound < 0 { bound + len } else { bound };
+            bound.clamp(lower, upper)
+        }
+    };
+    let (start, stop) = if step > 0 {
+        (resolve(start, 0), resolve(stop, len))
+    } else {
+        (resolve(start, len - 1), resolve(stop, -1))
+    };
+
+    let mut selected = Vec::new();
+    let mut i = start;
+    while (step > 0 && i < stop) || (step < 0 && i > stop) {
+        selected.push(items[i as usize].clone());
+        i = match i.checked_add(step) {
+            Some(i) => i,
+            None => break,
+        };
+    }
+
+    Ok(rebuild(selected))
+}
+
+/// Evaluates `COMPARE_OP` for the comparisons a helper is likely to use
+pub fn compare(op: usize, left: &Obj, right: &Obj) -> Result<bool, EvalError> {
+    use std::cmp::Ordering;
+
+    let ordering = match (left, right) {
+        (Obj::Long(left), Obj::Long(right)) => Some(left.cmp(right)),
+        (Obj::String(left), Obj::String(right)) => Some(left.cmp(right)),
+        (Obj::Bool(left), Obj::Bool(right)) => Some(left.cmp(right)),
+        (Obj::None, Obj::None) => Some(Ordering::Equal),
+        _ => None,
+    };
+    let contains = || -> Result<bool, EvalError> {
+        match (left, right) {
+            (Obj::String(needle), Obj::String(haystack)) => Ok(needle.is_empty()
+                || haystack
+                    .windows(needle.len())
+                    .any(|window| window == needle.as_slice())),
+            (_, container) => {
+                let items = iter_items(container)?;
+                Ok(items
+                    .iter()
+                    .any(|item| compare(2, left, item).unwrap_or(false)))
+            }
+        }
+    };
+
+    // Ordering comparisons between unrelated types are arbitrary in Python 2,
+    // but equality is simply false
+    let ordered = |f: fn(Ordering) -> bool| {
+        ordering
+            .map(f)
+            .ok_or_else(|| type_error("comparison between unsupported types"))
+    };
+    match op {
+        0 => ordered(|o| o == Ordering::Less),
+        1 => ordered(|o| o != Ordering::Greater),
+        2 => Ok(ordering == Some(Ordering::Equal)),
+        3 => Ok(ordering != Some(Ordering::Equal)),
+        4 => ordered(|o| o == Ordering::Greater),
+        5 => ordered(|o| o != Ordering::Less),
+        6 => contains(),
+        7 => contains().map(|contains| !contains),
+        _ => Err(type_error("unsupported comparison")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::compile;
+    use crate::bytecode::{code_objects, load_code};
+    use py27_marshal::ObjHashable;
+    use pydis::opcode::py27::Standard;
+
+    /// Compiles `source` and returns its code objects
+    fn compile_code(source: &str) -> Vec<Arc<Code>> {
+        code_objects(&load_code(&compile(source)).unwrap())
+    }
+
+    fn string(s: &str) -> Obj {
+        Obj::String(Arc::new(s.into()))
+    }
+
+    fn int(value: i64) -> Obj {
+        Obj::Long(Arc::new(BigInt::from(value)))
+    }
+
+    fn call(function: &Arc<Code>, args: Vec<Obj>) -> Result<Obj, EvalError> {
+        let builtins = Builtins::python27();
+        Interpreter::<Standard>::new(Arc::clone(function), &builtins).call(args)
+    }
+
+    #[test]
+    fn comprehensions_and_joins_are_evaluated() {
+        let codes = compile_code("def f(s):\n    return ''.join([chr(ord(c) ^ 1) for c in s])\n");
+
+        let result = call(&codes[1], vec![string("idmmn")]).unwrap();
+
+        assert!(matches!(result, Obj::String(s) if s.as_slice() == b"hello"));
+    }
+
+    #[test]
+    fn loops_can_be_broken_out_of() {
+        let codes = compile_code(concat!(
+            "def f(n):\n",
+            "    total = 0\n",
+            "    while 1:\n",
+            "        total += n\n",
+            "        if total > 10:\n",
+            "            break\n",
+            "    return total\n",
+        ));
+
+        let result = call(&codes[1], vec![int(3)]).unwrap();
+
+        assert!(matches!(result, Obj::Long(value) if *value == BigInt::from(12)));
+    }
+
+    #[test]
+    fn rotations_keep_python_order() {
+        let codes = compile_code(concat!(
+            "def f(a, b, c):\n",
+            "    a, b = b, a\n",
+            "    x, y, z = c, b, a\n",
+            "    return (x, y, z)\n",
+        ));
+
+        let result = call(&codes[1], vec![string("a"), string("b"), string("c")]).unwrap();
+
+        let items = match result {
+            Obj::Tuple(items) => items,
+            other => panic!("expected a tuple, got {:?}", other),
+        };
+        let items: Vec<String> = items
+            .iter()
+            .map(|item| match item {
+                Obj::String(s) => s.to_string(),
+                other => panic!("expected a string, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(items, ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn appending_to_the_value_being_appended_is_unsupported() {
+        let codes = compile_code("def f(s):\n    return [c for c in s]\n");
+        let function = &codes[1];
+        let list_append = basic_blocks::<Standard>(function)
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .find(|instr| instr.opcode.mnemonic() == Mnemonic::LIST_APPEND)
+            .unwrap();
+        let mut code = function.code.to_vec();
+        code[list_append.offset as usize + 1..list_append.offset as usize + 3].fill(0);
+        let function = Arc::new(Code {
+            code: Arc::new(code),
+            ..(**function).clone()
+        });
+
+        assert!(!is_pure::<Standard>(
+            &function,
+            &Builtins::python27(),
+            &HashMap::new()
+        ));
+        assert!(matches!(
+            call(&function, vec![string("ab")]),
+            Err(EvalError::Unsupported(Mnemonic::LIST_APPEND))
+        ));
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        let codes = compile_code(concat!(
+            "def parse(s):\n    return int(s)\n",
+            "def spin(n):\n    while 1:\n        pass\n    return n\n",
+            "def upper(s):\n    return s.upper()\n",
+        ));
+
+        assert!(matches!(
+            call(&codes[1], vec![string("zz")]),
+            Err(EvalError::Builtin(BuiltinError::ValueError(_)))
+        ));
+        assert!(matches!(
+            call(&codes[2], vec![int(1)]),
+            Err(EvalError::StepLimit)
+        ));
+        assert!(matches!(
+            call(&codes[3], vec![string("a")]),
+            Err(EvalError::Builtin(BuiltinError::TypeError(_)))
+        ));
+    }
+
+    #[test]
+    fn purity_depends_on_what_a_function_loads() {
+        let codes = compile_code(concat!(
+            "def uses_builtin(s):\n    return len(s)\n",
+            "def uses_global(s):\n    return s + suffix\n",
+            "def uses_attribute(s):\n    return s.upper()\n",
+            "def shadowed(s):\n    return len(s)\n",
+            "def no_args():\n    return 1\n",
+            "suffix = '!'\n",
+            "len = str\n",
+        ));
+        let builtins = Builtins::python27();
+        let bindings = module_bindings::<Standard>(&codes).unwrap();
+        let empty = HashMap::new();
+
+        assert!(is_pure::<Standard>(&codes[1], &builtins, &empty));
+        assert!(!is_pure::<Standard>(&codes[2], &builtins, &bindings));
+        assert!(!is_pure::<Standard>(&codes[3], &builtins, &bindings));
+        assert!(!is_pure::<Standard>(&codes[4], &builtins, &bindings));
+        assert!(!is_pure::<Standard>(&codes[5], &builtins, &bindings));
+    }
+
+    #[test]
+    fn module_bindings_count_module_level_stores() {
+        let codes = compile_code(concat!(
+            "x = 1\n",
+            "x = 2\n",
+            "class C:\n    y = 1\n",
+            "def f():\n    global z\n    z = 1\n",
+        ));
+        let bindings = module_bindings::<Standard>(&codes).unwrap();
+        let count = |name: &str| bindings.get(&Arc::new(BString::from(name))).copied();
+
+        assert_eq!(count("x"), Some(2));
+        assert_eq!(count("y"), None);
+        assert_eq!(count("z"), Some(1));
+        assert_eq!(count("C"), Some(1));
+        assert_eq!(count("f"), Some(1));
+
+        assert!(module_bindings::<Standard>(&compile_code("from os import *\n")).is_none());
+    }
+
+    #[test]
+    fn huge_slice_steps_keep_their_direction() {
+        let codes = compile_code("def f(s):\n    return s[::1 << 70] + s[::-(1 << 70)]\n");
+
+        let result = call(&codes[1], vec![string("abc")]).unwrap();
+
+        assert!(matches!(result, Obj::String(s) if s.as_slice() == b"ac"));
+    }
+
+    #[test]
+    fn missing_dict_keys_raise_key_error() {
+        let codes = compile_code("def f(d, k):\n    return d[k]\n");
+        let dict = || {
+            let items = [(ObjHashable::Long(Arc::new(BigInt::from(1))), int(2))];
+            Obj::Dict(Arc::new(RwLock::new(items.into_iter().collect())))
+        };
+
+        let found = call(&codes[1], vec![dict(), int(1)]);
+        assert!(matches!(found, Ok(Obj::Long(v)) if *v == BigInt::from(2)));
+        assert!(matches!(
+            call(&codes[1], vec![dict(), int(3)]),
+            Err(EvalError::Builtin(BuiltinError::KeyError(_)))
+        ));
+    }
+}
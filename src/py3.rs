@@ -0,0 +1,374 @@
+use crate::layers::{CodeShape, Layer, LayerDecoder, LayerKey, MAX_LAYERS};
+use crate::payload::{self, PayloadError};
+use crate::profiles::Profile;
+use crate::py3_marshal::{load_code, Code, MarshalError, Obj};
+use crate::stage1::{self, Stage1Error};
+use log::debug;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The first magic number of each supported Python 3 version, by minor
+/// version. Python 3.6 is the first version whose bytecode is wordcode.
+const VERSIONS: &[(u16, u8)] = &[
+    (3360, 6),
+    (3390, 7),
+    (3400, 8),
+    (3420, 9),
+    (3430, 10),
+    (3450, 11),
+    (3500, 12),
+    (3550, 13),
+];
+
+/// Magic numbers from this one on belong to versions newer than any in
+/// [`VERSIONS`]
+const FIRST_UNKNOWN_MAGIC: u16 = 3600;
+
+#[derive(Error, Debug)]
+pub enum Py3Error {
+    #[error("the file is too short to be a .pyc file")]
+    TooShort,
+    #[error("unsupported Python 3 magic number {0}. Only Python 3.6 to 3.13 are supported")]
+    UnsupportedMagic(u16),
+    #[error(transparent)]
+    Marshal(#[from] MarshalError),
+    #[error(transparent)]
+    Stage1(#[from] Stage1Error),
+    #[error(transparent)]
+    Payload(#[from] PayloadError),
+    #[error("gave up after peeling {MAX_LAYERS} layers")]
+    TooManyLayers,
+    #[error("no profile could unpack the module ({0})")]
+    NoProfile(String),
+}
+
+/// Whether `magic` is the magic of a .pyc file written by any Python 3
+/// version. Python 3 magics are a number from 3000 on followed by `\r\n`.
+pub fn is_python3(magic: u32) -> bool {
+    magic >> 16 == 0x0a0d && (3000..4000).contains(&(magic & 0xffff))
+}
+
+/// A Python 3 .pyc file, split into its header and marshalled code
+#[derive(Debug, Clone, Copy)]
+pub struct Pyc<'a> {
+    pub minor: u8,
+    /// The magic and everything else before the code. Since Python 3.7 this
+    /// is 16 bytes long, and before that it is 12.
+    pub header: &'a [u8],
+    pub data: &'a [u8],
+}
+
+impl<'a> Pyc<'a> {
+    pub fn parse(file: &'a [u8]) -> Result<Pyc<'a>, Py3Error> {
+        let number = file
+            .get(..2)
+            .map(|magic| u16::from_le_bytes([magic[0], magic[1]]))
+            .ok_or(Py3Error::TooShort)?;
+        let minor = VERSIONS
+            .iter()
+            .rev()
+            .find(|(first, _)| number >= *first)
+            .filter(|_| number < FIRST_UNKNOWN_MAGIC)
+            .map(|(_, minor)| *minor)
+            .ok_or(Py3Error::UnsupportedMagic(number))?;
+
+        // PEP 552 added a flags field before the timestamp and size
+        let header_len = if minor >= 7 { 16 } else { 12 };
+        if file.len() < header_len {
+            return Err(Py3Error::TooShort);
+        }
+
+        Ok(Pyc {
+            minor,
+            header: &file[..header_len],
+            data: &file[header_len..],
+        })
+    }
+}
+
+/// The layers peeled off of a Python 3 module
+pub struct Unpacked<'p> {
+    /// The profile the module was unpacked with, if one was needed
+    pub profile: Option<&'p Profile>,
+    pub layers: Vec<Layer>,
+    /// A layer which was recognized but can't be peeled. Running the swapmap
+    /// loader requires the Python 2.7 VM.
+    pub unpeeled: Option<LayerDecoder>,
+}
+
+/// Peels every layer off of `pyc` by trying each of `profiles` in order, the
+/// same way Python 2.7 modules are unpacked. Only profiles whose encrypted
+/// file name matches the module's internal file name are tried.
+pub fn unpack<'p>(pyc: &Pyc, profiles: &[&'p Profile]) -> Result<Unpacked<'p>, Py3Error> {
+    let code = load_code(pyc.data, pyc.minor)?;
+    debug!("Internal file name: {}", code.filename);
+
+    let matching: Vec<&Profile> = profiles
+        .iter()
+        .copied()
+        .filter(|profile| profile.stage1.encrypted_filename == *code.filename)
+        .collect();
+    if matching.is_empty() {
+        let (layers, unpeeled) = peel(pyc.data, pyc.minor, None)?;
+        return Ok(Unpacked {
+            profile: None,
+            layers,
+            unpeeled,
+        });
+    }
+
+    let mut errors = Vec::new();
+    for profile in matching {
+        match peel(pyc.data, pyc.minor, Some(profile)) {
+            Ok((layers, unpeeled)) => {
+                return Ok(Unpacked {
+                    profile: Some(profile),
+                    layers,
+                    unpeeled,
+                })
+            }
+            Err(e) => {
+                debug!("Profile `{}` failed: {:#}", profile.name, e);
+                errors.push(format!("{}: {:#}", profile.name, e));
+            }
+        }
+    }
+
+    Err(Py3Error::NoProfile(errors.join("; ")))
+}
+
+/// Repeatedly peels layers off of the marshalled code in `data`, stopping when
+/// no decoder recognizes the innermost code or it is a swapmap loader. Returns
+/// the layers along with the decoder of the layer peeling stopped at, if any.
+fn peel(
+    data: &[u8],
+    minor: u8,
+    profile: Option<&Profile>,
+) -> Result<(Vec<Layer>, Option<LayerDecoder>), Py3Error> {
+    let is_code = |data: &[u8]| load_code(data, minor).is_ok();
+    let mut layers: Vec<Layer> = Vec::new();
+    let mut code = load_code(data, minor)?;
+
+    loop {
+        let names: Vec<&[u8]> = code.names.iter().map(|name| name.as_bytes()).collect();
+        let shape = CodeShape {
+            filename: code.filename.as_bytes(),
+            names,
+            has_code_consts: code.consts.iter().any(|c| matches!(c, Obj::Code(_))),
+        };
+        let decoder = match LayerDecoder::ALL
+            .iter()
+            .copied()
+            .find(|decoder| decoder.matches(&shape, !layers.is_empty(), profile))
+        {
+            Some(decoder) => decoder,
+            None => break,
+        };
+        if layers.len() == MAX_LAYERS {
+            return Err(Py3Error::TooManyLayers);
+        }
+
+        debug!("Peeling a {} layer", decoder.name());
+        let consts = key_consts(&code);
+        let (inner, key) = match decoder {
+            LayerDecoder::Xor => {
+                let profile = profile.expect("xor layers require a profile");
+                let (inner, key) =
+                    stage1::decrypt_parts(&code.code, &consts, &profile.stage1, is_code)?;
+                (inner, LayerKey::Xor(key))
+            }
+            LayerDecoder::SwapMapLoader => return Ok((layers, Some(decoder))),
+            LayerDecoder::CompressedPayload => {
+                match payload::locate_parts(
+                    &code.code,
+                    &consts,
+                    profile.map(|profile| &profile.stage3),
+                    is_code,
+                ) {
+                    Ok((inner, location)) => (inner, LayerKey::Payload(location)),
+                    // Other modules may use marshal and zlib for their own
+                    // purposes
+                    Err(e) => {
+                        debug!("Not a compressed payload layer: {}", e);
+                        break;
+                    }
+                }
+            }
+        };
+
+        code = load_code(&inner, minor)?;
+        layers.push(Layer {
+            decoder,
+            data: inner,
+            key,
+        });
+    }
+
+    Ok((layers, None))
+}
+
+/// The bytes of each const which may hold a key or payload. Both `str` and
+/// `bytes` consts qualify.
+fn key_consts(code: &Code) -> Vec<Option<&[u8]>> {
+    code.consts
+        .iter()
+        .map(|c| match c {
+            Obj::String(s) => Some(s.as_bytes()),
+            Obj::Bytes(bytes) => Some(bytes.as_slice()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Where a dumped string was found
+#[derive(Serialize, Debug)]
+pub enum StringType {
+    Const,
+    VarName,
+    Name,
+}
+
+/// A string found in a code object. The fields match the strings Python 2.7
+/// modules are dumped to, so that both can go to the same CSV file.
+#[derive(Serialize, Debug)]
+pub struct CodeObjString<'a> {
+    string_type: StringType,
+    pyc_file_name: &'a Path,
+    embedded_file_name: String,
+    object_name: String,
+    value: String,
+}
+
+/// Dumps every name, variable name, and string const of `code` and the code
+/// objects nested in it. `bytes` consts are dumped as UTF-8 where they are
+/// valid.
+pub fn dump_strings<'a>(pyc_filename: &'a Path, code: &Arc<Code>) -> Vec<CodeObjString<'a>> {
+    let mut strings = Vec::new();
+    for code in crate::py3_marshal::code_objects(code) {
+        let string = |string_type, value: String| CodeObjString {
+            string_type,
+            pyc_file_name: pyc_filename,
+            embedded_file_name: code.filename.to_string(),
+            object_name: code.name.to_string(),
+            value,
+        };
+
+        strings.extend(
+            code.names
+                .iter()
+                .map(|name| string(StringType::Name, name.to_string())),
+        );
+        strings.extend(
+            code.varnames
+                .iter()
+                .map(|name| string(StringType::VarName, name.to_string())),
+        );
+        strings.extend(code.consts.iter().filter_map(|c| match c {
+            Obj::String(s) => Some(string(StringType::Const, s.to_string())),
+            Obj::Bytes(bytes) => Some(string(
+                StringType::Const,
+                String::from_utf8_lossy(bytes).into_owned(),
+            )),
+            _ => None,
+        }));
+    }
+
+    strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_material::{PayloadSource, Stage3Payload};
+    use crate::profiles::ProfileSet;
+    use crate::py3_marshal::code_objects;
+
+    fn unpack_file(file: &[u8]) -> Result<Option<String>, Py3Error> {
+        let profiles = ProfileSet::builtin();
+        let profiles: Vec<&Profile> = profiles.profiles.iter().collect();
+        let unpacked = unpack(&Pyc::parse(file)?, &profiles)?;
+        assert!(unpacked.unpeeled.is_none());
+
+        Ok(unpacked.profile.map(|profile| profile.name.clone()))
+    }
+
+    #[test]
+    fn headers_depend_on_the_version() {
+        let pyc = Pyc::parse(include_bytes!("../test_data/py3/sample.cpython-36.pyc")).unwrap();
+        assert_eq!((pyc.minor, pyc.header.len()), (6, 12));
+        let pyc = Pyc::parse(include_bytes!("../test_data/py3/sample.cpython-313.pyc")).unwrap();
+        assert_eq!((pyc.minor, pyc.header.len()), (13, 16));
+
+        assert!(matches!(Pyc::parse(b"\x55"), Err(Py3Error::TooShort)));
+        assert!(matches!(
+            Pyc::parse(b"\x55\x0d\x0d\x0a"),
+            Err(Py3Error::TooShort)
+        ));
+        assert!(matches!(
+            Pyc::parse(b"\x10\x0e\x0d\x0a"),
+            Err(Py3Error::UnsupportedMagic(3600))
+        ));
+        assert!(matches!(
+            Pyc::parse(b"\x00\x0d\x0d\x0a"),
+            Err(Py3Error::UnsupportedMagic(3328))
+        ));
+    }
+
+    #[test]
+    fn plain_modules_have_no_layers() {
+        let file = include_bytes!("../test_data/py3/sample.cpython-38.pyc");
+        let pyc = Pyc::parse(file).unwrap();
+
+        let unpacked = unpack(&pyc, &[]).unwrap();
+
+        assert!(unpacked.profile.is_none());
+        assert!(unpacked.layers.is_empty());
+        assert!(unpacked.unpeeled.is_none());
+        assert_eq!(unpack_file(file).unwrap(), None);
+    }
+
+    #[test]
+    fn compressed_payloads_are_peeled() {
+        let file = include_bytes!("../test_data/py3/packed.cpython-38.pyc");
+        let pyc = Pyc::parse(file).unwrap();
+
+        let unpacked = unpack(&pyc, &[]).unwrap();
+
+        assert!(unpacked.profile.is_none());
+        assert_eq!(unpacked.layers.len(), 1);
+        let layer = &unpacked.layers[0];
+        assert_eq!(layer.decoder, LayerDecoder::CompressedPayload);
+        assert!(matches!(
+            layer.key,
+            LayerKey::Payload(Stage3Payload {
+                source: PayloadSource::Const(_),
+                reversed: false,
+                ..
+            })
+        ));
+
+        // The payload is the sample module
+        let inner = load_code(&layer.data, pyc.minor).unwrap();
+        let names: Vec<String> = code_objects(&inner)
+            .iter()
+            .map(|code| code.name.to_string())
+            .collect();
+        assert_eq!(names, ["<module>", "total"]);
+        assert_eq!(inner.filename.as_str(), "sample.py");
+    }
+
+    #[test]
+    fn failing_profiles_are_listed() {
+        // The module has the internal file name of an encrypted one, but no
+        // payload
+        let file = include_bytes!("../test_data/py3/misnamed.cpython-38.pyc");
+
+        match unpack_file(file) {
+            Err(Py3Error::NoProfile(errors)) => assert!(errors.starts_with("lesta: ")),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(profile) => panic!("unpacked with {:?}", profile),
+        }
+    }
+}
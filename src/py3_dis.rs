@@ -0,0 +1,472 @@
+use crate::py3_marshal::{code_objects, Code};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::{Arc, OnceLock};
+
+/// Opcode specifications of the Python 3 versions whose bytecode can be
+/// disassembled, by minor version. They were generated from each version's
+/// `opcode` module.
+const BUILTIN_SPECS: &[(u8, &str)] = &[
+    (6, include_str!("../data/opcodes/python36.json")),
+    (7, include_str!("../data/opcodes/python37.json")),
+    (8, include_str!("../data/opcodes/python38.json")),
+    (9, include_str!("../data/opcodes/python39.json")),
+    (10, include_str!("../data/opcodes/python310.json")),
+    (11, include_str!("../data/opcodes/python311.json")),
+    (12, include_str!("../data/opcodes/python312.json")),
+    (13, include_str!("../data/opcodes/python313.json")),
+];
+
+static SPECS: OnceLock<Vec<OpcodeSpec>> = OnceLock::new();
+
+/// The on-disk form of an opcode specification. Opcodes are grouped by what
+/// their argument refers to, as in the `opcode` module.
+#[derive(Deserialize)]
+struct OpcodeSpecFile {
+    description: String,
+    /// Jump arguments count bytes before Python 3.10, and 2-byte code units
+    /// since
+    jump_unit: u32,
+    opcodes: BTreeMap<String, u8>,
+    /// Opcodes whose argument is ignored, which `dis` doesn't show
+    no_argument: Vec<String>,
+    /// Inline cache entries following each opcode, since Python 3.11
+    caches: BTreeMap<String, usize>,
+    consts: Vec<String>,
+    names: Vec<String>,
+    locals: Vec<String>,
+    frees: Vec<String>,
+    /// Opcodes whose argument holds two local indices in its nibbles
+    packed_locals: Vec<String>,
+    compares: Vec<String>,
+    /// How far the name index is shifted left in the argument of opcodes
+    /// which keep flags in its low bits
+    name_shifts: BTreeMap<String, u32>,
+    compare_shift: u32,
+    compare_ops: Vec<String>,
+    absolute_jumps: Vec<String>,
+    relative_jumps: Vec<String>,
+    backward_jumps: Vec<String>,
+}
+
+/// What an opcode's argument refers to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ArgKind {
+    Const,
+    Name,
+    Local,
+    PackedLocals,
+    Free,
+    Compare,
+    AbsoluteJump,
+    RelativeJump,
+    BackwardJump,
+}
+
+/// How a Python 3 version encodes its instructions
+#[derive(Debug)]
+pub struct OpcodeSpec {
+    pub minor: u8,
+    pub description: String,
+    names: Vec<Option<String>>,
+    kinds: Vec<Option<ArgKind>>,
+    has_arg: Vec<bool>,
+    caches: Vec<usize>,
+    name_shifts: Vec<u32>,
+    jump_unit: u32,
+    compare_shift: u32,
+    compare_ops: Vec<String>,
+    extended_arg: Option<u8>,
+}
+
+impl OpcodeSpec {
+    /// The specification of Python `3.<minor>`, if it is supported
+    pub fn builtin(minor: u8) -> Option<&'static OpcodeSpec> {
+        SPECS
+            .get_or_init(|| {
+                BUILTIN_SPECS
+                    .iter()
+                    .map(|(minor, json)| OpcodeSpec::parse(*minor, json))
+                    .collect()
+            })
+            .iter()
+            .find(|spec| spec.minor == minor)
+    }
+
+    fn parse(minor: u8, json: &str) -> OpcodeSpec {
+        let file: OpcodeSpecFile =
+            serde_json::from_str(json).expect("built-in opcode specification is invalid");
+        let byte = |name: &str| -> usize {
+            *file
+                .opcodes
+                .get(name)
+                .unwrap_or_else(|| panic!("unknown opcode `{}` in opcode specification", name))
+                as usize
+        };
+
+        let mut names = vec![None; 256];
+        for (name, byte) in &file.opcodes {
+            names[*byte as usize] = Some(name.clone());
+        }
+
+        // Later groups take precedence, so that packed locals aren't plain
+        // locals
+        let mut kinds = vec![None; 256];
+        for (group, kind) in [
+            (&file.consts, ArgKind::Const),
+            (&file.names, ArgKind::Name),
+            (&file.locals, ArgKind::Local),
+            (&file.packed_locals, ArgKind::PackedLocals),
+            (&file.frees, ArgKind::Free),
+            (&file.compares, ArgKind::Compare),
+            (&file.absolute_jumps, ArgKind::AbsoluteJump),
+            (&file.relative_jumps, ArgKind::RelativeJump),
+            (&file.backward_jumps, ArgKind::BackwardJump),
+        ] {
+            for name in group {
+                kinds[byte(name)] = Some(kind);
+            }
+        }
+
+        let mut has_arg = vec![true; 256];
+        for name in &file.no_argument {
+            has_arg[byte(name)] = false;
+        }
+
+        let mut caches = vec![0; 256];
+        for (name, count) in &file.caches {
+            caches[byte(name)] = *count;
+        }
+
+        let mut name_shifts = vec![0; 256];
+        for (name, shift) in &file.name_shifts {
+            name_shifts[byte(name)] = *shift;
+        }
+
+        OpcodeSpec {
+            minor,
+            extended_arg: file.opcodes.get("EXTENDED_ARG").copied(),
+            description: file.description,
+            names,
+            kinds,
+            has_arg,
+            caches,
+            name_shifts,
+            jump_unit: file.jump_unit,
+            compare_shift: file.compare_shift,
+            compare_ops: file.compare_ops,
+        }
+    }
+
+    /// Name of the opcode encoded as `byte`
+    pub fn opname(&self, byte: u8) -> Option<&str> {
+        self.names[byte as usize].as_deref()
+    }
+
+    /// Decodes every instruction in `code`. `EXTENDED_ARG` prefixes are
+    /// folded into the argument of the instruction that follows them, and
+    /// inline cache entries are skipped. A trailing odd byte is ignored.
+    pub fn decode(&self, code: &[u8]) -> Vec<Instruction> {
+        let mut instrs = Vec::new();
+        let mut offset = 0;
+        let mut prefix: Option<(usize, u32)> = None;
+        while offset + 2 <= code.len() {
+            let opcode = code[offset];
+            let arg = code[offset + 1] as u32;
+            let (start, extended) = prefix.take().unwrap_or((offset, 0));
+            let arg = (extended << 8) | arg;
+            if Some(opcode) == self.extended_arg {
+                prefix = Some((start, arg));
+                offset += 2;
+                continue;
+            }
+
+            let end = (offset + 2 + 2 * self.caches[opcode as usize]).min(code.len());
+            instrs.push(Instruction {
+                offset: start,
+                len: end - start,
+                opcode,
+                arg: self.has_arg[opcode as usize].then_some(arg),
+            });
+            offset = end;
+        }
+
+        instrs
+    }
+
+    /// Returns the offset `instr` may jump to, if it is a jump
+    pub fn jump_target(&self, instr: &Instruction) -> Option<usize> {
+        let distance = instr.arg? as usize * self.jump_unit as usize;
+        match self.kinds[instr.opcode as usize]? {
+            ArgKind::AbsoluteJump => Some(distance),
+            ArgKind::RelativeJump => Some(instr.next_offset() + distance),
+            ArgKind::BackwardJump => instr.next_offset().checked_sub(distance),
+            _ => None,
+        }
+    }
+
+    /// What `instr`'s argument refers to in `code`, as `dis` would show it
+    fn describe_arg(&self, code: &Code, instr: &Instruction) -> Option<String> {
+        let arg = instr.arg? as usize;
+        // Python 3.11 indexes local and cell variables in a single array
+        let local = |index: usize| {
+            if self.minor >= 11 {
+                code.localsplusnames.get(index)
+            } else {
+                code.varnames.get(index)
+            }
+        };
+        match self.kinds[instr.opcode as usize]? {
+            ArgKind::Const => code.consts.get(arg).map(ToString::to_string),
+            ArgKind::Name => {
+                let shift = self.name_shifts[instr.opcode as usize];
+                code.names.get(arg >> shift).map(|name| name.to_string())
+            }
+            ArgKind::Local => local(arg).map(|name| name.to_string()),
+            ArgKind::PackedLocals => Some(format!("{}, {}", local(arg >> 4)?, local(arg & 15)?)),
+            ArgKind::Free if self.minor >= 11 => local(arg).map(|name| name.to_string()),
+            ArgKind::Free => code
+                .localsplusnames
+                .get(code.varnames.len() + arg)
+                .map(|name| name.to_string()),
+            ArgKind::Compare => self.compare_ops.get(arg >> self.compare_shift).cloned(),
+            ArgKind::AbsoluteJump | ArgKind::RelativeJump | ArgKind::BackwardJump => self
+                .jump_target(instr)
+                .map(|target| format!("to {}", target)),
+        }
+    }
+}
+
+/// A decoded instruction along with its location in the bytecode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    /// Offset of the first byte of this instruction, including any
+    /// `EXTENDED_ARG` prefix
+    pub offset: usize,
+    /// Total length of this instruction in bytes, including any
+    /// `EXTENDED_ARG` prefix and inline cache entries
+    pub len: usize,
+    pub opcode: u8,
+    pub arg: Option<u32>,
+}
+
+impl Instruction {
+    /// Offset of the instruction immediately following this one
+    pub fn next_offset(&self) -> usize {
+        self.offset + self.len
+    }
+}
+
+/// A handler in a Python 3.11+ exception table, which covers the
+/// instructions from `start` up to `end`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExceptionEntry {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    /// Stack depth the handler starts at
+    pub depth: u32,
+    /// Whether the offset of the raising instruction is pushed
+    pub lasti: bool,
+}
+
+/// Parses a Python 3.11+ exception table. Each entry is four varints of 6 bits
+/// per byte, and offsets count 2-byte code units. Parsing stops at the first
+/// truncated entry.
+pub fn exception_table(table: &[u8]) -> Vec<ExceptionEntry> {
+    let mut bytes = table.iter().copied();
+    let mut varint = || -> Option<u32> {
+        let mut byte = bytes.next()?;
+        let mut value = (byte & 63) as u32;
+        while byte & 64 != 0 {
+            byte = bytes.next()?;
+            value = (value << 6) | (byte & 63) as u32;
+        }
+        Some(value)
+    };
+
+    let mut entries = Vec::new();
+    while let (Some(start), Some(len), Some(target), Some(depth_lasti)) =
+        (varint(), varint(), varint(), varint())
+    {
+        let start = start as usize * 2;
+        entries.push(ExceptionEntry {
+            start,
+            end: start + len as usize * 2,
+            target: target as usize * 2,
+            depth: depth_lasti >> 1,
+            lasti: depth_lasti & 1 != 0,
+        });
+    }
+
+    entries
+}
+
+/// Joins names with commas
+fn join(names: &[Arc<String>]) -> String {
+    names
+        .iter()
+        .map(|name| name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Disassembles `code` and every code object nested in it, in the style of
+/// the `dis` module. Jump targets and exception handlers are marked with `>>`.
+pub fn disassemble(code: &Arc<Code>, spec: &OpcodeSpec) -> String {
+    let mut out = String::new();
+    writeln!(out, "# Disassembled as {}", spec.description).unwrap();
+    for code in code_objects(code) {
+        writeln!(
+            out,
+            "\nDisassembly of {} ({}, line {}):",
+            code.qualname, code.filename, code.firstlineno
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# argcount {}, posonlyargcount {}, kwonlyargcount {}, stacksize {}, flags {:#x}",
+            code.argcount, code.posonlyargcount, code.kwonlyargcount, code.stacksize, code.flags
+        )
+        .unwrap();
+        if !code.cellvars.is_empty() {
+            writeln!(out, "# cellvars: {}", join(&code.cellvars)).unwrap();
+        }
+        if !code.freevars.is_empty() {
+            writeln!(out, "# freevars: {}", join(&code.freevars)).unwrap();
+        }
+
+        let instrs = spec.decode(code.code.as_slice());
+        let handlers = exception_table(code.exceptiontable.as_slice());
+        let targets: BTreeSet<usize> = instrs
+            .iter()
+            .filter_map(|instr| spec.jump_target(instr))
+            .chain(handlers.iter().map(|handler| handler.target))
+            .collect();
+        for instr in &instrs {
+            let marker = if targets.contains(&instr.offset) {
+                ">>"
+            } else {
+                ""
+            };
+            let name = spec
+                .opname(instr.opcode)
+                .map_or_else(|| format!("<{}>", instr.opcode), str::to_string);
+            let mut line = format!("{:>2} {:>6} {:<24}", marker, instr.offset, name);
+            if let Some(arg) = instr.arg {
+                write!(line, " {:>5}", arg).unwrap();
+                if let Some(description) = spec.describe_arg(&code, instr) {
+                    write!(line, " ({})", description).unwrap();
+                }
+            }
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+
+        if !handlers.is_empty() {
+            writeln!(out, "ExceptionTable:").unwrap();
+            for handler in &handlers {
+                writeln!(
+                    out,
+                    "  {} to {} -> {} [{}]{}",
+                    handler.start,
+                    handler.end.saturating_sub(2),
+                    handler.target,
+                    handler.depth,
+                    if handler.lasti { " lasti" } else { "" }
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::py3::Pyc;
+    use crate::py3_marshal::{code_objects, load_code};
+
+    /// The `total` function of test_data/py3/sample.py as compiled by each
+    /// version, along with the disassembly spec for it
+    fn sample(file: &[u8]) -> (Arc<Code>, &'static OpcodeSpec) {
+        let pyc = Pyc::parse(file).unwrap();
+        let module = load_code(pyc.data, pyc.minor).unwrap();
+        let function = code_objects(&module)
+            .into_iter()
+            .find(|code| code.name.as_str() == "total")
+            .unwrap();
+        (function, OpcodeSpec::builtin(pyc.minor).unwrap())
+    }
+
+    /// Each jump in the sample function along with its target
+    fn jumps(file: &[u8]) -> Vec<(&'static str, usize)> {
+        let (code, spec) = sample(file);
+        spec.decode(&code.code)
+            .iter()
+            .filter_map(|instr| Some((spec.opname(instr.opcode)?, spec.jump_target(instr)?)))
+            .collect()
+    }
+
+    #[test]
+    fn jumps_match_dis() {
+        assert_eq!(
+            jumps(include_bytes!("../test_data/py3/sample.cpython-36.pyc")),
+            [
+                ("SETUP_LOOP", 34),
+                ("FOR_ITER", 32),
+                ("POP_JUMP_IF_FALSE", 10),
+                ("JUMP_ABSOLUTE", 10),
+            ]
+        );
+        assert_eq!(
+            jumps(include_bytes!("../test_data/py3/sample.cpython-38.pyc")),
+            [
+                ("FOR_ITER", 30),
+                ("POP_JUMP_IF_FALSE", 8),
+                ("JUMP_ABSOLUTE", 8),
+            ]
+        );
+        // Python 3.12 counts in code units, past inline caches
+        assert_eq!(
+            jumps(include_bytes!("../test_data/py3/sample.cpython-312.pyc")),
+            [
+                ("FOR_ITER", 40),
+                ("POP_JUMP_IF_TRUE", 28),
+                ("JUMP_BACKWARD", 10),
+                ("JUMP_BACKWARD", 10),
+            ]
+        );
+        assert_eq!(
+            jumps(include_bytes!("../test_data/py3/sample.cpython-313.pyc")),
+            [
+                ("FOR_ITER", 44),
+                ("POP_JUMP_IF_TRUE", 32),
+                ("JUMP_BACKWARD", 10),
+                ("JUMP_BACKWARD", 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn disassembly_resolves_arguments() {
+        let (code, spec) = sample(include_bytes!("../test_data/py3/sample.cpython-313.pyc"));
+        let dis = disassemble(&code, spec);
+        for expected in [
+            "LOAD_FAST_LOAD_FAST",
+            "(result, item)",
+            "(1.5)",
+            "(>)",
+            ">>     10 FOR_ITER",
+        ] {
+            assert!(
+                dis.contains(expected),
+                "{} missing from:\n{}",
+                expected,
+                dis
+            );
+        }
+    }
+}
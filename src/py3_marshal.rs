@@ -0,0 +1,756 @@
+use num_bigint::BigInt;
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Set on a type code when the object may be referenced again later in the
+/// stream
+const FLAG_REF: u8 = 0x80;
+
+/// `co_localspluskinds` flags of Python 3.11+ code objects
+const CO_FAST_LOCAL: u8 = 0x20;
+const CO_FAST_CELL: u8 = 0x40;
+const CO_FAST_FREE: u8 = 0x80;
+
+/// Nesting deeper than this is rejected rather than overflowing the stack.
+/// Modules are read on rayon's worker threads, whose 2 MiB stacks only hold a
+/// few hundred levels in debug builds. Real code nests far less.
+const MAX_DEPTH: usize = 200;
+
+#[derive(Error, Debug)]
+pub enum MarshalError {
+    #[error("unexpected end of data at offset {0}")]
+    UnexpectedEof(usize),
+    #[error("unknown type code {code:#04x} at offset {offset}")]
+    UnknownType { code: u8, offset: usize },
+    #[error("negative length {len} at offset {offset}")]
+    NegativeLength { len: i32, offset: usize },
+    #[error("invalid float literal at offset {0}")]
+    InvalidFloat(usize),
+    #[error("reference {index} at offset {offset} is not a previously read object")]
+    InvalidRef { index: u32, offset: usize },
+    #[error("NULL at offset {0} outside of a dict")]
+    UnexpectedNull(usize),
+    #[error("nesting deeper than {MAX_DEPTH} objects")]
+    TooDeep,
+    #[error("code object field `{0}` has the wrong type")]
+    InvalidCodeField(&'static str),
+    #[error("expected a code object")]
+    NotCode,
+}
+
+/// An object unmarshalled from Python 3 data. `str` and `bytes` are kept
+/// apart, unlike in Python 2.7.
+#[derive(Debug, Clone)]
+pub enum Obj {
+    None,
+    StopIteration,
+    Ellipsis,
+    Bool(bool),
+    Long(Arc<BigInt>),
+    Float(f64),
+    Complex(f64, f64),
+    Bytes(Arc<Vec<u8>>),
+    String(Arc<String>),
+    Tuple(Arc<Vec<Obj>>),
+    List(Arc<Vec<Obj>>),
+    Dict(Arc<Vec<(Obj, Obj)>>),
+    Set(Arc<Vec<Obj>>),
+    FrozenSet(Arc<Vec<Obj>>),
+    Code(Arc<Code>),
+}
+
+/// Writes `items` separated by commas
+fn write_items<'a>(
+    f: &mut fmt::Formatter<'_>,
+    items: impl Iterator<Item = &'a Obj>,
+) -> fmt::Result {
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+
+    Ok(())
+}
+
+/// Writes `text` between quotes, escaped the way Python's `repr` does.
+/// Single quotes are used unless the text contains them but no double
+/// quotes.
+fn write_quoted(f: &mut fmt::Formatter<'_>, text: &str, bytes: bool) -> fmt::Result {
+    let quote = if text.contains('\'') && !text.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    write!(f, "{}", quote)?;
+    for c in text.chars() {
+        match c {
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c == quote => write!(f, "\\{}", c)?,
+            c if bytes && !(' '..='~').contains(&c) => write!(f, "\\x{:02x}", c as u32)?,
+            c if !is_printable(c) && (c as u32) < 0x100 => write!(f, "\\x{:02x}", c as u32)?,
+            c if !is_printable(c) && (c as u32) < 0x10000 => write!(f, "\\u{:04x}", c as u32)?,
+            c if !is_printable(c) => write!(f, "\\U{:08x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "{}", quote)
+}
+
+/// Whether Python's `repr` leaves `c` unescaped. This covers the control,
+/// separator, and common format characters rather than the full Unicode
+/// database.
+fn is_printable(c: char) -> bool {
+    !(c.is_control()
+        || (c.is_whitespace() && c != ' ')
+        || matches!(
+            c,
+            '\u{ad}' | '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{2064}' | '\u{feff}' | '\u{fffe}' | '\u{ffff}'
+        ))
+}
+
+/// `value` the way Python's `repr` writes it, which differs from Rust's in its
+/// exponents and special values
+fn float_repr(value: f64) -> String {
+    if value.is_nan() {
+        return "nan".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    let text = format!("{:?}", value);
+    match text.split_once('e') {
+        Some((mantissa, exponent)) => {
+            let (sign, digits) = match exponent.strip_prefix('-') {
+                Some(digits) => ('-', digits),
+                None => ('+', exponent),
+            };
+            format!("{}e{}{:0>2}", mantissa.trim_end_matches(".0"), sign, digits)
+        }
+        None => text,
+    }
+}
+
+/// A part of a complex number, which Python writes without a `.0`
+fn complex_part(value: f64) -> String {
+    let text = float_repr(value);
+    text.strip_suffix(".0").map(str::to_string).unwrap_or(text)
+}
+
+/// Formats the object the way Python's `repr` does
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obj::None => f.write_str("None"),
+            Obj::StopIteration => f.write_str("StopIteration"),
+            Obj::Ellipsis => f.write_str("Ellipsis"),
+            Obj::Bool(true) => f.write_str("True"),
+            Obj::Bool(false) => f.write_str("False"),
+            Obj::Long(value) => write!(f, "{}", value),
+            Obj::Float(value) => f.write_str(&float_repr(*value)),
+            Obj::Complex(re, im) if *re == 0.0 && re.is_sign_positive() => {
+                write!(f, "{}j", complex_part(*im))
+            }
+            Obj::Complex(re, im) => {
+                let im = complex_part(*im);
+                let sign = if im.starts_with('-') { "" } else { "+" };
+                write!(f, "({}{}{}j)", complex_part(*re), sign, im)
+            }
+            Obj::Bytes(bytes) => {
+                f.write_str("b")?;
+                let text: String = bytes.iter().map(|b| *b as char).collect();
+                write_quoted(f, &text, true)
+            }
+            Obj::String(text) => write_quoted(f, text, false),
+            Obj::Tuple(items) if items.len() == 1 => write!(f, "({},)", items[0]),
+            Obj::Tuple(items) => {
+                f.write_str("(")?;
+                write_items(f, items.iter())?;
+                f.write_str(")")
+            }
+            Obj::List(items) => {
+                f.write_str("[")?;
+                write_items(f, items.iter())?;
+                f.write_str("]")
+            }
+            Obj::Set(items) if items.is_empty() => f.write_str("set()"),
+            Obj::Set(items) => {
+                f.write_str("{")?;
+                write_items(f, items.iter())?;
+                f.write_str("}")
+            }
+            Obj::FrozenSet(items) if items.is_empty() => f.write_str("frozenset()"),
+            Obj::FrozenSet(items) => {
+                f.write_str("frozenset({")?;
+                write_items(f, items.iter())?;
+                f.write_str("})")
+            }
+            Obj::Dict(items) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                f.write_str("}")
+            }
+            Obj::Code(code) => write!(
+                f,
+                "<code object {}, file \"{}\", line {}>",
+                code.name, code.filename, code.firstlineno
+            ),
+        }
+    }
+}
+
+/// A Python 3 code object. Python 3.11 merged the local, cell, and free
+/// variable names into `co_localsplusnames`, which is split back into
+/// `varnames`, `cellvars`, and `freevars` here. For older versions
+/// `localsplusnames` is the concatenation of those three.
+#[derive(Debug, Clone)]
+pub struct Code {
+    pub argcount: u32,
+    pub posonlyargcount: u32,
+    pub kwonlyargcount: u32,
+    pub stacksize: u32,
+    pub flags: u32,
+    pub code: Arc<Vec<u8>>,
+    pub consts: Arc<Vec<Obj>>,
+    pub names: Vec<Arc<String>>,
+    pub varnames: Vec<Arc<String>>,
+    pub cellvars: Vec<Arc<String>>,
+    pub freevars: Vec<Arc<String>>,
+    pub localsplusnames: Vec<Arc<String>>,
+    pub filename: Arc<String>,
+    pub name: Arc<String>,
+    /// Only stored since Python 3.11. Older versions use `name`.
+    pub qualname: Arc<String>,
+    pub firstlineno: u32,
+    /// Only present since Python 3.11
+    pub exceptiontable: Arc<Vec<u8>>,
+}
+
+/// Unmarshals the code object in `data`, which was written by Python
+/// `3.<minor>`. The layout of code objects depends on the version.
+pub fn load_code(data: &[u8], minor: u8) -> Result<Arc<Code>, MarshalError> {
+    match loads(data, minor)? {
+        Obj::Code(code) => Ok(code),
+        _ => Err(MarshalError::NotCode),
+    }
+}
+
+/// Unmarshals the first object in `data`
+pub fn loads(data: &[u8], minor: u8) -> Result<Obj, MarshalError> {
+    let mut reader = Reader {
+        data,
+        pos: 0,
+        minor,
+        refs: Vec::new(),
+        depth: 0,
+    };
+
+    reader.object()
+}
+
+/// Every code object nested in `code`, including `code` itself, in
+/// depth-first order
+pub fn code_objects(code: &Arc<Code>) -> Vec<Arc<Code>> {
+    let mut objects = vec![Arc::clone(code)];
+    for c in code.consts.iter() {
+        if let Obj::Code(inner) = c {
+            objects.extend(code_objects(inner));
+        }
+    }
+
+    objects
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    minor: u8,
+    /// Objects flagged with `FLAG_REF`, in the order they were started. A slot
+    /// is `None` until its object has been read completely.
+    refs: Vec<Option<Obj>>,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], MarshalError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(MarshalError::UnexpectedEof(self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, MarshalError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, MarshalError> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, MarshalError> {
+        Ok(self.i32()? as u32)
+    }
+
+    fn f64(&mut self) -> Result<f64, MarshalError> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A 32-bit length, which must not be negative
+    fn len(&mut self) -> Result<usize, MarshalError> {
+        let offset = self.pos;
+        let len = self.i32()?;
+        usize::try_from(len).map_err(|_| MarshalError::NegativeLength { len, offset })
+    }
+
+    /// A float written as text, with a one byte length
+    fn text_float(&mut self) -> Result<f64, MarshalError> {
+        let offset = self.pos;
+        let len = self.u8()? as usize;
+        std::str::from_utf8(self.bytes(len)?)
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .ok_or(MarshalError::InvalidFloat(offset))
+    }
+
+    fn string(&mut self, len: usize) -> Result<Obj, MarshalError> {
+        // Lone surrogates are allowed in marshalled strings, and don't
+        // survive the conversion
+        let text = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+        Ok(Obj::String(Arc::new(text)))
+    }
+
+    fn sequence(&mut self, len: usize) -> Result<Vec<Obj>, MarshalError> {
+        // The length is untrusted, so don't reserve space for all of it
+        let mut items = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            items.push(self.object()?);
+        }
+
+        Ok(items)
+    }
+
+    /// A `long`, stored as base 2**15 digits
+    fn long(&mut self) -> Result<BigInt, MarshalError> {
+        let count = self.i32()?;
+        let mut value = BigInt::from(0);
+        let mut digits = Vec::new();
+        for _ in 0..count.unsigned_abs() {
+            digits.push(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()));
+        }
+        for digit in digits.iter().rev() {
+            value = (value << 15) + BigInt::from(*digit);
+        }
+
+        Ok(if count < 0 { -value } else { value })
+    }
+
+    fn object(&mut self) -> Result<Obj, MarshalError> {
+        if self.depth == MAX_DEPTH {
+            return Err(MarshalError::TooDeep);
+        }
+        self.depth += 1;
+        let obj = self.object_inner();
+        self.depth -= 1;
+
+        obj?.ok_or(MarshalError::UnexpectedNull(self.pos - 1))
+    }
+
+    /// Reads an object, or `None` for the NULL which ends a dict
+    fn object_inner(&mut self) -> Result<Option<Obj>, MarshalError> {
+        let offset = self.pos;
+        let code = self.u8()?;
+        let flagged = code & FLAG_REF != 0;
+        // The slot is reserved before any nested objects are read, since they
+        // are numbered in the order they start
+        let slot = if flagged {
+            self.refs.push(None);
+            Some(self.refs.len() - 1)
+        } else {
+            None
+        };
+
+        let obj = match code & !FLAG_REF {
+            b'0' => return Ok(None),
+            b'N' => Obj::None,
+            b'S' => Obj::StopIteration,
+            b'.' => Obj::Ellipsis,
+            b'F' => Obj::Bool(false),
+            b'T' => Obj::Bool(true),
+            b'i' => Obj::Long(Arc::new(BigInt::from(self.i32()?))),
+            b'l' => Obj::Long(Arc::new(self.long()?)),
+            b'g' => Obj::Float(self.f64()?),
+            b'f' => Obj::Float(self.text_float()?),
+            b'y' => Obj::Complex(self.f64()?, self.f64()?),
+            b'x' => Obj::Complex(self.text_float()?, self.text_float()?),
+            b's' => {
+                let len = self.len()?;
+                Obj::Bytes(Arc::new(self.bytes(len)?.to_vec()))
+            }
+            b't' | b'u' | b'a' | b'A' => {
+                let len = self.len()?;
+                self.string(len)?
+            }
+            b'z' | b'Z' => {
+                let len = self.u8()? as usize;
+                self.string(len)?
+            }
+            b'(' => {
+                let len = self.len()?;
+                Obj::Tuple(Arc::new(self.sequence(len)?))
+            }
+            b')' => {
+                let len = self.u8()? as usize;
+                Obj::Tuple(Arc::new(self.sequence(len)?))
+            }
+            b'[' => {
+                let len = self.len()?;
+                Obj::List(Arc::new(self.sequence(len)?))
+            }
+            b'<' => {
+                let len = self.len()?;
+                Obj::Set(Arc::new(self.sequence(len)?))
+            }
+            b'>' => {
+                let len = self.len()?;
+                Obj::FrozenSet(Arc::new(self.sequence(len)?))
+            }
+            b'{' => {
+                let mut items = Vec::new();
+                loop {
+                    self.depth += 1;
+                    let key = self.object_inner();
+                    self.depth -= 1;
+                    match key? {
+                        Some(key) => items.push((key, self.object()?)),
+                        None => break,
+                    }
+                }
+                Obj::Dict(Arc::new(items))
+            }
+            b'c' => Obj::Code(Arc::new(self.code()?)),
+            b'r' => {
+                let index = self.u32()?;
+                return self
+                    .refs
+                    .get(index as usize)
+                    .cloned()
+                    .flatten()
+                    .map(Some)
+                    .ok_or(MarshalError::InvalidRef { index, offset });
+            }
+            code => return Err(MarshalError::UnknownType { code, offset }),
+        };
+
+        if let Some(slot) = slot {
+            self.refs[slot] = Some(obj.clone());
+        }
+
+        Ok(Some(obj))
+    }
+
+    fn names(&mut self, field: &'static str) -> Result<Vec<Arc<String>>, MarshalError> {
+        match self.object()? {
+            Obj::Tuple(items) => items
+                .iter()
+                .map(|item| match item {
+                    Obj::String(s) => Ok(Arc::clone(s)),
+                    _ => Err(MarshalError::InvalidCodeField(field)),
+                })
+                .collect(),
+            _ => Err(MarshalError::InvalidCodeField(field)),
+        }
+    }
+
+    fn name(&mut self, field: &'static str) -> Result<Arc<String>, MarshalError> {
+        match self.object()? {
+            Obj::String(s) => Ok(s),
+            _ => Err(MarshalError::InvalidCodeField(field)),
+        }
+    }
+
+    fn bytes_field(&mut self, field: &'static str) -> Result<Arc<Vec<u8>>, MarshalError> {
+        match self.object()? {
+            Obj::Bytes(bytes) => Ok(bytes),
+            _ => Err(MarshalError::InvalidCodeField(field)),
+        }
+    }
+
+    fn code(&mut self) -> Result<Code, MarshalError> {
+        let argcount = self.u32()?;
+        let posonlyargcount = if self.minor >= 8 { self.u32()? } else { 0 };
+        let kwonlyargcount = self.u32()?;
+        if self.minor < 11 {
+            // co_nlocals, which is always the length of co_varnames
+            self.u32()?;
+        }
+        let stacksize = self.u32()?;
+        let flags = self.u32()?;
+        let code = self.bytes_field("co_code")?;
+        let consts = match self.object()? {
+            Obj::Tuple(consts) => consts,
+            _ => return Err(MarshalError::InvalidCodeField("co_consts")),
+        };
+        let names = self.names("co_names")?;
+
+        let (varnames, cellvars, freevars, localsplusnames) = if self.minor >= 11 {
+            let localsplusnames = self.names("co_localsplusnames")?;
+            let kinds = self.bytes_field("co_localspluskinds")?;
+            if kinds.len() != localsplusnames.len() {
+                return Err(MarshalError::InvalidCodeField("co_localspluskinds"));
+            }
+            let with_kind = |kind: u8| -> Vec<Arc<String>> {
+                localsplusnames
+                    .iter()
+                    .zip(kinds.iter())
+                    .filter(|(_, k)| *k & kind != 0)
+                    .map(|(name, _)| Arc::clone(name))
+                    .collect()
+            };
+            (
+                with_kind(CO_FAST_LOCAL),
+                with_kind(CO_FAST_CELL),
+                with_kind(CO_FAST_FREE),
+                localsplusnames,
+            )
+        } else {
+            let varnames = self.names("co_varnames")?;
+            let freevars = self.names("co_freevars")?;
+            let cellvars = self.names("co_cellvars")?;
+            let localsplusnames = varnames
+                .iter()
+                .chain(cellvars.iter())
+                .chain(freevars.iter())
+                .cloned()
+                .collect();
+            (varnames, cellvars, freevars, localsplusnames)
+        };
+
+        let filename = self.name("co_filename")?;
+        let name = self.name("co_name")?;
+        let qualname = if self.minor >= 11 {
+            self.name("co_qualname")?
+        } else {
+            Arc::clone(&name)
+        };
+        let firstlineno = self.u32()?;
+        // co_lnotab before Python 3.10, and co_linetable since
+        self.bytes_field("co_linetable")?;
+        let exceptiontable = if self.minor >= 11 {
+            self.bytes_field("co_exceptiontable")?
+        } else {
+            Arc::new(Vec::new())
+        };
+
+        Ok(Code {
+            argcount,
+            posonlyargcount,
+            kwonlyargcount,
+            stacksize,
+            flags,
+            code,
+            consts,
+            names,
+            varnames,
+            cellvars,
+            freevars,
+            localsplusnames,
+            filename,
+            name,
+            qualname,
+            firstlineno,
+            exceptiontable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::py3::Pyc;
+
+    const SAMPLES: [&[u8]; 4] = [
+        include_bytes!("../test_data/py3/sample.cpython-36.pyc"),
+        include_bytes!("../test_data/py3/sample.cpython-38.pyc"),
+        include_bytes!("../test_data/py3/sample.cpython-312.pyc"),
+        include_bytes!("../test_data/py3/sample.cpython-313.pyc"),
+    ];
+
+    /// A 32-bit length or count
+    fn len(value: i32) -> [u8; 4] {
+        value.to_le_bytes()
+    }
+
+    #[test]
+    fn samples_load_on_every_version() {
+        for sample in SAMPLES {
+            let pyc = Pyc::parse(sample).unwrap();
+            let module = load_code(pyc.data, pyc.minor).unwrap();
+            let codes = code_objects(&module);
+            assert_eq!(codes.len(), 2, "Python 3.{}", pyc.minor);
+
+            let total = &codes[1];
+            assert_eq!(module.name.as_str(), "<module>");
+            assert_eq!(total.name.as_str(), "total");
+            assert_eq!(total.qualname.as_str(), "total");
+            assert_eq!(total.argcount, 1);
+            assert_eq!(total.firstlineno, 4);
+            assert_eq!(
+                total
+                    .varnames
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>(),
+                ["items", "result", "item"]
+            );
+            assert_eq!(total.localsplusnames, total.varnames);
+            assert!(total.cellvars.is_empty() && total.freevars.is_empty());
+            assert!(module
+                .consts
+                .iter()
+                .any(|c| matches!(c, Obj::String(s) if s.as_str() == "secret")));
+            assert!(total
+                .consts
+                .iter()
+                .any(|c| matches!(c, Obj::Float(value) if *value == 1.5)));
+
+            // The nested code object's file name is a reference to the
+            // module's
+            assert!(Arc::ptr_eq(&total.filename, &module.filename));
+        }
+    }
+
+    #[test]
+    fn every_type_code_is_read() {
+        let mut data = vec![b')', 26];
+        data.extend_from_slice(b"NS.FT");
+        data.push(b'i');
+        data.extend_from_slice(&len(-7));
+        // -(1 + 2 * 2**15)
+        data.push(b'l');
+        data.extend_from_slice(&len(-2));
+        data.extend_from_slice(&[1, 0, 2, 0]);
+        data.push(b'g');
+        data.extend_from_slice(&2.5f64.to_le_bytes());
+        data.extend_from_slice(b"f\x040.25");
+        data.push(b'y');
+        data.extend_from_slice(&1.0f64.to_le_bytes());
+        data.extend_from_slice(&(-2.0f64).to_le_bytes());
+        data.extend_from_slice(b"x\x010\x013");
+        data.push(b's');
+        data.extend_from_slice(&len(2));
+        data.extend_from_slice(b"\x00'");
+        for code in [b't', b'u', b'a', b'A'] {
+            data.push(code);
+            data.extend_from_slice(&len(1));
+            data.push(code);
+        }
+        data.extend_from_slice(b"z\x01zZ\x02\xc3\xa9");
+        data.push(b'(');
+        data.extend_from_slice(&len(1));
+        data.push(b'N');
+        data.push(b'[');
+        data.extend_from_slice(&len(2));
+        data.extend_from_slice(b"TF");
+        data.push(b'<');
+        data.extend_from_slice(&len(0));
+        data.push(b'>');
+        data.extend_from_slice(&len(1));
+        data.push(b'N');
+        data.extend_from_slice(b"{NT0");
+        // A flagged string and two references to it
+        data.push(b'z' | FLAG_REF);
+        data.extend_from_slice(b"\x03ref");
+        data.push(b'r');
+        data.extend_from_slice(&len(0));
+        data.push(b'r');
+        data.extend_from_slice(&len(0));
+
+        let obj = loads(&data, 8).unwrap();
+
+        assert_eq!(
+            obj.to_string(),
+            concat!(
+                "(None, StopIteration, Ellipsis, False, True, -7, -65537, 2.5, 0.25, ",
+                "(1-2j), 3j, b\"\\x00'\", 't', 'u', 'a', 'A', 'z', 'é', (None,), ",
+                "[True, False], set(), frozenset({None}), {None: True}, ",
+                "'ref', 'ref', 'ref')"
+            )
+        );
+    }
+
+    #[test]
+    fn truncated_data_is_an_unexpected_eof() {
+        for sample in SAMPLES {
+            let pyc = Pyc::parse(sample).unwrap();
+            for end in 0..pyc.data.len() {
+                match load_code(&pyc.data[..end], pyc.minor) {
+                    Err(MarshalError::UnexpectedEof(offset)) => assert!(offset <= end),
+                    other => panic!(
+                        "Python 3.{} data cut off at {} gave {:?}",
+                        pyc.minor,
+                        end,
+                        other.map(|code| code.name.clone())
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        assert!(matches!(
+            loads(b"?", 8),
+            Err(MarshalError::UnknownType {
+                code: b'?',
+                offset: 0
+            })
+        ));
+        assert!(matches!(
+            loads(b"(\xff\xff\xff\xff", 8),
+            Err(MarshalError::NegativeLength { len: -1, offset: 1 })
+        ));
+        assert!(matches!(
+            loads(b"f\x03abc", 8),
+            Err(MarshalError::InvalidFloat(1))
+        ));
+        assert!(matches!(
+            loads(b")\x01r\x00\x00\x00\x00", 8),
+            Err(MarshalError::InvalidRef {
+                index: 0,
+                offset: 2
+            })
+        ));
+        // An object can't refer to itself before it has been read
+        assert!(matches!(
+            loads(b"\xa9\x01r\x00\x00\x00\x00", 8),
+            Err(MarshalError::InvalidRef {
+                index: 0,
+                offset: 2
+            })
+        ));
+        assert!(matches!(
+            loads(b")\x010", 8),
+            Err(MarshalError::UnexpectedNull(2))
+        ));
+        assert!(matches!(load_code(b"N", 8), Err(MarshalError::NotCode)));
+
+        let nested = [b"[\x01\x00\x00\x00".repeat(MAX_DEPTH), b"N".to_vec()].concat();
+        assert!(matches!(loads(&nested, 8), Err(MarshalError::TooDeep)));
+    }
+}
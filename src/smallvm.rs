@@ -1,30 +1,169 @@
+use crate::builtins::{BuiltinError, Builtins};
+use crate::bytecode::{decode_at, DecodedInstr};
+use crate::pattern::{MatchState, Matcher, Pattern};
+use crate::profiles::Stage2Profile;
+use crate::stage2_static;
 use anyhow::Result;
-use log::trace;
+use log::{debug, trace};
 use num_bigint::ToBigInt;
 use num_traits::ToPrimitive;
 use py27_marshal::bstr::BString;
 use py27_marshal::*;
-use std::collections::{HashMap, VecDeque};
-use std::convert::TryFrom;
+use pydis::error::DecodeError;
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use pydis::prelude::Instruction;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use thiserror::Error;
 use unfuck::smallvm::*;
-type TargetOpcode = pydis::opcode::py27::Standard;
 
-pub fn exec_stage2(code: Arc<Code>, outer_code: Arc<Code>) -> Result<Vec<u8>> {
+/// Errors which may occur while locating the stage2 loader's structures
+#[derive(Error, Debug)]
+pub enum Stage2Error {
+    #[error("pattern not found: {name} (`{pattern}`){}", offset.map(|offset| format!(" at offset {}", offset)).unwrap_or_default())]
+    PatternNotFound {
+        name: &'static str,
+        pattern: String,
+        /// Offset of the instruction which did not match, or `None` if the
+        /// end of the code was reached
+        offset: Option<u64>,
+    },
+    #[error("const {index} is a {found:?}, expected {expected}")]
+    UnexpectedConst {
+        index: usize,
+        found: Type,
+        expected: &'static str,
+    },
+    #[error("swapmap function does not load a swapmap")]
+    SwapMapNotFound,
+    #[error("swapmap has no long value for byte {0:#x}")]
+    BadSwapMapEntry(u8),
+    #[error("decode loop at offset {0} was not recognized and cannot be decoded statically")]
+    UnrecognizedDecodeLoop(u64),
+    #[error("unknown stage2 mode `{0}`. Valid modes are: {}", Stage2Mode::ALL.join(", "))]
+    UnknownMode(String),
+    #[error("builtin call in the decode loop failed: {0}")]
+    Builtin(#[from] BuiltinError),
+    #[error("the VM failed to execute `{mnemonic:?}` at offset {offset}: {message}")]
+    Execution {
+        offset: u64,
+        mnemonic: Mnemonic,
+        message: String,
+    },
+    #[error("`{mnemonic:?} {arg}` at offset {offset} has an argument too large for the VM")]
+    ArgumentTooLarge {
+        offset: u64,
+        mnemonic: Mnemonic,
+        arg: u32,
+    },
+}
+
+/// How the stage2 decode loop is run
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stage2Mode {
+    /// Decode statically if the loop is recognized, otherwise emulate it
+    Auto,
+    /// Always decode statically. Fails if the loop is not recognized.
+    Static,
+    /// Always emulate the loop in the VM
+    Vm,
+}
+
+impl Stage2Mode {
+    pub const ALL: &'static [&'static str] = &["auto", "static", "vm"];
+}
+
+impl std::str::FromStr for Stage2Mode {
+    type Err = Stage2Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Stage2Mode::Auto),
+            "static" => Ok(Stage2Mode::Static),
+            "vm" => Ok(Stage2Mode::Vm),
+            other => Err(Stage2Error::UnknownMode(other.to_string())),
+        }
+    }
+}
+
+/// The VM's state at an instruction in the stage2 loader
+pub struct VmState<'a> {
+    pub stack: &'a VmStack<()>,
+    pub vars: &'a VmVars<()>,
+    pub names: &'a VmNames<()>,
+    pub globals: &'a VmNames<()>,
+    /// Names loaded so far, most recent last
+    pub names_loaded: &'a LoadedNames,
+}
+
+/// An instruction about to be processed by [`exec_stage2`]
+pub struct Stage2Step<'a, O: Opcode<Mnemonic = Mnemonic>> {
+    pub offset: u64,
+    /// The instruction, with any `EXTENDED_ARG` prefix folded into its
+    /// argument
+    pub instr: &'a DecodedInstr<O>,
+    /// Which part of the loader is being searched for, or `vm` once the decode
+    /// loop is being executed
+    pub phase: &'static str,
+    /// The VM's state before the instruction executes. `None` until the VM
+    /// starts running.
+    pub vm: Option<VmState<'a>>,
+}
+
+/// Observes every instruction processed while unpacking stage2. Returning an
+/// error stops unpacking with that error.
+pub trait Stage2Observer<O: Opcode<Mnemonic = Mnemonic>> {
+    fn instruction(&mut self, step: &Stage2Step<'_, O>) -> Result<()>;
+}
+
+/// The swapmap applied to the stage2 payload before it is decoded
+#[derive(Debug, Clone, Serialize)]
+pub struct SwapMap {
+    /// Const index of the swapmap function's code object in the loader
+    pub function_index: usize,
+    /// Const index of the swapmap dict in the swapmap function
+    pub const_index: usize,
+    /// Every byte-to-byte entry of the swapmap dict
+    pub entries: BTreeMap<u8, u8>,
+}
+
+/// The result of unpacking stage2
+#[derive(Debug, Clone)]
+pub struct Stage2Output {
+    /// Marshalled stage3 code
+    pub payload: Vec<u8>,
+    pub swapmap: SwapMap,
+}
+
+/// Decodes the stage2 payload in `outer_code` by locating and running the
+/// decode loop of the stage2 loader in `code`. If provided, `observer` is
+/// called before each instruction is processed.
+pub fn exec_stage2<O: 'static + Opcode<Mnemonic = Mnemonic>>(
+    code: Arc<Code>,
+    outer_code: Arc<Code>,
+    profile: &Stage2Profile,
+    mode: Stage2Mode,
+    mut observer: Option<&mut dyn Stage2Observer<O>>,
+) -> Result<Stage2Output> {
     let output = Arc::new(BString::from(Vec::with_capacity(outer_code.code.len())));
-    let mut state = State::FindXorStart {
-        make_functions_found: 0,
+    let mut state = State::FindSwapMapFunction {
+        matcher: Matcher::unanchored(&profile.swapmap_function),
+        occurrences: 0,
         function_index: 0,
     };
 
     #[derive(Clone)]
-    enum State {
-        FindXorStart {
-            make_functions_found: usize,
-            function_index: u16,
+    enum State<'p> {
+        FindSwapMapFunction {
+            matcher: Matcher<'p>,
+            occurrences: usize,
+            /// Argument of the last `LOAD_CONST` executed
+            function_index: usize,
         },
-        FindSwapMap(VecDeque<TargetOpcode>, u16),
-        AssertInstructionSequence(VecDeque<TargetOpcode>, Box<State>),
+        FindSwapMap(Matcher<'p>, usize),
+        AssertInstructionSequence(&'static str, Matcher<'p>, Box<State<'p>>),
         ExecuteVm(
             VmStack<()>,
             VmVars<()>,
@@ -36,115 +175,132 @@ pub fn exec_stage2(code: Arc<Code>, outer_code: Arc<Code>) -> Result<Vec<u8>> {
         ),
     }
 
+    impl<'p> State<'p> {
+        /// The pattern this state is waiting on, if any
+        fn pending_pattern(&self) -> Option<(&'static str, &'p Pattern)> {
+            match self {
+                State::FindSwapMapFunction { matcher, .. } => {
+                    Some(("swapmap-function", matcher.pattern()))
+                }
+                State::FindSwapMap(matcher, _) => Some(("swapmap-call", matcher.pattern())),
+                State::AssertInstructionSequence(name, matcher, _) => {
+                    Some((name, matcher.pattern()))
+                }
+                State::ExecuteVm(..) => None,
+            }
+        }
+
+        /// Name of the phase this state belongs to
+        fn phase(&self) -> &'static str {
+            match self {
+                State::FindSwapMapFunction { .. } => "swapmap-function",
+                State::FindSwapMap(..) => "swapmap-call",
+                State::AssertInstructionSequence(name, ..) => name,
+                State::ExecuteVm(..) => "vm",
+            }
+        }
+    }
+
+    let builtins = Builtins::python27();
     let mut original_code = Vec::clone(&outer_code.code);
+    let mut error: Option<anyhow::Error> = None;
+    let mut static_output: Option<Vec<u8>> = None;
+    let mut swapmap: Option<SwapMap> = None;
+
+    walk_instructions(code.code.as_slice(), |instr: &DecodedInstr<O>| {
+        let offset = instr.offset;
+        trace!("Instruction at {}: {:?}", offset, instr);
+        if let Some(observer) = observer.as_deref_mut() {
+            let vm = match &state {
+                State::ExecuteVm(stack, vars, names, globals, names_loaded) => Some(VmState {
+                    stack,
+                    vars,
+                    names,
+                    globals,
+                    names_loaded,
+                }),
+                _ => None,
+            };
+            let step = Stage2Step {
+                offset,
+                instr,
+                phase: state.phase(),
+                vm,
+            };
+
+            if let Err(e) = observer.instruction(&step) {
+                error = Some(e);
+                return WalkerState::Break;
+            }
+        }
+
+        let mnemonic = instr.opcode.mnemonic();
+        let arg = instr.arg;
+        let not_found = |state: &State| {
+            let (name, pattern) = state.pending_pattern().unwrap();
+            Stage2Error::PatternNotFound {
+                name,
+                pattern: pattern.to_string(),
+                offset: Some(offset),
+            }
+        };
+
+        match &mut state {
+            State::FindSwapMapFunction {
+                matcher,
+                occurrences,
+                function_index,
+            } => {
+                if let (Mnemonic::LOAD_CONST, Some(arg)) = (mnemonic, arg) {
+                    *function_index = arg as usize;
+                }
+
+                if let MatchState::Matched(captures) = matcher.feed(mnemonic, arg) {
+                    *occurrences += 1;
+                    if *occurrences == profile.swapmap_function_occurrence {
+                        // The walker interleaves the instructions of both
+                        // sides of a branch, so the code object's LOAD_CONST
+                        // may not immediately precede the match
+                        let function_index = captures
+                            .get("function")
+                            .map(|index| index as usize)
+                            .unwrap_or(*function_index);
 
-    const_jmp_instruction_walker(
-        code.code.as_slice(),
-        Arc::clone(&code.consts),
-        |instr, offset| {
-            trace!("Instruction at {}: {:?}", offset, instr);
-            match &mut state {
-                State::FindXorStart {
-                    make_functions_found,
-                    function_index,
-                } => {
-                    if let TargetOpcode::LOAD_CONST = instr.opcode {
-                        *function_index = instr.arg.unwrap();
-                    }
-                    if let TargetOpcode::MAKE_FUNCTION = instr.opcode {
-                        *make_functions_found += 1;
-                    }
-                    if *make_functions_found == 3 {
                         // The next instruction processed will be our code that
                         // invokes the swapmap
                         state = State::FindSwapMap(
-                            vec![
-                                TargetOpcode::STORE_FAST,
-                                TargetOpcode::BUILD_LIST,
-                                TargetOpcode::BUILD_LIST,
-                                TargetOpcode::LOAD_FAST,
-                                TargetOpcode::LOAD_FAST,
-                                TargetOpcode::CALL_FUNCTION,
-                            ]
-                            .into(),
-                            *function_index,
+                            Matcher::anchored(&profile.swapmap_call),
+                            function_index,
                         );
 
                         return WalkerState::ContinueIgnoreAnalyzedInstructions;
                     }
                 }
-                State::FindSwapMap(seq, function_index) => {
-                    assert_eq!(instr.opcode, seq.pop_front().unwrap());
-
-                    // The last instruction is calling our SWAP_MAP function. Invoke that now
-                    if seq.is_empty() {
-                        // Now that we've discovered our swapmap function, let's figure out which
-                        // of these consts is our swapmap
-                        let function_const = &code.consts[*function_index as usize];
-                        if let py27_marshal::Obj::Code(function_code) = function_const {
-                            let mut swapmap_index = None;
-                            trace!("Found the swapmap function -- finding swapmap index");
-                            const_jmp_instruction_walker(
-                                function_code.code.as_slice(),
-                                Arc::clone(&function_code.consts),
-                                |instr, _offset| {
-                                    if let TargetOpcode::LOAD_CONST = instr.opcode {
-                                        swapmap_index = Some(instr.arg.unwrap() as usize);
-                                        WalkerState::Break
-                                    } else {
-                                        WalkerState::Continue
-                                    }
-                                },
-                            )
-                            .expect("failed to walk function instructions");
-
-                            // Now that we've found the swapmap, let's apply it to our
-                            // original code
-                            let swapmap_const = &function_code.consts[swapmap_index.unwrap()];
-                            if let Obj::Dict(swapmap) = swapmap_const {
-                                let swapmap = swapmap.read().unwrap();
-                                for byte in &mut original_code {
-                                    let byte_as_bigint = (*byte).to_bigint().unwrap();
-                                    let swapmap_value = &swapmap[&ObjHashable::try_from(
-                                        &Obj::Long(Arc::new(byte_as_bigint)),
-                                    )
-                                    .unwrap()];
-                                    if let Obj::Long(value) = swapmap_value {
-                                        *byte = (&*value).to_u8().unwrap();
-                                    } else {
-                                        panic!(
-                                            "swapmap value should be a long, found: {:?}",
-                                            swapmap_value.typ()
-                                        );
-                                    }
-                                }
-                            } else {
-                                panic!(
-                                    "suspected swapmap at index {} is a {:?}, not dict!",
-                                    swapmap_index.unwrap(),
-                                    function_const.typ()
-                                );
+            }
+            State::FindSwapMap(matcher, function_index) => {
+                match matcher.feed(mnemonic, arg) {
+                    MatchState::Incomplete => {}
+                    MatchState::Failed => {
+                        error = Some(not_found(&state).into());
+                        return WalkerState::Break;
+                    }
+                    // The last instruction is calling our SWAP_MAP function
+                    MatchState::Matched(_) => {
+                        match apply_swapmap::<O>(&code, *function_index, &mut original_code) {
+                            Ok(applied) => swapmap = Some(applied),
+                            Err(e) => {
+                                error = Some(e);
+                                return WalkerState::Break;
                             }
-                        } else {
-                            panic!(
-                                "const index {} is a {:?}, not code!",
-                                function_index,
-                                function_const.typ()
-                            );
                         }
 
                         // We've successfully applied the swapmap! Let's now get
-                        // to the point where we may execute the VM freely
+                        // to the point where we may execute the VM freely. When
+                        // we encounter the decode loop's FOR_ITER we need to
+                        // jump out of the loop
                         state = State::AssertInstructionSequence(
-                            vec![
-                                TargetOpcode::GET_ITER,
-                                // when we encounter the FOR_ITER we need to jump
-                                // out of the loop
-                                TargetOpcode::FOR_ITER,
-                                // These instructions are post-loop
-                                TargetOpcode::GET_ITER,
-                            ]
-                            .into(),
+                            "decode-loop",
+                            Matcher::anchored(&profile.decode_loop),
                             Box::new(State::ExecuteVm(
                                 vec![
                                     (
@@ -172,85 +328,659 @@ pub fn exec_stage2(code: Arc<Code>, outer_code: Arc<Code>) -> Result<Vec<u8>> {
                             )),
                         );
                     }
-
-                    return WalkerState::ContinueIgnoreAnalyzedInstructions;
                 }
-                State::AssertInstructionSequence(seq, next_state) => {
-                    assert_eq!(instr.opcode, seq.pop_front().unwrap());
 
-                    if seq.is_empty() {
+                return WalkerState::ContinueIgnoreAnalyzedInstructions;
+            }
+            State::AssertInstructionSequence(_name, matcher, next_state) => {
+                match matcher.feed(mnemonic, arg) {
+                    MatchState::Incomplete => {}
+                    MatchState::Failed => {
+                        error = Some(not_found(&state).into());
+                        return WalkerState::Break;
+                    }
+                    MatchState::Matched(_) => {
+                        if mode != Stage2Mode::Vm {
+                            let loop_start = instr.next_offset();
+                            match stage2_static::decode_table::<O>(&code, loop_start) {
+                                Some(table) => {
+                                    trace!("Decoding stage2 statically");
+                                    static_output =
+                                        Some(stage2_static::apply_table(&table, &original_code));
+                                    return WalkerState::Break;
+                                }
+                                None if mode == Stage2Mode::Static => {
+                                    error = Some(
+                                        Stage2Error::UnrecognizedDecodeLoop(loop_start).into(),
+                                    );
+                                    return WalkerState::Break;
+                                }
+                                None => {
+                                    debug!("Decode loop not recognized -- falling back to the VM")
+                                }
+                            }
+                        }
+
                         // TODO: bad allocation since we cannot move out of a referenced
                         // box
                         state = *(next_state.clone());
                     }
+                }
 
-                    // Jump out of any loops
-                    if let TargetOpcode::FOR_ITER = instr.opcode {
-                        return WalkerState::JumpTo(offset + 3 + (instr.arg.unwrap() as u64));
-                    }
+                // Jump out of any loops
+                if let (Mnemonic::FOR_ITER, Some(target)) = (mnemonic, instr.jump_target()) {
+                    return WalkerState::JumpTo(target);
+                }
 
-                    return WalkerState::ContinueIgnoreAnalyzedInstructions;
+                return WalkerState::ContinueIgnoreAnalyzedInstructions;
+            }
+            State::ExecuteVm(stack, vars, names, globals, names_loaded) => {
+                let mut call_error = None;
+                // Check if our bytecode has been drained. This should be index 0 on the satck
+                if let (Some(Obj::String(s)), _modifying_instrs) = &stack[1] {
+                    if s.is_empty() && mnemonic == Mnemonic::FOR_ITER {
+                        return WalkerState::Break;
+                    }
                 }
-                State::ExecuteVm(stack, vars, names, globals, names_loaded) => {
-                    // Check if our bytecode has been drained. This should be index 0 on the satck
-                    if let (Some(Obj::String(s)), _modifying_instrs) = &stack[1] {
-                        if s.is_empty() && instr.opcode == TargetOpcode::FOR_ITER {
-                            return WalkerState::Break;
-                        }
+
+                let offset = instr.offset;
+                let instr = match vm_instruction(instr) {
+                    Ok(instr) => instr,
+                    Err(e) => {
+                        error = Some(e.into());
+                        return WalkerState::Break;
                     }
+                };
 
-                    execute_instruction(
-                        &instr,
-                        Arc::clone(&code),
-                        stack,
-                        vars,
-                        names,
-                        globals,
-                        Arc::clone(&*names_loaded),
-                        |_function, args, _kwargs| match names_loaded.lock().unwrap().last() {
-                            Some(s) => match std::str::from_utf8(&*s.as_slice())
-                                .expect("string is not valid utf8")
-                            {
-                                "chr" => match &args[0] {
-                                    Some(Obj::Long(l)) => {
-                                        return Some(Obj::Long(Arc::new(
-                                            l.to_u8().unwrap().to_bigint().unwrap(),
-                                        )));
-                                    }
-                                    Some(other) => {
-                                        panic!(
-                                            "unexpected input type of {:?} for chr",
-                                            other.typ()
-                                        );
-                                    }
-                                    None => {
-                                        panic!("cannot use chr on unknown value");
-                                    }
-                                },
-                                other => {
-                                    panic!("unsupported function: {}", other);
-                                }
-                            },
-                            other => {
-                                panic!("unsupported callable: {:?}", other);
+                let result = execute_instruction(
+                    &instr,
+                    Arc::clone(&code),
+                    stack,
+                    vars,
+                    names,
+                    globals,
+                    Arc::clone(&*names_loaded),
+                    |_function, args, _kwargs| {
+                        let name = names_loaded.lock().unwrap().last().cloned();
+                        let result = match name {
+                            Some(name) => call_builtin(&builtins, name.as_slice(), args),
+                            None => Err(BuiltinError::UnknownFunction(
+                                "<unknown callable>".to_string(),
+                            )),
+                        };
+
+                        match result {
+                            Ok(value) => value,
+                            Err(e) => {
+                                call_error = Some(e);
+                                None
                             }
-                        },
-                        (), // we don't care about tracking offsets
-                    )
-                    .expect("error executing stage2");
-
-                    // We want to execute sequentially -- ignore the rest of the queue
-                    // for now
-                    return WalkerState::ContinueIgnoreAnalyzedInstructions;
+                        }
+                    },
+                    (), // we don't care about tracking offsets
+                );
+
+                if let Err(e) = result {
+                    error = Some(
+                        Stage2Error::Execution {
+                            offset,
+                            mnemonic,
+                            message: e.to_string(),
+                        }
+                        .into(),
+                    );
+                    return WalkerState::Break;
                 }
+
+                if let Some(e) = call_error {
+                    error = Some(Stage2Error::Builtin(e).into());
+                    return WalkerState::Break;
+                }
+
+                // We want to execute sequentially -- ignore the rest of the queue
+                // for now
+                return WalkerState::ContinueIgnoreAnalyzedInstructions;
             }
+        }
 
+        WalkerState::Continue
+    })?;
+
+    if let Some(error) = error {
+        return Err(error);
+    }
+
+    let payload = match static_output {
+        Some(payload) => payload,
+        None => {
+            if let Some((name, pattern)) = state.pending_pattern() {
+                return Err(Stage2Error::PatternNotFound {
+                    name,
+                    pattern: pattern.to_string(),
+                    offset: None,
+                }
+                .into());
+            }
+
+            // Reverse the bytecode
+            output.iter().rev().copied().collect()
+        }
+    };
+
+    Ok(Stage2Output {
+        payload,
+        // The decode loop is only found after the swapmap has been applied
+        swapmap: swapmap.expect("decode loop found before the swapmap"),
+    })
+}
+
+/// Finds the swapmap loaded by the swapmap function at const `function_index`
+/// and applies it to `original_code`
+fn apply_swapmap<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+    function_index: usize,
+    original_code: &mut [u8],
+) -> Result<SwapMap> {
+    // Now that we've discovered our swapmap function, let's figure out which
+    // of these consts is our swapmap
+    let function_code = match code.consts.get(function_index) {
+        Some(Obj::Code(function_code)) => function_code,
+        other => {
+            return Err(Stage2Error::UnexpectedConst {
+                index: function_index,
+                found: other.map(Obj::typ).unwrap_or(Type::Null),
+                expected: "code",
+            }
+            .into())
+        }
+    };
+
+    let mut swapmap_index = None;
+    trace!("Found the swapmap function -- finding swapmap index");
+    walk_instructions::<O, _>(function_code.code.as_slice(), |instr| {
+        if let (Mnemonic::LOAD_CONST, Some(arg)) = (instr.opcode.mnemonic(), instr.arg) {
+            swapmap_index = Some(arg as usize);
+            WalkerState::Break
+        } else {
             WalkerState::Continue
-        },
-    )?;
+        }
+    })?;
+    let swapmap_index = swapmap_index.ok_or(Stage2Error::SwapMapNotFound)?;
+
+    // Now that we've found the swapmap, let's apply it to our
+    // original code
+    let swapmap = match function_code.consts.get(swapmap_index) {
+        Some(Obj::Dict(swapmap)) => swapmap,
+        other => {
+            return Err(Stage2Error::UnexpectedConst {
+                index: swapmap_index,
+                found: other.map(Obj::typ).unwrap_or(Type::Null),
+                expected: "dict",
+            }
+            .into())
+        }
+    };
+
+    let entries: BTreeMap<u8, u8> = swapmap
+        .read()
+        .unwrap()
+        .iter()
+        .filter_map(|(key, value)| match (key, value) {
+            (ObjHashable::Long(key), Obj::Long(value)) => Some((key.to_u8()?, value.to_u8()?)),
+            _ => None,
+        })
+        .collect();
+    for byte in original_code {
+        *byte = *entries
+            .get(byte)
+            .ok_or(Stage2Error::BadSwapMapEntry(*byte))?;
+    }
+
+    Ok(SwapMap {
+        function_index,
+        const_index: swapmap_index,
+        entries,
+    })
+}
 
-    // Reverse the bytecode
-    let output: Vec<u8> = output.iter().rev().copied().collect();
+/// Walks `bytecode` from its first instruction, calling `callback` with each
+/// instruction and following its jumps the same way unfuck's
+/// `const_jmp_instruction_walker` does. Unlike that walker, `EXTENDED_ARG`
+/// prefixes are folded into the instruction they precede, so jumps to targets
+/// past 64KiB are followed correctly.
+fn walk_instructions<O, F>(bytecode: &[u8], mut callback: F) -> Result<()>
+where
+    O: Opcode<Mnemonic = Mnemonic>,
+    F: FnMut(&DecodedInstr<O>) -> WalkerState,
+{
+    let len = bytecode.len() as u64;
+    let mut analyzed = HashSet::new();
+    let mut queue = VecDeque::from([0u64]);
 
-    Ok(output)
+    while let Some(offset) = queue.pop_front() {
+        if offset >= len {
+            continue;
+        }
+
+        let instr = match decode_at::<O>(bytecode, offset) {
+            Ok(instr) => instr,
+            Err(DecodeError::UnknownOpcode(opcode)) => {
+                debug!("Skipping unknown opcode {} at offset {}", opcode, offset);
+                analyzed.insert(offset);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let force = match callback(&instr) {
+            WalkerState::Break => break,
+            WalkerState::JumpTo(target) => {
+                queue.push_front(target);
+                continue;
+            }
+            WalkerState::Continue => false,
+            WalkerState::ContinueIgnoreAnalyzedInstructions | WalkerState::AssumeComparison(_) => {
+                true
+            }
+        };
+        analyzed.insert(offset);
+
+        // Forced offsets are processed next even if they were seen before
+        let mut enqueue = |target: u64, force: bool| {
+            if force {
+                queue.push_front(target);
+            } else if !analyzed.contains(&target) && !queue.contains(&target) {
+                queue.push_back(target);
+            }
+        };
+
+        let mnemonic = instr.opcode.mnemonic();
+        if let Some(target) = instr.jump_target() {
+            let unconditional = matches!(
+                mnemonic,
+                Mnemonic::JUMP_ABSOLUTE | Mnemonic::JUMP_FORWARD | Mnemonic::CONTINUE_LOOP
+            );
+            if unconditional {
+                if target >= len {
+                    debug!("`{}` at {} jumps past the end of the code", instr, offset);
+                    continue;
+                }
+                if decode_at::<O>(bytecode, target).is_ok() {
+                    enqueue(target, force);
+                    continue;
+                }
+            }
+
+            if target > len {
+                debug!("`{}` at {} has a bad target", instr, offset);
+            } else {
+                enqueue(target, force && instr.opcode.is_absolute_jump());
+            }
+        }
+
+        if !matches!(mnemonic, Mnemonic::RETURN_VALUE | Mnemonic::RAISE_VARARGS) {
+            enqueue(instr.next_offset(), force);
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts an instruction to the form the VM executes. The VM's instructions
+/// only hold 16-bit arguments, which is enough for everything but jumps, and
+/// the VM never looks at jump targets since the walker follows them.
+fn vm_instruction<O: Opcode<Mnemonic = Mnemonic>>(
+    instr: &DecodedInstr<O>,
+) -> Result<Instruction<O>, Stage2Error> {
+    let arg = match instr.arg {
+        Some(arg) if instr.jump_target().is_some() => Some(arg as u16),
+        Some(arg) => Some(
+            u16::try_from(arg).map_err(|_| Stage2Error::ArgumentTooLarge {
+                offset: instr.offset,
+                mnemonic: instr.opcode.mnemonic(),
+                arg,
+            })?,
+        ),
+        None => None,
+    };
+
+    Ok(Instruction {
+        opcode: instr.opcode,
+        arg,
+    })
+}
+
+/// Calls the builtin `name` on behalf of the VM. The single-character string
+/// returned by `chr` is converted to its ordinal, since the VM builds the
+/// decoded payload as a string and can only append ints to it. Every other
+/// builtin's result is returned as is.
+fn call_builtin(builtins: &Builtins, name: &[u8], args: Vec<VmVar>) -> Result<VmVar, BuiltinError> {
+    let value = builtins.call_vm(name, args)?;
+
+    Ok(if name == b"chr" {
+        to_vm_byte(value)
+    } else {
+        value
+    })
+}
+
+/// Converts a single-character string to its ordinal
+fn to_vm_byte(value: VmVar) -> VmVar {
+    match value {
+        Some(Obj::String(s)) if s.len() == 1 => {
+            Some(Obj::Long(Arc::new(s[0].to_bigint().unwrap())))
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::make_code;
+    use crate::profiles::ProfileSet;
+    use num_bigint::BigInt;
+    use num_traits::ToPrimitive;
+    use pydis::opcode::py27::Standard;
+    use std::sync::RwLock;
+
+    /// Bytes of filler which push the code after it past the reach of a
+    /// 16-bit argument
+    const LARGE_FILLER: usize = 0x11000;
+
+    /// Const index of the key the decode loop XORs each byte with
+    const KEY_INDEX: u32 = 2;
+    const KEY: i64 = 0x2a;
+
+    /// An element of a synthetic loader. Jumps to labels are always emitted
+    /// with an `EXTENDED_ARG` prefix, and so are arguments which need one.
+    enum Item {
+        Op(Standard),
+        Arg(Standard, u32),
+        Jump(Standard, &'static str),
+        Label(&'static str),
+        /// `count` copies of a byte
+        Filler(usize, u8),
+    }
+
+    fn item_len(item: &Item) -> usize {
+        match item {
+            Item::Op(_) => 1,
+            Item::Arg(_, arg) if *arg <= u16::MAX as u32 => 3,
+            Item::Arg(..) | Item::Jump(..) => 6,
+            Item::Label(_) => 0,
+            Item::Filler(count, _) => *count,
+        }
+    }
+
+    fn assemble(items: &[Item]) -> Vec<u8> {
+        let mut labels = HashMap::new();
+        let mut offset = 0;
+        for item in items {
+            if let Item::Label(name) = item {
+                labels.insert(*name, offset);
+            }
+            offset += item_len(item);
+        }
+
+        let mut out = Vec::with_capacity(offset);
+        let emit = |out: &mut Vec<u8>, opcode: Standard, arg: u32, extended: bool| {
+            if extended {
+                out.push(Standard::EXTENDED_ARG.to_u8().unwrap());
+                out.extend_from_slice(&((arg >> 16) as u16).to_le_bytes());
+            }
+            out.push(opcode.to_u8().unwrap());
+            out.extend_from_slice(&(arg as u16).to_le_bytes());
+        };
+        for item in items {
+            match item {
+                Item::Op(opcode) => out.push(opcode.to_u8().unwrap()),
+                Item::Arg(opcode, arg) => emit(&mut out, *opcode, *arg, *arg > u16::MAX as u32),
+                Item::Jump(opcode, label) => {
+                    let target = labels[label];
+                    let arg = if opcode.is_relative_jump() {
+                        target - (out.len() + 6)
+                    } else {
+                        target
+                    };
+                    emit(&mut out, *opcode, arg as u32, true);
+                }
+                Item::Label(_) => {}
+                Item::Filler(count, byte) => out.resize(out.len() + count, *byte),
+            }
+        }
+
+        out
+    }
+
+    /// The locals of the generated loader
+    const VARNAMES: &[&str] = &["sys", "f123", "byte"];
+
+    fn long(value: i64) -> Obj {
+        Obj::Long(Arc::new(BigInt::from(value)))
+    }
+
+    /// The byte the swapmap function maps `byte` to
+    fn swap(byte: u8) -> u8 {
+        byte.rotate_left(3)
+    }
+
+    /// `def f123(): return {byte: swap(byte) for byte in range(256)}`
+    fn swapmap_function() -> Code {
+        use Standard::*;
+
+        let swapmap = (0..=255u8)
+            .map(|byte| {
+                (
+                    ObjHashable::Long(Arc::new(BigInt::from(byte))),
+                    long(swap(byte) as i64),
+                )
+            })
+            .collect();
+        make_code(
+            assemble(&[Item::Arg(LOAD_CONST, 1), Item::Op(RETURN_VALUE)]),
+            vec![Obj::None, Obj::Dict(Arc::new(RwLock::new(swapmap)))],
+            &[],
+            VARNAMES,
+        )
+    }
+
+    /// Builds a stage2 loader matching the built-in profile. `filler` bytes
+    /// of garbage are jumped over before the swapmap function is made, and
+    /// as many `NOP`s pad the body of the loop before the decode loop, so
+    /// that a large `filler` forces every jump to need an `EXTENDED_ARG`.
+    /// The decode loop loads its key from const `key_index`.
+    fn loader(filler: usize, key_index: u32) -> Code {
+        use Item::*;
+        use Standard::*;
+
+        let items = [
+            // The first two functions made aren't the swapmap function
+            Arg(LOAD_CONST, 1),
+            Arg(MAKE_FUNCTION, 0),
+            Op(POP_TOP),
+            Arg(LOAD_CONST, 1),
+            Arg(MAKE_FUNCTION, 0),
+            Op(POP_TOP),
+            Jump(JUMP_ABSOLUTE, "swapmap"),
+            Filler(filler, 0xff),
+            Label("swapmap"),
+            Arg(LOAD_CONST, 1),
+            Arg(MAKE_FUNCTION, 0),
+            Arg(STORE_FAST, 1),
+            Arg(BUILD_LIST, 0),
+            Arg(BUILD_LIST, 0),
+            Arg(LOAD_FAST, 1),
+            Arg(LOAD_FAST, 0),
+            Arg(CALL_FUNCTION, 1),
+            Op(GET_ITER),
+            // [ord(byte) for byte in f123(code)] is skipped over
+            Label("ord_loop"),
+            Jump(FOR_ITER, "ord_done"),
+            Arg(STORE_FAST, 2),
+            Filler(filler, NOP.to_u8().unwrap()),
+            Arg(LOAD_FAST, 2),
+            Arg(LIST_APPEND, 2),
+            Jump(JUMP_ABSOLUTE, "ord_loop"),
+            Label("ord_done"),
+            Op(GET_ITER),
+            // [chr(byte ^ KEY) for byte in ...]
+            Label("decode_loop"),
+            Jump(FOR_ITER, "decode_done"),
+            Arg(STORE_FAST, 2),
+            Arg(LOAD_NAME, 0),
+            Arg(LOAD_FAST, 2),
+            Arg(LOAD_CONST, key_index),
+            Op(BINARY_XOR),
+            Arg(CALL_FUNCTION, 1),
+            Arg(LIST_APPEND, 2),
+            Jump(JUMP_ABSOLUTE, "decode_loop"),
+            Label("decode_done"),
+            Arg(LOAD_CONST, 0),
+            Op(RETURN_VALUE),
+        ];
+
+        let mut consts = vec![Obj::None, Obj::Code(Arc::new(swapmap_function()))];
+        consts.resize(key_index as usize, Obj::None);
+        consts.push(long(KEY));
+
+        make_code(assemble(&items), consts, &["chr"], VARNAMES)
+    }
+
+    /// The stage1 code whose bytecode holds the encoded stage2 payload
+    fn outer_code() -> Code {
+        make_code(
+            (0..=255u8).cycle().take(1000).collect(),
+            vec![],
+            &[],
+            VARNAMES,
+        )
+    }
+
+    fn expected_payload(outer: &Code) -> Vec<u8> {
+        outer
+            .code
+            .iter()
+            .rev()
+            .map(|&byte| swap(byte) ^ KEY as u8)
+            .collect()
+    }
+
+    fn run(loader: Code, mode: Stage2Mode) -> Result<Stage2Output> {
+        let profiles = ProfileSet::builtin();
+        exec_stage2::<Standard>(
+            Arc::new(loader),
+            Arc::new(outer_code()),
+            &profiles.profiles[0].stage2,
+            mode,
+            None,
+        )
+    }
+
+    #[test]
+    fn stage2_follows_extended_jumps() {
+        let expected = expected_payload(&outer_code());
+        for filler in [0, LARGE_FILLER] {
+            let code = loader(filler, KEY_INDEX);
+            assert_eq!(code.code.len() > 2 * LARGE_FILLER, filler == LARGE_FILLER);
+
+            for mode in [Stage2Mode::Vm, Stage2Mode::Static] {
+                let output = run(code.clone(), mode).unwrap();
+                assert_eq!(output.payload, expected, "{} {:?}", filler, mode);
+                assert_eq!(output.swapmap.function_index, 1);
+                assert_eq!(output.swapmap.entries.len(), 256);
+            }
+        }
+    }
+
+    #[test]
+    fn stage2_vm_rejects_arguments_too_large_for_it() {
+        let code = loader(LARGE_FILLER, 0x10000);
+
+        let output = run(code.clone(), Stage2Mode::Static).unwrap();
+        assert_eq!(output.payload, expected_payload(&outer_code()));
+
+        let error = run(code, Stage2Mode::Vm).unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<Stage2Error>(),
+                Some(Stage2Error::ArgumentTooLarge {
+                    mnemonic: Mnemonic::LOAD_CONST,
+                    arg: 0x10000,
+                    ..
+                })
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn stage2_vm_errors_are_returned() {
+        let mut code = loader(0, KEY_INDEX);
+        let mut xor = None;
+        walk_instructions::<Standard, _>(&code.code, |instr| {
+            if instr.opcode.mnemonic() == Mnemonic::BINARY_XOR {
+                xor = Some(instr.offset as usize);
+            }
+            WalkerState::Continue
+        })
+        .unwrap();
+        let mut bytecode = code.code.to_vec();
+        bytecode[xor.unwrap()] = Standard::UNARY_INVERT.to_u8().unwrap();
+        code.code = Arc::new(bytecode);
+
+        let error = run(code, Stage2Mode::Vm).unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<Stage2Error>(),
+                Some(Stage2Error::Execution {
+                    mnemonic: Mnemonic::UNARY_INVERT,
+                    ..
+                })
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn walker_skips_argless_instructions_after_extended_arg() {
+        use Standard::*;
+
+        // EXTENDED_ARG 1; POP_TOP; LOAD_CONST 0; RETURN_VALUE
+        let mut bytecode = assemble(&[Item::Arg(EXTENDED_ARG, 1)]);
+        bytecode.extend(assemble(&[
+            Item::Op(POP_TOP),
+            Item::Arg(LOAD_CONST, 0),
+            Item::Op(RETURN_VALUE),
+        ]));
+
+        let mut seen = Vec::new();
+        walk_instructions::<Standard, _>(&bytecode, |instr| {
+            seen.push((instr.offset, instr.opcode.mnemonic(), instr.arg));
+            WalkerState::Continue
+        })
+        .unwrap();
+
+        assert_eq!(
+            seen,
+            vec![
+                (0, Mnemonic::POP_TOP, None),
+                (4, Mnemonic::LOAD_CONST, Some(0)),
+                (7, Mnemonic::RETURN_VALUE, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_chr_results_become_bytes() {
+        let builtins = Builtins::python27();
+
+        let byte = call_builtin(&builtins, b"chr", vec![Some(long(65))]).unwrap();
+        assert!(matches!(byte, Some(Obj::Long(value)) if *value == BigInt::from(65)));
+
+        let string = call_builtin(&builtins, b"str", vec![Some(long(7))]).unwrap();
+        assert!(matches!(string, Some(Obj::String(s)) if s.as_slice() == b"7"));
+    }
 }
@@ -0,0 +1,519 @@
+use crate::bytecode::{code_objects, load_code, opcode_byte, replace_bytecode, DecodedInstr};
+use crate::pure_vm::basic_blocks;
+use anyhow::Result;
+use log::trace;
+use py27_marshal::{Code, CodeFlags};
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use std::collections::HashSet;
+
+/// The result of putting a stage's shuffled stack sequences back in order
+pub struct ReorderedStack {
+    /// Marshalled code of the stage with the sequences rewritten
+    pub data: Vec<u8>,
+    /// Number of sequences which were rewritten
+    pub sequences: usize,
+}
+
+/// Finds runs of loads whose values are shuffled into place with `ROT_TWO`,
+/// `ROT_THREE`, and `ROT_FOUR`, or which are pushed only to be popped again,
+/// and rewrites each run as the loads in the order their values end up on the
+/// stack. The freed bytes are filled with `NOP`s so that no jumps need to be
+/// fixed up.
+///
+/// The stack is tracked through each basic block, and a run ends at the first
+/// instruction which touches a value the run did not load. Rotations whose
+/// result is then stored are the compiler's own `a, b = b, a` idiom and are
+/// left in place. Runs which would reorder two loads that may raise are not
+/// rewritten, so the same exception is raised first. Constants and arguments
+/// which are never deleted can't raise.
+///
+/// Returns `None` if nothing was reordered.
+pub fn reorder<O: Opcode<Mnemonic = Mnemonic>>(data: &[u8]) -> Result<Option<ReorderedStack>> {
+    let module = load_code(data)?;
+
+    let mut codes = Vec::new();
+    let mut sequences = 0;
+    for code in code_objects(&module) {
+        let (new_code, count) = reorder_code::<O>(&code)?;
+        sequences += count;
+        codes.push(new_code);
+    }
+
+    if sequences == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(ReorderedStack {
+        data: replace_bytecode(data, &codes)?,
+        sequences,
+    }))
+}
+
+/// Rewrites the shuffled sequences in a single code object, ignoring any
+/// nested code objects. Returns the new bytecode, or `None` if it is
+/// unchanged, and the number of sequences rewritten.
+fn reorder_code<O: Opcode<Mnemonic = Mnemonic>>(code: &Code) -> Result<(Option<Vec<u8>>, usize)> {
+    let blocks = match basic_blocks::<O>(code) {
+        Some(blocks) => blocks,
+        None => return Ok((None, 0)),
+    };
+    let nop = opcode_byte::<O>(Mnemonic::NOP)?;
+
+    let bound = always_bound(code, &blocks);
+    let mut new_code = code.code.to_vec();
+    let mut sequences = 0;
+    for block in &blocks {
+        let mut start = 0;
+        while start < block.len() {
+            let run = &block[start..];
+            let len = canonical_len(run, &bound);
+            if len == 0 {
+                start += 1;
+                continue;
+            }
+            start += len;
+
+            let run = &run[..len];
+            let order = simulate(run, &bound).expect("the run was simulated once already");
+            if !run.iter().any(|instr| reshuffles(instr.opcode.mnemonic())) {
+                continue;
+            }
+
+            if !keeps_fallible_order(run, &order, &bound) {
+                continue;
+            }
+
+            let run_start = run[0].offset as usize;
+            let run_end = run[len - 1].next_offset() as usize;
+            let mut replacement: Vec<u8> = order
+                .iter()
+                .flat_map(|&index| {
+                    let instr = &run[index];
+                    code.code[instr.offset as usize..instr.next_offset() as usize].to_vec()
+                })
+                .collect();
+            replacement.resize(run_end - run_start, nop);
+            trace!(
+                "Reordering {} instructions at offset {} of `{}`",
+                len,
+                run_start,
+                code.name
+            );
+            new_code[run_start..run_end].copy_from_slice(&replacement);
+            sequences += 1;
+        }
+    }
+
+    Ok(((sequences > 0).then_some(new_code), sequences))
+}
+
+/// Returns how many instructions at the start of `instrs` form a run which
+/// can be put in canonical order. `instrs` runs to the end of the basic block.
+fn canonical_len<O: Opcode<Mnemonic = Mnemonic>>(
+    instrs: &[DecodedInstr<O>],
+    bound: &HashSet<u32>,
+) -> usize {
+    let len = match simulate(instrs, bound) {
+        Ok(_) => instrs.len(),
+        Err(len) => len,
+    };
+
+    // Stop before the first group of rotations whose result is stored
+    let mut group_start = None;
+    for index in 0..len {
+        let is_rotation = rotation_depth(instrs[index].opcode.mnemonic()).is_some();
+        if is_rotation && group_start.is_none() {
+            group_start = Some(index);
+        }
+
+        let group_ends =
+            index + 1 == len || rotation_depth(instrs[index + 1].opcode.mnemonic()).is_none();
+        if let (Some(start), true) = (group_start, is_rotation && group_ends) {
+            let depth = simulate(&instrs[..=index], bound)
+                .expect("a prefix of the run simulates")
+                .len();
+            if is_stored(&instrs[index + 1..], depth) {
+                return start;
+            }
+
+            group_start = None;
+        }
+    }
+
+    len
+}
+
+/// Whether the value on top of a stack `depth` values deep is consumed by a
+/// store when `instrs` are executed. This is how the compiler assigns to a
+/// tuple of targets, so rotations producing a value which is stored are left
+/// alone. If the consumer can't be found, the value is assumed to be stored.
+fn is_stored<O: Opcode<Mnemonic = Mnemonic>>(instrs: &[DecodedInstr<O>], depth: usize) -> bool {
+    let mut current = depth;
+    for instr in instrs {
+        let (pops, pushes) = match stack_io(instr) {
+            Some(io) => io,
+            None => return true,
+        };
+
+        if current < pops + depth {
+            return is_store(instr.opcode.mnemonic());
+        }
+        current = current - pops + pushes;
+    }
+
+    true
+}
+
+/// Number of values an instruction pops and pushes, for the instructions which
+/// may appear between a rotation and whatever consumes its result
+fn stack_io<O: Opcode<Mnemonic = Mnemonic>>(instr: &DecodedInstr<O>) -> Option<(usize, usize)> {
+    let mnemonic = instr.opcode.mnemonic();
+    let arg = instr.arg.unwrap_or(0) as usize;
+
+    Some(match mnemonic {
+        mnemonic if is_load(mnemonic) => (0, 1),
+        Mnemonic::LOAD_ATTR
+        | Mnemonic::UNARY_POSITIVE
+        | Mnemonic::UNARY_NEGATIVE
+        | Mnemonic::UNARY_NOT
+        | Mnemonic::UNARY_CONVERT
+        | Mnemonic::UNARY_INVERT => (1, 1),
+        Mnemonic::BINARY_POWER
+        | Mnemonic::BINARY_MULTIPLY
+        | Mnemonic::BINARY_DIVIDE
+        | Mnemonic::BINARY_MODULO
+        | Mnemonic::BINARY_ADD
+        | Mnemonic::BINARY_SUBTRACT
+        | Mnemonic::BINARY_SUBSC
+        | Mnemonic::BINARY_FLOOR_DIVIDE
+        | Mnemonic::BINARY_TRUE_DIVIDE
+        | Mnemonic::BINARY_LSHIFT
+        | Mnemonic::BINARY_RSHIFT
+        | Mnemonic::BINARY_AND
+        | Mnemonic::BINARY_XOR
+        | Mnemonic::BINARY_OR
+        | Mnemonic::COMPARE_OP => (2, 1),
+        Mnemonic::BUILD_TUPLE | Mnemonic::BUILD_LIST => (arg, 1),
+        Mnemonic::CALL_FUNCTION => ((arg & 0xff) + 2 * ((arg >> 8) & 0xff) + 1, 1),
+        Mnemonic::STORE_FAST
+        | Mnemonic::STORE_NAME
+        | Mnemonic::STORE_GLOBAL
+        | Mnemonic::STORE_DEREF
+        | Mnemonic::POP_TOP => (1, 0),
+        Mnemonic::STORE_ATTR | Mnemonic::STORE_SLICE_0 => (2, 0),
+        Mnemonic::STORE_SUBSCR | Mnemonic::STORE_SLICE_1 | Mnemonic::STORE_SLICE_2 => (3, 0),
+        Mnemonic::STORE_SLICE_3 => (4, 0),
+        Mnemonic::UNPACK_SEQUENCE => (1, arg),
+        _ => return None,
+    })
+}
+
+/// Tracks which load produced each value on the stack through `instrs`.
+/// Returns the indices of the loads whose values are left on the stack,
+/// bottom first, or the index of the first instruction which touches a value
+/// not loaded by `instrs`.
+fn simulate<O: Opcode<Mnemonic = Mnemonic>>(
+    instrs: &[DecodedInstr<O>],
+    bound: &HashSet<u32>,
+) -> Result<Vec<usize>, usize> {
+    let mut stack: Vec<usize> = Vec::new();
+    for (index, instr) in instrs.iter().enumerate() {
+        let mnemonic = instr.opcode.mnemonic();
+        if is_load(mnemonic) {
+            stack.push(index);
+        } else if let Some(depth) = rotation_depth(mnemonic) {
+            if stack.len() < depth {
+                return Err(index);
+            }
+            let top = stack.pop().unwrap();
+            stack.insert(stack.len() + 1 - depth, top);
+        } else if mnemonic == Mnemonic::POP_TOP {
+            // Dropping a load which may raise would also drop the exception
+            match stack.last() {
+                Some(&load) if is_infallible(&instrs[load], bound) => {
+                    stack.pop();
+                }
+                _ => return Err(index),
+            }
+        } else {
+            return Err(index);
+        }
+    }
+
+    Ok(stack)
+}
+
+/// Whether the loads in `run` which may raise are still executed in the same
+/// order when `order` is loaded
+fn keeps_fallible_order<O: Opcode<Mnemonic = Mnemonic>>(
+    run: &[DecodedInstr<O>],
+    order: &[usize],
+    bound: &HashSet<u32>,
+) -> bool {
+    let fallible = |index: &usize| {
+        let instr = &run[*index];
+        is_load(instr.opcode.mnemonic()) && !is_infallible(instr, bound)
+    };
+    let original: Vec<usize> = (0..run.len()).filter(fallible).collect();
+    let reordered: Vec<usize> = order.iter().copied().filter(fallible).collect();
+
+    original == reordered
+}
+
+/// Instructions which push a single value without side effects
+fn is_load(mnemonic: Mnemonic) -> bool {
+    matches!(
+        mnemonic,
+        Mnemonic::LOAD_CONST
+            | Mnemonic::LOAD_FAST
+            | Mnemonic::LOAD_NAME
+            | Mnemonic::LOAD_GLOBAL
+            | Mnemonic::LOAD_DEREF
+    )
+}
+
+/// Whether a load can never raise. `bound` holds the locals which are
+/// always bound.
+fn is_infallible<O: Opcode<Mnemonic = Mnemonic>>(
+    instr: &DecodedInstr<O>,
+    bound: &HashSet<u32>,
+) -> bool {
+    match instr.opcode.mnemonic() {
+        Mnemonic::LOAD_CONST => true,
+        Mnemonic::LOAD_FAST => instr.arg.is_some_and(|arg| bound.contains(&arg)),
+        _ => false,
+    }
+}
+
+/// Returns the locals of `code` which are always bound: its arguments, unless
+/// they are deleted somewhere in `blocks`
+fn always_bound<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+    blocks: &[Vec<DecodedInstr<O>>],
+) -> HashSet<u32> {
+    let mut arguments = code.argcount;
+    if code.flags.contains(CodeFlags::VARARGS) {
+        arguments += 1;
+    }
+    if code.flags.contains(CodeFlags::VARKEYWORDS) {
+        arguments += 1;
+    }
+
+    let mut bound: HashSet<u32> = (0..arguments).collect();
+    for instr in blocks.iter().flatten() {
+        if instr.opcode.mnemonic() == Mnemonic::DELETE_FAST {
+            bound.remove(&instr.arg.unwrap_or(0));
+        }
+    }
+
+    bound
+}
+
+/// Instructions which only move or discard values that have been loaded
+fn reshuffles(mnemonic: Mnemonic) -> bool {
+    rotation_depth(mnemonic).is_some() || mnemonic == Mnemonic::POP_TOP
+}
+
+/// Number of values a rotation moves the top of the stack beneath, plus one
+fn rotation_depth(mnemonic: Mnemonic) -> Option<usize> {
+    match mnemonic {
+        Mnemonic::ROT_TWO => Some(2),
+        Mnemonic::ROT_THREE => Some(3),
+        Mnemonic::ROT_FOUR => Some(4),
+        _ => None,
+    }
+}
+
+/// Instructions which assign a value to a target. Unpacking assigns to a
+/// nested tuple of targets.
+fn is_store(mnemonic: Mnemonic) -> bool {
+    matches!(
+        mnemonic,
+        Mnemonic::STORE_FAST
+            | Mnemonic::STORE_NAME
+            | Mnemonic::STORE_GLOBAL
+            | Mnemonic::STORE_DEREF
+            | Mnemonic::STORE_ATTR
+            | Mnemonic::STORE_SUBSCR
+            | Mnemonic::STORE_SLICE_0
+            | Mnemonic::STORE_SLICE_1
+            | Mnemonic::STORE_SLICE_2
+            | Mnemonic::STORE_SLICE_3
+            | Mnemonic::UNPACK_SEQUENCE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::{assemble, compile};
+    use crate::bytecode::{rewrite_code_objects, CodeOverrides};
+    use pydis::opcode::py27::Standard::{self, *};
+
+    /// Replaces the code of a function with arguments `a`, `b`, `c`, and `d`,
+    /// which returns the globals `g` and `h`, with `instrs` and reorders it.
+    /// Returns the reordered code, or `None` if nothing was reordered.
+    fn reorder_function(instrs: &[(Standard, Option<u16>)]) -> Option<Vec<u8>> {
+        let data = compile("def f(a, b, c, d):\n    return g, h\n");
+        let overrides = [
+            CodeOverrides::default(),
+            CodeOverrides {
+                code: Some(assemble(instrs)),
+                ..Default::default()
+            },
+        ];
+        let data = rewrite_code_objects(&data, &overrides).unwrap();
+
+        let reordered = reorder::<Standard>(&data).unwrap()?;
+        assert_eq!(reordered.sequences, 1);
+
+        Some(
+            code_objects(&load_code(&reordered.data).unwrap())[1]
+                .code
+                .to_vec(),
+        )
+    }
+
+    #[test]
+    fn rotations_are_undone() {
+        let reordered = reorder_function(&[
+            (LOAD_FAST, Some(1)),
+            (LOAD_FAST, Some(0)),
+            (ROT_TWO, None),
+            (BUILD_TUPLE, Some(2)),
+            (RETURN_VALUE, None),
+        ]);
+        assert_eq!(
+            reordered.unwrap(),
+            assemble(&[
+                (LOAD_FAST, Some(0)),
+                (LOAD_FAST, Some(1)),
+                (NOP, None),
+                (BUILD_TUPLE, Some(2)),
+                (RETURN_VALUE, None),
+            ])
+        );
+
+        let reordered = reorder_function(&[
+            (LOAD_FAST, Some(1)),
+            (LOAD_FAST, Some(2)),
+            (LOAD_FAST, Some(0)),
+            (ROT_THREE, None),
+            (BUILD_TUPLE, Some(3)),
+            (RETURN_VALUE, None),
+        ]);
+        assert_eq!(
+            reordered.unwrap(),
+            assemble(&[
+                (LOAD_FAST, Some(0)),
+                (LOAD_FAST, Some(1)),
+                (LOAD_FAST, Some(2)),
+                (NOP, None),
+                (BUILD_TUPLE, Some(3)),
+                (RETURN_VALUE, None),
+            ])
+        );
+
+        let reordered = reorder_function(&[
+            (LOAD_FAST, Some(1)),
+            (LOAD_FAST, Some(2)),
+            (LOAD_FAST, Some(3)),
+            (LOAD_FAST, Some(0)),
+            (ROT_FOUR, None),
+            (BUILD_TUPLE, Some(4)),
+            (RETURN_VALUE, None),
+        ]);
+        assert_eq!(
+            reordered.unwrap(),
+            assemble(&[
+                (LOAD_FAST, Some(0)),
+                (LOAD_FAST, Some(1)),
+                (LOAD_FAST, Some(2)),
+                (LOAD_FAST, Some(3)),
+                (NOP, None),
+                (BUILD_TUPLE, Some(4)),
+                (RETURN_VALUE, None),
+            ])
+        );
+    }
+
+    #[test]
+    fn popped_constants_are_removed() {
+        let reordered = reorder_function(&[
+            (LOAD_FAST, Some(0)),
+            (LOAD_CONST, Some(0)),
+            (POP_TOP, None),
+            (RETURN_VALUE, None),
+        ]);
+
+        assert_eq!(
+            reordered.unwrap(),
+            assemble(&[
+                (LOAD_FAST, Some(0)),
+                (NOP, None),
+                (NOP, None),
+                (NOP, None),
+                (NOP, None),
+                (RETURN_VALUE, None),
+            ])
+        );
+    }
+
+    #[test]
+    fn swaps_which_are_stored_are_left_alone() {
+        let reordered = reorder_function(&[
+            (LOAD_FAST, Some(1)),
+            (LOAD_FAST, Some(0)),
+            (ROT_TWO, None),
+            (STORE_FAST, Some(0)),
+            (STORE_FAST, Some(1)),
+            (LOAD_CONST, Some(0)),
+            (RETURN_VALUE, None),
+        ]);
+
+        assert_eq!(reordered, None);
+    }
+
+    #[test]
+    fn loads_which_may_raise_keep_their_order() {
+        let reordered = reorder_function(&[
+            (LOAD_GLOBAL, Some(1)),
+            (LOAD_GLOBAL, Some(0)),
+            (ROT_TWO, None),
+            (BUILD_TUPLE, Some(2)),
+            (RETURN_VALUE, None),
+        ]);
+        assert_eq!(reordered, None);
+
+        // Constants and arguments can be moved past a global
+        let reordered = reorder_function(&[
+            (LOAD_GLOBAL, Some(0)),
+            (LOAD_FAST, Some(0)),
+            (ROT_TWO, None),
+            (BUILD_TUPLE, Some(2)),
+            (RETURN_VALUE, None),
+        ]);
+        assert_eq!(
+            reordered.unwrap(),
+            assemble(&[
+                (LOAD_FAST, Some(0)),
+                (LOAD_GLOBAL, Some(0)),
+                (NOP, None),
+                (BUILD_TUPLE, Some(2)),
+                (RETURN_VALUE, None),
+            ])
+        );
+
+        // Unless the argument is deleted somewhere
+        let reordered = reorder_function(&[
+            (LOAD_GLOBAL, Some(0)),
+            (LOAD_FAST, Some(0)),
+            (ROT_TWO, None),
+            (BUILD_TUPLE, Some(2)),
+            (DELETE_FAST, Some(0)),
+            (RETURN_VALUE, None),
+        ]);
+        assert_eq!(reordered, None);
+    }
+}
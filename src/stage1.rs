@@ -0,0 +1,256 @@
+use crate::bytecode::load_code;
+use crate::key_material::Stage1Key;
+use crate::profiles::Stage1Profile;
+use crate::unpack_b64_compressed_data;
+use log::debug;
+use py27_marshal::{Code, Obj};
+use std::fmt;
+use thiserror::Error;
+
+/// Why a string const was rejected as the stage1 key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRejection {
+    /// The XORed data is not base64-encoded zlib-compressed data
+    NotCompressed,
+    /// The decompressed data is not a marshalled code object
+    NotCode,
+}
+
+impl fmt::Display for KeyRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyRejection::NotCompressed => "not base64-encoded zlib data",
+            KeyRejection::NotCode => "not a marshalled code object",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Stage1Error {
+    #[error("the module has no code to decrypt")]
+    EmptyCode,
+    #[error("the module has no string consts to use as a key")]
+    NoStringConsts,
+    #[error("no string const is a valid key ({})", format_rejections(.0))]
+    NoValidKey(Vec<(usize, KeyRejection)>),
+}
+
+fn format_rejections(rejections: &[(usize, KeyRejection)]) -> String {
+    rejections
+        .iter()
+        .map(|(index, reason)| format!("const {}: {}", index, reason))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Decrypts and decompresses the stage2 payload, which is the module's code
+/// XORed with a key stored in its consts. Every string const is tried as the
+/// key, starting with the profile's `key-const-index`, and the first one which
+/// yields a valid code object is used. Returns the payload and the key used.
+pub fn decrypt(code: &Code, profile: &Stage1Profile) -> Result<(Vec<u8>, Stage1Key), Stage1Error> {
+    let consts: Vec<Option<&[u8]>> = code
+        .consts
+        .iter()
+        .map(|c| match c {
+            Obj::String(s) => Some(s.as_slice()),
+            _ => None,
+        })
+        .collect();
+
+    decrypt_parts(code.code.as_slice(), &consts, profile, |data| {
+        load_code(data).is_ok()
+    })
+}
+
+/// Like [`decrypt`], for code read from any marshal format. `consts` holds
+/// each const's bytes if it can be a key, and `is_code` checks whether
+/// decrypted data is a marshalled code object.
+pub fn decrypt_parts(
+    code: &[u8],
+    consts: &[Option<&[u8]>],
+    profile: &Stage1Profile,
+    is_code: impl Fn(&[u8]) -> bool,
+) -> Result<(Vec<u8>, Stage1Key), Stage1Error> {
+    if code.is_empty() {
+        return Err(Stage1Error::EmptyCode);
+    }
+
+    let hint = profile.key_const_index;
+    let candidates = hint
+        .into_iter()
+        .chain((0..consts.len()).filter(|index| Some(*index) != hint))
+        .filter_map(|index| match consts.get(index) {
+            Some(Some(key)) if !key.is_empty() => Some((index, *key)),
+            _ => None,
+        });
+
+    let mut rejections = Vec::new();
+    for (index, key) in candidates {
+        match try_key(code, key, &is_code) {
+            Ok(stage2) => {
+                debug!("Using the string at const {} as the stage1 key", index);
+                let key = Stage1Key {
+                    const_index: index,
+                    key: key.to_vec(),
+                };
+
+                return Ok((stage2, key));
+            }
+            Err(reason) => rejections.push((index, reason)),
+        }
+    }
+
+    if rejections.is_empty() {
+        Err(Stage1Error::NoStringConsts)
+    } else {
+        Err(Stage1Error::NoValidKey(rejections))
+    }
+}
+
+/// Decrypts `code` with `key` and checks that the result is a compressed,
+/// marshalled code object
+fn try_key(
+    code: &[u8],
+    key: &[u8],
+    is_code: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, KeyRejection> {
+    let decrypted: Vec<u8> = key
+        .iter()
+        .enumerate()
+        .map(|(i, k)| code[i % code.len()] ^ k)
+        .collect();
+
+    let stage2 = unpack_b64_compressed_data(decrypted.as_slice())
+        .map_err(|_| KeyRejection::NotCompressed)?;
+    if !is_code(stage2.as_slice()) {
+        return Err(KeyRejection::NotCode);
+    }
+
+    Ok(stage2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const CODE: &[u8] = b"\x13\x37\x42\x99";
+    const STAGE2: &[u8] = b"marshalled stage2";
+
+    fn profile(key_const_index: Option<usize>) -> Stage1Profile {
+        Stage1Profile {
+            encrypted_filename: "Lesta".to_string(),
+            key_const_index,
+        }
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        base64::encode(encoder.finish().unwrap()).into_bytes()
+    }
+
+    /// The key which decrypts `CODE` to `payload`
+    fn key_for(payload: &[u8]) -> Vec<u8> {
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ CODE[i % CODE.len()])
+            .collect()
+    }
+
+    fn decrypt(
+        consts: &[Option<&[u8]>],
+        hint: Option<usize>,
+    ) -> Result<(Vec<u8>, Stage1Key), Stage1Error> {
+        decrypt_parts(CODE, consts, &profile(hint), |data| data == STAGE2)
+    }
+
+    #[test]
+    fn the_key_const_hint_is_tried_first() {
+        let key = key_for(&compress(STAGE2));
+        let other = key_for(&compress(b"another stage"));
+        let consts = [Some(other.as_slice()), Some(key.as_slice())];
+
+        // Both keys yield code, but the hinted one wins
+        let (stage2, key_used) = decrypt_parts(CODE, &consts, &profile(Some(1)), |_| true).unwrap();
+        assert_eq!(stage2, STAGE2);
+        assert_eq!(key_used.const_index, 1);
+        assert_eq!(key_used.key, key);
+
+        let (_, key_used) = decrypt_parts(CODE, &consts, &profile(None), |_| true).unwrap();
+        assert_eq!(key_used.const_index, 0);
+    }
+
+    #[test]
+    fn other_string_consts_are_tried_when_the_hint_is_wrong() {
+        let key = key_for(&compress(STAGE2));
+        let consts = [
+            Some(&b"not a key"[..]),
+            None,
+            Some(b""),
+            Some(key.as_slice()),
+        ];
+
+        let (stage2, key_used) = decrypt(&consts, Some(0)).unwrap();
+        assert_eq!(stage2, STAGE2);
+        assert_eq!(key_used.const_index, 3);
+
+        // A hint past the end of the consts is ignored
+        assert_eq!(decrypt(&consts, Some(10)).unwrap().1.const_index, 3);
+    }
+
+    #[test]
+    fn every_rejected_key_is_listed() {
+        let wrong_code = key_for(&compress(b"another stage"));
+        let consts = [Some(&b"not a key"[..]), None, Some(wrong_code.as_slice())];
+
+        let err = decrypt(&consts, Some(2)).unwrap_err();
+        match &err {
+            Stage1Error::NoValidKey(rejections) => assert_eq!(
+                rejections,
+                &[(2, KeyRejection::NotCode), (0, KeyRejection::NotCompressed)]
+            ),
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(
+            err.to_string(),
+            "no string const is a valid key (const 2: not a marshalled code object, const 0: not base64-encoded zlib data)"
+        );
+    }
+
+    #[test]
+    fn modules_without_code_or_string_consts_are_rejected() {
+        let key = key_for(&compress(STAGE2));
+        assert!(matches!(
+            decrypt_parts(b"", &[Some(key.as_slice())], &profile(None), |_| true),
+            Err(Stage1Error::EmptyCode)
+        ));
+        assert!(matches!(
+            decrypt(&[None, Some(b"")], None),
+            Err(Stage1Error::NoStringConsts)
+        ));
+    }
+
+    #[test]
+    fn fixture_modules_decrypt_with_the_builtin_profile() {
+        let profiles = crate::profiles::ProfileSet::builtin();
+        let profile = &profiles.profiles[0].stage1;
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test_data/obfuscated/compiler/ast.pyc"
+        );
+        let data = std::fs::read(path).unwrap();
+        let code = load_code(&data[8..]).unwrap();
+        assert_eq!(
+            code.filename.as_slice(),
+            profile.encrypted_filename.as_bytes()
+        );
+
+        let (stage2, key) = super::decrypt(&code, profile).unwrap();
+        assert_eq!(Some(key.const_index), profile.key_const_index);
+        assert!(load_code(&stage2).is_ok());
+    }
+}
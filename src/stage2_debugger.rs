@@ -0,0 +1,343 @@
+use crate::bytecode::mnemonic_from_name;
+use crate::smallvm::{Stage2Observer, Stage2Step, VmState};
+use crate::stage2_trace::summarize;
+use anyhow::Result;
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use std::io::{BufRead, Write};
+use thiserror::Error;
+
+const HELP: &str = "\
+commands:
+  s, step [n]         execute the next n instructions (default 1)
+  c, continue         run until a breakpoint is hit
+  b, break <target>   break at an offset (decimal or 0x-prefixed hex) or an opcode
+  d, delete <target>  remove a breakpoint
+  bl, breakpoints     list breakpoints
+  i, info             show the current instruction
+  stack               show the VM stack, top last
+  vars                show the VM's fast locals
+  names               show the VM's names, globals, and the names loaded so far
+  q, quit             stop unpacking
+  h, help             show this message";
+
+#[derive(Error, Debug)]
+#[error("stopped by the debugger")]
+pub struct DebuggerQuit;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Breakpoint {
+    Offset(u64),
+    Opcode(Mnemonic),
+}
+
+impl Breakpoint {
+    fn parse(s: &str) -> Option<Breakpoint> {
+        let offset = match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+
+        offset
+            .map(Breakpoint::Offset)
+            .or_else(|| mnemonic_from_name(&s.to_ascii_uppercase()).map(Breakpoint::Opcode))
+    }
+
+    fn hit<O: Opcode<Mnemonic = Mnemonic>>(&self, step: &Stage2Step<'_, O>) -> bool {
+        match self {
+            Breakpoint::Offset(offset) => step.offset == *offset,
+            Breakpoint::Opcode(mnemonic) => step.instr.opcode.mnemonic() == *mnemonic,
+        }
+    }
+}
+
+/// Interactive debugger for stage2 unpacking. Commands are read from `input`
+/// whenever execution stops, and output is written to `output`.
+pub struct Debugger<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    breakpoints: Vec<Breakpoint>,
+    /// Instructions left to execute before stopping. `None` runs until a
+    /// breakpoint is hit.
+    steps_left: Option<usize>,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    /// Creates a debugger which stops before the first instruction
+    pub fn new(input: R, output: W) -> Debugger<R, W> {
+        Debugger {
+            input,
+            output,
+            breakpoints: Vec::new(),
+            steps_left: Some(0),
+        }
+    }
+
+    fn should_stop<O: Opcode<Mnemonic = Mnemonic>>(&mut self, step: &Stage2Step<'_, O>) -> bool {
+        if self.breakpoints.iter().any(|bp| bp.hit(step)) {
+            return true;
+        }
+
+        match &mut self.steps_left {
+            Some(0) => true,
+            Some(n) => {
+                *n -= 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn show_instruction<O: Opcode<Mnemonic = Mnemonic>>(
+        &mut self,
+        step: &Stage2Step<'_, O>,
+    ) -> Result<()> {
+        write!(
+            self.output,
+            "[{}] {}: {:?}",
+            step.phase,
+            step.offset,
+            step.instr.opcode.mnemonic()
+        )?;
+        if let Some(arg) = step.instr.arg {
+            write!(self.output, " {}", arg)?;
+        }
+        writeln!(self.output)?;
+
+        Ok(())
+    }
+
+    /// Handles a command. Returns `true` if execution should resume.
+    fn command<O: Opcode<Mnemonic = Mnemonic>>(
+        &mut self,
+        line: &str,
+        step: &Stage2Step<'_, O>,
+    ) -> Result<bool> {
+        let mut parts = line.split_whitespace();
+        let command = match parts.next() {
+            Some(command) => command,
+            None => return Ok(false),
+        };
+        let arg = parts.next();
+
+        match command {
+            "s" | "step" => {
+                let count = match arg.map(str::parse::<usize>) {
+                    Some(Ok(count)) if count > 0 => count,
+                    Some(_) => {
+                        writeln!(self.output, "step count must be a positive number")?;
+                        return Ok(false);
+                    }
+                    None => 1,
+                };
+                // This instruction counts as the first step
+                self.steps_left = Some(count - 1);
+                return Ok(true);
+            }
+            "c" | "continue" => {
+                self.steps_left = None;
+                return Ok(true);
+            }
+            "b" | "break" | "d" | "delete" => match arg.and_then(Breakpoint::parse) {
+                Some(bp) if command.starts_with('b') => {
+                    if !self.breakpoints.contains(&bp) {
+                        self.breakpoints.push(bp);
+                    }
+                }
+                Some(bp) => self.breakpoints.retain(|existing| *existing != bp),
+                None => writeln!(self.output, "expected an offset or opcode name")?,
+            },
+            "bl" | "breakpoints" => {
+                for bp in &self.breakpoints {
+                    match bp {
+                        Breakpoint::Offset(offset) => writeln!(self.output, "offset {}", offset)?,
+                        Breakpoint::Opcode(mnemonic) => {
+                            writeln!(self.output, "opcode {:?}", mnemonic)?
+                        }
+                    }
+                }
+            }
+            "i" | "info" => self.show_instruction(step)?,
+            "stack" | "vars" | "names" => match &step.vm {
+                Some(vm) => self.show_vm(command, vm)?,
+                None => writeln!(
+                    self.output,
+                    "the VM has not started yet (phase `{}`)",
+                    step.phase
+                )?,
+            },
+            "q" | "quit" => return Err(DebuggerQuit.into()),
+            "h" | "help" => writeln!(self.output, "{}", HELP)?,
+            other => writeln!(self.output, "unknown command `{}`. Try `help`", other)?,
+        }
+
+        Ok(false)
+    }
+
+    fn show_vm(&mut self, what: &str, vm: &VmState<'_>) -> Result<()> {
+        match what {
+            "stack" => {
+                for (depth, (value, _)) in vm.stack.iter().enumerate() {
+                    writeln!(self.output, "{:>4}: {}", depth, summarize(value))?;
+                }
+            }
+            "vars" => {
+                let mut vars: Vec<_> = vm.vars.iter().collect();
+                vars.sort_by_key(|(index, _)| **index);
+                for (index, (value, _)) in vars {
+                    writeln!(self.output, "{:>4}: {}", index, summarize(value))?;
+                }
+            }
+            _ => {
+                for (title, names) in [("names", vm.names), ("globals", vm.globals)] {
+                    let mut names: Vec<_> = names.iter().collect();
+                    names.sort_by_key(|(name, _)| *name);
+                    writeln!(self.output, "{}:", title)?;
+                    for (name, (value, _)) in names {
+                        writeln!(self.output, "  {}: {}", name, summarize(value))?;
+                    }
+                }
+
+                let loaded = vm.names_loaded.lock().unwrap();
+                let loaded: Vec<String> = loaded.iter().map(|name| name.to_string()).collect();
+                writeln!(self.output, "loaded: {}", loaded.join(", "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: BufRead, W: Write, O: Opcode<Mnemonic = Mnemonic>> Stage2Observer<O> for Debugger<R, W> {
+    fn instruction(&mut self, step: &Stage2Step<'_, O>) -> Result<()> {
+        if !self.should_stop(step) {
+            return Ok(());
+        }
+
+        self.show_instruction(step)?;
+        loop {
+            write!(self.output, "(stage2) ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                // End of input
+                return Err(DebuggerQuit.into());
+            }
+
+            if self.command(line.trim(), step)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::load_code;
+    use crate::profiles::ProfileSet;
+    use crate::smallvm::{exec_stage2, Stage2Mode};
+    use pydis::opcode::py27::Standard;
+    use std::io::Cursor;
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test_data/obfuscated/compiler/ast.pyc"
+    );
+
+    /// Unpacks stage2 of the fixture module in the VM under a debugger fed
+    /// `script`. Returns whether unpacking succeeded and the debugger's output.
+    fn debug(script: &str) -> (Result<()>, String) {
+        let profile = &ProfileSet::builtin().profiles[0];
+        let data = std::fs::read(FIXTURE).unwrap();
+        let stage1 = load_code(&data[8..]).unwrap();
+        let stage2 =
+            load_code(&crate::stage1::decrypt(&stage1, &profile.stage1).unwrap().0).unwrap();
+
+        let mut debugger = Debugger::new(Cursor::new(script.as_bytes()), Vec::new());
+        let result = exec_stage2::<Standard>(
+            stage2,
+            stage1,
+            &profile.stage2,
+            Stage2Mode::Vm,
+            Some(&mut debugger),
+        )
+        .map(|_| ());
+
+        (result, String::from_utf8(debugger.output).unwrap())
+    }
+
+    /// Splits the debugger's output into what was printed after each prompt
+    fn responses(output: &str) -> Vec<&str> {
+        output.split("(stage2) ").collect()
+    }
+
+    #[test]
+    fn steps_and_breakpoints_stop_at_the_fixture_instructions() {
+        let (result, output) = debug(
+            "stack\n\
+             step 3\n\
+             break 0x12b\n\
+             break BINARY_RSHIFT\n\
+             breakpoints\n\
+             continue\n\
+             stack\n\
+             delete 299\n\
+             continue\n\
+             delete binary_rshift\n\
+             continue\n",
+        );
+        result.unwrap();
+
+        let responses = responses(&output);
+        assert_eq!(
+            responses[..6],
+            [
+                "[swapmap-function] 0: LOAD_CONST 1\n",
+                "the VM has not started yet (phase `swapmap-function`)\n",
+                "[swapmap-function] 9: STORE_FAST 0\n",
+                "",
+                "",
+                "offset 299\nopcode BINARY_RSHIFT\n",
+            ]
+        );
+
+        // The decode loop starts with the empty output below the payload
+        assert_eq!(responses[6], "[vm] 299: FOR_ITER 62\n");
+        let stack: Vec<&str> = responses[7].lines().collect();
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0], "   0: str len=0 ''");
+        assert!(
+            stack[1].starts_with("   1: str len=35384 '\\x1c\\xfe"),
+            "{}",
+            stack[1]
+        );
+
+        // The offset breakpoint is gone, so the opcode breakpoint is hit next
+        assert_eq!(responses[8..], ["", "[vm] 329: BINARY_RSHIFT\n", "", ""]);
+    }
+
+    #[test]
+    fn quitting_stops_unpacking() {
+        let (result, output) = debug("step 0\nbogus\nquit\n");
+
+        assert!(result.unwrap_err().downcast_ref::<DebuggerQuit>().is_some());
+        assert_eq!(
+            responses(&output),
+            [
+                "[swapmap-function] 0: LOAD_CONST 1\n",
+                "step count must be a positive number\n",
+                "unknown command `bogus`. Try `help`\n",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn end_of_input_stops_unpacking() {
+        let (result, _) = debug("");
+
+        assert!(result.unwrap_err().downcast_ref::<DebuggerQuit>().is_some());
+    }
+}
@@ -0,0 +1,416 @@
+use crate::bytecode::{decode_at, DecodedInstr};
+use log::debug;
+use num_traits::ToPrimitive;
+use py27_marshal::{Code, Obj};
+use pydis::opcode::py27::Mnemonic;
+use pydis::prelude::*;
+
+/// Maximum number of instructions evaluated for a single byte before the loop
+/// body is considered unrecognized
+const MAX_STEPS: usize = 1024;
+
+/// A value on the evaluation stack
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Value {
+    Int(i64),
+    /// The `chr` builtin
+    Chr,
+    /// A single-character string returned by `chr`
+    Char(u8),
+}
+
+/// Maps each input byte of the decode loop to the byte it appends to the
+/// output
+pub type DecodeTable = [u8; 256];
+
+/// Recognizes the stage2 decode loop, which looks like
+/// `[chr(f(byte)) for byte in data]` where `f` only uses integer constants and
+/// arithmetic, and evaluates its body for every possible input byte.
+///
+/// `start` is the offset of the loop's `FOR_ITER` instruction, or of a chain of
+/// unconditional jumps leading to it. Returns `None` if the loop body does
+/// anything else, in which case the loop must be emulated instead.
+pub fn decode_table<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+    start: u64,
+) -> Option<DecodeTable> {
+    let (for_iter, loop_var) = find_loop_header::<O>(code, start)?;
+
+    let mut table = [0u8; 256];
+    for (byte, entry) in table.iter_mut().enumerate() {
+        *entry = eval_body::<O>(code, for_iter, loop_var, byte as i64)?;
+    }
+
+    Some(table)
+}
+
+/// Runs `table` over the swapmapped stage2 data. The loader joins the decoded
+/// bytes back to front, so the output is reversed.
+pub fn apply_table(table: &DecodeTable, data: &[u8]) -> Vec<u8> {
+    data.iter().rev().map(|b| table[*b as usize]).collect()
+}
+
+/// Returns the offset of the loop's `FOR_ITER` and the index of the loop
+/// variable it stores to
+fn find_loop_header<O: Opcode<Mnemonic = Mnemonic>>(code: &Code, start: u64) -> Option<(u64, u32)> {
+    let mut offset = start;
+    for _ in 0..MAX_STEPS {
+        let instr = decode::<O>(code, offset)?;
+        match instr.opcode.mnemonic() {
+            Mnemonic::FOR_ITER => {
+                let store = decode::<O>(code, instr.next_offset())?;
+                if store.opcode.mnemonic() != Mnemonic::STORE_FAST {
+                    debug!("decode loop does not store to a local");
+                    return None;
+                }
+
+                return Some((instr.offset, store.arg?));
+            }
+            Mnemonic::JUMP_ABSOLUTE | Mnemonic::JUMP_FORWARD => offset = instr.jump_target()?,
+            Mnemonic::NOP => offset = instr.next_offset(),
+            other => {
+                debug!(
+                    "expected decode loop to start with FOR_ITER, found {:?}",
+                    other
+                );
+                return None;
+            }
+        }
+    }
+
+    None
+}
+
+/// Evaluates one iteration of the loop body with the loop variable set to
+/// `byte`, returning the byte appended to the output
+fn eval_body<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+    for_iter: u64,
+    loop_var: u32,
+    byte: i64,
+) -> Option<u8> {
+    let mut stack: Vec<Value> = Vec::new();
+    // Skip the FOR_ITER and the STORE_FAST of the loop variable
+    let mut offset = decode::<O>(code, decode::<O>(code, for_iter)?.next_offset())?.next_offset();
+
+    for _ in 0..MAX_STEPS {
+        let instr = decode::<O>(code, offset)?;
+        let mut next = instr.next_offset();
+
+        match instr.opcode.mnemonic() {
+            Mnemonic::NOP => {}
+            Mnemonic::LOAD_FAST if instr.arg? == loop_var => stack.push(Value::Int(byte)),
+            Mnemonic::LOAD_CONST => stack.push(const_value(code, instr.arg? as usize)?),
+            Mnemonic::LOAD_NAME | Mnemonic::LOAD_GLOBAL => {
+                if code.names.get(instr.arg? as usize)?.as_slice() != b"chr" {
+                    return None;
+                }
+                stack.push(Value::Chr);
+            }
+            Mnemonic::POP_TOP => {
+                stack.pop()?;
+            }
+            Mnemonic::DUP_TOP => stack.push(*stack.last()?),
+            Mnemonic::ROT_TWO => {
+                let len = stack.len();
+                if len < 2 {
+                    return None;
+                }
+                stack.swap(len - 1, len - 2);
+            }
+            Mnemonic::ROT_THREE => {
+                let top = stack.pop()?;
+                let len = stack.len();
+                if len < 2 {
+                    return None;
+                }
+                stack.insert(len - 2, top);
+            }
+            Mnemonic::UNARY_NEGATIVE => {
+                let value = pop_int(&mut stack)?;
+                stack.push(Value::Int(value.checked_neg()?));
+            }
+            Mnemonic::UNARY_INVERT => {
+                let value = pop_int(&mut stack)?;
+                stack.push(Value::Int(!value));
+            }
+            Mnemonic::UNARY_NOT => {
+                let value = pop_int(&mut stack)?;
+                stack.push(Value::Int((value == 0) as i64));
+            }
+            Mnemonic::COMPARE_OP => {
+                let right = pop_int(&mut stack)?;
+                let left = pop_int(&mut stack)?;
+                let result = match instr.arg? {
+                    0 => left < right,
+                    1 => left <= right,
+                    2 => left == right,
+                    3 => left != right,
+                    4 => left > right,
+                    5 => left >= right,
+                    _ => return None,
+                };
+                stack.push(Value::Int(result as i64));
+            }
+            Mnemonic::POP_JUMP_IF_TRUE | Mnemonic::POP_JUMP_IF_FALSE => {
+                let value = pop_int(&mut stack)? != 0;
+                if value == (instr.opcode.mnemonic() == Mnemonic::POP_JUMP_IF_TRUE) {
+                    next = instr.jump_target()?;
+                }
+            }
+            Mnemonic::JUMP_ABSOLUTE | Mnemonic::JUMP_FORWARD => {
+                next = instr.jump_target()?;
+                // Jumping back to the loop header before appending anything
+                // means this isn't the loop we know how to decode
+                if next == for_iter {
+                    return None;
+                }
+            }
+            Mnemonic::CALL_FUNCTION => {
+                if instr.arg? != 1 {
+                    return None;
+                }
+                let value = pop_int(&mut stack)?;
+                if stack.pop()? != Value::Chr {
+                    return None;
+                }
+                // chr() raises a ValueError for anything outside of this range
+                stack.push(Value::Char(u8::try_from(value).ok()?));
+            }
+            Mnemonic::LIST_APPEND => {
+                return match (stack.pop()?, stack.is_empty()) {
+                    (Value::Char(c), true) => Some(c),
+                    _ => None,
+                };
+            }
+            mnemonic => {
+                let right = pop_int(&mut stack)?;
+                let left = pop_int(&mut stack)?;
+                stack.push(Value::Int(binary_op(mnemonic, left, right)?));
+            }
+        }
+
+        offset = next;
+    }
+
+    None
+}
+
+/// Evaluates a binary operator with Python's semantics for ints. Returns
+/// `None` for unsupported operators.
+fn binary_op(mnemonic: Mnemonic, left: i64, right: i64) -> Option<i64> {
+    use Mnemonic::*;
+
+    let result = match mnemonic {
+        BINARY_XOR | INPLACE_XOR => left ^ right,
+        BINARY_AND | INPLACE_AND => left & right,
+        BINARY_OR | INPLACE_OR => left | right,
+        BINARY_ADD | INPLACE_ADD => left.checked_add(right)?,
+        BINARY_SUBTRACT | INPLACE_SUBTRACT => left.checked_sub(right)?,
+        BINARY_MULTIPLY | INPLACE_MULTIPLY => left.checked_mul(right)?,
+        BINARY_LSHIFT | INPLACE_LSHIFT => {
+            if !(0..32).contains(&right) {
+                return None;
+            }
+            left.checked_shl(right as u32)?
+        }
+        BINARY_RSHIFT | INPLACE_RSHIFT => {
+            if right < 0 {
+                return None;
+            }
+            left >> right.min(63)
+        }
+        BINARY_MODULO | INPLACE_MODULO => {
+            left.checked_sub(floor_div(left, right)?.checked_mul(right)?)?
+        }
+        BINARY_FLOOR_DIVIDE | INPLACE_FLOOR_DIVIDE => floor_div(left, right)?,
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// Integer division rounding towards negative infinity, like Python's `//`
+fn floor_div(left: i64, right: i64) -> Option<i64> {
+    let quotient = left.checked_div(right)?;
+    if left % right != 0 && (left < 0) != (right < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+fn pop_int(stack: &mut Vec<Value>) -> Option<i64> {
+    match stack.pop()? {
+        Value::Int(value) => Some(value),
+        Value::Chr | Value::Char(_) => None,
+    }
+}
+
+fn const_value(code: &Code, index: usize) -> Option<Value> {
+    match code.consts.get(index)? {
+        Obj::Long(value) => value.to_i64().map(Value::Int),
+        Obj::Bool(value) => Some(Value::Int(*value as i64)),
+        _ => None,
+    }
+}
+
+fn decode<O: Opcode<Mnemonic = Mnemonic>>(code: &Code, offset: u64) -> Option<DecodedInstr<O>> {
+    if offset as usize >= code.code.len() {
+        return None;
+    }
+
+    decode_at(code.code.as_slice(), offset).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::load_code;
+    use crate::bytecode::testing::{self, assemble};
+    use crate::profiles::ProfileSet;
+    use crate::smallvm::{exec_stage2, Stage2Mode};
+    use num_bigint::BigInt;
+    use pydis::opcode::py27::Standard;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/obfuscated/compiler");
+
+    fn make_code(instrs: &[(Standard, Option<u16>)], consts: Vec<i64>, names: &[&str]) -> Code {
+        let consts = consts
+            .into_iter()
+            .map(|c| Obj::Long(Arc::new(BigInt::from(c))))
+            .collect();
+        testing::make_code(assemble(instrs), consts, names, &["byte"])
+    }
+
+    #[test]
+    fn decode_table_evaluates_loop_body() {
+        use Standard::*;
+
+        // for byte in data: out.append(chr((byte ^ 38) + 3 & 255))
+        let code = make_code(
+            &[
+                (FOR_ITER, Some(25)),
+                (STORE_FAST, Some(0)),
+                (LOAD_NAME, Some(0)),
+                (LOAD_FAST, Some(0)),
+                (LOAD_CONST, Some(0)),
+                (BINARY_XOR, None),
+                (LOAD_CONST, Some(1)),
+                (BINARY_ADD, None),
+                (LOAD_CONST, Some(2)),
+                (BINARY_AND, None),
+                (CALL_FUNCTION, Some(1)),
+                (LIST_APPEND, Some(2)),
+                (JUMP_ABSOLUTE, Some(0)),
+            ],
+            vec![38, 3, 255],
+            &["chr"],
+        );
+
+        let table = decode_table::<Standard>(&code, 0).expect("loop was not recognized");
+        for byte in 0..=255u8 {
+            assert_eq!(table[byte as usize], ((byte ^ 38) as u16 + 3) as u8);
+        }
+
+        assert_eq!(apply_table(&table, &[0, 1]), vec![table[1], table[0]]);
+    }
+
+    #[test]
+    fn decode_table_rejects_unknown_calls() {
+        use Standard::*;
+
+        let code = make_code(
+            &[
+                (FOR_ITER, Some(13)),
+                (STORE_FAST, Some(0)),
+                (LOAD_NAME, Some(0)),
+                (LOAD_FAST, Some(0)),
+                (CALL_FUNCTION, Some(1)),
+                (LIST_APPEND, Some(2)),
+                (JUMP_ABSOLUTE, Some(0)),
+            ],
+            vec![],
+            &["ord"],
+        );
+
+        assert!(decode_table::<Standard>(&code, 0).is_none());
+    }
+
+    #[test]
+    fn decode_table_rejects_out_of_range_chr() {
+        use Standard::*;
+
+        // chr(byte + 1) raises a ValueError for byte 255
+        let code = make_code(
+            &[
+                (FOR_ITER, Some(17)),
+                (STORE_FAST, Some(0)),
+                (LOAD_NAME, Some(0)),
+                (LOAD_FAST, Some(0)),
+                (LOAD_CONST, Some(0)),
+                (BINARY_ADD, None),
+                (CALL_FUNCTION, Some(1)),
+                (LIST_APPEND, Some(2)),
+                (JUMP_ABSOLUTE, Some(0)),
+            ],
+            vec![1],
+            &["chr"],
+        );
+
+        assert!(decode_table::<Standard>(&code, 0).is_none());
+    }
+
+    #[test]
+    fn binary_ops_follow_python_semantics() {
+        assert_eq!(binary_op(Mnemonic::BINARY_MODULO, -7, 2), Some(1));
+        assert_eq!(binary_op(Mnemonic::BINARY_MODULO, 7, -2), Some(-1));
+        assert_eq!(binary_op(Mnemonic::BINARY_FLOOR_DIVIDE, -7, 2), Some(-4));
+        assert_eq!(binary_op(Mnemonic::BINARY_FLOOR_DIVIDE, 7, -2), Some(-4));
+        assert_eq!(binary_op(Mnemonic::BINARY_MODULO, 7, 0), None);
+        assert_eq!(binary_op(Mnemonic::BINARY_RSHIFT, -1, 100), Some(-1));
+    }
+
+    /// Static decoding must produce exactly what the VM produces for every
+    /// encrypted module in the test data
+    #[test]
+    fn static_decoding_matches_vm() {
+        let profiles = ProfileSet::builtin();
+        let profile = &profiles.profiles[0];
+
+        let mut checked = 0;
+        for entry in std::fs::read_dir(Path::new(FIXTURE_DIR)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("pyc") {
+                continue;
+            }
+
+            let data = std::fs::read(&path).unwrap();
+            let stage1 = load_code(&data[8..]).unwrap();
+            if stage1.filename.as_slice() != profile.stage1.encrypted_filename.as_bytes() {
+                continue;
+            }
+
+            let stage2 =
+                load_code(&crate::stage1::decrypt(&stage1, &profile.stage1).unwrap().0).unwrap();
+            let run = |mode| {
+                exec_stage2::<Standard>(
+                    Arc::clone(&stage2),
+                    Arc::clone(&stage1),
+                    &profile.stage2,
+                    mode,
+                    None,
+                )
+                .unwrap()
+                .payload
+            };
+
+            assert_eq!(run(Stage2Mode::Static), run(Stage2Mode::Vm), "{:?}", path);
+            checked += 1;
+        }
+
+        assert!(checked > 0, "no encrypted fixtures found");
+    }
+}
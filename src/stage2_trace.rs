@@ -0,0 +1,240 @@
+use crate::smallvm::{Stage2Observer, Stage2Step};
+use anyhow::Result;
+use py27_marshal::Obj;
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use serde::Serialize;
+use std::io::Write;
+use unfuck::smallvm::VmVar;
+
+/// Longest string shown in a stack summary before it is truncated
+const MAX_SUMMARY_STRING_LEN: usize = 32;
+
+/// A single line of a stage2 trace
+#[derive(Debug, Serialize)]
+struct TraceLine<'a> {
+    offset: u64,
+    opcode: String,
+    arg: Option<u32>,
+    phase: &'a str,
+    /// Depth of the VM's stack before the instruction executes. `None` until
+    /// the VM starts running, and throughout if the decode loop is decoded
+    /// statically instead.
+    stack_depth: Option<usize>,
+    /// Summary of the value on top of the VM's stack
+    top: Option<String>,
+}
+
+/// Records every instruction processed while unpacking stage2 as JSON Lines
+pub struct TraceRecorder<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TraceRecorder<W> {
+    pub fn new(writer: W) -> TraceRecorder<W> {
+        TraceRecorder { writer }
+    }
+
+    /// Flushes the trace and returns the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write, O: Opcode<Mnemonic = Mnemonic>> Stage2Observer<O> for TraceRecorder<W> {
+    fn instruction(&mut self, step: &Stage2Step<'_, O>) -> Result<()> {
+        let line = TraceLine {
+            offset: step.offset,
+            opcode: format!("{:?}", step.instr.opcode.mnemonic()),
+            arg: step.instr.arg,
+            phase: step.phase,
+            stack_depth: step.vm.as_ref().map(|vm| vm.stack.len()),
+            top: step
+                .vm
+                .as_ref()
+                .and_then(|vm| vm.stack.last())
+                .map(|(value, _)| summarize(value)),
+        };
+
+        serde_json::to_writer(&mut self.writer, &line)?;
+        self.writer.write_all(b"\n")?;
+
+        Ok(())
+    }
+}
+
+/// Returns a short, stable description of a VM value
+pub fn summarize(value: &VmVar) -> String {
+    let obj = match value {
+        Some(obj) => obj,
+        None => return "<unknown>".to_string(),
+    };
+
+    match obj {
+        Obj::Long(value) => format!("int {}", value),
+        Obj::Bool(value) => format!("bool {}", value),
+        Obj::Float(value) => format!("float {}", value),
+        Obj::String(s) => summarize_string("str", s.as_slice()),
+        Obj::Bytes(s) => summarize_string("bytes", s.as_slice()),
+        Obj::Tuple(items) => format!("tuple len={}", items.len()),
+        Obj::List(items) => format!("list len={}", items.read().unwrap().len()),
+        Obj::Dict(items) => format!("dict len={}", items.read().unwrap().len()),
+        Obj::Set(items) => format!("set len={}", items.read().unwrap().len()),
+        Obj::FrozenSet(items) => format!("frozenset len={}", items.len()),
+        Obj::Code(code) => format!("code {}", code.name),
+        other => format!("{:?}", other.typ()),
+    }
+}
+
+fn summarize_string(kind: &str, s: &[u8]) -> String {
+    let shown = &s[..s.len().min(MAX_SUMMARY_STRING_LEN)];
+    let escaped: String = shown
+        .iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect();
+    format!(
+        "{} len={} '{}'{}",
+        kind,
+        s.len(),
+        escaped,
+        if shown.len() < s.len() { "..." } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::load_code;
+    use crate::profiles::ProfileSet;
+    use crate::smallvm::{exec_stage2, Stage2Mode};
+    use num_bigint::BigInt;
+    use py27_marshal::bstr::BString;
+    use pydis::opcode::py27::Standard;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test_data/obfuscated/compiler/ast.pyc"
+    );
+
+    /// Traces unpacking stage2 of the fixture module and returns each line
+    fn trace(mode: Stage2Mode) -> Vec<String> {
+        let profile = &ProfileSet::builtin().profiles[0];
+        let data = std::fs::read(FIXTURE).unwrap();
+        let stage1 = load_code(&data[8..]).unwrap();
+        let stage2 =
+            load_code(&crate::stage1::decrypt(&stage1, &profile.stage1).unwrap().0).unwrap();
+
+        let mut recorder = TraceRecorder::new(Vec::new());
+        exec_stage2::<Standard>(stage2, stage1, &profile.stage2, mode, Some(&mut recorder))
+            .unwrap();
+        let output = recorder.finish().unwrap();
+
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn parse(line: &str) -> Value {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn lines_record_each_instruction() {
+        let lines = trace(Stage2Mode::Auto);
+
+        assert_eq!(
+            lines[0],
+            r#"{"offset":0,"opcode":"LOAD_CONST","arg":1,"phase":"swapmap-function","stack_depth":null,"top":null}"#
+        );
+
+        for line in &lines {
+            let line = parse(line);
+            let fields: Vec<&str> = line
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(String::as_str)
+                .collect();
+            assert_eq!(
+                fields,
+                ["arg", "offset", "opcode", "phase", "stack_depth", "top"]
+            );
+
+            assert!(line["offset"].is_u64());
+            assert!(line["opcode"].is_string());
+            assert!(line["arg"].is_u64() || line["arg"].is_null());
+        }
+    }
+
+    #[test]
+    fn static_traces_end_at_the_decode_loop() {
+        let lines: Vec<Value> = trace(Stage2Mode::Auto)
+            .iter()
+            .map(|line| parse(line))
+            .collect();
+
+        assert_eq!(lines.last().unwrap()["phase"], "decode-loop");
+        for line in &lines {
+            assert_ne!(line["phase"], "vm");
+            assert!(line["stack_depth"].is_null(), "{}", line);
+            assert!(line["top"].is_null(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn vm_traces_record_the_stack() {
+        let lines: Vec<Value> = trace(Stage2Mode::Vm)
+            .iter()
+            .map(|line| parse(line))
+            .collect();
+
+        let first_vm_line = lines
+            .iter()
+            .position(|line| line["phase"] == "vm")
+            .expect("the VM never ran");
+        for line in &lines[..first_vm_line] {
+            assert!(line["stack_depth"].is_null(), "{}", line);
+            assert!(line["top"].is_null(), "{}", line);
+        }
+
+        // The VM starts with the output and the reversed payload on its stack
+        let line = &lines[first_vm_line];
+        assert_eq!(line["stack_depth"], 2);
+        assert!(
+            line["top"].as_str().unwrap().starts_with("str len="),
+            "{}",
+            line
+        );
+
+        for line in &lines[first_vm_line..] {
+            assert_eq!(line["phase"], "vm");
+            assert!(line["stack_depth"].is_u64(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn summaries_are_short() {
+        assert_eq!(summarize(&None), "<unknown>");
+        assert_eq!(
+            summarize(&Some(Obj::Long(Arc::new(BigInt::from(-3))))),
+            "int -3"
+        );
+        assert_eq!(
+            summarize(&Some(Obj::String(Arc::new(BString::from("a\n"))))),
+            "str len=2 'a\\n'"
+        );
+
+        let long = Obj::Bytes(Arc::new(vec![b'x'; MAX_SUMMARY_STRING_LEN + 1]));
+        assert_eq!(
+            summarize(&Some(long)),
+            format!("bytes len=33 '{}'...", "x".repeat(MAX_SUMMARY_STRING_LEN))
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use crate::cfg_diff::{paired_code_objects, CfgDiff, InstrFate};
+use crate::config::PassConfig;
+use crate::differential::DifferentialReport;
+use crate::tricks::FunctionTricks;
+use crate::validate::ValidationIssue;
+use anyhow::Result;
+use pydis::opcode::py27;
+use pydis::opcode::Opcode;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Name of the run manifest written to the output directory
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+/// Name of the archive-wide statistics CSV written to the output directory
+pub const STATS_FILE_NAME: &str = "stats.csv";
+
+/// Instruction counts comparing code before and after deobfuscation. Only
+/// instructions reachable from a function's entry point are counted, so junk
+/// bytes which are never executed are not. Every original instruction is
+/// either kept or counted in exactly one of the `*_removed` categories, and
+/// every deobfuscated instruction is either kept or added, so
+/// `instructions_before - instructions_after` always equals
+/// `predicate_instructions_removed + jumps_removed +
+/// garbage_instructions_removed - instructions_added`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InstructionCounts {
+    pub instructions_before: usize,
+    pub instructions_after: usize,
+    /// Conditional jumps which were folded
+    pub const_predicates_removed: usize,
+    /// The folded conditional jumps along with the `LOAD_CONST`s their
+    /// conditions were loaded from
+    pub predicate_instructions_removed: usize,
+    /// Unconditional jumps removed when the blocks around them were merged
+    pub jumps_removed: usize,
+    /// Every other instruction which was removed
+    pub garbage_instructions_removed: usize,
+    /// Instructions in the deobfuscated code which don't correspond to any
+    /// original instruction
+    pub instructions_added: usize,
+    /// `RETURN_VALUE` instructions added to undo jump-to-return optimizations.
+    /// These are included in `instructions_added`.
+    pub returns_deoptimized: usize,
+}
+
+impl InstructionCounts {
+    fn of<O: Opcode<Mnemonic = py27::Mnemonic>>(diff: &CfgDiff<O>) -> InstructionCounts {
+        InstructionCounts {
+            instructions_before: diff.instructions_before(),
+            instructions_after: diff.instructions_after(),
+            const_predicates_removed: diff.const_predicates_removed(),
+            predicate_instructions_removed: diff.count(InstrFate::ConstPredicate),
+            jumps_removed: diff.count(InstrFate::Jump),
+            garbage_instructions_removed: diff.count(InstrFate::Garbage),
+            instructions_added: diff.instructions_added(),
+            returns_deoptimized: diff.returns_added(),
+        }
+    }
+
+    fn add(&mut self, other: &InstructionCounts) {
+        self.instructions_before += other.instructions_before;
+        self.instructions_after += other.instructions_after;
+        self.const_predicates_removed += other.const_predicates_removed;
+        self.predicate_instructions_removed += other.predicate_instructions_removed;
+        self.jumps_removed += other.jumps_removed;
+        self.garbage_instructions_removed += other.garbage_instructions_removed;
+        self.instructions_added += other.instructions_added;
+        self.returns_deoptimized += other.returns_deoptimized;
+    }
+}
+
+/// Deobfuscation metrics for a single function
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FunctionStats {
+    /// Position of the function in the stage's depth-first code object order
+    pub index: usize,
+    pub name: String,
+    #[serde(flatten)]
+    pub counts: InstructionCounts,
+}
+
+/// Deobfuscation metrics for a single stage of a module. The counts are the
+/// sums of the per-function counts.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StageStats {
+    pub stage: String,
+    pub code_objects: usize,
+    #[serde(flatten)]
+    pub counts: InstructionCounts,
+    pub functions: Vec<FunctionStats>,
+}
+
+impl StageStats {
+    /// Compares the original and deobfuscated data of a stage, function by
+    /// function
+    pub fn collect<O: Opcode<Mnemonic = py27::Mnemonic>>(
+        stage: &str,
+        before: &[u8],
+        after: &[u8],
+    ) -> Result<StageStats> {
+        let pairs = paired_code_objects(before, after)?;
+        let mut stats = StageStats {
+            stage: stage.to_string(),
+            code_objects: pairs.len(),
+            ..Default::default()
+        };
+
+        for (index, (before, after)) in pairs.iter().enumerate() {
+            let diff = CfgDiff::<O>::new(before.code.as_slice(), after.code.as_slice());
+            let function = FunctionStats {
+                index,
+                name: before.name.to_string(),
+                counts: InstructionCounts::of(&diff),
+            };
+
+            stats.counts.add(&function.counts);
+            stats.functions.push(function);
+        }
+
+        Ok(stats)
+    }
+}
+
+/// An obfuscation layer peeled off of a module
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerStats {
+    /// Name of the stage the layer decoded to
+    pub stage: String,
+    /// Name of the decoder which peeled the layer
+    pub decoder: &'static str,
+    /// Size of the decoded stage's marshalled code
+    pub size: usize,
+}
+
+/// Deobfuscation metrics for every stage of a module that was deobfuscated
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModuleStats {
+    /// Path of the module, relative to the output directory
+    pub module: PathBuf,
+    /// Name of the profile the module was unpacked with, if it was encrypted
+    pub profile: Option<String>,
+    /// Index of the const used as the stage1 key, if the module was encrypted
+    pub stage1_key_index: Option<usize>,
+    /// Layers peeled off of the module, outermost first
+    pub layers: Vec<LayerStats>,
+    /// Calls to string decoding helpers in the innermost stage which were
+    /// replaced with their result
+    pub strings_inlined: usize,
+    /// Shuffled stack sequences in the deobfuscated innermost stage which were
+    /// put back in order
+    pub stack_sequences_reordered: usize,
+    pub stages: Vec<StageStats>,
+    /// Structural problems found in the deobfuscated stages
+    pub validation_issues: Vec<ValidationIssue>,
+    /// Comparison of the innermost stage's pure functions before and after
+    /// deobfuscation
+    pub differential: Option<DifferentialReport>,
+    /// Anti-decompiler tricks found in each stage, by function
+    pub tricks: Vec<FunctionTricks>,
+    /// Anti-decompiler tricks removed from the innermost stage
+    pub tricks_normalized: usize,
+    /// Why the module's layers could not be unpacked. `layers` lists the
+    /// layers peeled before the failure, and none of the stages are
+    /// deobfuscated.
+    pub unpack_error: Option<String>,
+}
+
+/// A module which could not be dumped
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleError {
+    pub module: String,
+    pub error: String,
+}
+
+/// Summary of a single run, written to `manifest.json` in the output directory
+#[derive(Debug, Serialize)]
+pub struct RunManifest<'a> {
+    pub input: &'a Path,
+    pub passes: &'a PassConfig,
+    pub extracted_files: usize,
+    pub modules: &'a [ModuleStats],
+    pub errors: &'a [ModuleError],
+}
+
+impl RunManifest<'_> {
+    /// Writes the manifest to `output_dir`
+    pub fn write(&self, output_dir: &Path) -> Result<()> {
+        let serialized_data = serde_json::to_string_pretty(self)?;
+        std::fs::write(
+            output_dir.join(MANIFEST_FILE_NAME),
+            serialized_data.as_bytes(),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// A row of the archive-wide statistics CSV
+#[derive(Debug, Serialize)]
+struct StatsRow<'a> {
+    module: &'a Path,
+    stage: &'a str,
+    code_objects: usize,
+    instructions_before: usize,
+    instructions_after: usize,
+    /// Percentage of instructions removed by deobfuscation
+    instructions_removed_pct: f64,
+    const_predicates_removed: usize,
+    predicate_instructions_removed: usize,
+    jumps_removed: usize,
+    garbage_instructions_removed: usize,
+    instructions_added: usize,
+    returns_deoptimized: usize,
+}
+
+/// Writes one row per deobfuscated stage of every module to `stats.csv` in
+/// `output_dir`. Rows are sorted by module path.
+pub fn write_csv(output_dir: &Path, modules: &[ModuleStats]) -> Result<()> {
+    let mut modules: Vec<&ModuleStats> = modules.iter().collect();
+    modules.sort_by(|a, b| a.module.cmp(&b.module));
+
+    let mut writer = csv::WriterBuilder::new().from_path(output_dir.join(STATS_FILE_NAME))?;
+    for module in modules {
+        for stage in &module.stages {
+            let counts = &stage.counts;
+            let instructions_removed = counts
+                .instructions_before
+                .saturating_sub(counts.instructions_after);
+            let instructions_removed_pct = if counts.instructions_before == 0 {
+                0.0
+            } else {
+                100.0 * instructions_removed as f64 / counts.instructions_before as f64
+            };
+
+            writer.serialize(StatsRow {
+                module: &module.module,
+                stage: &stage.stage,
+                code_objects: stage.code_objects,
+                instructions_before: counts.instructions_before,
+                instructions_after: counts.instructions_after,
+                instructions_removed_pct,
+                const_predicates_removed: counts.const_predicates_removed,
+                predicate_instructions_removed: counts.predicate_instructions_removed,
+                jumps_removed: counts.jumps_removed,
+                garbage_instructions_removed: counts.garbage_instructions_removed,
+                instructions_added: counts.instructions_added,
+                returns_deoptimized: counts.returns_deoptimized,
+            })?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::load_code;
+    use crate::profiles::ProfileSet;
+    use pydis::opcode::py27::Standard;
+    use std::path::Path;
+
+    const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/obfuscated/compiler");
+
+    fn assert_counts_add_up(counts: &InstructionCounts) {
+        assert_eq!(
+            counts.instructions_before + counts.instructions_added,
+            counts.instructions_after
+                + counts.predicate_instructions_removed
+                + counts.jumps_removed
+                + counts.garbage_instructions_removed,
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn removed_instructions_add_up_to_the_size_difference() {
+        let profiles = ProfileSet::builtin();
+        let profile = &profiles.profiles[0];
+
+        let path = Path::new(FIXTURE_DIR).join("ast.pyc");
+        let data = std::fs::read(path).unwrap();
+        let stage1 = load_code(&data[8..]).unwrap();
+        let stage2 = crate::stage1::decrypt(&stage1, &profile.stage1).unwrap().0;
+        let deobfuscated = unfuck::Deobfuscator::<Standard>::new(&stage2)
+            .deobfuscate()
+            .unwrap()
+            .data;
+
+        let stats = StageStats::collect::<Standard>("stage2", &stage2, &deobfuscated).unwrap();
+        assert_eq!(stats.functions.len(), stats.code_objects);
+        assert!(stats.counts.const_predicates_removed > 0);
+        assert!(stats.counts.garbage_instructions_removed > 0);
+        assert_counts_add_up(&stats.counts);
+        for function in &stats.functions {
+            assert_counts_add_up(&function.counts);
+        }
+    }
+
+    #[test]
+    fn stages_with_different_code_objects_are_not_compared() {
+        let data = std::fs::read(Path::new(FIXTURE_DIR).join("ast.pyc")).unwrap();
+        let profiles = ProfileSet::builtin();
+        let stage1 = load_code(&data[8..]).unwrap();
+        let stage2 = crate::stage1::decrypt(&stage1, &profiles.profiles[0].stage1)
+            .unwrap()
+            .0;
+
+        let err = StageStats::collect::<Standard>("stage2", &data[8..], &stage2).unwrap_err();
+        assert!(err.to_string().contains("code objects"), "{}", err);
+    }
+}
@@ -0,0 +1,482 @@
+use crate::bytecode::{
+    code_objects, decode_at, load_code, opcode_byte, replace_bytecode, DecodedInstr,
+};
+use crate::cfg::FlowGraph;
+use anyhow::Result;
+use py27_marshal::Code;
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+/// A pattern which trips up disassemblers and decompilers but doesn't change
+/// what the code does
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrickKind {
+    /// A jump lands inside another instruction which is also executed, so the
+    /// same bytes are decoded as two different instructions
+    JumpIntoInstruction,
+    /// An `EXTENDED_ARG` prefix which isn't needed, or which precedes an opcode
+    /// that takes no argument
+    ExtendedArg,
+    /// Bytes which aren't a valid instruction, either in code which is never
+    /// executed or where execution may reach them
+    InvalidOpcode,
+    /// Exception handling blocks which partially overlap, or whose handler
+    /// comes before the block it handles
+    OverlappingHandlers,
+}
+
+impl fmt::Display for TrickKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TrickKind::JumpIntoInstruction => "jump into instruction",
+            TrickKind::ExtendedArg => "EXTENDED_ARG abuse",
+            TrickKind::InvalidOpcode => "invalid opcode",
+            TrickKind::OverlappingHandlers => "overlapping handlers",
+        })
+    }
+}
+
+/// An anti-decompiler trick found in a function
+#[derive(Debug, Clone, Serialize)]
+pub struct Trick {
+    pub offset: u64,
+    pub kind: TrickKind,
+    pub message: String,
+    /// Whether `--enable-pass normalize-tricks` removes this trick
+    pub normalizable: bool,
+}
+
+/// The anti-decompiler tricks found in a single function
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionTricks {
+    pub stage: String,
+    /// Position of the function in the stage's depth-first code object order
+    pub function_index: usize,
+    pub function: String,
+    pub tricks: Vec<Trick>,
+}
+
+/// The result of normalizing a stage's tricks
+pub struct NormalizedTricks {
+    /// Marshalled code of the stage with the tricks removed
+    pub data: Vec<u8>,
+    /// Number of tricks which were removed
+    pub tricks: usize,
+}
+
+/// Finds the anti-decompiler tricks in every function of the marshalled code
+/// in `data`. Functions without any tricks are not listed.
+pub fn detect<O: Opcode<Mnemonic = Mnemonic>>(
+    stage: &str,
+    data: &[u8],
+) -> Result<Vec<FunctionTricks>> {
+    let module = load_code(data)?;
+
+    Ok(code_objects(&module)
+        .iter()
+        .enumerate()
+        .filter_map(|(index, code)| {
+            let tricks: Vec<Trick> = analyze::<O>(code)
+                .into_iter()
+                .map(|(trick, _)| trick)
+                .collect();
+            (!tricks.is_empty()).then(|| FunctionTricks {
+                stage: stage.to_string(),
+                function_index: index,
+                function: code.name.to_string(),
+                tricks,
+            })
+        })
+        .collect())
+}
+
+/// Removes the tricks which can be removed without moving any instructions:
+/// bytes which are never executed are replaced with `NOP`s, as are
+/// `EXTENDED_ARG` prefixes whose argument is zero. Returns `None` if there
+/// was nothing to remove.
+pub fn normalize<O: Opcode<Mnemonic = Mnemonic>>(data: &[u8]) -> Result<Option<NormalizedTricks>> {
+    let module = load_code(data)?;
+    let nop = opcode_byte::<O>(Mnemonic::NOP)?;
+
+    let mut codes = Vec::new();
+    let mut tricks = 0;
+    for code in code_objects(&module) {
+        let nops: Vec<Range<u64>> = analyze::<O>(&code)
+            .into_iter()
+            .filter_map(|(_, nops)| nops)
+            .collect();
+        if nops.is_empty() {
+            codes.push(None);
+            continue;
+        }
+
+        tricks += nops.len();
+        let mut new_code = code.code.to_vec();
+        for range in nops {
+            new_code[range.start as usize..range.end as usize].fill(nop);
+        }
+        codes.push(Some(new_code));
+    }
+
+    if tricks == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(NormalizedTricks {
+        data: replace_bytecode(data, &codes)?,
+        tricks,
+    }))
+}
+
+/// Finds the tricks in a single code object, ignoring any nested code
+/// objects. Each trick is paired with the bytes to replace with `NOP`s to
+/// remove it, if that is safe.
+fn analyze<O: Opcode<Mnemonic = Mnemonic>>(code: &Code) -> Vec<(Trick, Option<Range<u64>>)> {
+    let bytecode = code.code.as_slice();
+    let flow_graph = FlowGraph::<O>::from_bytecode(bytecode);
+
+    let mut reachable = BTreeMap::new();
+    let mut bad_offsets = Vec::new();
+    for block in flow_graph.graph.node_weights() {
+        for instr in &block.instrs {
+            reachable.insert(instr.offset, instr);
+        }
+        if block.has_bad_instr {
+            bad_offsets.push(block.end);
+        }
+    }
+
+    let mut tricks = Vec::new();
+    let mut trick = |offset, kind, message: String, nops: Option<Range<u64>>| {
+        tricks.push((
+            Trick {
+                offset,
+                kind,
+                message,
+                normalizable: nops.is_some(),
+            },
+            nops,
+        ))
+    };
+
+    for instr in reachable.values() {
+        // Jumps into the middle of another executed instruction
+        if let Some(target) = instr.jump_target() {
+            if let Some((_, overlapped)) = reachable.range(..target).next_back() {
+                if overlapped.next_offset() > target {
+                    trick(
+                        instr.offset,
+                        TrickKind::JumpIntoInstruction,
+                        format!(
+                            "`{}` jumps to {}, inside `{}` at {}",
+                            instr, target, overlapped, overlapped.offset
+                        ),
+                        None,
+                    );
+                }
+            }
+        }
+
+        let prefix_len = instr.len - if instr.arg.is_some() { 3 } else { 1 };
+        if prefix_len == 0 {
+            continue;
+        }
+        match instr.arg {
+            None => trick(
+                instr.offset,
+                TrickKind::ExtendedArg,
+                format!("EXTENDED_ARG precedes `{}`, which takes no argument", instr),
+                None,
+            ),
+            Some(arg) if arg <= u16::MAX as u32 => trick(
+                instr.offset,
+                TrickKind::ExtendedArg,
+                format!("`{}` has an EXTENDED_ARG prefix of zero", instr),
+                Some(instr.offset..instr.offset + prefix_len),
+            ),
+            Some(_) => {}
+        }
+    }
+
+    for &offset in &bad_offsets {
+        let message = if offset >= bytecode.len() as u64 {
+            format!(
+                "execution may continue to {}, past the end of the code",
+                offset
+            )
+        } else {
+            format!(
+                "execution may reach invalid opcode {}",
+                bytecode[offset as usize]
+            )
+        };
+        trick(offset, TrickKind::InvalidOpcode, message, None);
+    }
+
+    for range in dead_ranges(&reachable, bytecode.len() as u64) {
+        // Execution may reach bytes right after a branch which falls through
+        // into garbage, so they are not dead
+        if bad_offsets.iter().any(|offset| range.contains(offset)) {
+            continue;
+        }
+        if let Some(offset) = first_invalid::<O>(bytecode, range.clone()) {
+            trick(
+                offset,
+                TrickKind::InvalidOpcode,
+                format!(
+                    "bytes {}..{} are never executed and contain invalid opcode {}",
+                    range.start, range.end, bytecode[offset as usize]
+                ),
+                Some(range),
+            );
+        }
+    }
+
+    let setups: Vec<(&DecodedInstr<O>, Range<u64>)> = reachable
+        .values()
+        .filter(|instr| {
+            // The compiler's own loop blocks may partially overlap when
+            // loops end with the same jump
+            matches!(
+                instr.opcode.mnemonic(),
+                Mnemonic::SETUP_EXCEPT | Mnemonic::SETUP_FINALLY | Mnemonic::SETUP_WITH
+            )
+        })
+        .filter_map(|instr| Some((*instr, instr.next_offset()..instr.jump_target()?)))
+        .collect();
+    for (index, (setup, block)) in setups.iter().enumerate() {
+        if block.end <= setup.offset {
+            trick(
+                setup.offset,
+                TrickKind::OverlappingHandlers,
+                format!(
+                    "`{}` has its handler at {}, before the block",
+                    setup, block.end
+                ),
+                None,
+            );
+            continue;
+        }
+
+        for (other, other_block) in &setups[index + 1..] {
+            let partial = (block.start < other_block.start
+                && other_block.start < block.end
+                && block.end < other_block.end)
+                || (other_block.start < block.start
+                    && block.start < other_block.end
+                    && other_block.end < block.end);
+            if partial {
+                trick(
+                    setup.offset,
+                    TrickKind::OverlappingHandlers,
+                    format!(
+                        "the block set up by `{}` ({}..{}) partially overlaps the block set up by `{}` at {} ({}..{})",
+                        setup,
+                        block.start,
+                        block.end,
+                        other,
+                        other.offset,
+                        other_block.start,
+                        other_block.end
+                    ),
+                    None,
+                );
+            }
+        }
+    }
+
+    tricks.sort_by_key(|(trick, _)| trick.offset);
+    tricks
+}
+
+/// Returns the ranges of bytes which are not part of any reachable
+/// instruction
+fn dead_ranges<O: Opcode<Mnemonic = Mnemonic>>(
+    reachable: &BTreeMap<u64, &DecodedInstr<O>>,
+    len: u64,
+) -> Vec<Range<u64>> {
+    let mut ranges = Vec::new();
+    let mut covered_to = 0;
+    for instr in reachable.values() {
+        if instr.offset > covered_to {
+            ranges.push(covered_to..instr.offset);
+        }
+        covered_to = covered_to.max(instr.next_offset());
+    }
+    if covered_to < len {
+        ranges.push(covered_to..len);
+    }
+
+    ranges
+}
+
+/// Decodes `range` of `bytecode` linearly and returns the offset of the first
+/// byte which is not a valid instruction, if any
+fn first_invalid<O: Opcode<Mnemonic = Mnemonic>>(
+    bytecode: &[u8],
+    range: Range<u64>,
+) -> Option<u64> {
+    let mut offset = range.start;
+    while offset < range.end {
+        match decode_at::<O>(bytecode, offset) {
+            Ok(instr) => offset = instr.next_offset(),
+            Err(_) => return Some(offset),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::{assemble, compile};
+    use crate::bytecode::{rewrite_code_objects, CodeOverrides};
+    use num_traits::FromPrimitive;
+    use pydis::opcode::py27::Standard::{self, *};
+
+    /// A module whose code is replaced with `code`
+    fn module(code: Vec<u8>) -> Vec<u8> {
+        let overrides = CodeOverrides {
+            code: Some(code),
+            ..Default::default()
+        };
+
+        rewrite_code_objects(&compile("x = 1\n"), &[overrides]).unwrap()
+    }
+
+    /// The kind, offset, and whether it's normalizable of each trick found in
+    /// the module's code
+    fn tricks(data: &[u8]) -> Vec<(TrickKind, u64, bool)> {
+        detect::<Standard>("stage", data)
+            .unwrap()
+            .into_iter()
+            .flat_map(|function| {
+                assert_eq!(function.function_index, 0);
+                function.tricks
+            })
+            .map(|trick| (trick.kind, trick.offset, trick.normalizable))
+            .collect()
+    }
+
+    /// The module's code after normalizing, or `None` if nothing changed
+    fn normalized(data: &[u8]) -> Option<Vec<u8>> {
+        let normalized = normalize::<Standard>(data).unwrap()?;
+        Some(load_code(&normalized.data).unwrap().code.to_vec())
+    }
+
+    fn invalid_opcode() -> u8 {
+        (0..=u8::MAX)
+            .find(|&byte| Standard::from_u8(byte).is_none())
+            .unwrap()
+    }
+
+    #[test]
+    fn compiled_code_has_no_tricks() {
+        let data = compile(concat!(
+            "def f(a):\n",
+            "    try:\n",
+            "        with a:\n",
+            "            return [x for x in a]\n",
+            "    finally:\n",
+            "        pass\n",
+        ));
+
+        assert!(detect::<Standard>("stage", &data).unwrap().is_empty());
+        assert!(normalize::<Standard>(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn jumps_into_executed_instructions_are_detected() {
+        // The argument of the `LOAD_CONST` is also a `RETURN_VALUE`
+        let return_value = opcode_byte::<Standard>(Mnemonic::RETURN_VALUE).unwrap() as u16;
+        let data = module(assemble(&[
+            (LOAD_CONST, Some(return_value)),
+            (JUMP_ABSOLUTE, Some(1)),
+        ]));
+
+        assert_eq!(tricks(&data), [(TrickKind::JumpIntoInstruction, 3, false)]);
+        assert_eq!(normalized(&data), None);
+    }
+
+    #[test]
+    fn zero_extended_args_are_removed() {
+        let data = module(assemble(&[
+            (EXTENDED_ARG, Some(0)),
+            (LOAD_CONST, Some(0)),
+            (RETURN_VALUE, None),
+        ]));
+
+        assert_eq!(tricks(&data), [(TrickKind::ExtendedArg, 0, true)]);
+        assert_eq!(
+            normalized(&data).unwrap(),
+            assemble(&[
+                (NOP, None),
+                (NOP, None),
+                (NOP, None),
+                (LOAD_CONST, Some(0)),
+                (RETURN_VALUE, None),
+            ])
+        );
+    }
+
+    #[test]
+    fn extended_args_before_opcodes_without_arguments_are_kept() {
+        let data = module(assemble(&[
+            (LOAD_CONST, Some(0)),
+            (EXTENDED_ARG, Some(0)),
+            (RETURN_VALUE, None),
+        ]));
+
+        assert_eq!(tricks(&data), [(TrickKind::ExtendedArg, 3, false)]);
+        assert_eq!(normalized(&data), None);
+    }
+
+    #[test]
+    fn invalid_opcodes_which_are_never_executed_are_removed() {
+        let mut code = assemble(&[(LOAD_CONST, Some(0)), (RETURN_VALUE, None)]);
+        code.push(invalid_opcode());
+        let data = module(code);
+
+        assert_eq!(tricks(&data), [(TrickKind::InvalidOpcode, 4, true)]);
+        assert_eq!(
+            normalized(&data).unwrap(),
+            assemble(&[(LOAD_CONST, Some(0)), (RETURN_VALUE, None), (NOP, None)])
+        );
+    }
+
+    #[test]
+    fn invalid_opcodes_which_may_be_executed_are_kept() {
+        let mut code = assemble(&[
+            (LOAD_CONST, Some(0)),
+            (POP_JUMP_IF_FALSE, Some(7)),
+            (RETURN_VALUE, None),
+        ]);
+        code.push(invalid_opcode());
+        let data = module(code);
+
+        assert_eq!(tricks(&data), [(TrickKind::InvalidOpcode, 7, false)]);
+        assert_eq!(normalized(&data), None);
+    }
+
+    #[test]
+    fn partially_overlapping_handlers_are_detected() {
+        let data = module(assemble(&[
+            (SETUP_EXCEPT, Some(6)),
+            (SETUP_FINALLY, Some(7)),
+            (LOAD_CONST, Some(0)),
+            (LOAD_CONST, Some(0)),
+            (RETURN_VALUE, None),
+            (LOAD_CONST, Some(0)),
+            (RETURN_VALUE, None),
+        ]));
+
+        assert_eq!(tricks(&data), [(TrickKind::OverlappingHandlers, 0, false)]);
+        assert_eq!(normalized(&data), None);
+    }
+}
@@ -0,0 +1,485 @@
+use crate::bytecode::{code_objects, decode_at, load_code, stack_depths, DecodedInstr};
+use cpython::{PyBytes, PyDict, PyList, PyResult, Python};
+use py27_marshal::Code;
+use pydis::opcode::py27::Mnemonic;
+use pydis::opcode::Opcode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Number of comparisons `COMPARE_OP` supports in Python 2.7
+const COMPARE_OPS: u32 = 11;
+
+/// Python which loads the marshalled code in `data`, dumps it again, and
+/// loads the result. `codes` is set to the bytecode of every code object in
+/// both loaded copies, in depth-first order.
+const ROUND_TRIP_SOURCE: &str = r#"
+def walk(code):
+    out = [code.co_code]
+    for c in code.co_consts:
+        if type(c) == types.CodeType:
+            out.extend(walk(c))
+    return out
+
+code = marshal.loads(data)
+if type(code) != types.CodeType:
+    raise TypeError("root object is a %s, not code" % type(code).__name__)
+codes = [walk(code), walk(marshal.loads(marshal.dumps(code)))]
+"#;
+
+/// The kind of problem a validation issue describes
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IssueKind {
+    /// The bytecode could not be decoded, or uses an opcode the compiler
+    /// never emits
+    BadInstruction,
+    /// A jump lands outside of the code or in the middle of an instruction
+    BadJumpTarget,
+    /// Execution can continue past the last instruction
+    FallsOffEnd,
+    /// An instruction pops more values than are on the stack
+    StackUnderflow,
+    /// The stack can grow deeper than `co_stacksize`
+    StackOverflow,
+    /// An instruction's argument indexes past the end of a table
+    IndexOutOfRange,
+    /// The marshalled data does not survive being loaded and dumped by Python
+    RoundTrip,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IssueKind::BadInstruction => "bad instruction",
+            IssueKind::BadJumpTarget => "bad jump target",
+            IssueKind::FallsOffEnd => "falls off end",
+            IssueKind::StackUnderflow => "stack underflow",
+            IssueKind::StackOverflow => "stack overflow",
+            IssueKind::IndexOutOfRange => "index out of range",
+            IssueKind::RoundTrip => "marshal round trip",
+        })
+    }
+}
+
+/// A structural problem found in a deobfuscated stage
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationIssue {
+    pub stage: String,
+    /// Position of the offending function in the stage's depth-first code
+    /// object order. `None` if the problem is with the stage as a whole.
+    pub function_index: Option<usize>,
+    pub function: Option<String>,
+    /// Offset of the offending instruction
+    pub offset: Option<u64>,
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.stage)?;
+        if let (Some(index), Some(function)) = (self.function_index, &self.function) {
+            write!(f, " function {} ({})", index, function)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+
+        write!(f, ": {}: {}", self.kind, self.message)
+    }
+}
+
+/// Checks that the marshalled code in `data` is structurally sound: every
+/// instruction decodes, jumps land on instruction boundaries, the stack stays
+/// within `co_stacksize`, table indices are in range, and Python can load and
+/// dump the data again. Returns every problem found.
+pub fn validate<O: Opcode<Mnemonic = Mnemonic>>(stage: &str, data: &[u8]) -> Vec<ValidationIssue> {
+    let stage_issue = |kind, message: String| ValidationIssue {
+        stage: stage.to_string(),
+        function_index: None,
+        function: None,
+        offset: None,
+        kind,
+        message,
+    };
+
+    let code = match load_code(data) {
+        Ok(code) => code,
+        Err(e) => {
+            return vec![stage_issue(
+                IssueKind::RoundTrip,
+                format!("failed to unmarshal the stage: {}", e),
+            )]
+        }
+    };
+    let code_objects = code_objects(&code);
+
+    let mut issues = Vec::new();
+    for (index, code) in code_objects.iter().enumerate() {
+        issues.extend(
+            validate_code::<O>(code)
+                .into_iter()
+                .map(|(offset, kind, message)| ValidationIssue {
+                    stage: stage.to_string(),
+                    function_index: Some(index),
+                    function: Some(code.name.to_string()),
+                    offset,
+                    kind,
+                    message,
+                }),
+        );
+    }
+
+    let expected: Vec<&[u8]> = code_objects
+        .iter()
+        .map(|code| code.code.as_slice())
+        .collect();
+    match round_trip(data) {
+        Ok([loaded, reloaded]) => {
+            if loaded != expected {
+                issues.push(stage_issue(
+                    IssueKind::RoundTrip,
+                    "Python loads different code objects than were written".to_string(),
+                ));
+            } else if reloaded != expected {
+                issues.push(stage_issue(
+                    IssueKind::RoundTrip,
+                    "code objects change after being dumped by Python".to_string(),
+                ));
+            }
+        }
+        Err(e) => issues.push(stage_issue(
+            IssueKind::RoundTrip,
+            format!("Python failed to load and dump the stage: {}", e),
+        )),
+    }
+
+    issues
+}
+
+/// Validates a single code object, ignoring any nested code objects
+fn validate_code<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+) -> Vec<(Option<u64>, IssueKind, String)> {
+    let mut issues = Vec::new();
+    let bytecode = code.code.as_slice();
+
+    let mut instrs = BTreeMap::new();
+    let mut offset = 0;
+    while offset < bytecode.len() as u64 {
+        match decode_at::<O>(bytecode, offset) {
+            Ok(instr) => {
+                offset = instr.next_offset();
+                instrs.insert(instr.offset, instr);
+            }
+            Err(e) => {
+                issues.push((
+                    Some(offset),
+                    IssueKind::BadInstruction,
+                    format!("failed to decode instruction: {:?}", e),
+                ));
+                break;
+            }
+        }
+    }
+
+    for instr in instrs.values() {
+        if let Some(target) = instr.jump_target() {
+            if !instrs.contains_key(&target) {
+                issues.push((
+                    Some(instr.offset),
+                    IssueKind::BadJumpTarget,
+                    format!(
+                        "`{}` jumps to {}, which is not an instruction",
+                        instr, target
+                    ),
+                ));
+            }
+        }
+
+        if let Some((table, len)) = indexed_table(code, instr.opcode.mnemonic()) {
+            let index = instr.arg.unwrap_or(0);
+            if index as usize >= len {
+                issues.push((
+                    Some(instr.offset),
+                    IssueKind::IndexOutOfRange,
+                    format!("`{}` indexes {} which has {} entries", instr, table, len),
+                ));
+            }
+        }
+    }
+
+    issues.extend(check_stack(code, &instrs));
+
+    issues
+}
+
+/// Returns the name and length of the table the argument of `mnemonic`
+/// indexes, if any
+fn indexed_table(code: &Code, mnemonic: Mnemonic) -> Option<(&'static str, usize)> {
+    Some(match mnemonic {
+        Mnemonic::LOAD_CONST => ("co_consts", code.consts.len()),
+        Mnemonic::LOAD_NAME
+        | Mnemonic::STORE_NAME
+        | Mnemonic::DELETE_NAME
+        | Mnemonic::LOAD_ATTR
+        | Mnemonic::STORE_ATTR
+        | Mnemonic::DELETE_ATTR
+        | Mnemonic::LOAD_GLOBAL
+        | Mnemonic::STORE_GLOBAL
+        | Mnemonic::DELETE_GLOBAL
+        | Mnemonic::IMPORT_NAME
+        | Mnemonic::IMPORT_FROM => ("co_names", code.names.len()),
+        Mnemonic::LOAD_FAST | Mnemonic::STORE_FAST | Mnemonic::DELETE_FAST => {
+            ("co_varnames", code.varnames.len())
+        }
+        Mnemonic::LOAD_CLOSURE | Mnemonic::LOAD_DEREF | Mnemonic::STORE_DEREF => (
+            "co_cellvars + co_freevars",
+            code.cellvars.len() + code.freevars.len(),
+        ),
+        Mnemonic::COMPARE_OP => ("the comparison operators", COMPARE_OPS as usize),
+        _ => return None,
+    })
+}
+
+/// Simulates the stack depth of the code against its `co_stacksize`. Only the
+/// first underflow and overflow are reported.
+fn check_stack<O: Opcode<Mnemonic = Mnemonic>>(
+    code: &Code,
+    instrs: &BTreeMap<u64, DecodedInstr<O>>,
+) -> Vec<(Option<u64>, IssueKind, String)> {
+    let mut issues = Vec::new();
+    let stacksize = code.stacksize as isize;
+    let depths = stack_depths(instrs, code.code.len() as u64, stacksize);
+
+    for offset in &depths.unknown_effects {
+        issues.push((
+            Some(*offset),
+            IssueKind::BadInstruction,
+            format!("`{}` is not emitted by the compiler", instrs[offset]),
+        ));
+    }
+    if let Some(offset) = depths.falls_off_end {
+        issues.push((
+            Some(offset),
+            IssueKind::FallsOffEnd,
+            format!("`{}` continues past the end of the code", instrs[&offset]),
+        ));
+    }
+    if let Some((offset, depth)) = depths.underflow {
+        issues.push((
+            Some(offset),
+            IssueKind::StackUnderflow,
+            format!("`{}` leaves the stack at depth {}", instrs[&offset], depth),
+        ));
+    }
+    if let Some((offset, depth)) = depths.overflow {
+        issues.push((
+            Some(offset),
+            IssueKind::StackOverflow,
+            format!(
+                "`{}` grows the stack to depth {}, but co_stacksize is {}",
+                instrs[&offset], depth, stacksize
+            ),
+        ));
+    }
+
+    issues
+}
+
+/// Loads `data` with Python's marshal, dumps it, and loads it again. Returns
+/// the bytecode of every code object in the first and second loaded copies,
+/// or the exception Python raised.
+fn round_trip(data: &[u8]) -> Result<[Vec<Vec<u8>>; 2], String> {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    run_round_trip(py, data).map_err(|mut e| e.instance(py).to_string())
+}
+
+fn run_round_trip(py: Python, data: &[u8]) -> PyResult<[Vec<Vec<u8>>; 2]> {
+    let globals = PyDict::new(py);
+    globals.set_item(py, "__builtins__", py.import("__builtin__")?)?;
+    globals.set_item(py, "marshal", py.import("marshal")?)?;
+    globals.set_item(py, "types", py.import("types")?)?;
+    globals.set_item(py, "data", PyBytes::new(py, data))?;
+    py.run(ROUND_TRIP_SOURCE, Some(&globals), None)?;
+
+    let codes = globals
+        .get_item(py, "codes")
+        .expect("the round trip script sets `codes`");
+    let codes = codes.cast_as::<PyList>(py)?;
+    let copy = |index: usize| -> PyResult<Vec<Vec<u8>>> {
+        let copy = codes.get_item(py, index);
+        copy.cast_as::<PyList>(py)?
+            .iter(py)
+            .map(|code| Ok(code.cast_as::<PyBytes>(py)?.data(py).to_vec()))
+            .collect()
+    };
+
+    Ok([copy(0)?, copy(1)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::testing::{assemble, compile};
+    use crate::bytecode::{rewrite_code_objects, CodeOverrides};
+    use num_traits::FromPrimitive;
+    use pydis::opcode::py27::Standard::{self, *};
+
+    /// A module whose code is replaced with `instrs`. Its consts are `1` and
+    /// `None`.
+    fn module(instrs: &[(Standard, Option<u16>)], stacksize: Option<u32>) -> Vec<u8> {
+        let overrides = CodeOverrides {
+            code: Some(assemble(instrs)),
+            stacksize,
+            ..Default::default()
+        };
+
+        rewrite_code_objects(&compile("x = 1\n"), &[overrides]).unwrap()
+    }
+
+    fn kinds(data: &[u8]) -> Vec<IssueKind> {
+        validate::<Standard>("stage", data)
+            .into_iter()
+            .map(|issue| issue.kind)
+            .collect()
+    }
+
+    #[test]
+    fn compiled_code_is_valid() {
+        let data = compile("def f(a):\n    for x in a:\n        print x\n    return a\n");
+
+        assert_eq!(kinds(&data), []);
+    }
+
+    #[test]
+    fn undecodable_opcodes_are_bad_instructions() {
+        let byte = (0..=u8::MAX)
+            .find(|&byte| Standard::from_u8(byte).is_none())
+            .unwrap();
+        let mut code = assemble(&[(LOAD_CONST, Some(0)), (RETURN_VALUE, None)]);
+        code.insert(0, byte);
+        let overrides = CodeOverrides {
+            code: Some(code),
+            ..Default::default()
+        };
+        let data = rewrite_code_objects(&compile("x = 1\n"), &[overrides]).unwrap();
+
+        let issues = validate::<Standard>("stage", &data);
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, IssueKind::BadInstruction);
+        assert_eq!(issues[0].offset, Some(0));
+        assert_eq!(issues[0].function_index, Some(0));
+    }
+
+    #[test]
+    fn opcodes_the_compiler_never_emits_are_bad_instructions() {
+        let data = module(
+            &[
+                (STOP_CODE, None),
+                (LOAD_CONST, Some(0)),
+                (RETURN_VALUE, None),
+            ],
+            None,
+        );
+
+        assert_eq!(kinds(&data), [IssueKind::BadInstruction]);
+    }
+
+    #[test]
+    fn jumps_into_instructions_are_bad_jump_targets() {
+        let data = module(
+            &[
+                (LOAD_CONST, Some(0)),
+                (JUMP_ABSOLUTE, Some(1)),
+                (RETURN_VALUE, None),
+            ],
+            None,
+        );
+
+        let issues = validate::<Standard>("stage", &data);
+
+        assert_eq!(issues[0].kind, IssueKind::BadJumpTarget);
+        assert_eq!(issues[0].offset, Some(3));
+    }
+
+    #[test]
+    fn code_without_a_return_falls_off_the_end() {
+        let data = module(&[(LOAD_CONST, Some(0)), (POP_TOP, None)], None);
+
+        assert_eq!(kinds(&data), [IssueKind::FallsOffEnd]);
+    }
+
+    #[test]
+    fn popping_an_empty_stack_is_an_underflow() {
+        let data = module(
+            &[(POP_TOP, None), (LOAD_CONST, Some(0)), (RETURN_VALUE, None)],
+            None,
+        );
+
+        let issues = validate::<Standard>("stage", &data);
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, IssueKind::StackUnderflow);
+        assert_eq!(issues[0].offset, Some(0));
+    }
+
+    #[test]
+    fn growing_past_the_stacksize_is_an_overflow() {
+        let data = module(
+            &[
+                (LOAD_CONST, Some(0)),
+                (LOAD_CONST, Some(1)),
+                (BINARY_ADD, None),
+                (RETURN_VALUE, None),
+            ],
+            Some(1),
+        );
+
+        let issues = validate::<Standard>("stage", &data);
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, IssueKind::StackOverflow);
+        assert_eq!(issues[0].offset, Some(3));
+    }
+
+    #[test]
+    fn arguments_past_the_end_of_a_table_are_out_of_range() {
+        let data = module(
+            &[
+                (LOAD_CONST, Some(2)),
+                (LOAD_NAME, Some(1)),
+                (COMPARE_OP, Some(COMPARE_OPS as u16)),
+                (RETURN_VALUE, None),
+            ],
+            Some(2),
+        );
+
+        let issues = validate::<Standard>("stage", &data);
+
+        assert_eq!(
+            issues.iter().map(|issue| issue.kind).collect::<Vec<_>>(),
+            [IssueKind::IndexOutOfRange; 3]
+        );
+        assert_eq!(
+            issues.iter().map(|issue| issue.offset).collect::<Vec<_>>(),
+            [Some(0), Some(3), Some(6)]
+        );
+    }
+
+    #[test]
+    fn unloadable_data_fails_the_round_trip() {
+        let data = compile("x = 1\n");
+
+        let issues = validate::<Standard>("stage", &data[..data.len() - 1]);
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, IssueKind::RoundTrip);
+        assert_eq!(issues[0].function_index, None);
+        assert_eq!(issues[0].to_string().split(':').next(), Some("stage"));
+    }
+}
